@@ -0,0 +1,153 @@
+//! ANSI/VT100 escape sequence parser.
+//!
+//! Parser gets output characters one at a time and returns what the
+//! terminal should do. Supported sequences are `ESC [ parameters
+//! command` (CSI) and two character `ESC command` sequences. Parameters
+//! are decimal numbers separated by `;`, and missing parameters are 0.
+//! Malformed sequences are ignored.
+
+use arrayvec::ArrayVec;
+
+pub const ESCAPE: char = '\u{1B}';
+pub const MAX_PARAMETERS: usize = 8;
+
+pub type Parameters = ArrayVec<[u16; MAX_PARAMETERS]>;
+
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Printable character.
+    Print(char),
+    /// Control character, for example `\n` or `\r`.
+    Control(char),
+    /// Control sequence. `private` is true if the parameters start
+    /// with `?`.
+    Csi {
+        parameters: Parameters,
+        private: bool,
+        command: char,
+    },
+    /// `ESC` and a command character, for example `ESC 7`.
+    Escape(char),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    /// Too many parameters or an unknown character. Characters are
+    /// skipped until the end of the sequence.
+    IgnoreCsi,
+}
+
+#[derive(Debug)]
+pub struct Parser {
+    state: State,
+    parameters: Parameters,
+    /// Parameter which is being parsed.
+    current: Option<u16>,
+    private: bool,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Ground,
+            parameters: ArrayVec::new(),
+            current: None,
+            private: false,
+        }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                ESCAPE => {
+                    self.state = State::Escape;
+                    None
+                }
+                c if c.is_control() => Some(Action::Control(c)),
+                c => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.state = State::Csi;
+                    self.parameters.clear();
+                    self.current = None;
+                    self.private = false;
+                    None
+                }
+                ESCAPE => None,
+                c => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(c))
+                }
+            },
+            State::Csi | State::IgnoreCsi => self.advance_csi(c),
+        }
+    }
+
+    fn advance_csi(&mut self, c: char) -> Option<Action> {
+        match c {
+            '0'..='9' if self.state == State::Csi => {
+                let digit = c as u16 - '0' as u16;
+                let value = self.current.unwrap_or(0).saturating_mul(10).saturating_add(digit);
+                self.current = Some(value);
+                None
+            }
+            ';' if self.state == State::Csi => {
+                self.end_parameter();
+                None
+            }
+            '?' if self.state == State::Csi && self.parameters.is_empty() && self.current.is_none() => {
+                self.private = true;
+                None
+            }
+            // Final characters end the sequence.
+            '@'..='~' => {
+                let ignored = self.state == State::IgnoreCsi;
+                self.state = State::Ground;
+
+                if ignored {
+                    return None;
+                }
+
+                if self.current.is_some() || !self.parameters.is_empty() {
+                    self.end_parameter();
+                }
+
+                Some(Action::Csi {
+                    parameters: self.parameters.clone(),
+                    private: self.private,
+                    command: c,
+                })
+            }
+            // Cancel the sequence.
+            '\u{18}' | '\u{1A}' => {
+                self.state = State::Ground;
+                None
+            }
+            ESCAPE => {
+                self.state = State::Escape;
+                None
+            }
+            _ => {
+                self.state = State::IgnoreCsi;
+                None
+            }
+        }
+    }
+
+    fn end_parameter(&mut self) {
+        let value = self.current.take().unwrap_or(0);
+        if self.parameters.try_push(value).is_err() {
+            self.state = State::IgnoreCsi;
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
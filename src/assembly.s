@@ -43,12 +43,29 @@ interrupt_\number:
     push %ecx
     push %edx
 
+    # User mode can leave any selector to DS and ES, so store them and
+    # load the kernel data segment selector.
+    push %ds
+    push %es
+    mov $0x10, %eax
+    mov %ax, %ds
+    mov %ax, %es
+
     # Call function.
 
+    # Interrupt stack frame starts after saved registers.
+    lea 20(%esp), %eax
+    push %eax
     push $\number
-    # extern "C" fn rust_interrupt_handler(interrupt_number: u32)
+    # extern "C" fn rust_interrupt_handler(
+    #     interrupt_number: u32,
+    #     frame: &InterruptFrame)
     call rust_interrupt_handler
-    add $4, %esp
+    add $8, %esp
+
+    # Restore segment registers of the interrupted code.
+    pop %es
+    pop %ds
 
     # Restore general-purpose registers.
     mov (%esp), %edx
@@ -76,16 +93,32 @@ interrupt_with_error_\number:
     push %ecx
     push %edx
 
+    # User mode can leave any selector to DS and ES, so store them and
+    # load the kernel data segment selector.
+    push %ds
+    push %es
+    mov $0x10, %eax
+    mov %ax, %ds
+    mov %ax, %es
+
     # Call function
 
-    mov 12(%esp), %eax
+    # Interrupt stack frame starts after saved registers and error code.
+    lea 24(%esp), %eax
+    push %eax
+    mov 24(%esp), %eax
     push %eax
     push $\number
     # extern "C" fn rust_interrupt_handler_with_error(
     #     interrupt_number: u32,
-    #     error_code: u32)
+    #     error_code: u32,
+    #     frame: &InterruptFrame)
     call rust_interrupt_handler_with_error
-    add $8, %esp
+    add $12, %esp
+
+    # Restore segment registers of the interrupted code.
+    pop %es
+    pop %ds
 
     # Restore general-purpose registers.
     mov (%esp), %edx
@@ -103,6 +136,248 @@ interrupt_with_error_\number:
     iret
 .endm
 
+# User mode entry and exit
+
+# extern "C" fn enter_user_mode(
+#     saved_kernel_stack: *mut usize,
+#     registers: &UserRegisters)
+#
+# Saves callee-saved registers and EFLAGS to the current stack, stores
+# the stack pointer to `saved_kernel_stack` and jumps to ring 3 with
+# `iret`. Returns when `leave_user_mode` is called.
+#
+# `UserRegisters` field offsets: EAX 0, EBX 4, ECX 8, EDX 12, ESI 16,
+# EDI 20, EBP 24, EIP 28 and ESP 32.
+.text
+.global enter_user_mode
+enter_user_mode:
+    pushf
+    push %ebp
+    push %ebx
+    push %esi
+    push %edi
+
+    mov 24(%esp), %eax
+    mov %esp, (%eax)
+    mov 28(%esp), %eax
+
+    # User data segment selector with RPL 3.
+    mov $0x23, %ecx
+    mov %cx, %ds
+    mov %cx, %es
+    mov %cx, %fs
+    mov %cx, %gs
+
+    # Interrupt stack frame for returning to ring 3.
+    push $0x23
+    push 32(%eax)
+    # EFLAGS with interrupts enabled.
+    push $0x202
+    # User code segment selector with RPL 3.
+    push $0x1B
+    push 28(%eax)
+
+    # Only values from `registers` are visible to user mode.
+    mov 4(%eax), %ebx
+    mov 8(%eax), %ecx
+    mov 12(%eax), %edx
+    mov 16(%eax), %esi
+    mov 20(%eax), %edi
+    mov 24(%eax), %ebp
+    mov (%eax), %eax
+
+    iret
+
+# extern "C" fn leave_user_mode(saved_kernel_stack: usize) -> !
+#
+# Switches back to the stack saved by `enter_user_mode` and returns
+# from it.
+.text
+.global leave_user_mode
+leave_user_mode:
+    mov 4(%esp), %ecx
+
+    # Kernel data segment selector.
+    mov $0x10, %edx
+    mov %dx, %ds
+    mov %dx, %es
+    mov %dx, %fs
+    mov %dx, %gs
+
+    mov %ecx, %esp
+    pop %edi
+    pop %esi
+    pop %ebx
+    pop %ebp
+    popf
+    ret
+
+# Kernel thread context switch
+
+# extern "C" fn switch_context(
+#     old_stack_pointer: *mut usize,
+#     new_stack_pointer: usize)
+#
+# Saves callee-saved registers and EFLAGS to the current stack, stores
+# the stack pointer to `old_stack_pointer` and restores registers from
+# the stack at `new_stack_pointer`. Returns when some other thread
+# switches back to the saved stack.
+.text
+.global switch_context
+switch_context:
+    mov 4(%esp), %eax
+    mov 8(%esp), %edx
+
+    pushf
+    push %ebp
+    push %ebx
+    push %esi
+    push %edi
+
+    mov %esp, (%eax)
+    mov %edx, %esp
+
+    pop %edi
+    pop %esi
+    pop %ebx
+    pop %ebp
+    popf
+    ret
+
+# System call entry points
+
+# Both entry points store user registers to a `SyscallFrame` on the
+# stack. Field offsets: EBX 0, ECX 4, EDX 8, ESI 12, EDI 16, EBP 20,
+# EIP 24 and ESP 28. System calls like `execve` can modify the frame,
+# and the registers are restored from it.
+
+# int 0x80
+#
+# System call number is in EAX and arguments are in EBX, ECX, EDX, ESI,
+# EDI and EBP. Return value is stored to EAX.
+.text
+.global syscall_interrupt
+syscall_interrupt:
+    cld
+
+    # User mode can leave any selector to DS and ES, so they are stored
+    # and restored around the system call.
+    push %ds
+    push %es
+
+    # User ESP and EIP from the interrupt stack frame.
+    push 20(%esp)
+    push 12(%esp)
+
+    push %ebp
+    push %edi
+    push %esi
+    push %edx
+    push %ecx
+    push %ebx
+
+    # Kernel data segment selector. ECX is already saved.
+    mov $0x10, %ecx
+    mov %cx, %ds
+    mov %cx, %es
+
+    # First six fields of the frame are also the arguments array.
+    mov %esp, %ecx
+    push %ecx
+    push %ecx
+    push %eax
+    # extern "C" fn rust_syscall_handler(
+    #     number: u32,
+    #     arguments: *const [u32; 6],
+    #     frame: *mut SyscallFrame) -> u32
+    call rust_syscall_handler
+    add $12, %esp
+
+    # Copy EIP and ESP back to the interrupt stack frame, which is
+    # after the frame and the segment registers.
+    mov 24(%esp), %ecx
+    mov %ecx, 40(%esp)
+    mov 28(%esp), %ecx
+    mov %ecx, 52(%esp)
+
+    pop %ebx
+    pop %ecx
+    pop %edx
+    pop %esi
+    pop %edi
+    pop %ebp
+    add $8, %esp
+
+    pop %es
+    pop %ds
+
+    iret
+
+# SYSENTER
+#
+# System call number is in EAX and arguments are in EBX, ESI, EDI and EBP.
+# User mode stores its stack pointer to ECX and return address to EDX.
+# Return value is stored to EAX.
+.text
+.global sysenter_entry
+sysenter_entry:
+    cld
+
+    # User mode can leave any selector to DS and ES, and SYSEXIT doesn't
+    # load them, so they are stored and restored around the system call.
+    push %ds
+    push %es
+    push %eax
+    # Kernel data segment selector.
+    mov $0x10, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    pop %eax
+
+    # SYSEXIT loads ESP from ECX and EIP from EDX, so those registers
+    # have the same values after returning.
+    push %ecx
+    push %edx
+    push %ebp
+    push %edi
+    push %esi
+    push %edx
+    push %ecx
+    push %ebx
+    mov %esp, %ecx
+
+    # Arguments array.
+    push $0
+    push $0
+    push %ebp
+    push %edi
+    push %esi
+    push %ebx
+    mov %esp, %edx
+
+    # SYSENTER disables interrupts.
+    sti
+
+    push %ecx
+    push %edx
+    push %eax
+    call rust_syscall_handler
+    # Remove the call arguments and the arguments array.
+    add $36, %esp
+
+    pop %ebx
+    add $8, %esp
+    pop %esi
+    pop %edi
+    pop %ebp
+    pop %edx
+    pop %ecx
+
+    pop %es
+    pop %ds
+
+    sysexit
+
 # Interrupt handlers
 
 interrupt 0
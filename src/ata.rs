@@ -0,0 +1,522 @@
+//! ATA hard disk and ATAPI CD-ROM driver using PIO mode.
+//!
+//! Disk interrupts are disabled and the driver polls the status register.
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+
+const PRIMARY_BUS_IO_BASE: u16 = 0x1F0;
+const PRIMARY_BUS_CONTROL_BASE: u16 = 0x3F6;
+const SECONDARY_BUS_IO_BASE: u16 = 0x170;
+const SECONDARY_BUS_CONTROL_BASE: u16 = 0x376;
+
+// Register offsets from IO base.
+const REGISTER_DATA: u16 = 0;
+const REGISTER_ERROR: u16 = 1;
+const REGISTER_SECTOR_COUNT: u16 = 2;
+const REGISTER_LBA_LOW: u16 = 3;
+const REGISTER_LBA_MID: u16 = 4;
+const REGISTER_LBA_HIGH: u16 = 5;
+const REGISTER_DRIVE_SELECT: u16 = 6;
+const REGISTER_COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+const CONTROL_DISABLE_INTERRUPTS: u8 = 1 << 1;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+const COMMAND_PACKET: u8 = 0xA0;
+const COMMAND_IDENTIFY_PACKET: u8 = 0xA1;
+
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+const ATAPI_SECTOR_SIZE: usize = 2048;
+const ATAPI_PACKET_SIZE: usize = 12;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+/// Sense key in the error register which means that there is no medium.
+const SENSE_KEY_NOT_READY: u8 = 0x02;
+
+const LBA28_MAX_SECTORS: u64 = 1 << 28;
+/// Sector count register value 0 means 256 sectors, so use smaller transfers.
+const MAX_SECTORS_PER_COMMAND: usize = 255;
+const POLL_TIMEOUT: usize = 10_000_000;
+
+#[derive(Debug, Copy, Clone)]
+pub struct AtaBus {
+    io_base: u16,
+    control_base: u16,
+}
+
+pub const PRIMARY_BUS: AtaBus = AtaBus {
+    io_base: PRIMARY_BUS_IO_BASE,
+    control_base: PRIMARY_BUS_CONTROL_BASE,
+};
+
+pub const SECONDARY_BUS: AtaBus = AtaBus {
+    io_base: SECONDARY_BUS_IO_BASE,
+    control_base: SECONDARY_BUS_CONTROL_BASE,
+};
+
+impl AtaBus {
+    pub fn read(&self, register: u16) -> u8 {
+        unsafe { x86::io::inb(self.io_base + register) }
+    }
+
+    pub fn write(&self, register: u16, value: u8) {
+        unsafe { x86::io::outb(self.io_base + register, value) }
+    }
+
+    pub fn read_data(&self) -> u16 {
+        unsafe { x86::io::inw(self.io_base + REGISTER_DATA) }
+    }
+
+    pub fn write_data(&self, value: u16) {
+        unsafe { x86::io::outw(self.io_base + REGISTER_DATA, value) }
+    }
+
+    pub fn alternate_status(&self) -> u8 {
+        unsafe { x86::io::inb(self.control_base) }
+    }
+
+    pub fn disable_interrupts(&self) {
+        unsafe { x86::io::outb(self.control_base, CONTROL_DISABLE_INTERRUPTS) }
+    }
+
+    /// Wait about 400 ns by reading the alternate status register.
+    pub fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    pub fn select_drive(&self, slave: bool, lba_high_bits: u8) {
+        let slave_bit = if slave { 1 << 4 } else { 0 };
+        self.write(REGISTER_DRIVE_SELECT, 0xE0 | slave_bit | (lba_high_bits & 0x0F));
+        self.delay();
+    }
+
+    pub fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_TIMEOUT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Wait until the drive is ready to transfer data.
+    pub fn wait_data_request(&self) -> Result<(), BlockError> {
+        for _ in 0..POLL_TIMEOUT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY != 0 {
+                continue;
+            }
+
+            if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+                return Err(BlockError::DeviceError(self.read(REGISTER_ERROR)));
+            }
+
+            if status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    pub fn write_command(&self, command: u8) {
+        self.write(REGISTER_COMMAND, command);
+        self.delay();
+    }
+
+    pub fn signature(&self) -> (u8, u8) {
+        (self.read(REGISTER_LBA_MID), self.read(REGISTER_LBA_HIGH))
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct AtaDrive {
+    bus: AtaBus,
+    slave: bool,
+    sector_count: u64,
+    lba48: bool,
+    model: [u8; 40],
+}
+
+impl AtaDrive {
+    /// Detect an ATA drive with the IDENTIFY command.
+    pub fn identify(bus: AtaBus, slave: bool) -> Option<Self> {
+        bus.disable_interrupts();
+        bus.select_drive(slave, 0);
+
+        bus.write(REGISTER_SECTOR_COUNT, 0);
+        bus.write(REGISTER_LBA_LOW, 0);
+        bus.write(REGISTER_LBA_MID, 0);
+        bus.write(REGISTER_LBA_HIGH, 0);
+        bus.write_command(COMMAND_IDENTIFY);
+
+        // Status 0 means that there is no drive. Floating bus reads 0xFF.
+        let status = bus.alternate_status();
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+
+        bus.wait_not_busy().ok()?;
+
+        // ATAPI and SATA devices set these registers.
+        if bus.signature() != (0, 0) {
+            return None;
+        }
+
+        bus.wait_data_request().ok()?;
+
+        let identify = read_identify_data(bus);
+
+        let lba48 = identify[83] & (1 << 10) != 0;
+        let sector_count = if lba48 {
+            identify[100] as u64 | (identify[101] as u64) << 16 | (identify[102] as u64) << 32 | (identify[103] as u64) << 48
+        } else {
+            identify[60] as u64 | (identify[61] as u64) << 16
+        };
+
+        Some(Self {
+            bus,
+            slave,
+            sector_count,
+            lba48,
+            model: identify_model(&identify),
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        model_string(&self.model)
+    }
+
+    fn setup_transfer(&self, lba: u64, count: usize) -> Result<bool, BlockError> {
+        self.bus.wait_not_busy()?;
+
+        if lba + count as u64 <= LBA28_MAX_SECTORS {
+            self.bus.select_drive(self.slave, (lba >> 24) as u8);
+            self.bus.write(REGISTER_SECTOR_COUNT, count as u8);
+            self.bus.write(REGISTER_LBA_LOW, lba as u8);
+            self.bus.write(REGISTER_LBA_MID, (lba >> 8) as u8);
+            self.bus.write(REGISTER_LBA_HIGH, (lba >> 16) as u8);
+            Ok(false)
+        } else if self.lba48 {
+            self.bus.select_drive(self.slave, 0);
+            // High bytes are written first.
+            self.bus.write(REGISTER_SECTOR_COUNT, (count >> 8) as u8);
+            self.bus.write(REGISTER_LBA_LOW, (lba >> 24) as u8);
+            self.bus.write(REGISTER_LBA_MID, (lba >> 32) as u8);
+            self.bus.write(REGISTER_LBA_HIGH, (lba >> 40) as u8);
+            self.bus.write(REGISTER_SECTOR_COUNT, count as u8);
+            self.bus.write(REGISTER_LBA_LOW, lba as u8);
+            self.bus.write(REGISTER_LBA_MID, (lba >> 8) as u8);
+            self.bus.write(REGISTER_LBA_HIGH, (lba >> 16) as u8);
+            Ok(true)
+        } else {
+            Err(BlockError::OutOfRange)
+        }
+    }
+
+    fn check_range(&self, lba: u64, length: usize) -> Result<(), BlockError> {
+        if length % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        if lba + (length / SECTOR_SIZE) as u64 > self.sector_count {
+            return Err(BlockError::OutOfRange);
+        }
+
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&mut self, mut lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(lba, buffer.len())?;
+
+        for chunk in buffer.chunks_mut(SECTOR_SIZE * MAX_SECTORS_PER_COMMAND) {
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.setup_transfer(lba, count)?;
+            self.bus.write_command(if lba48 { COMMAND_READ_SECTORS_EXT } else { COMMAND_READ_SECTORS });
+
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                self.bus.wait_data_request()?;
+                for bytes in sector.chunks_mut(2) {
+                    let word = self.bus.read_data();
+                    bytes[0] = word as u8;
+                    bytes[1] = (word >> 8) as u8;
+                }
+            }
+
+            lba += count as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, mut lba: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.check_range(lba, data.len())?;
+
+        for chunk in data.chunks(SECTOR_SIZE * MAX_SECTORS_PER_COMMAND) {
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.setup_transfer(lba, count)?;
+            self.bus.write_command(if lba48 { COMMAND_WRITE_SECTORS_EXT } else { COMMAND_WRITE_SECTORS });
+
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                self.bus.wait_data_request()?;
+                for bytes in sector.chunks(2) {
+                    self.bus.write_data(u16::from(bytes[0]) | u16::from(bytes[1]) << 8);
+                }
+            }
+
+            self.bus.write_command(if lba48 { COMMAND_CACHE_FLUSH_EXT } else { COMMAND_CACHE_FLUSH });
+            self.bus.wait_not_busy()?;
+
+            lba += count as u64;
+        }
+
+        Ok(())
+    }
+}
+
+/// ATAPI drive. Block device interface uses 512 byte sectors, so reads
+/// are done one 2048 byte ATAPI sector at a time.
+#[derive(Copy, Clone)]
+pub struct AtapiDrive {
+    bus: AtaBus,
+    slave: bool,
+    /// Number of 2048 byte sectors. Zero if there was no medium when the
+    /// drive was detected.
+    sector_count: u64,
+    model: [u8; 40],
+}
+
+impl AtapiDrive {
+    /// Detect an ATAPI drive with the IDENTIFY PACKET DEVICE command.
+    pub fn identify(bus: AtaBus, slave: bool) -> Option<Self> {
+        bus.disable_interrupts();
+        bus.select_drive(slave, 0);
+        bus.write_command(COMMAND_IDENTIFY);
+
+        let status = bus.alternate_status();
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+
+        bus.wait_not_busy().ok()?;
+
+        if bus.signature() != ATAPI_SIGNATURE {
+            return None;
+        }
+
+        bus.write_command(COMMAND_IDENTIFY_PACKET);
+        bus.wait_data_request().ok()?;
+        let identify = read_identify_data(bus);
+
+        let mut drive = Self {
+            bus,
+            slave,
+            sector_count: 0,
+            model: identify_model(&identify),
+        };
+
+        drive.sector_count = drive.read_capacity().unwrap_or(0);
+
+        Some(drive)
+    }
+
+    pub fn model(&self) -> &str {
+        model_string(&self.model)
+    }
+
+    /// Send SCSI command and read the response to `buffer`.
+    fn packet_command(&self, packet: &[u8; ATAPI_PACKET_SIZE], buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.bus.wait_not_busy()?;
+        self.bus.select_drive(self.slave, 0);
+
+        // PIO mode and the maximum byte count for one data transfer.
+        self.bus.write(REGISTER_ERROR, 0);
+        self.bus.write(REGISTER_LBA_MID, buffer.len() as u8);
+        self.bus.write(REGISTER_LBA_HIGH, (buffer.len() >> 8) as u8);
+        self.bus.write_command(COMMAND_PACKET);
+
+        self.bus.wait_data_request().map_err(sense_error)?;
+        for bytes in packet.chunks(2) {
+            self.bus.write_data(u16::from(bytes[0]) | u16::from(bytes[1]) << 8);
+        }
+
+        let mut done = 0;
+        while done < buffer.len() {
+            self.bus.wait_data_request().map_err(sense_error)?;
+
+            let byte_count = usize::from(self.bus.read(REGISTER_LBA_MID)) | usize::from(self.bus.read(REGISTER_LBA_HIGH)) << 8;
+            if byte_count == 0 {
+                return Err(BlockError::DeviceError(0));
+            }
+
+            for _ in 0..(byte_count + 1) / 2 {
+                let word = self.bus.read_data();
+                for &byte in [word as u8, (word >> 8) as u8].iter() {
+                    if done < buffer.len() {
+                        buffer[done] = byte;
+                    }
+                    done += 1;
+                }
+            }
+        }
+
+        let status = self.bus.wait_not_busy()?;
+        if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+            return Err(sense_error(BlockError::DeviceError(self.bus.read(REGISTER_ERROR))));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of 2048 byte sectors.
+    fn read_capacity(&self) -> Result<u64, BlockError> {
+        let mut packet = [0u8; ATAPI_PACKET_SIZE];
+        packet[0] = SCSI_READ_CAPACITY;
+
+        let mut response = [0u8; 8];
+        self.packet_command(&packet, &mut response)?;
+
+        let last_lba = u32::from_be_bytes([response[0], response[1], response[2], response[3]]);
+        Ok(u64::from(last_lba) + 1)
+    }
+
+    fn read_atapi_sector(&self, lba: u32, buffer: &mut [u8; ATAPI_SECTOR_SIZE]) -> Result<(), BlockError> {
+        let mut packet = [0u8; ATAPI_PACKET_SIZE];
+        packet[0] = SCSI_READ_10;
+        packet[2..6].copy_from_slice(&lba.to_be_bytes());
+        packet[8] = 1;
+
+        self.packet_command(&packet, buffer)
+    }
+}
+
+impl BlockDevice for AtapiDrive {
+    fn sector_count(&self) -> u64 {
+        self.sector_count * (ATAPI_SECTOR_SIZE / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        if buffer.len() % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        if self.sector_count == 0 {
+            return Err(BlockError::NoMedia);
+        }
+
+        if lba + (buffer.len() / SECTOR_SIZE) as u64 > self.sector_count() {
+            return Err(BlockError::OutOfRange);
+        }
+
+        let mut atapi_sector = [0u8; ATAPI_SECTOR_SIZE];
+        let mut loaded = None;
+
+        for (i, sector) in buffer.chunks_mut(SECTOR_SIZE).enumerate() {
+            let offset = (lba + i as u64) * SECTOR_SIZE as u64;
+            let atapi_lba = (offset / ATAPI_SECTOR_SIZE as u64) as u32;
+
+            if loaded != Some(atapi_lba) {
+                self.read_atapi_sector(atapi_lba, &mut atapi_sector)?;
+                loaded = Some(atapi_lba);
+            }
+
+            let start = (offset % ATAPI_SECTOR_SIZE as u64) as usize;
+            sector.copy_from_slice(&atapi_sector[start..start + SECTOR_SIZE]);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, _lba: u64, _data: &[u8]) -> Result<(), BlockError> {
+        Err(BlockError::ReadOnly)
+    }
+}
+
+fn sense_error(e: BlockError) -> BlockError {
+    match e {
+        BlockError::DeviceError(error) if error >> 4 == SENSE_KEY_NOT_READY => BlockError::NoMedia,
+        e => e,
+    }
+}
+
+fn read_identify_data(bus: AtaBus) -> [u16; 256] {
+    let mut identify = [0u16; 256];
+    for word in identify.iter_mut() {
+        *word = bus.read_data();
+    }
+    identify
+}
+
+/// Model string is stored as big endian words.
+fn identify_model(identify: &[u16; 256]) -> [u8; 40] {
+    let mut model = [0u8; 40];
+    for (i, word) in identify[27..47].iter().enumerate() {
+        model[i * 2] = (word >> 8) as u8;
+        model[i * 2 + 1] = *word as u8;
+    }
+    model
+}
+
+fn model_string(model: &[u8; 40]) -> &str {
+    core::str::from_utf8(model).unwrap_or_default().trim()
+}
+
+const DRIVE_NAMES: [&str; 4] = ["hda", "hdb", "hdc", "hdd"];
+const CDROM_NAME: &str = "cdrom";
+
+static mut ATA_DRIVES: [Option<AtaDrive>; 4] = [None; 4];
+static mut ATAPI_DRIVE: Option<AtapiDrive> = None;
+
+/// Detect ATA and ATAPI drives and register them as block devices. Only
+/// the first ATAPI drive is registered.
+pub fn init(log: &mut impl core::fmt::Write) {
+    let locations = [(PRIMARY_BUS, false), (PRIMARY_BUS, true), (SECONDARY_BUS, false), (SECONDARY_BUS, true)];
+
+    for (i, &(bus, slave)) in locations.iter().enumerate() {
+        let (name, device): (&'static str, &'static mut dyn BlockDevice) = if let Some(drive) = AtaDrive::identify(bus, slave) {
+            let _ = writeln!(log, "ATA drive {}: {}, {} MiB", DRIVE_NAMES[i], drive.model(), drive.sector_count * SECTOR_SIZE as u64 / (1024 * 1024));
+
+            unsafe {
+                ATA_DRIVES[i] = Some(drive);
+                (DRIVE_NAMES[i], ATA_DRIVES[i].as_mut().unwrap())
+            }
+        } else if unsafe { ATAPI_DRIVE.is_none() } {
+            let drive = match AtapiDrive::identify(bus, slave) {
+                Some(drive) => drive,
+                None => continue,
+            };
+
+            let _ = writeln!(log, "ATAPI drive {}: {}, {} MiB", CDROM_NAME, drive.model(), drive.sector_count * ATAPI_SECTOR_SIZE as u64 / (1024 * 1024));
+
+            unsafe {
+                ATAPI_DRIVE = Some(drive);
+                (CDROM_NAME, ATAPI_DRIVE.as_mut().unwrap())
+            }
+        } else {
+            continue;
+        };
+
+        if let Err(e) = block::register_disk(name, device) {
+            let _ = writeln!(log, "Block device registration failed: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Block devices.
+//!
+//! Drivers register whole disks and the MBR partitions of registered disks
+//! are added automatically. Devices are referred by index, so filesystem
+//! drivers don't need to hold references to the device.
+//!
+//! Device table is behind a `Mutex`, so requests to the drives are
+//! serialised and a thread can sleep while it waits for a drive.
+
+use arrayvec::ArrayVec;
+
+use crate::sync::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const BLOCK_DEVICE_COUNT: usize = 16;
+const MBR_PARTITION_COUNT: usize = 4;
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_PARTITION_TYPE_EMPTY: u8 = 0;
+const MBR_PARTITION_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const MBR_PARTITION_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange,
+    InvalidBuffer,
+    DeviceError(u8),
+    Timeout,
+    ReadOnly,
+    NoMedia,
+    UnknownDevice,
+}
+
+pub trait BlockDevice: Send {
+    fn sector_count(&self) -> u64;
+
+    /// Read sectors starting from `lba`. Buffer length must be a multiple of `SECTOR_SIZE`.
+    fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write sectors starting from `lba`. Data length must be a multiple of `SECTOR_SIZE`.
+    fn write_sectors(&mut self, lba: u64, data: &[u8]) -> Result<(), BlockError>;
+}
+
+pub type BlockDeviceId = usize;
+
+enum DeviceKind {
+    Disk(&'static mut dyn BlockDevice),
+    Partition {
+        disk: BlockDeviceId,
+        start: u64,
+        sector_count: u64,
+    },
+}
+
+pub struct BlockDeviceEntry {
+    pub name: &'static str,
+    kind: DeviceKind,
+}
+
+impl BlockDeviceEntry {
+    pub fn is_partition(&self) -> bool {
+        match self.kind {
+            DeviceKind::Partition { .. } => true,
+            DeviceKind::Disk(_) => false,
+        }
+    }
+}
+
+type DeviceTable = ArrayVec<[BlockDeviceEntry; BLOCK_DEVICE_COUNT]>;
+
+static BLOCK_DEVICES: Mutex<Option<DeviceTable>> = Mutex::new("block devices", None);
+
+fn with_devices<T>(function: impl FnOnce(&mut DeviceTable) -> T) -> T {
+    let mut devices = BLOCK_DEVICES.lock();
+    function(devices.get_or_insert_with(ArrayVec::new))
+}
+
+/// Partition names are created by appending partition number to the disk name.
+static PARTITION_NAMES: [[&str; MBR_PARTITION_COUNT]; 4] = [
+    ["hda1", "hda2", "hda3", "hda4"],
+    ["hdb1", "hdb2", "hdb3", "hdb4"],
+    ["hdc1", "hdc2", "hdc3", "hdc4"],
+    ["hdd1", "hdd2", "hdd3", "hdd4"],
+];
+
+fn partition_name(disk_name: &str, number: usize) -> Option<&'static str> {
+    PARTITION_NAMES.iter()
+        .map(|names| names[number])
+        .find(|name| name.starts_with(disk_name) && name.len() == disk_name.len() + 1)
+}
+
+/// Register a disk and its MBR partitions.
+pub fn register_disk(name: &'static str, device: &'static mut dyn BlockDevice) -> Result<BlockDeviceId, BlockError> {
+    let disk = with_devices(move |devices| {
+        devices.try_push(BlockDeviceEntry {
+            name,
+            kind: DeviceKind::Disk(device),
+        }).map_err(|_| BlockError::OutOfRange)?;
+        Ok(devices.len() - 1)
+    })?;
+
+    // Disk might not contain a partition table, so errors are ignored.
+    let _ = scan_partitions(disk);
+
+    Ok(disk)
+}
+
+fn scan_partitions(disk: BlockDeviceId) -> Result<(), BlockError> {
+    let mut mbr = [0u8; SECTOR_SIZE];
+    read_sectors(disk, 0, &mut mbr)?;
+
+    if mbr[510] != 0x55 || mbr[511] != 0xAA {
+        return Ok(());
+    }
+
+    let disk_sectors = sector_count(disk)?;
+    let disk_name = with_devices(|devices| devices[disk].name);
+
+    for i in 0..MBR_PARTITION_COUNT {
+        let entry = &mbr[MBR_PARTITION_TABLE_OFFSET + i * 16..MBR_PARTITION_TABLE_OFFSET + (i + 1) * 16];
+
+        // Boot indicator must be 0x00 or 0x80, otherwise the sector is
+        // probably a FAT boot sector without a partition table.
+        if entry[0] & 0x7F != 0 {
+            return Ok(());
+        }
+
+        let partition_type = entry[4];
+        let start = u32_le(&entry[8..12]) as u64;
+        let sector_count = u32_le(&entry[12..16]) as u64;
+
+        if partition_type == MBR_PARTITION_TYPE_EMPTY ||
+            partition_type == MBR_PARTITION_TYPE_GPT_PROTECTIVE ||
+            MBR_PARTITION_TYPE_EXTENDED.contains(&partition_type) ||
+            sector_count == 0 ||
+            start + sector_count > disk_sectors {
+            continue;
+        }
+
+        if let Some(name) = partition_name(disk_name, i) {
+            let _ = with_devices(|devices| devices.try_push(BlockDeviceEntry {
+                name,
+                kind: DeviceKind::Partition { disk, start, sector_count },
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+/// Name of the device `id`.
+pub fn device_name(id: BlockDeviceId) -> Option<&'static str> {
+    with_devices(|devices| devices.get(id).map(|device| device.name))
+}
+
+/// Find device by name. Prefix `/dev/` is allowed.
+pub fn find(name: &str) -> Option<BlockDeviceId> {
+    let name = name.trim_start_matches("/dev/");
+    with_devices(|devices| devices.iter().position(|d| d.name == name))
+}
+
+pub fn sector_count(id: BlockDeviceId) -> Result<u64, BlockError> {
+    with_devices(|devices| device_sector_count(devices, id))
+}
+
+fn device_sector_count(devices: &DeviceTable, id: BlockDeviceId) -> Result<u64, BlockError> {
+    match &devices.get(id).ok_or(BlockError::UnknownDevice)?.kind {
+        DeviceKind::Disk(device) => Ok(device.sector_count()),
+        DeviceKind::Partition { sector_count, .. } => Ok(*sector_count),
+    }
+}
+
+/// Translate partition relative location to disk location.
+fn disk_location(devices: &DeviceTable, id: BlockDeviceId, lba: u64, length: usize) -> Result<(BlockDeviceId, u64), BlockError> {
+    if length % SECTOR_SIZE != 0 {
+        return Err(BlockError::InvalidBuffer);
+    }
+
+    let count = (length / SECTOR_SIZE) as u64;
+    if lba + count > device_sector_count(devices, id)? {
+        return Err(BlockError::OutOfRange);
+    }
+
+    match devices[id].kind {
+        DeviceKind::Disk(_) => Ok((id, lba)),
+        DeviceKind::Partition { disk, start, .. } => Ok((disk, start + lba)),
+    }
+}
+
+pub fn read_sectors(id: BlockDeviceId, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+    with_devices(|devices| {
+        let (disk, lba) = disk_location(devices, id, lba, buffer.len())?;
+        match &mut devices[disk].kind {
+            DeviceKind::Disk(device) => device.read_sectors(lba, buffer),
+            DeviceKind::Partition { .. } => Err(BlockError::UnknownDevice),
+        }
+    })
+}
+
+pub fn write_sectors(id: BlockDeviceId, lba: u64, data: &[u8]) -> Result<(), BlockError> {
+    with_devices(|devices| {
+        let (disk, lba) = disk_location(devices, id, lba, data.len())?;
+        match &mut devices[disk].kind {
+            DeviceKind::Disk(device) => device.write_sectors(lba, data),
+            DeviceKind::Partition { .. } => Err(BlockError::UnknownDevice),
+        }
+    })
+}
+
+pub fn u16_le(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) | u16::from(bytes[1]) << 8
+}
+
+pub fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from(u16_le(&bytes[0..2])) | u32::from(u16_le(&bytes[2..4])) << 16
+}
+
+pub fn set_u16_le(bytes: &mut [u8], value: u16) {
+    bytes[0] = value as u8;
+    bytes[1] = (value >> 8) as u8;
+}
+
+pub fn set_u32_le(bytes: &mut [u8], value: u32) {
+    set_u16_le(&mut bytes[0..2], value as u16);
+    set_u16_le(&mut bytes[2..4], (value >> 16) as u16);
+}
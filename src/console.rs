@@ -0,0 +1,157 @@
+//! Console device.
+//!
+//! Writes to `/dev/console` are buffered for the virtual console of the
+//! writing thread, see `scheduler::console`, so commands of a shell
+//! session and the programs which they start write to the console of
+//! the session. The kernel main loop flushes the buffers to the
+//! terminals, and writes from other threads wake it up. When a buffer is
+//! full, writes from other threads wait until it is flushed.
+
+use core::fmt::{self, Write};
+
+use arrayvec::ArrayString;
+
+use crate::devfs::CharDevice;
+use crate::idt;
+use crate::process;
+use crate::ring_buffer::ByteRing;
+use crate::scheduler;
+use crate::sync::IrqSpinlock;
+use crate::utf8::Utf8Decoder;
+use crate::vfs::FsError;
+use crate::virtual_console::CONSOLE_COUNT;
+
+/// How long a writer sleeps when the buffer is full.
+const FULL_BUFFER_SLEEP_MILLISECONDS: usize = 10;
+
+/// Bytes which `flush` moves out of the buffer at a time.
+const FLUSH_CHUNK_SIZE: usize = 64;
+
+struct ConsoleBuffer {
+    output: ByteRing,
+    /// Character which is split between flushes is kept here.
+    decoder: Utf8Decoder,
+}
+
+impl ConsoleBuffer {
+    const fn new() -> Self {
+        Self {
+            output: ByteRing::new(),
+            decoder: Utf8Decoder::new(),
+        }
+    }
+}
+
+static CONSOLE_BUFFERS: IrqSpinlock<[ConsoleBuffer; CONSOLE_COUNT]> = IrqSpinlock::new("console buffers", [
+    ConsoleBuffer::new(),
+    ConsoleBuffer::new(),
+    ConsoleBuffer::new(),
+    ConsoleBuffer::new(),
+    ConsoleBuffer::new(),
+    ConsoleBuffer::new(),
+]);
+
+/// Buffer data for the console of the current thread. Returns the
+/// number of buffered bytes.
+pub fn write(data: &[u8]) -> usize {
+    let console = scheduler::console();
+    let mut buffers = CONSOLE_BUFFERS.lock();
+    let output = &mut buffers[console].output;
+    data.iter().take_while(|&&byte| output.push(byte)).count()
+}
+
+/// Buffer data and wait while the buffer is full. Thread 0 runs the main
+/// loop which flushes the buffers, so it gets an error instead of
+/// waiting.
+fn write_or_wait(data: &[u8]) -> Result<usize, FsError> {
+    let main_thread = scheduler::current_thread_id() == 0;
+
+    loop {
+        let count = write(data);
+
+        if !main_thread {
+            idt::request_wake_up();
+        }
+
+        match count {
+            0 if !data.is_empty() => {
+                if main_thread {
+                    return Err(FsError::NoSpace);
+                }
+                if process::kill_pending() {
+                    return Err(FsError::Interrupted);
+                }
+                scheduler::sleep(FULL_BUFFER_SLEEP_MILLISECONDS);
+            }
+            count => return Ok(count),
+        }
+    }
+}
+
+/// Write buffered output of virtual console `console`, which is UTF-8,
+/// to `out`. Terminal is written without holding the lock, so only one
+/// thread, the main loop, should flush.
+pub fn flush(console: usize, out: &mut impl Write) {
+    loop {
+        // Each byte adds at most four bytes of text.
+        let mut text = ArrayString::<[u8; FLUSH_CHUNK_SIZE * 4]>::new();
+        let mut count = 0;
+
+        {
+            let mut buffers = CONSOLE_BUFFERS.lock();
+            let buffer = &mut buffers[console];
+            while count < FLUSH_CHUNK_SIZE {
+                let byte = match buffer.output.pop() {
+                    Some(byte) => byte,
+                    None => break,
+                };
+                buffer.decoder.push_bytes(&[byte], |c| {
+                    let _ = text.try_push(c);
+                });
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            break;
+        }
+
+        let _ = out.write_str(&text);
+    }
+}
+
+/// Writes text to the console of the current thread, waiting while the
+/// buffer is full.
+pub struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut data = s.as_bytes();
+        while !data.is_empty() {
+            let count = write_or_wait(data).map_err(|_| fmt::Error)?;
+            data = &data[count..];
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ConsoleDevice;
+
+impl CharDevice for ConsoleDevice {
+    /// Writers wait for the main loop, which writes to the console too.
+    fn locks_internally(&self) -> bool {
+        true
+    }
+
+    /// Console input is not implemented yet.
+    fn read(&mut self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        write_or_wait(data)
+    }
+}
+
+pub static mut CONSOLE_DEVICE: ConsoleDevice = ConsoleDevice;
@@ -0,0 +1,113 @@
+//! Code page 437, the character set of the VGA text mode font.
+//!
+//! Unicode characters are translated to code page 437 bytes. Bytes
+//! 0x20-0x7E are ASCII, bytes 0x01-0x1F and 0x7F are symbols and bytes
+//! 0x80-0xFF are accented letters, box drawing characters and Greek and
+//! mathematical symbols. Some characters which the code page doesn't
+//! have are shown as a similar character, and others as `REPLACEMENT`.
+
+/// Glyph of unmapped characters, a black square.
+pub const REPLACEMENT: u8 = 0xFE;
+
+/// Characters of bytes 0x00-0x1F. Byte 0 is empty.
+const LOW: [char; 32] = [
+    ' ', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+const DELETE: char = '⌂';
+
+/// Characters of bytes 0x80-0xFF.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Code page 437 byte of `c`.
+pub fn from_char(c: char) -> u8 {
+    if (' '..='~').contains(&c) {
+        return c as u8;
+    }
+
+    if let Some(i) = HIGH.iter().position(|&high| high == c) {
+        return 0x80 + i as u8;
+    }
+
+    if let Some(i) = LOW.iter().skip(1).position(|&low| low == c) {
+        return 1 + i as u8;
+    }
+
+    if c == DELETE {
+        return 0x7F;
+    }
+
+    similar(c).unwrap_or(REPLACEMENT)
+}
+
+/// Unicode character of code page 437 byte `byte`.
+pub fn to_char(byte: u8) -> char {
+    match byte {
+        0x20..=0x7E => byte as char,
+        0x7F => DELETE,
+        0x80..=0xFF => HIGH[usize::from(byte - 0x80)],
+        _ => LOW[usize::from(byte)],
+    }
+}
+
+/// Byte of a similar character for characters which the code page
+/// doesn't have.
+fn similar(c: char) -> Option<u8> {
+    let similar = match c {
+        'À' | 'Á' | 'Â' | 'Ã' => 'A',
+        'È' | 'Ê' | 'Ë' => 'E',
+        'Ì' | 'Í' | 'Î' | 'Ï' => 'I',
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ø' => 'O',
+        'Ù' | 'Ú' | 'Û' => 'U',
+        'Ý' => 'Y',
+        'ã' => 'a',
+        'õ' | 'ø' => 'o',
+        'ý' => 'y',
+        'Ð' => 'D',
+        'ð' => 'd',
+        '×' => 'x',
+        '\u{AD}' | '‐' | '‑' | '‒' | '–' | '—' | '−' => '-',
+        '‘' | '’' | '‚' | '′' | '´' => '\'',
+        '“' | '”' | '„' | '″' => '"',
+        '¦' => '|',
+        '©' => 'c',
+        '®' => 'r',
+        '³' => '3',
+        '¹' => '1',
+        // Greek letters and mathematical symbols which have a similar
+        // glyph.
+        'β' => 'ß',
+        '\u{3BC}' => 'µ',
+        '\u{2126}' => 'Ω',
+        '∑' => 'Σ',
+        'ϕ' => 'φ',
+        '∈' | 'ϵ' => 'ε',
+        '∗' => '*',
+        '∣' => '|',
+        '∼' => '~',
+        '≅' | '≃' => '≈',
+        '⋅' => '·',
+        '⌈' | '⌊' => '│',
+        '▪' | '◼' => '■',
+        '●' => '•',
+        '┃' => '│',
+        '━' => '─',
+        '┏' => '┌',
+        '┓' => '┐',
+        '┗' => '└',
+        '┛' => '┘',
+        _ => return None,
+    };
+
+    Some(from_char(similar))
+}
@@ -0,0 +1,422 @@
+//! Device filesystem, usually mounted at `/dev`.
+//!
+//! Drivers register character devices with `register_char_device`.
+//! Every registered block device is also available as a device file.
+//!
+//! Device table is behind a spinlock. Each character device has a
+//! `Mutex` for its calls, unless the device locks its own data.
+
+use arrayvec::ArrayVec;
+
+use crate::block::{self, BlockDeviceId, BlockError, SECTOR_SIZE};
+use crate::sync::{IrqSpinlock, Mutex};
+use crate::vfs::{FileSystem, FileSystemType, InodeNumber, Metadata, DirEntry, FileType, FsError, FileName};
+
+const ROOT_INODE: InodeNumber = 1;
+const CHAR_DEVICE_INODE_START: InodeNumber = 2;
+const BLOCK_DEVICE_INODE_START: InodeNumber = 0x100;
+const CHAR_DEVICE_COUNT: usize = 16;
+
+/// Only the first 1 GiB of physical memory is available from `/dev/mem`.
+const MEM_DEVICE_SIZE: u64 = 1024 * 1024 * 1024;
+
+pub trait CharDevice: Send {
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<usize, FsError>;
+
+    /// Oldest offset which can still be read, see
+    /// `FileSystem::first_offset`.
+    fn first_offset(&self) -> u64 {
+        0
+    }
+
+    /// File size which is displayed in metadata.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// True if the device locks its own data, so calls to it are not
+    /// serialised. Devices which block must do this, like filesystems
+    /// in `FileSystem::locks_internally`.
+    fn locks_internally(&self) -> bool {
+        false
+    }
+}
+
+struct CharDeviceNode {
+    name: &'static str,
+    mode: u16,
+    device: &'static mut dyn CharDevice,
+    lock: Option<Mutex<()>>,
+}
+
+type CharDeviceTable = ArrayVec<[CharDeviceNode; CHAR_DEVICE_COUNT]>;
+
+/// Devices are never removed, so a device and its lock stay at the same
+/// address after the table is unlocked.
+static CHAR_DEVICES: IrqSpinlock<Option<CharDeviceTable>> = IrqSpinlock::new("char devices", None);
+
+fn with_char_devices<T>(function: impl FnOnce(&mut CharDeviceTable) -> T) -> T {
+    let mut devices = CHAR_DEVICES.lock();
+    function(devices.get_or_insert_with(ArrayVec::new))
+}
+
+/// Call `function` with the device `i` while holding the lock of the
+/// device.
+fn with_char_device<T>(i: usize, function: impl FnOnce(&mut dyn CharDevice) -> T) -> T {
+    let (device, lock) = with_char_devices(|devices| {
+        let node = &mut devices[i];
+        (&mut *node.device as *mut dyn CharDevice, node.lock.as_ref().map(|lock| lock as *const Mutex<()>))
+    });
+
+    let _guard = lock.map(|lock| unsafe { &*lock }.lock());
+    function(unsafe { &mut *device })
+}
+
+pub fn register_char_device(name: &'static str, mode: u16, device: &'static mut dyn CharDevice) -> Result<(), FsError> {
+    let lock = if device.locks_internally() {
+        None
+    } else {
+        Some(Mutex::new(name, ()))
+    };
+
+    with_char_devices(move |devices| {
+        if devices.iter().any(|d| d.name == name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        devices.try_push(CharDeviceNode {
+            name,
+            mode,
+            device,
+            lock,
+        }).map_err(|_| FsError::NoSpace)
+    })
+}
+
+/// Register devices which don't need a driver.
+pub fn register_memory_devices() -> Result<(), FsError> {
+    unsafe {
+        register_char_device("null", 0o666, &mut NULL_DEVICE)?;
+        register_char_device("zero", 0o666, &mut ZERO_DEVICE)?;
+        register_char_device("random", 0o666, &mut RANDOM_DEVICE)?;
+        register_char_device("mem", 0o600, &mut MEM_DEVICE)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Node {
+    Root,
+    Char(usize),
+    Block(BlockDeviceId),
+}
+
+impl Node {
+    fn from_inode(inode: InodeNumber) -> Result<Self, FsError> {
+        if inode == ROOT_INODE {
+            Ok(Node::Root)
+        } else if inode >= BLOCK_DEVICE_INODE_START {
+            let id = (inode - BLOCK_DEVICE_INODE_START) as BlockDeviceId;
+            block::sector_count(id).map_err(|_| FsError::NotFound)?;
+            Ok(Node::Block(id))
+        } else if inode >= CHAR_DEVICE_INODE_START && ((inode - CHAR_DEVICE_INODE_START) as usize) < with_char_devices(|devices| devices.len()) {
+            Ok(Node::Char((inode - CHAR_DEVICE_INODE_START) as usize))
+        } else {
+            Err(FsError::NotFound)
+        }
+    }
+}
+
+fn block_error(e: BlockError) -> FsError {
+    match e {
+        BlockError::ReadOnly => FsError::ReadOnly,
+        BlockError::OutOfRange => FsError::NoSpace,
+        _ => FsError::IoError,
+    }
+}
+
+pub const DEVFS_TYPE: FileSystemType = FileSystemType {
+    name: "devfs",
+    mount: mount_devfs,
+};
+
+/// Device list is global, so all mounts use the same instance.
+static mut DEVFS: DevFs = DevFs;
+
+fn mount_devfs(_source: &str) -> Result<&'static mut dyn FileSystem, FsError> {
+    unsafe {
+        Ok(&mut DEVFS)
+    }
+}
+
+pub struct DevFs;
+
+impl DevFs {
+    fn block_device_size(&self, id: BlockDeviceId) -> Result<u64, FsError> {
+        Ok(block::sector_count(id).map_err(block_error)? * SECTOR_SIZE as u64)
+    }
+
+    fn read_block_device(&self, id: BlockDeviceId, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let size = self.block_device_size(id)?;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let count = core::cmp::min(buffer.len() as u64, size - offset) as usize;
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+
+        while done < count {
+            let position = offset + done as u64;
+            let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+            let chunk = core::cmp::min(SECTOR_SIZE - sector_offset, count - done);
+
+            block::read_sectors(id, position / SECTOR_SIZE as u64, &mut sector).map_err(block_error)?;
+            buffer[done..done + chunk].copy_from_slice(&sector[sector_offset..sector_offset + chunk]);
+
+            done += chunk;
+        }
+
+        Ok(count)
+    }
+
+    fn write_block_device(&self, id: BlockDeviceId, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let size = self.block_device_size(id)?;
+        if offset >= size && !data.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+
+        let count = core::cmp::min(data.len() as u64, size.saturating_sub(offset)) as usize;
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+
+        while done < count {
+            let position = offset + done as u64;
+            let lba = position / SECTOR_SIZE as u64;
+            let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+            let chunk = core::cmp::min(SECTOR_SIZE - sector_offset, count - done);
+
+            if chunk < SECTOR_SIZE {
+                block::read_sectors(id, lba, &mut sector).map_err(block_error)?;
+            }
+
+            sector[sector_offset..sector_offset + chunk].copy_from_slice(&data[done..done + chunk]);
+            block::write_sectors(id, lba, &sector).map_err(block_error)?;
+
+            done += chunk;
+        }
+
+        Ok(count)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    /// Devices such as the console block in `read` and `write`.
+    fn locks_internally(&self) -> bool {
+        true
+    }
+
+    fn root(&self) -> InodeNumber {
+        ROOT_INODE
+    }
+
+    fn lookup(&mut self, directory: InodeNumber, name: &str) -> Result<InodeNumber, FsError> {
+        if let Node::Root = Node::from_inode(directory)? {
+            if let Some(i) = with_char_devices(|devices| devices.iter().position(|d| d.name == name)) {
+                return Ok(CHAR_DEVICE_INODE_START + i as InodeNumber);
+            }
+
+            block::find(name)
+                .map(|id| BLOCK_DEVICE_INODE_START + id as InodeNumber)
+                .ok_or(FsError::NotFound)
+        } else {
+            Err(FsError::NotDirectory)
+        }
+    }
+
+    fn metadata(&mut self, inode: InodeNumber) -> Result<Metadata, FsError> {
+        let (file_type, mode, size) = match Node::from_inode(inode)? {
+            Node::Root => (FileType::Directory, 0o755, 0),
+            Node::Char(i) => {
+                let mode = with_char_devices(|devices| devices[i].mode);
+                (FileType::CharDevice, mode, with_char_device(i, |device| device.size()))
+            }
+            Node::Block(id) => (FileType::BlockDevice, 0o660, self.block_device_size(id)?),
+        };
+
+        Ok(Metadata {
+            inode,
+            file_type,
+            size,
+            mode,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            modified: 0,
+        })
+    }
+
+    fn read(&mut self, inode: InodeNumber, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match Node::from_inode(inode)? {
+            Node::Root => Err(FsError::IsDirectory),
+            Node::Char(i) => with_char_device(i, |device| device.read(offset, buffer)),
+            Node::Block(id) => self.read_block_device(id, offset, buffer),
+        }
+    }
+
+    fn first_offset(&mut self, inode: InodeNumber) -> u64 {
+        match Node::from_inode(inode) {
+            Ok(Node::Char(i)) => with_char_device(i, |device| device.first_offset()),
+            _ => 0,
+        }
+    }
+
+    fn read_dir(&mut self, directory: InodeNumber, index: usize) -> Result<Option<DirEntry>, FsError> {
+        if let Node::Root = Node::from_inode(directory)? {
+            let (name, char_device_count) = with_char_devices(|devices| {
+                (devices.get(index).map(|node| node.name), devices.len())
+            });
+
+            if let Some(name) = name {
+                return Ok(Some(DirEntry {
+                    name: FileName::from(name).unwrap_or_default(),
+                    inode: CHAR_DEVICE_INODE_START + index as InodeNumber,
+                    file_type: FileType::CharDevice,
+                }));
+            }
+
+            let id = index - char_device_count;
+            let entry = block::device_name(id).map(|name| {
+                DirEntry {
+                    name: FileName::from(name).unwrap_or_default(),
+                    inode: BLOCK_DEVICE_INODE_START + id as InodeNumber,
+                    file_type: FileType::BlockDevice,
+                }
+            });
+
+            Ok(entry)
+        } else {
+            Err(FsError::NotDirectory)
+        }
+    }
+
+    fn write(&mut self, inode: InodeNumber, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        match Node::from_inode(inode)? {
+            Node::Root => Err(FsError::IsDirectory),
+            Node::Char(i) => with_char_device(i, |device| device.write(offset, data)),
+            Node::Block(id) => self.write_block_device(id, offset, data),
+        }
+    }
+
+    /// Opening a device with `OpenFlags::TRUNCATE` doesn't do anything.
+    fn truncate(&mut self, _inode: InodeNumber, _size: u64) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+pub struct NullDevice;
+
+impl CharDevice for NullDevice {
+    fn read(&mut self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+pub struct ZeroDevice;
+
+impl CharDevice for ZeroDevice {
+    fn read(&mut self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        for byte in buffer.iter_mut() {
+            *byte = 0;
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// Uses RDRAND if the CPU supports it, otherwise a xorshift generator
+/// seeded from the time stamp counter. Not suitable for cryptography.
+pub struct RandomDevice {
+    state: u32,
+    rdrand: Option<bool>,
+}
+
+impl RandomDevice {
+    fn next(&mut self) -> u32 {
+        let rdrand = *self.rdrand.get_or_insert_with(|| {
+            x86::cpuid::CpuId::new().get_feature_info().map(|f| f.has_rdrand()).unwrap_or(false)
+        });
+
+        if rdrand {
+            let mut value = 0;
+            if unsafe { x86::random::rdrand32(&mut value) } {
+                return value;
+            }
+        }
+
+        if self.state == 0 {
+            self.state = unsafe { x86::time::rdtsc() } as u32 | 1;
+        }
+
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+}
+
+impl CharDevice for RandomDevice {
+    fn read(&mut self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        for chunk in buffer.chunks_mut(4) {
+            let value = self.next().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// Physical memory. Reading uses the identity mapping. Writing is not allowed.
+pub struct MemDevice;
+
+impl CharDevice for MemDevice {
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= MEM_DEVICE_SIZE {
+            return Ok(0);
+        }
+
+        let count = core::cmp::min(buffer.len() as u64, MEM_DEVICE_SIZE - offset) as usize;
+        for (i, byte) in buffer[..count].iter_mut().enumerate() {
+            *byte = unsafe { core::ptr::read_volatile((offset as usize + i) as *const u8) };
+        }
+
+        Ok(count)
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn size(&self) -> u64 {
+        MEM_DEVICE_SIZE
+    }
+}
+
+static mut NULL_DEVICE: NullDevice = NullDevice;
+static mut ZERO_DEVICE: ZeroDevice = ZeroDevice;
+static mut RANDOM_DEVICE: RandomDevice = RandomDevice { state: 0, rdrand: None };
+static mut MEM_DEVICE: MemDevice = MemDevice;
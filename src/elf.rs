@@ -0,0 +1,448 @@
+//! ELF32 i386 executable loader.
+//!
+//! `PT_LOAD` segments are copied to frames which are mapped to user
+//! space. Memory after the file contents of a segment is zero, so `.bss`
+//! doesn't need special handling. Heap starts after the last segment.
+//!
+//! Stack is a memory area which is mapped on demand. Initial stack
+//! follows the System V i386 ABI: `argc`, `argv` pointers, `envp`
+//! pointers and auxiliary vector.
+
+use arrayvec::ArrayVec;
+
+use crate::block::{u16_le, u32_le};
+use crate::frame_allocator::{self, Frame};
+use crate::page_table::{AddressSpace, L1Flags, MapError, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START};
+use crate::process::{self, Pid, ProcessError};
+use crate::usermode::{ExitReason, UserRegisters, USER_STACK_TOP};
+use crate::vfs::{self, Context, FsError, OpenFlags, SeekFrom, FileDescriptor};
+use crate::vma::{self, Backing, Protection, VmaError};
+
+const ELF_HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const MAX_PROGRAM_HEADERS: usize = 16;
+
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_386: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+// Auxiliary vector entry types.
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+
+const STACK_SIZE: usize = 1024 * 1024;
+/// Arguments, environment and auxiliary vector must fit to this many
+/// pages at the top of the stack.
+const ARGUMENT_PAGES: usize = 16;
+pub const MAX_ARGUMENTS: usize = 16;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Fs(FsError),
+    NotElf,
+    Unsupported,
+    InvalidProgramHeader,
+    TooManyProgramHeaders,
+    SegmentOutOfRange,
+    TooManyArguments,
+    ArgumentsTooLarge,
+    OutOfMemory,
+    Map(MapError),
+    Process(ProcessError),
+    Vma(VmaError),
+}
+
+impl From<FsError> for ElfError {
+    fn from(error: FsError) -> Self {
+        ElfError::Fs(error)
+    }
+}
+
+impl From<MapError> for ElfError {
+    fn from(error: MapError) -> Self {
+        ElfError::Map(error)
+    }
+}
+
+impl From<VmaError> for ElfError {
+    fn from(error: VmaError) -> Self {
+        ElfError::Vma(error)
+    }
+}
+
+impl From<ProcessError> for ElfError {
+    fn from(error: ProcessError) -> Self {
+        ElfError::Process(error)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ProgramHeader {
+    segment_type: u32,
+    offset: u32,
+    virtual_address: u32,
+    file_size: u32,
+    memory_size: u32,
+    flags: u32,
+}
+
+impl ProgramHeader {
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            segment_type: u32_le(&bytes[0..4]),
+            offset: u32_le(&bytes[4..8]),
+            virtual_address: u32_le(&bytes[8..12]),
+            file_size: u32_le(&bytes[16..20]),
+            memory_size: u32_le(&bytes[20..24]),
+            flags: u32_le(&bytes[24..28]),
+        }
+    }
+
+    fn page_flags(&self) -> L1Flags {
+        let mut flags = L1Flags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= L1Flags::READ_WRITE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= L1Flags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    fn protection(&self) -> Protection {
+        let mut protection = Protection::empty();
+        if self.flags & PF_R != 0 {
+            protection |= Protection::READ;
+        }
+        if self.flags & PF_W != 0 {
+            protection |= Protection::WRITE;
+        }
+        if self.flags & PF_X != 0 {
+            protection |= Protection::EXECUTE;
+        }
+        protection
+    }
+}
+
+/// Executable information which the loader needs after loading segments.
+struct LoadedProgram {
+    entry: u32,
+    program_header_address: Option<u32>,
+    program_header_count: u32,
+}
+
+/// Executable which is ready to run.
+pub struct Executable {
+    pub address_space: AddressSpace,
+    pub entry: usize,
+    pub stack_pointer: usize,
+}
+
+/// Load executable at `path` to a new address space. The path is
+/// resolved relative to the working directory of `ctx`.
+pub fn load(ctx: &mut Context, path: &str, arguments: &[&str], environment: &[&str]) -> Result<Executable, ElfError> {
+    let mut address_space = AddressSpace::new()?;
+
+    let vfs = vfs::vfs();
+    let fd = vfs.open(ctx, path, OpenFlags::READ)?;
+    let result = load_file(&mut address_space, ctx, fd);
+    vfs.close(ctx, fd)?;
+    let program = result?;
+
+    let stack_pointer = setup_stack(&mut address_space, &program, arguments, environment)?;
+
+    Ok(Executable {
+        address_space,
+        entry: program.entry as usize,
+        stack_pointer,
+    })
+}
+
+/// Load executable at `path` and run it in a new process until it
+/// exits.
+pub fn run(ctx: &mut Context, path: &str, arguments: &[&str], environment: &[&str]) -> Result<ExitReason, ElfError> {
+    let pid = spawn(ctx, path, arguments, environment)?;
+    let (_, reason) = process::wait(0, Some(pid))?;
+    Ok(reason)
+}
+
+/// Start an executable in a new process without waiting for it. The
+/// process gets a copy of the working directory and the open files of
+/// `ctx`.
+pub fn spawn(ctx: &mut Context, path: &str, arguments: &[&str], environment: &[&str]) -> Result<Pid, ElfError> {
+    let executable = load(ctx, path, arguments, environment)?;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let registers = UserRegisters::new(executable.entry, executable.stack_pointer);
+    let context = vfs::vfs().clone_context(ctx);
+    let pid = process::spawn(name, executable.address_space, &registers, context)?;
+    Ok(pid)
+}
+
+fn load_file(address_space: &mut AddressSpace, ctx: &mut Context, fd: FileDescriptor) -> Result<LoadedProgram, ElfError> {
+    let file_size = vfs::vfs().fstat(ctx, fd)?.size;
+
+    let mut header = [0u8; ELF_HEADER_SIZE];
+    read_exact(ctx, fd, 0, &mut header).map_err(|_| ElfError::NotElf)?;
+
+    if &header[0..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+
+    if header[4] != CLASS_32 ||
+        header[5] != DATA_LITTLE_ENDIAN ||
+        header[6] != VERSION_CURRENT ||
+        u16_le(&header[16..18]) != TYPE_EXECUTABLE ||
+        u16_le(&header[18..20]) != MACHINE_386 {
+        return Err(ElfError::Unsupported);
+    }
+
+    let entry = u32_le(&header[24..28]);
+    let program_header_offset = u32_le(&header[28..32]);
+    let program_header_size = u16_le(&header[42..44]) as usize;
+    let program_header_count = u16_le(&header[44..46]) as usize;
+
+    if program_header_size != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::InvalidProgramHeader);
+    }
+
+    if program_header_count > MAX_PROGRAM_HEADERS {
+        return Err(ElfError::TooManyProgramHeaders);
+    }
+
+    if (entry as usize) < USER_SPACE_START || entry as usize >= USER_SPACE_END {
+        return Err(ElfError::SegmentOutOfRange);
+    }
+
+    let mut program_headers = [0u8; PROGRAM_HEADER_SIZE * MAX_PROGRAM_HEADERS];
+    let program_headers = &mut program_headers[..PROGRAM_HEADER_SIZE * program_header_count];
+    read_exact(ctx, fd, program_header_offset as u64, program_headers).map_err(|_| ElfError::InvalidProgramHeader)?;
+
+    let mut program_header_address = None;
+    let mut program_end = 0;
+
+    for bytes in program_headers.chunks(PROGRAM_HEADER_SIZE) {
+        let program_header = ProgramHeader::parse(bytes);
+
+        match program_header.segment_type {
+            PT_LOAD => (),
+            PT_PHDR => {
+                program_header_address = Some(program_header.virtual_address);
+                continue;
+            }
+            _ => continue,
+        }
+
+        if program_header.file_size > program_header.memory_size ||
+            program_header.offset as u64 + program_header.file_size as u64 > file_size {
+            return Err(ElfError::InvalidProgramHeader);
+        }
+
+        let start = program_header.virtual_address as usize;
+        let end = start.checked_add(program_header.memory_size as usize).ok_or(ElfError::SegmentOutOfRange)?;
+        if start < USER_SPACE_START || end > USER_SPACE_END {
+            return Err(ElfError::SegmentOutOfRange);
+        }
+
+        // Program headers are usually in the first segment.
+        if program_header_address.is_none() {
+            let segment_offset = program_header_offset.checked_sub(program_header.offset)
+                .filter(|&offset| offset < program_header.file_size);
+            if let Some(segment_offset) = segment_offset {
+                let address = program_header.virtual_address.checked_add(segment_offset)
+                    .ok_or(ElfError::InvalidProgramHeader)?;
+                program_header_address = Some(address);
+            }
+        }
+
+        load_segment(address_space, ctx, fd, &program_header)?;
+        program_end = core::cmp::max(program_end, end);
+    }
+
+    address_space.memory_map.set_heap_start(program_end);
+
+    Ok(LoadedProgram {
+        entry,
+        program_header_address,
+        program_header_count: program_header_count as u32,
+    })
+}
+
+fn load_segment(address_space: &mut AddressSpace, ctx: &mut Context, fd: FileDescriptor, program_header: &ProgramHeader) -> Result<(), ElfError> {
+    let start = program_header.virtual_address as usize;
+    let file_end = start + program_header.file_size as usize;
+    let end = start + program_header.memory_size as usize;
+    let flags = program_header.page_flags();
+
+    let mut page = start & !(PAGE_SIZE - 1);
+
+    while page < end {
+        let frame = segment_frame(address_space, page, flags)?;
+
+        // Part of the page which has data from the file.
+        let copy_start = core::cmp::max(page, start);
+        let copy_end = core::cmp::min(page + PAGE_SIZE, file_end);
+
+        if copy_start < copy_end {
+            let data = unsafe { &mut frame.data_mut()[copy_start - page..copy_end - page] };
+            let file_offset = program_header.offset as u64 + (copy_start - start) as u64;
+            read_exact(ctx, fd, file_offset, data)?;
+        }
+
+        page += PAGE_SIZE;
+    }
+
+    // Pages are already mapped, so the area only describes them. If
+    // segments share a page, the later segment's area contains the page.
+    let area_start = start & !(PAGE_SIZE - 1);
+    if area_start < page {
+        address_space.memory_map.add(area_start, page, program_header.protection(), Backing::Anonymous)?;
+    }
+
+    Ok(())
+}
+
+/// Frame of a segment page. Segments might share a page, so the page
+/// might already be mapped.
+fn segment_frame(address_space: &mut AddressSpace, page: usize, flags: L1Flags) -> Result<Frame, ElfError> {
+    if let Some(entry) = address_space.user_page_mut(page) {
+        let mut combined_flags = entry.flags() | (flags & L1Flags::READ_WRITE);
+        if !flags.contains(L1Flags::NO_EXECUTE) {
+            combined_flags.remove(L1Flags::NO_EXECUTE);
+        }
+        entry.flags_mut(combined_flags);
+        let frame = Frame::containing_address(entry.address() as usize);
+        address_space.flush_user_page(page);
+        return Ok(frame);
+    }
+
+    let frame = frame_allocator::allocate_zeroed_frame().ok_or(ElfError::OutOfMemory)?;
+    if let Err(e) = address_space.map_user_page(page, frame, flags) {
+        frame_allocator::free_frame(frame);
+        return Err(e.into());
+    }
+
+    Ok(frame)
+}
+
+fn read_exact(ctx: &mut Context, fd: FileDescriptor, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+    let vfs = vfs::vfs();
+    vfs.seek(ctx, fd, SeekFrom::Start(offset))?;
+
+    let mut count = 0;
+    while count < buffer.len() {
+        match vfs.read(ctx, fd, &mut buffer[count..])? {
+            0 => return Err(FsError::IoError),
+            read_count => count += read_count,
+        }
+    }
+
+    Ok(())
+}
+
+/// Map the user stack and write arguments, environment and auxiliary
+/// vector to it. Returns the initial stack pointer.
+fn setup_stack(address_space: &mut AddressSpace, program: &LoadedProgram, arguments: &[&str], environment: &[&str]) -> Result<usize, ElfError> {
+    if arguments.len() + environment.len() > MAX_ARGUMENTS {
+        return Err(ElfError::TooManyArguments);
+    }
+
+    vma::map(address_space, Some(USER_STACK_TOP - STACK_SIZE), STACK_SIZE, Protection::READ | Protection::WRITE, Backing::Anonymous)?;
+
+    let stack_bottom = USER_STACK_TOP - ARGUMENT_PAGES * PAGE_SIZE;
+    let mut pointer = USER_STACK_TOP;
+
+    // Strings are copied to the top of the stack.
+    let mut push_string = |text: &str| -> Result<u32, ElfError> {
+        let length = text.len() + 1;
+        if pointer - stack_bottom < length {
+            return Err(ElfError::ArgumentsTooLarge);
+        }
+        pointer -= length;
+        write_stack(address_space, pointer, text.as_bytes())?;
+        write_stack(address_space, pointer + text.len(), &[0])?;
+        Ok(pointer as u32)
+    };
+
+    let mut argument_pointers: ArrayVec<[u32; MAX_ARGUMENTS]> = ArrayVec::new();
+    for argument in arguments {
+        argument_pointers.push(push_string(argument)?);
+    }
+
+    let mut environment_pointers: ArrayVec<[u32; MAX_ARGUMENTS]> = ArrayVec::new();
+    for variable in environment {
+        environment_pointers.push(push_string(variable)?);
+    }
+
+    let mut auxiliary_vector: ArrayVec<[(u32, u32); 6]> = ArrayVec::new();
+    if let Some(address) = program.program_header_address {
+        auxiliary_vector.push((AT_PHDR, address));
+    }
+    auxiliary_vector.push((AT_PHENT, PROGRAM_HEADER_SIZE as u32));
+    auxiliary_vector.push((AT_PHNUM, program.program_header_count));
+    auxiliary_vector.push((AT_PAGESZ, PAGE_SIZE as u32));
+    auxiliary_vector.push((AT_ENTRY, program.entry));
+    auxiliary_vector.push((AT_NULL, 0));
+
+    // argc, argv pointers, null, envp pointers, null and auxiliary vector.
+    let word_count = 1 + argument_pointers.len() + 1 + environment_pointers.len() + 1 + auxiliary_vector.len() * 2;
+    let size = word_count * 4;
+    if pointer - stack_bottom < size + 16 {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    // ABI requires 16 byte alignment.
+    let stack_pointer = (pointer - size) & !0xF;
+
+    let words = core::iter::once(argument_pointers.len() as u32)
+        .chain(argument_pointers.iter().cloned())
+        .chain(core::iter::once(0))
+        .chain(environment_pointers.iter().cloned())
+        .chain(core::iter::once(0))
+        .chain(auxiliary_vector.iter().flat_map(|&(key, value)| core::iter::once(key).chain(core::iter::once(value))));
+
+    for (i, word) in words.enumerate() {
+        write_stack(address_space, stack_pointer + i * 4, &word.to_le_bytes())?;
+    }
+
+    Ok(stack_pointer)
+}
+
+/// Write to user pages using the identity mapping. Stack pages are
+/// mapped if they are not mapped yet.
+fn write_stack(address_space: &mut AddressSpace, address: usize, data: &[u8]) -> Result<(), ElfError> {
+    let mut written = 0;
+
+    while written < data.len() {
+        let current = address + written;
+        let page_offset = current % PAGE_SIZE;
+        let count = core::cmp::min(PAGE_SIZE - page_offset, data.len() - written);
+
+        if address_space.user_page_mut(current).is_none() && !vma::handle_page_fault(address_space, current, true) {
+            return Err(ElfError::OutOfMemory);
+        }
+
+        let entry = address_space.user_page_mut(current).ok_or(ElfError::ArgumentsTooLarge)?;
+        let frame = Frame::containing_address(entry.address() as usize);
+        unsafe {
+            frame.data_mut()[page_offset..page_offset + count].copy_from_slice(&data[written..written + count]);
+        }
+
+        written += count;
+    }
+
+    Ok(())
+}
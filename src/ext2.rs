@@ -0,0 +1,456 @@
+//! Read-only ext2 filesystem driver.
+//!
+//! Inode numbers are ext2 inode numbers. Block sizes from 1 KiB to
+//! 4 KiB are supported.
+
+use crate::block::{self, BlockDeviceId, SECTOR_SIZE, u16_le, u32_le};
+use crate::sync::IrqSpinlock;
+use crate::vfs::{FileSystem, FileSystemType, InodeNumber, Metadata, DirEntry, FileType, FsError, FileName, PathBuf};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_MAGIC: u16 = 0xEF53;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const MAX_BLOCK_SIZE: usize = 4096;
+
+const ROOT_INODE: InodeNumber = 2;
+const REVISION_0_INODE_SIZE: u32 = 128;
+
+const DIRECT_BLOCKS: u32 = 12;
+const SINGLY_INDIRECT_BLOCK: usize = 12;
+const DOUBLY_INDIRECT_BLOCK: usize = 13;
+const TRIPLY_INDIRECT_BLOCK: usize = 14;
+
+/// Directory entries contain file type.
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Only changes where bitmaps and inode tables are, so reading works.
+const FEATURE_INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT_FEATURES: u32 = FEATURE_INCOMPAT_FILETYPE | FEATURE_INCOMPAT_FLEX_BG;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+const MODE_PERMISSIONS_MASK: u16 = 0o7777;
+
+/// Symlinks shorter than this store the target in the block pointers.
+const FAST_SYMLINK_MAX_LENGTH: u64 = 60;
+
+const EXT2_INSTANCE_COUNT: usize = 2;
+
+static EXT2_INSTANCES: IrqSpinlock<[Option<Ext2Fs>; EXT2_INSTANCE_COUNT]> = IrqSpinlock::new("ext2 instances", [None, None]);
+
+pub const EXT2_TYPE: FileSystemType = FileSystemType {
+    name: "ext2",
+    mount: mount_ext2,
+};
+
+fn mount_ext2(source: &str) -> Result<&'static mut dyn FileSystem, FsError> {
+    let device = block::find(source).ok_or(FsError::NotFound)?;
+    let fs = Ext2Fs::open(device)?;
+
+    let mut instances = EXT2_INSTANCES.lock();
+    let instance = instances.iter_mut()
+        .find(|instance| instance.is_none())
+        .ok_or(FsError::NoSpace)?;
+    let fs = instance.get_or_insert(fs) as *mut Ext2Fs;
+
+    // Instances are never freed, and the VFS locks the mount for every
+    // call to the instance.
+    Ok(unsafe { &mut *fs })
+}
+
+#[derive(Copy, Clone)]
+struct Inode {
+    mode: u16,
+    uid: u16,
+    size: u64,
+    modified: u32,
+    gid: u16,
+    links: u16,
+    /// Allocated space in 512 byte units.
+    sectors: u32,
+    file_acl: u32,
+    blocks: [u32; 15],
+}
+
+impl Inode {
+    fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            // VFS doesn't have types for FIFOs and sockets.
+            _ => FileType::Regular,
+        }
+    }
+}
+
+struct DirectoryCursor {
+    position: u64,
+    buffer: [u8; MAX_BLOCK_SIZE],
+    loaded_block: Option<u32>,
+}
+
+impl DirectoryCursor {
+    fn new() -> Self {
+        Self {
+            position: 0,
+            buffer: [0; MAX_BLOCK_SIZE],
+            loaded_block: None,
+        }
+    }
+}
+
+struct RawDirEntry {
+    inode: InodeNumber,
+    file_type: u8,
+    name: FileName,
+}
+
+pub struct Ext2Fs {
+    device: BlockDeviceId,
+    block_size: u32,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u32,
+    group_descriptor_table_block: u32,
+    directory_entries_have_type: bool,
+}
+
+impl Ext2Fs {
+    pub fn open(device: BlockDeviceId) -> Result<Self, FsError> {
+        let mut superblock = [0u8; SECTOR_SIZE * 2];
+        block::read_sectors(device, SUPERBLOCK_OFFSET / SECTOR_SIZE as u64, &mut superblock).map_err(|_| FsError::IoError)?;
+
+        if u16_le(&superblock[56..58]) != SUPERBLOCK_MAGIC {
+            return Err(FsError::Corrupted);
+        }
+
+        let log_block_size = u32_le(&superblock[24..28]);
+        if log_block_size > 2 {
+            return Err(FsError::NotSupported);
+        }
+        let block_size = 1024 << log_block_size;
+
+        let revision = u32_le(&superblock[76..80]);
+        let (inode_size, incompat_features) = if revision == 0 {
+            (REVISION_0_INODE_SIZE, 0)
+        } else {
+            (u32::from(u16_le(&superblock[88..90])), u32_le(&superblock[96..100]))
+        };
+
+        if incompat_features & !SUPPORTED_INCOMPAT_FEATURES != 0 {
+            return Err(FsError::NotSupported);
+        }
+
+        let inodes_per_group = u32_le(&superblock[40..44]);
+        if inodes_per_group == 0 || inode_size < REVISION_0_INODE_SIZE || !inode_size.is_power_of_two() || inode_size as usize > SECTOR_SIZE {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(Self {
+            device,
+            block_size,
+            inode_count: u32_le(&superblock[0..4]),
+            inodes_per_group,
+            inode_size,
+            group_descriptor_table_block: u32_le(&superblock[20..24]) + 1,
+            directory_entries_have_type: incompat_features & FEATURE_INCOMPAT_FILETYPE != 0,
+        })
+    }
+
+    /// Read sector which contains byte `offset` and return offset inside the sector.
+    fn read_sector_at(&self, offset: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<usize, FsError> {
+        block::read_sectors(self.device, offset / SECTOR_SIZE as u64, buffer).map_err(|_| FsError::IoError)?;
+        Ok((offset % SECTOR_SIZE as u64) as usize)
+    }
+
+    fn read_block(&self, block: u32, buffer: &mut [u8]) -> Result<(), FsError> {
+        let buffer = &mut buffer[..self.block_size as usize];
+
+        if block == 0 {
+            // Sparse file.
+            for byte in buffer.iter_mut() {
+                *byte = 0;
+            }
+            return Ok(());
+        }
+
+        let sector = u64::from(block) * u64::from(self.block_size) / SECTOR_SIZE as u64;
+        block::read_sectors(self.device, sector, buffer).map_err(|_| FsError::IoError)
+    }
+
+    fn inode(&self, inode: InodeNumber) -> Result<Inode, FsError> {
+        if inode == 0 || inode > self.inode_count {
+            return Err(FsError::NotFound);
+        }
+
+        let group = (inode - 1) / self.inodes_per_group;
+        let index = (inode - 1) % self.inodes_per_group;
+
+        let mut buffer = [0u8; SECTOR_SIZE];
+        let descriptor_offset = u64::from(self.group_descriptor_table_block) * u64::from(self.block_size) + u64::from(group) * GROUP_DESCRIPTOR_SIZE;
+        let i = self.read_sector_at(descriptor_offset, &mut buffer)?;
+        let inode_table = u32_le(&buffer[i + 8..i + 12]);
+
+        let inode_offset = u64::from(inode_table) * u64::from(self.block_size) + u64::from(index) * u64::from(self.inode_size);
+        let i = self.read_sector_at(inode_offset, &mut buffer)?;
+        let raw = &buffer[i..i + REVISION_0_INODE_SIZE as usize];
+
+        let mode = u16_le(&raw[0..2]);
+        let size_high = if mode & MODE_TYPE_MASK == MODE_REGULAR { u32_le(&raw[108..112]) } else { 0 };
+
+        let mut blocks = [0u32; 15];
+        for (j, block) in blocks.iter_mut().enumerate() {
+            *block = u32_le(&raw[40 + j * 4..44 + j * 4]);
+        }
+
+        Ok(Inode {
+            mode,
+            uid: u16_le(&raw[2..4]),
+            size: u64::from(u32_le(&raw[4..8])) | u64::from(size_high) << 32,
+            modified: u32_le(&raw[16..20]),
+            gid: u16_le(&raw[24..26]),
+            links: u16_le(&raw[26..28]),
+            sectors: u32_le(&raw[28..32]),
+            file_acl: u32_le(&raw[104..108]),
+            blocks,
+        })
+    }
+
+    fn indirect_block_entry(&self, block: u32, index: u32) -> Result<u32, FsError> {
+        let mut buffer = [0u8; SECTOR_SIZE];
+        let i = self.read_sector_at(u64::from(block) * u64::from(self.block_size) + u64::from(index) * 4, &mut buffer)?;
+        Ok(u32_le(&buffer[i..i + 4]))
+    }
+
+    /// Convert file block number to filesystem block number. Zero means that
+    /// the block is not allocated.
+    fn block_address(&self, inode: &Inode, mut logical: u32) -> Result<u32, FsError> {
+        if logical < DIRECT_BLOCKS {
+            return Ok(inode.blocks[logical as usize]);
+        }
+        logical -= DIRECT_BLOCKS;
+
+        let pointers = self.block_size / 4;
+        let (mut block, levels) = if logical < pointers {
+            (inode.blocks[SINGLY_INDIRECT_BLOCK], 1)
+        } else if logical - pointers < pointers * pointers {
+            logical -= pointers;
+            (inode.blocks[DOUBLY_INDIRECT_BLOCK], 2)
+        } else {
+            logical -= pointers + pointers * pointers;
+            if logical / pointers / pointers >= pointers {
+                return Err(FsError::InvalidArgument);
+            }
+            (inode.blocks[TRIPLY_INDIRECT_BLOCK], 3)
+        };
+
+        for level in (0..levels).rev() {
+            if block == 0 {
+                return Ok(0);
+            }
+            let index = logical / pointers.pow(level) % pointers;
+            block = self.indirect_block_entry(block, index)?;
+        }
+
+        Ok(block)
+    }
+
+    fn read_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+
+        let count = core::cmp::min(buffer.len() as u64, inode.size - offset) as usize;
+        let block_size = u64::from(self.block_size);
+        let mut block_buffer = [0u8; MAX_BLOCK_SIZE];
+        let mut done = 0;
+
+        while done < count {
+            let position = offset + done as u64;
+            let block_offset = (position % block_size) as usize;
+            let chunk = core::cmp::min(self.block_size as usize - block_offset, count - done);
+
+            let block = self.block_address(inode, (position / block_size) as u32)?;
+            self.read_block(block, &mut block_buffer)?;
+            buffer[done..done + chunk].copy_from_slice(&block_buffer[block_offset..block_offset + chunk]);
+
+            done += chunk;
+        }
+
+        Ok(count)
+    }
+
+    fn directory(&self, inode: InodeNumber) -> Result<Inode, FsError> {
+        let directory = self.inode(inode)?;
+        if directory.file_type() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(directory)
+    }
+
+    /// Return next used directory entry.
+    fn next_dir_entry(&self, directory: &Inode, cursor: &mut DirectoryCursor) -> Result<Option<RawDirEntry>, FsError> {
+        let block_size = u64::from(self.block_size);
+
+        while cursor.position < directory.size {
+            let logical = (cursor.position / block_size) as u32;
+            if cursor.loaded_block != Some(logical) {
+                let block = self.block_address(directory, logical)?;
+                self.read_block(block, &mut cursor.buffer)?;
+                cursor.loaded_block = Some(logical);
+            }
+
+            let i = (cursor.position % block_size) as usize;
+            let raw = &cursor.buffer[i..self.block_size as usize];
+            if raw.len() < 8 {
+                return Err(FsError::Corrupted);
+            }
+
+            let inode = u32_le(&raw[0..4]);
+            let record_length = u16_le(&raw[4..6]) as usize;
+            let name_length = raw[6] as usize;
+
+            if record_length < 8 || record_length % 4 != 0 || record_length > raw.len() || name_length + 8 > record_length {
+                return Err(FsError::Corrupted);
+            }
+
+            cursor.position += record_length as u64;
+
+            if inode == 0 {
+                continue;
+            }
+
+            let name_bytes = &raw[8..8 + name_length];
+            let mut name = FileName::new();
+            match core::str::from_utf8(name_bytes) {
+                Ok(text) if text.len() <= name.capacity() => name.push_str(text),
+                _ => {
+                    for &byte in name_bytes.iter().take(name.capacity()) {
+                        name.push(if byte.is_ascii() { byte as char } else { '?' });
+                    }
+                }
+            }
+
+            return Ok(Some(RawDirEntry {
+                inode,
+                file_type: if self.directory_entries_have_type { raw[7] } else { 0 },
+                name,
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> InodeNumber {
+        ROOT_INODE
+    }
+
+    fn lookup(&mut self, directory: InodeNumber, name: &str) -> Result<InodeNumber, FsError> {
+        let directory = self.directory(directory)?;
+        let mut cursor = DirectoryCursor::new();
+
+        while let Some(entry) = self.next_dir_entry(&directory, &mut cursor)? {
+            if entry.name.as_str() == name {
+                return Ok(entry.inode);
+            }
+        }
+
+        Err(FsError::NotFound)
+    }
+
+    fn metadata(&mut self, inode_number: InodeNumber) -> Result<Metadata, FsError> {
+        let inode = self.inode(inode_number)?;
+
+        Ok(Metadata {
+            inode: inode_number,
+            file_type: inode.file_type(),
+            size: inode.size,
+            mode: inode.mode & MODE_PERMISSIONS_MASK,
+            uid: inode.uid,
+            gid: inode.gid,
+            links: inode.links,
+            modified: inode.modified,
+        })
+    }
+
+    fn read(&mut self, inode: InodeNumber, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.inode(inode)?;
+
+        match inode.file_type() {
+            FileType::Regular => self.read_data(&inode, offset, buffer),
+            FileType::Directory => Err(FsError::IsDirectory),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn read_dir(&mut self, directory: InodeNumber, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let directory = self.directory(directory)?;
+        let mut cursor = DirectoryCursor::new();
+        let mut i = 0;
+
+        while let Some(entry) = self.next_dir_entry(&directory, &mut cursor)? {
+            if entry.name.as_str() == "." || entry.name.as_str() == ".." {
+                continue;
+            }
+
+            if i == index {
+                let file_type = match entry.file_type {
+                    2 => FileType::Directory,
+                    3 => FileType::CharDevice,
+                    4 => FileType::BlockDevice,
+                    7 => FileType::Symlink,
+                    1 | 5 | 6 => FileType::Regular,
+                    _ => self.inode(entry.inode)?.file_type(),
+                };
+
+                return Ok(Some(DirEntry {
+                    name: entry.name,
+                    inode: entry.inode,
+                    file_type,
+                }));
+            }
+
+            i += 1;
+        }
+
+        Ok(None)
+    }
+
+    fn read_link(&mut self, inode: InodeNumber, target: &mut PathBuf) -> Result<(), FsError> {
+        let inode = self.inode(inode)?;
+        if inode.file_type() != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+
+        target.clear();
+
+        let extended_attribute_sectors = if inode.file_acl != 0 { self.block_size / SECTOR_SIZE as u32 } else { 0 };
+        let mut buffer = [0u8; crate::vfs::PATH_MAX_LENGTH];
+
+        let length = if inode.size < FAST_SYMLINK_MAX_LENGTH && inode.sectors == extended_attribute_sectors {
+            for (i, block) in inode.blocks.iter().enumerate() {
+                buffer[i * 4..i * 4 + 4].copy_from_slice(&block.to_le_bytes());
+            }
+            inode.size as usize
+        } else {
+            if inode.size > buffer.len() as u64 {
+                return Err(FsError::PathTooLong);
+            }
+            self.read_data(&inode, 0, &mut buffer)?
+        };
+
+        let text = core::str::from_utf8(&buffer[..length]).map_err(|_| FsError::Corrupted)?;
+        target.try_push_str(text).map_err(|_| FsError::PathTooLong)
+    }
+}
@@ -0,0 +1,1354 @@
+//! FAT12, FAT16 and FAT32 filesystem driver.
+//!
+//! FAT doesn't have inode numbers, so the location of the short directory
+//! entry is used as the inode number: `sector * 16 + entry index`. Root
+//! directory doesn't have a directory entry and it uses inode number 0, which
+//! is always the boot sector.
+//!
+//! Only 512 byte sectors are supported.
+
+use arrayvec::ArrayVec;
+
+use crate::block::{self, BlockDeviceId, SECTOR_SIZE, u16_le, u32_le, set_u16_le, set_u32_le};
+use crate::sync::IrqSpinlock;
+use crate::vfs::{FileSystem, FileSystemType, InodeNumber, Metadata, DirEntry, FileType, FsError, FileName};
+
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / DIRECTORY_ENTRY_SIZE) as u32;
+const ROOT_INODE: InodeNumber = 0;
+
+const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+const ATTRIBUTE_LONG_NAME_MASK: u8 = 0x3F;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xE5;
+
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXTENSION: u8 = 0x10;
+
+const LONG_NAME_LAST_ENTRY: u8 = 0x40;
+const LONG_NAME_MAX_LENGTH: usize = 255;
+const LONG_NAME_CHARS_PER_ENTRY: usize = 13;
+const LONG_NAME_CHAR_OFFSETS: [usize; LONG_NAME_CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LONG_NAME_MAX_ENTRIES: usize = (LONG_NAME_MAX_LENGTH + LONG_NAME_CHARS_PER_ENTRY - 1) / LONG_NAME_CHARS_PER_ENTRY;
+/// Long name entries and the short entry.
+const MAX_ENTRIES_PER_FILE: usize = LONG_NAME_MAX_ENTRIES + 1;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// There is no real-time clock driver, so new entries use the FAT epoch 1980-01-01.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+const DEFAULT_TIME: u16 = 0;
+
+const FAT_INSTANCE_COUNT: usize = 2;
+
+static FAT_INSTANCES: IrqSpinlock<[Option<FatFs>; FAT_INSTANCE_COUNT]> = IrqSpinlock::new("fat instances", [None, None]);
+
+pub const FAT_TYPE: FileSystemType = FileSystemType {
+    name: "fat",
+    mount: mount_fat,
+};
+
+fn mount_fat(source: &str) -> Result<&'static mut dyn FileSystem, FsError> {
+    let device = block::find(source).ok_or(FsError::NotFound)?;
+    let fs = FatFs::open(device)?;
+
+    let mut instances = FAT_INSTANCES.lock();
+    let instance = instances.iter_mut()
+        .find(|instance| instance.is_none())
+        .ok_or(FsError::NoSpace)?;
+    let fs = instance.get_or_insert(fs) as *mut FatFs;
+
+    // Instances are never freed, and the VFS locks the mount for every
+    // call to the instance.
+    Ok(unsafe { &mut *fs })
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn is_end_of_chain(self, value: u32) -> bool {
+        match self {
+            FatType::Fat12 => value >= 0xFF8,
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= 0x0FFF_FFF8,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Directory {
+    /// FAT12 and FAT16 root directory which has a fixed location and size.
+    FixedRoot,
+    Clusters(u32),
+}
+
+struct DirectoryCursor {
+    directory: Directory,
+    cluster: u32,
+    sector_index: u32,
+    entry_index: u32,
+    /// Clusters which the cursor has moved to after the first one. A
+    /// chain longer than the cluster count has a cycle.
+    clusters_followed: u32,
+    buffer: [u8; SECTOR_SIZE],
+    loaded_sector: Option<u32>,
+    finished: bool,
+}
+
+impl DirectoryCursor {
+    fn new(directory: Directory) -> Self {
+        let cluster = match directory {
+            Directory::FixedRoot => 0,
+            Directory::Clusters(cluster) => cluster,
+        };
+
+        Self {
+            directory,
+            cluster,
+            sector_index: 0,
+            entry_index: 0,
+            clusters_followed: 0,
+            buffer: [0; SECTOR_SIZE],
+            loaded_sector: None,
+            finished: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct RawEntry {
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+    write_time: u16,
+    write_date: u16,
+}
+
+impl RawEntry {
+    fn parse(raw: &[u8]) -> Self {
+        Self {
+            attributes: raw[11],
+            first_cluster: u32::from(u16_le(&raw[26..28])) | u32::from(u16_le(&raw[20..22])) << 16,
+            size: u32_le(&raw[28..32]),
+            write_time: u16_le(&raw[22..24]),
+            write_date: u16_le(&raw[24..26]),
+        }
+    }
+
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+}
+
+/// Directory entry with its long name entries.
+struct FileEntry {
+    name: FileName,
+    short_name: [u8; 11],
+    nt_flags: u8,
+    entry: RawEntry,
+    /// Location of the short entry.
+    location: u32,
+    /// Locations of all entries, including the short entry.
+    locations: ArrayVec<[u32; MAX_ENTRIES_PER_FILE]>,
+}
+
+pub struct FatFs {
+    device: BlockDeviceId,
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    fat_count: u32,
+    sectors_per_fat: u32,
+    root_directory_sectors: u32,
+    first_data_sector: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info_sector: Option<u32>,
+    free_clusters: u32,
+    next_free_cluster: u32,
+    fs_info_dirty: bool,
+    fat_cache: [u8; SECTOR_SIZE],
+    fat_cache_sector: Option<u32>,
+    fat_cache_dirty: bool,
+}
+
+impl FatFs {
+    pub fn open(device: BlockDeviceId) -> Result<Self, FsError> {
+        let mut boot_sector = [0u8; SECTOR_SIZE];
+        block::read_sectors(device, 0, &mut boot_sector).map_err(|_| FsError::IoError)?;
+
+        if boot_sector[510] != 0x55 || boot_sector[511] != 0xAA {
+            return Err(FsError::Corrupted);
+        }
+
+        if u16_le(&boot_sector[11..13]) as usize != SECTOR_SIZE {
+            return Err(FsError::NotSupported);
+        }
+
+        let sectors_per_cluster = u32::from(boot_sector[13]);
+        let reserved_sectors = u32::from(u16_le(&boot_sector[14..16]));
+        let fat_count = u32::from(boot_sector[16]);
+        let root_entry_count = u32::from(u16_le(&boot_sector[17..19]));
+
+        let total_sectors = match u16_le(&boot_sector[19..21]) {
+            0 => u32_le(&boot_sector[32..36]),
+            count => u32::from(count),
+        };
+
+        let sectors_per_fat = match u16_le(&boot_sector[22..24]) {
+            0 => u32_le(&boot_sector[36..40]),
+            count => u32::from(count),
+        };
+
+        if !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0 || fat_count == 0 || sectors_per_fat == 0 {
+            return Err(FsError::Corrupted);
+        }
+
+        let root_directory_sectors = (root_entry_count * DIRECTORY_ENTRY_SIZE as u32 + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
+        let first_data_sector = reserved_sectors + fat_count * sectors_per_fat + root_directory_sectors;
+        let data_sectors = total_sectors.checked_sub(first_data_sector).ok_or(FsError::Corrupted)?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+
+        let fat_type = if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        let (root_cluster, fs_info_sector) = if fat_type == FatType::Fat32 {
+            (u32_le(&boot_sector[44..48]), Some(u32::from(u16_le(&boot_sector[48..50]))))
+        } else {
+            (0, None)
+        };
+
+        let mut fs = Self {
+            device,
+            fat_type,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            sectors_per_fat,
+            root_directory_sectors,
+            first_data_sector,
+            cluster_count,
+            root_cluster,
+            fs_info_sector,
+            free_clusters: FSINFO_UNKNOWN,
+            next_free_cluster: FSINFO_UNKNOWN,
+            fs_info_dirty: false,
+            fat_cache: [0; SECTOR_SIZE],
+            fat_cache_sector: None,
+            fat_cache_dirty: false,
+        };
+
+        if fat_type == FatType::Fat32 && !fs.is_valid_cluster(root_cluster) {
+            return Err(FsError::Corrupted);
+        }
+
+        fs.read_fs_info()?;
+
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    fn read_sector(&self, sector: u32, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), FsError> {
+        block::read_sectors(self.device, u64::from(sector), buffer).map_err(|_| FsError::IoError)
+    }
+
+    fn write_sector(&self, sector: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), FsError> {
+        block::write_sectors(self.device, u64::from(sector), data).map_err(|_| FsError::IoError)
+    }
+
+    fn read_fs_info(&mut self) -> Result<(), FsError> {
+        let sector = match self.fs_info_sector {
+            Some(sector) if sector != 0 && sector < self.reserved_sectors => sector,
+            _ => {
+                self.fs_info_sector = None;
+                return Ok(());
+            }
+        };
+
+        let mut buffer = [0u8; SECTOR_SIZE];
+        self.read_sector(sector, &mut buffer)?;
+
+        if u32_le(&buffer[0..4]) != FSINFO_LEAD_SIGNATURE || u32_le(&buffer[484..488]) != FSINFO_STRUCT_SIGNATURE {
+            self.fs_info_sector = None;
+            return Ok(());
+        }
+
+        let free_clusters = u32_le(&buffer[488..492]);
+        if free_clusters <= self.cluster_count {
+            self.free_clusters = free_clusters;
+        }
+
+        let next_free_cluster = u32_le(&buffer[492..496]);
+        if self.is_valid_cluster(next_free_cluster) {
+            self.next_free_cluster = next_free_cluster;
+        }
+
+        Ok(())
+    }
+
+    fn write_fs_info(&mut self) -> Result<(), FsError> {
+        let sector = match (self.fs_info_dirty, self.fs_info_sector) {
+            (true, Some(sector)) => sector,
+            _ => return Ok(()),
+        };
+
+        let mut buffer = [0u8; SECTOR_SIZE];
+        self.read_sector(sector, &mut buffer)?;
+        set_u32_le(&mut buffer[488..492], self.free_clusters);
+        set_u32_le(&mut buffer[492..496], self.next_free_cluster);
+        self.write_sector(sector, &buffer)?;
+
+        self.fs_info_dirty = false;
+        Ok(())
+    }
+
+    /// Write cached FAT sector to every FAT copy and update FSInfo.
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.flush_fat_cache()?;
+        self.write_fs_info()
+    }
+
+    fn flush_fat_cache(&mut self) -> Result<(), FsError> {
+        if let (true, Some(sector)) = (self.fat_cache_dirty, self.fat_cache_sector) {
+            for i in 0..self.fat_count {
+                self.write_sector(self.reserved_sectors + i * self.sectors_per_fat + sector, &self.fat_cache)?;
+            }
+            self.fat_cache_dirty = false;
+        }
+        Ok(())
+    }
+
+    fn load_fat_sector(&mut self, fat_sector: u32) -> Result<(), FsError> {
+        if self.fat_cache_sector == Some(fat_sector) {
+            return Ok(());
+        }
+
+        if fat_sector >= self.sectors_per_fat {
+            return Err(FsError::Corrupted);
+        }
+
+        self.flush_fat_cache()?;
+
+        let mut buffer = [0u8; SECTOR_SIZE];
+        self.read_sector(self.reserved_sectors + fat_sector, &mut buffer)?;
+        self.fat_cache = buffer;
+        self.fat_cache_sector = Some(fat_sector);
+        Ok(())
+    }
+
+    fn fat_byte(&mut self, offset: u32) -> Result<u8, FsError> {
+        self.load_fat_sector(offset / SECTOR_SIZE as u32)?;
+        Ok(self.fat_cache[offset as usize % SECTOR_SIZE])
+    }
+
+    fn set_fat_byte(&mut self, offset: u32, value: u8) -> Result<(), FsError> {
+        self.load_fat_sector(offset / SECTOR_SIZE as u32)?;
+        self.fat_cache[offset as usize % SECTOR_SIZE] = value;
+        self.fat_cache_dirty = true;
+        Ok(())
+    }
+
+    fn fat_u16(&mut self, offset: u32) -> Result<u16, FsError> {
+        Ok(u16::from(self.fat_byte(offset)?) | u16::from(self.fat_byte(offset + 1)?) << 8)
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let value = match self.fat_type {
+            FatType::Fat12 => {
+                let value = self.fat_u16(cluster + cluster / 2)?;
+                if cluster % 2 == 1 {
+                    value >> 4
+                } else {
+                    value & 0x0FFF
+                }.into()
+            }
+            FatType::Fat16 => self.fat_u16(cluster * 2)?.into(),
+            FatType::Fat32 => {
+                let offset = cluster * 4;
+                (u32::from(self.fat_u16(offset)?) | u32::from(self.fat_u16(offset + 2)?) << 16) & 0x0FFF_FFFF
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        match self.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                if cluster % 2 == 1 {
+                    let low = self.fat_byte(offset)?;
+                    self.set_fat_byte(offset, (low & 0x0F) | (value << 4) as u8)?;
+                    self.set_fat_byte(offset + 1, (value >> 4) as u8)?;
+                } else {
+                    let high = self.fat_byte(offset + 1)?;
+                    self.set_fat_byte(offset, value as u8)?;
+                    self.set_fat_byte(offset + 1, (high & 0xF0) | ((value >> 8) as u8 & 0x0F))?;
+                }
+            }
+            FatType::Fat16 => {
+                let offset = cluster * 2;
+                self.set_fat_byte(offset, value as u8)?;
+                self.set_fat_byte(offset + 1, (value >> 8) as u8)?;
+            }
+            FatType::Fat32 => {
+                // Highest 4 bits are reserved and must be preserved.
+                let offset = cluster * 4;
+                let reserved = self.fat_byte(offset + 3)? & 0xF0;
+                self.set_fat_byte(offset, value as u8)?;
+                self.set_fat_byte(offset + 1, (value >> 8) as u8)?;
+                self.set_fat_byte(offset + 2, (value >> 16) as u8)?;
+                self.set_fat_byte(offset + 3, reserved | ((value >> 24) as u8 & 0x0F))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let next = self.fat_entry(cluster)?;
+
+        if self.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else if self.fat_type.is_end_of_chain(next) {
+            Ok(None)
+        } else {
+            Err(FsError::Corrupted)
+        }
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.first_data_sector + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    /// Allocate a zeroed cluster and append it to the cluster chain which ends with `previous`.
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, FsError> {
+        let start = if self.is_valid_cluster(self.next_free_cluster) { self.next_free_cluster } else { 2 };
+
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (start - 2 + i) % self.cluster_count;
+
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+
+            self.set_fat_entry(cluster, self.fat_type.end_of_chain())?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+
+            let zeros = [0u8; SECTOR_SIZE];
+            for sector in 0..self.sectors_per_cluster {
+                self.write_sector(self.cluster_sector(cluster) + sector, &zeros)?;
+            }
+
+            self.next_free_cluster = cluster + 1;
+            // A free cluster was found, so a zero count is stale.
+            self.free_clusters = match self.free_clusters {
+                FSINFO_UNKNOWN | 0 => FSINFO_UNKNOWN,
+                count => count - 1,
+            };
+            self.fs_info_dirty = true;
+
+            return Ok(cluster);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_cluster_chain(&mut self, first: u32) -> Result<(), FsError> {
+        let mut cluster = if self.is_valid_cluster(first) { Some(first) } else { None };
+
+        while let Some(current) = cluster {
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, 0)?;
+
+            // Unknown counts are larger than the cluster count and stay
+            // unknown, and stale counts become unknown.
+            self.free_clusters = if self.free_clusters < self.cluster_count {
+                self.free_clusters + 1
+            } else {
+                FSINFO_UNKNOWN
+            };
+            self.fs_info_dirty = true;
+        }
+
+        Ok(())
+    }
+
+    fn root_directory(&self) -> Directory {
+        match self.fat_type {
+            FatType::Fat32 => Directory::Clusters(self.root_cluster),
+            FatType::Fat12 | FatType::Fat16 => Directory::FixedRoot,
+        }
+    }
+
+    fn directory(&mut self, inode: InodeNumber) -> Result<Directory, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(self.root_directory());
+        }
+
+        let entry = self.entry(inode)?;
+        if !entry.is_directory() {
+            return Err(FsError::NotDirectory);
+        }
+
+        // Cluster 0 in ".." entries refers to the root directory.
+        if entry.first_cluster == 0 {
+            Ok(self.root_directory())
+        } else if self.is_valid_cluster(entry.first_cluster) {
+            Ok(Directory::Clusters(entry.first_cluster))
+        } else {
+            Err(FsError::Corrupted)
+        }
+    }
+
+    fn cursor_sector(&self, cursor: &DirectoryCursor) -> u32 {
+        match cursor.directory {
+            Directory::FixedRoot => self.reserved_sectors + self.fat_count * self.sectors_per_fat + cursor.sector_index,
+            Directory::Clusters(_) => self.cluster_sector(cursor.cluster) + cursor.sector_index,
+        }
+    }
+
+    /// Return next directory entry and its location.
+    fn next_raw_entry(&mut self, cursor: &mut DirectoryCursor) -> Result<Option<(u32, [u8; DIRECTORY_ENTRY_SIZE])>, FsError> {
+        if cursor.finished {
+            return Ok(None);
+        }
+
+        if let Directory::FixedRoot = cursor.directory {
+            if self.root_directory_sectors == 0 {
+                cursor.finished = true;
+                return Ok(None);
+            }
+        }
+
+        let sector = self.cursor_sector(cursor);
+        if cursor.loaded_sector != Some(sector) {
+            self.read_sector(sector, &mut cursor.buffer)?;
+            cursor.loaded_sector = Some(sector);
+        }
+
+        let start = cursor.entry_index as usize * DIRECTORY_ENTRY_SIZE;
+        let mut raw = [0u8; DIRECTORY_ENTRY_SIZE];
+        raw.copy_from_slice(&cursor.buffer[start..start + DIRECTORY_ENTRY_SIZE]);
+        let location = sector * ENTRIES_PER_SECTOR + cursor.entry_index;
+
+        cursor.entry_index += 1;
+        if cursor.entry_index == ENTRIES_PER_SECTOR {
+            cursor.entry_index = 0;
+            cursor.sector_index += 1;
+
+            match cursor.directory {
+                Directory::FixedRoot => {
+                    if cursor.sector_index >= self.root_directory_sectors {
+                        cursor.finished = true;
+                    }
+                }
+                Directory::Clusters(_) => {
+                    if cursor.sector_index >= self.sectors_per_cluster {
+                        cursor.sector_index = 0;
+                        match self.next_cluster(cursor.cluster)? {
+                            Some(next) => {
+                                cursor.clusters_followed += 1;
+                                if cursor.clusters_followed >= self.cluster_count {
+                                    return Err(FsError::Corrupted);
+                                }
+                                cursor.cluster = next;
+                            }
+                            None => cursor.finished = true,
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Some((location, raw)))
+    }
+
+    /// Return next file or directory. Long name is used if there is a valid one.
+    fn next_file(&mut self, cursor: &mut DirectoryCursor) -> Result<Option<FileEntry>, FsError> {
+        let mut long_name = [0u16; LONG_NAME_MAX_ENTRIES * LONG_NAME_CHARS_PER_ENTRY];
+        let mut long_name_checksum = 0;
+        let mut next_long_name_number = 0;
+        let mut long_name_valid = false;
+        let mut locations = ArrayVec::<[u32; MAX_ENTRIES_PER_FILE]>::new();
+
+        while let Some((location, raw)) = self.next_raw_entry(cursor)? {
+            if raw[0] == ENTRY_END {
+                cursor.finished = true;
+                return Ok(None);
+            }
+
+            if raw[0] == ENTRY_FREE {
+                long_name_valid = false;
+                locations.clear();
+                continue;
+            }
+
+            if raw[11] & ATTRIBUTE_LONG_NAME_MASK == ATTRIBUTE_LONG_NAME {
+                let number = (raw[0] & 0x1F) as usize;
+
+                if raw[0] & LONG_NAME_LAST_ENTRY != 0 {
+                    long_name_valid = number >= 1 && number <= LONG_NAME_MAX_ENTRIES;
+                    long_name_checksum = raw[13];
+                    locations.clear();
+                    for c in long_name.iter_mut() {
+                        *c = 0;
+                    }
+                } else if number != next_long_name_number || raw[13] != long_name_checksum {
+                    long_name_valid = false;
+                }
+
+                if long_name_valid {
+                    for (i, &offset) in LONG_NAME_CHAR_OFFSETS.iter().enumerate() {
+                        long_name[(number - 1) * LONG_NAME_CHARS_PER_ENTRY + i] = u16_le(&raw[offset..offset + 2]);
+                    }
+                    next_long_name_number = number - 1;
+                    let _ = locations.try_push(location);
+                }
+
+                continue;
+            }
+
+            // Skip volume label and "." and ".." entries.
+            if raw[11] & ATTRIBUTE_VOLUME_ID != 0 || raw[0] == b'.' {
+                long_name_valid = false;
+                locations.clear();
+                continue;
+            }
+
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&raw[0..11]);
+            let nt_flags = raw[12];
+
+            let mut name = None;
+            if long_name_valid && next_long_name_number == 0 && long_name_checksum == short_name_checksum(&short_name) {
+                name = decode_long_name(&long_name);
+            }
+            if name.is_none() {
+                locations.clear();
+            }
+
+            let _ = locations.try_push(location);
+
+            return Ok(Some(FileEntry {
+                name: name.unwrap_or_else(|| short_name_to_string(&short_name, nt_flags)),
+                short_name,
+                nt_flags,
+                entry: RawEntry::parse(&raw),
+                location,
+                locations,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    fn find_file(&mut self, directory: InodeNumber, name: &str) -> Result<FileEntry, FsError> {
+        let mut cursor = DirectoryCursor::new(self.directory(directory)?);
+
+        while let Some(file) = self.next_file(&mut cursor)? {
+            if file.name.eq_ignore_ascii_case(name) || short_name_to_string(&file.short_name, file.nt_flags).eq_ignore_ascii_case(name) {
+                return Ok(file);
+            }
+        }
+
+        Err(FsError::NotFound)
+    }
+
+    fn entry(&mut self, inode: InodeNumber) -> Result<RawEntry, FsError> {
+        let mut buffer = [0u8; SECTOR_SIZE];
+        self.read_sector(inode / ENTRIES_PER_SECTOR, &mut buffer)?;
+
+        let start = (inode % ENTRIES_PER_SECTOR) as usize * DIRECTORY_ENTRY_SIZE;
+        let raw = &buffer[start..start + DIRECTORY_ENTRY_SIZE];
+
+        if raw[0] == ENTRY_END || raw[0] == ENTRY_FREE || raw[11] & ATTRIBUTE_LONG_NAME_MASK == ATTRIBUTE_LONG_NAME {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(RawEntry::parse(raw))
+    }
+
+    /// Read directory entry at `location`, modify it with `f` and write it back.
+    fn modify_entry(&mut self, location: u32, f: impl FnOnce(&mut [u8])) -> Result<(), FsError> {
+        let sector = location / ENTRIES_PER_SECTOR;
+        let mut buffer = [0u8; SECTOR_SIZE];
+        self.read_sector(sector, &mut buffer)?;
+
+        let start = (location % ENTRIES_PER_SECTOR) as usize * DIRECTORY_ENTRY_SIZE;
+        f(&mut buffer[start..start + DIRECTORY_ENTRY_SIZE]);
+
+        self.write_sector(sector, &buffer)
+    }
+
+    fn store_entry(&mut self, inode: InodeNumber, entry: &RawEntry) -> Result<(), FsError> {
+        let entry = *entry;
+        self.modify_entry(inode, |raw| {
+            set_u16_le(&mut raw[20..22], (entry.first_cluster >> 16) as u16);
+            set_u16_le(&mut raw[26..28], entry.first_cluster as u16);
+            set_u32_le(&mut raw[28..32], entry.size);
+            set_u16_le(&mut raw[22..24], DEFAULT_TIME);
+            set_u16_le(&mut raw[24..26], DEFAULT_DATE);
+            raw[11] |= if entry.is_directory() { 0 } else { ATTRIBUTE_ARCHIVE };
+        })
+    }
+
+    fn regular_file(&mut self, inode: InodeNumber) -> Result<RawEntry, FsError> {
+        if inode == ROOT_INODE {
+            return Err(FsError::IsDirectory);
+        }
+
+        let entry = self.entry(inode)?;
+        if entry.is_directory() {
+            return Err(FsError::IsDirectory);
+        }
+
+        Ok(entry)
+    }
+
+    /// Write data and allocate clusters if needed. Updates `entry` but doesn't store it.
+    fn write_data(&mut self, entry: &mut RawEntry, offset: u32, data: &[u8]) -> Result<usize, FsError> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        if entry.first_cluster == 0 {
+            entry.first_cluster = self.allocate_cluster(None)?;
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut cluster = entry.first_cluster;
+        let mut cluster_index = 0;
+        let mut done = 0;
+        let mut sector_buffer = [0u8; SECTOR_SIZE];
+
+        'write: while done < data.len() {
+            let position = offset + done as u32;
+
+            while cluster_index < position / cluster_size {
+                cluster = match self.next_cluster(cluster)? {
+                    Some(next) => next,
+                    None => match self.allocate_cluster(Some(cluster)) {
+                        Ok(new) => new,
+                        Err(FsError::NoSpace) if done > 0 => break 'write,
+                        Err(e) => return Err(e),
+                    },
+                };
+                cluster_index += 1;
+            }
+
+            let cluster_offset = position % cluster_size;
+            let sector = self.cluster_sector(cluster) + cluster_offset / SECTOR_SIZE as u32;
+            let sector_offset = cluster_offset as usize % SECTOR_SIZE;
+            let chunk = core::cmp::min(SECTOR_SIZE - sector_offset, data.len() - done);
+
+            if chunk < SECTOR_SIZE {
+                self.read_sector(sector, &mut sector_buffer)?;
+            }
+
+            sector_buffer[sector_offset..sector_offset + chunk].copy_from_slice(&data[done..done + chunk]);
+            self.write_sector(sector, &sector_buffer)?;
+
+            done += chunk;
+        }
+
+        entry.size = core::cmp::max(entry.size, offset + done as u32);
+        Ok(done)
+    }
+
+    /// Extend file with zeros from current size to `size`.
+    fn extend_with_zeros(&mut self, entry: &mut RawEntry, size: u32) -> Result<(), FsError> {
+        let zeros = [0u8; SECTOR_SIZE];
+
+        while entry.size < size {
+            let count = core::cmp::min(SECTOR_SIZE as u32, size - entry.size) as usize;
+            let offset = entry.size;
+            self.write_data(entry, offset, &zeros[..count])?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a short name for `name`. Returns short name, NT case flags and
+    /// true if long name entries are required.
+    fn make_short_name(&mut self, directory: Directory, name: &str) -> Result<([u8; 11], u8, bool), FsError> {
+        if let Some((short_name, nt_flags)) = exact_short_name(name) {
+            return Ok((short_name, nt_flags, false));
+        }
+
+        let (base, extension) = match name.rfind('.') {
+            Some(i) if i > 0 => (&name[..i], &name[i+1..]),
+            _ => (name, ""),
+        };
+
+        let mut basis = [b' '; 11];
+        let mut base_length = 0;
+        for c in base.chars().filter(|&c| c != ' ' && c != '.') {
+            if base_length == 8 {
+                break;
+            }
+            basis[base_length] = short_name_char(c);
+            base_length += 1;
+        }
+
+        for (i, c) in extension.chars().filter(|&c| c != ' ' && c != '.').take(3).enumerate() {
+            basis[8 + i] = short_name_char(c);
+        }
+
+        if base_length == 0 {
+            basis[0] = b'_';
+            base_length = 1;
+        }
+
+        let mut tail = arrayvec::ArrayString::<[u8; 8]>::new();
+        for number in 1..1_000_000 {
+            tail.clear();
+            let _ = core::fmt::write(&mut tail, format_args!("~{}", number));
+
+            let mut candidate = basis;
+            let tail_start = core::cmp::min(base_length, 8 - tail.len());
+            candidate[tail_start..tail_start + tail.len()].copy_from_slice(tail.as_bytes());
+            for c in candidate[tail_start + tail.len()..8].iter_mut() {
+                *c = b' ';
+            }
+
+            if !self.short_name_exists(directory, &candidate)? {
+                return Ok((candidate, 0, true));
+            }
+        }
+
+        Err(FsError::AlreadyExists)
+    }
+
+    fn short_name_exists(&mut self, directory: Directory, short_name: &[u8; 11]) -> Result<bool, FsError> {
+        let mut cursor = DirectoryCursor::new(directory);
+
+        while let Some((_, raw)) = self.next_raw_entry(&mut cursor)? {
+            if raw[0] == ENTRY_END {
+                break;
+            }
+
+            if raw[0] != ENTRY_FREE && raw[11] & ATTRIBUTE_LONG_NAME_MASK != ATTRIBUTE_LONG_NAME && &raw[0..11] == short_name {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Find `count` consecutive free directory entries. Directory is
+    /// extended if there is not enough free entries.
+    fn find_free_entries(&mut self, directory: Directory, count: usize) -> Result<ArrayVec<[u32; MAX_ENTRIES_PER_FILE]>, FsError> {
+        let mut cursor = DirectoryCursor::new(directory);
+        let mut locations = ArrayVec::<[u32; MAX_ENTRIES_PER_FILE]>::new();
+
+        while let Some((location, raw)) = self.next_raw_entry(&mut cursor)? {
+            if raw[0] == ENTRY_FREE || raw[0] == ENTRY_END {
+                locations.push(location);
+                if locations.len() == count {
+                    return Ok(locations);
+                }
+            } else {
+                locations.clear();
+            }
+        }
+
+        if let Directory::FixedRoot = directory {
+            return Err(FsError::NoSpace);
+        }
+
+        let mut last_cluster = cursor.cluster;
+        while locations.len() < count {
+            let cluster = self.allocate_cluster(Some(last_cluster))?;
+            let first_sector = self.cluster_sector(cluster);
+
+            for i in 0..self.sectors_per_cluster * ENTRIES_PER_SECTOR {
+                if locations.len() == count {
+                    break;
+                }
+                locations.push(first_sector * ENTRIES_PER_SECTOR + i);
+            }
+
+            last_cluster = cluster;
+        }
+
+        Ok(locations)
+    }
+
+    fn write_dot_entries(&mut self, cluster: u32, parent_cluster: u32) -> Result<(), FsError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+
+        for (i, (name, target)) in [(b".          ", cluster), (b"..         ", parent_cluster)].iter().enumerate() {
+            let raw = &mut sector[i * DIRECTORY_ENTRY_SIZE..(i + 1) * DIRECTORY_ENTRY_SIZE];
+            raw[0..11].copy_from_slice(*name);
+            raw[11] = ATTRIBUTE_DIRECTORY;
+            set_u16_le(&mut raw[20..22], (target >> 16) as u16);
+            set_u16_le(&mut raw[26..28], *target as u16);
+            set_u16_le(&mut raw[16..18], DEFAULT_DATE);
+            set_u16_le(&mut raw[24..26], DEFAULT_DATE);
+        }
+
+        self.write_sector(self.cluster_sector(cluster), &sector)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> InodeNumber {
+        ROOT_INODE
+    }
+
+    fn lookup(&mut self, directory: InodeNumber, name: &str) -> Result<InodeNumber, FsError> {
+        self.find_file(directory, name).map(|file| file.location)
+    }
+
+    fn metadata(&mut self, inode: InodeNumber) -> Result<Metadata, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(Metadata {
+                inode,
+                file_type: FileType::Directory,
+                size: 0,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                links: 1,
+                modified: 0,
+            });
+        }
+
+        let entry = self.entry(inode)?;
+
+        let (file_type, mode) = if entry.is_directory() {
+            (FileType::Directory, 0o755)
+        } else if entry.attributes & ATTRIBUTE_READ_ONLY != 0 {
+            (FileType::Regular, 0o444)
+        } else {
+            (FileType::Regular, 0o644)
+        };
+
+        Ok(Metadata {
+            inode,
+            file_type,
+            size: u64::from(entry.size),
+            mode,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            modified: fat_time_to_unix_time(entry.write_date, entry.write_time),
+        })
+    }
+
+    fn read(&mut self, inode: InodeNumber, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let entry = self.regular_file(inode)?;
+        let size = u64::from(entry.size);
+
+        if offset >= size {
+            return Ok(0);
+        }
+
+        if !self.is_valid_cluster(entry.first_cluster) {
+            return Err(FsError::Corrupted);
+        }
+
+        let count = core::cmp::min(buffer.len() as u64, size - offset) as usize;
+        let offset = offset as u32;
+        let cluster_size = self.cluster_size();
+        let mut cluster = entry.first_cluster;
+        let mut cluster_index = 0;
+        let mut done = 0;
+        let mut sector_buffer = [0u8; SECTOR_SIZE];
+
+        while done < count {
+            let position = offset + done as u32;
+
+            while cluster_index < position / cluster_size {
+                cluster = self.next_cluster(cluster)?.ok_or(FsError::Corrupted)?;
+                cluster_index += 1;
+            }
+
+            let cluster_offset = position % cluster_size;
+            let sector = self.cluster_sector(cluster) + cluster_offset / SECTOR_SIZE as u32;
+            let sector_offset = cluster_offset as usize % SECTOR_SIZE;
+            let chunk = core::cmp::min(SECTOR_SIZE - sector_offset, count - done);
+
+            self.read_sector(sector, &mut sector_buffer)?;
+            buffer[done..done + chunk].copy_from_slice(&sector_buffer[sector_offset..sector_offset + chunk]);
+
+            done += chunk;
+        }
+
+        Ok(count)
+    }
+
+    fn read_dir(&mut self, directory: InodeNumber, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let mut cursor = DirectoryCursor::new(self.directory(directory)?);
+
+        for _ in 0..index {
+            if self.next_file(&mut cursor)?.is_none() {
+                return Ok(None);
+            }
+        }
+
+        let entry = self.next_file(&mut cursor)?.map(|file| {
+            DirEntry {
+                name: file.name,
+                inode: file.location,
+                file_type: if file.entry.is_directory() { FileType::Directory } else { FileType::Regular },
+            }
+        });
+
+        Ok(entry)
+    }
+
+    fn write(&mut self, inode: InodeNumber, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut entry = self.regular_file(inode)?;
+
+        if offset + data.len() as u64 > u64::from(u32::max_value()) {
+            return Err(FsError::NoSpace);
+        }
+
+        let offset = offset as u32;
+        let result = if offset > entry.size {
+            self.extend_with_zeros(&mut entry, offset).and_then(|_| self.write_data(&mut entry, offset, data))
+        } else {
+            self.write_data(&mut entry, offset, data)
+        };
+
+        self.store_entry(inode, &entry)?;
+        self.flush()?;
+        result
+    }
+
+    fn create(&mut self, directory: InodeNumber, name: &str, file_type: FileType, mode: u16) -> Result<InodeNumber, FsError> {
+        let attributes = match file_type {
+            FileType::Regular => ATTRIBUTE_ARCHIVE,
+            FileType::Directory => ATTRIBUTE_DIRECTORY,
+            _ => return Err(FsError::NotSupported),
+        };
+        let attributes = if mode & 0o222 == 0 { attributes | ATTRIBUTE_READ_ONLY } else { attributes };
+
+        let mut long_name = [0u16; LONG_NAME_MAX_LENGTH];
+        let long_name_length = encode_long_name(name, &mut long_name)?;
+
+        match self.find_file(directory, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        let parent = self.directory(directory)?;
+        let (short_name, nt_flags, needs_long_name) = self.make_short_name(parent, name)?;
+        let long_entry_count = if needs_long_name {
+            (long_name_length + LONG_NAME_CHARS_PER_ENTRY - 1) / LONG_NAME_CHARS_PER_ENTRY
+        } else {
+            0
+        };
+
+        let locations = self.find_free_entries(parent, long_entry_count + 1)?;
+
+        let first_cluster = if file_type == FileType::Directory {
+            let cluster = self.allocate_cluster(None)?;
+            let parent_cluster = match parent {
+                Directory::Clusters(cluster) if directory != ROOT_INODE => cluster,
+                _ => 0,
+            };
+            self.write_dot_entries(cluster, parent_cluster)?;
+            cluster
+        } else {
+            0
+        };
+
+        let checksum = short_name_checksum(&short_name);
+        for (i, &location) in locations[..long_entry_count].iter().enumerate() {
+            let number = long_entry_count - i;
+            self.modify_entry(location, |raw| {
+                for byte in raw.iter_mut() {
+                    *byte = 0;
+                }
+                raw[0] = number as u8 | if i == 0 { LONG_NAME_LAST_ENTRY } else { 0 };
+                raw[11] = ATTRIBUTE_LONG_NAME;
+                raw[13] = checksum;
+
+                for (j, &offset) in LONG_NAME_CHAR_OFFSETS.iter().enumerate() {
+                    let index = (number - 1) * LONG_NAME_CHARS_PER_ENTRY + j;
+                    let unit = if index < long_name_length {
+                        long_name[index]
+                    } else if index == long_name_length {
+                        0
+                    } else {
+                        0xFFFF
+                    };
+                    set_u16_le(&mut raw[offset..offset + 2], unit);
+                }
+            })?;
+        }
+
+        let location = locations[long_entry_count];
+        self.modify_entry(location, |raw| {
+            for byte in raw.iter_mut() {
+                *byte = 0;
+            }
+            raw[0..11].copy_from_slice(&short_name);
+            raw[11] = attributes;
+            raw[12] = nt_flags;
+            set_u16_le(&mut raw[14..16], DEFAULT_TIME);
+            set_u16_le(&mut raw[16..18], DEFAULT_DATE);
+            set_u16_le(&mut raw[18..20], DEFAULT_DATE);
+            set_u16_le(&mut raw[20..22], (first_cluster >> 16) as u16);
+            set_u16_le(&mut raw[22..24], DEFAULT_TIME);
+            set_u16_le(&mut raw[24..26], DEFAULT_DATE);
+            set_u16_le(&mut raw[26..28], first_cluster as u16);
+        })?;
+
+        self.flush()?;
+        Ok(location)
+    }
+
+    fn truncate(&mut self, inode: InodeNumber, size: u64) -> Result<(), FsError> {
+        let mut entry = self.regular_file(inode)?;
+
+        if size > u64::from(u32::max_value()) {
+            return Err(FsError::NoSpace);
+        }
+
+        let size = size as u32;
+        let result = if size > entry.size {
+            self.extend_with_zeros(&mut entry, size)
+        } else if size < entry.size {
+            let cluster_size = self.cluster_size();
+            let keep_clusters = (size + cluster_size - 1) / cluster_size;
+
+            let result = if keep_clusters == 0 {
+                let first = entry.first_cluster;
+                entry.first_cluster = 0;
+                self.free_cluster_chain(first)
+            } else {
+                let mut last = entry.first_cluster;
+                let mut result = Ok(());
+                for _ in 1..keep_clusters {
+                    match self.next_cluster(last) {
+                        Ok(Some(next)) => last = next,
+                        Ok(None) => break,
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                    }
+                }
+
+                result.and_then(|_| {
+                    if let Some(next) = self.next_cluster(last)? {
+                        let end_of_chain = self.fat_type.end_of_chain();
+                        self.set_fat_entry(last, end_of_chain)?;
+                        self.free_cluster_chain(next)?;
+                    }
+                    Ok(())
+                })
+            };
+
+            entry.size = size;
+            result
+        } else {
+            Ok(())
+        };
+
+        self.store_entry(inode, &entry)?;
+        self.flush()?;
+        result
+    }
+
+    fn remove(&mut self, directory: InodeNumber, name: &str) -> Result<(), FsError> {
+        let file = self.find_file(directory, name)?;
+
+        if file.entry.is_directory() {
+            let mut cursor = DirectoryCursor::new(self.directory(file.location)?);
+            if self.next_file(&mut cursor)?.is_some() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+
+        for &location in file.locations.iter() {
+            self.modify_entry(location, |raw| raw[0] = ENTRY_FREE)?;
+        }
+
+        self.free_cluster_chain(file.entry.first_cluster)?;
+        self.flush()
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.flush()
+    }
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// Convert character to a valid short name character.
+fn short_name_char(c: char) -> u8 {
+    if c.is_ascii() && is_short_name_char(c.to_ascii_uppercase() as u8) {
+        c.to_ascii_uppercase() as u8
+    } else {
+        b'_'
+    }
+}
+
+/// If `name` is a valid 8.3 name where base name and extension are both
+/// either upper case or lower case, return short name and NT case flags.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.find('.') {
+        Some(i) => (&name[..i], &name[i+1..]),
+        None => (name, ""),
+    };
+
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || (name.contains('.') && extension.is_empty()) {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    let mut nt_flags = 0;
+
+    for (part, start, lowercase_flag) in [(base, 0, NT_LOWERCASE_BASE), (extension, 8, NT_LOWERCASE_EXTENSION)].iter() {
+        let has_lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        let has_upper = part.bytes().any(|c| c.is_ascii_uppercase());
+
+        if has_lower && has_upper {
+            return None;
+        }
+
+        if has_lower {
+            nt_flags |= lowercase_flag;
+        }
+
+        for (i, c) in part.bytes().enumerate() {
+            let c = c.to_ascii_uppercase();
+            if !is_short_name_char(c) {
+                return None;
+            }
+            short_name[start + i] = c;
+        }
+    }
+
+    Some((short_name, nt_flags))
+}
+
+fn short_name_to_string(short_name: &[u8; 11], nt_flags: u8) -> FileName {
+    let mut name = FileName::new();
+
+    let part_to_string = |name: &mut FileName, part: &[u8], lowercase: bool| {
+        for &c in part.iter().take_while(|&&c| c != b' ') {
+            let c = if lowercase { c.to_ascii_lowercase() } else { c };
+            // Character 0x05 is used to store initial character 0xE5.
+            let c = if c.is_ascii() && c != 0x05 { c as char } else { '_' };
+            let _ = name.try_push(c);
+        }
+    };
+
+    part_to_string(&mut name, &short_name[0..8], nt_flags & NT_LOWERCASE_BASE != 0);
+
+    if short_name[8] != b' ' {
+        let _ = name.try_push('.');
+        part_to_string(&mut name, &short_name[8..11], nt_flags & NT_LOWERCASE_EXTENSION != 0);
+    }
+
+    name
+}
+
+/// Returns `None` if the name doesn't fit to `FileName`.
+fn decode_long_name(long_name: &[u16]) -> Option<FileName> {
+    let length = long_name.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(long_name.len());
+    let mut name = FileName::new();
+
+    for c in core::char::decode_utf16(long_name[..length].iter().cloned()) {
+        name.try_push(c.unwrap_or(core::char::REPLACEMENT_CHARACTER)).ok()?;
+    }
+
+    Some(name)
+}
+
+fn encode_long_name(name: &str, buffer: &mut [u16; LONG_NAME_MAX_LENGTH]) -> Result<usize, FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.ends_with('.') || name.ends_with(' ') {
+        return Err(FsError::InvalidPath);
+    }
+
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut length = 0;
+    for unit in name.encode_utf16() {
+        *buffer.get_mut(length).ok_or(FsError::NameTooLong)? = unit;
+        length += 1;
+    }
+
+    Ok(length)
+}
+
+fn fat_time_to_unix_time(date: u16, time: u16) -> u32 {
+    let year = 1980 + i64::from(date >> 9);
+    let month = i64::from((date >> 5) & 0x0F);
+    let day = i64::from(date & 0x1F);
+
+    if month == 0 || day == 0 {
+        return 0;
+    }
+
+    let hours = i64::from(time >> 11);
+    let minutes = i64::from((time >> 5) & 0x3F);
+    let seconds = i64::from(time & 0x1F) * 2;
+
+    // Days from civil algorithm by Howard Hinnant.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    (days * 86400 + hours * 3600 + minutes * 60 + seconds) as u32
+}
@@ -0,0 +1,261 @@
+//! Physical memory frame allocator.
+//!
+//! Free frames are tracked with a bitmap which is initialized from
+//! the Multiboot2 memory map. Physical memory is identity mapped, so
+//! allocated frames can be accessed using the physical address.
+//!
+//! Allocated frames have a reference count, so that address spaces can
+//! share frames. Frame is freed when the last reference is freed.
+//!
+//! Allocator is behind an `IrqSpinlock`, so frames can be allocated and
+//! freed from any thread and from interrupt handlers.
+
+use multiboot2::BootInformation;
+
+use crate::sync::IrqSpinlock;
+
+pub const FRAME_SIZE: usize = 4096;
+
+/// Only first 1 GiB of physical memory is managed.
+const MAX_FRAME_COUNT: usize = 1024 * 1024 * 1024 / FRAME_SIZE;
+const BITMAP_SIZE: usize = MAX_FRAME_COUNT / 32;
+
+/// First 1 MiB contains BIOS data and VGA memory.
+const LOW_MEMORY_END: usize = 1024 * 1024;
+
+extern "C" {
+    #[allow(improper_ctypes)]
+    pub static KERNEL_END_LOCATION: ();
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame(usize);
+
+impl Frame {
+    pub fn containing_address(address: usize) -> Self {
+        Frame(address / FRAME_SIZE)
+    }
+
+    pub fn number(&self) -> usize {
+        self.0
+    }
+
+    pub fn start_address(&self) -> usize {
+        self.0 * FRAME_SIZE
+    }
+
+    /// Frame contents using the identity mapping.
+    ///
+    /// # Safety
+    /// Frame must be allocated and there must not be other references to it.
+    pub unsafe fn data_mut(&self) -> &'static mut [u8; FRAME_SIZE] {
+        &mut *(self.start_address() as *mut [u8; FRAME_SIZE])
+    }
+}
+
+struct FrameAllocator {
+    /// Bit is set if frame is used or doesn't exist.
+    bitmap: [u32; BITMAP_SIZE],
+    total_frames: usize,
+    free_frames: usize,
+    /// Search for free frames starts from this bitmap index.
+    next_index: usize,
+    /// Reference counts of used frames.
+    reference_counts: [u8; MAX_FRAME_COUNT],
+}
+
+static FRAME_ALLOCATOR: IrqSpinlock<FrameAllocator> = IrqSpinlock::new("frame allocator", FrameAllocator {
+    bitmap: [u32::max_value(); BITMAP_SIZE],
+    total_frames: 0,
+    free_frames: 0,
+    next_index: 0,
+    reference_counts: [0; MAX_FRAME_COUNT],
+});
+
+impl FrameAllocator {
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 32] & (1 << (frame % 32)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if frame >= MAX_FRAME_COUNT || self.is_used(frame) == used {
+            return;
+        }
+
+        if used {
+            self.bitmap[frame / 32] |= 1 << (frame % 32);
+            self.free_frames -= 1;
+            self.reference_counts[frame] = 1;
+        } else {
+            self.bitmap[frame / 32] &= !(1 << (frame % 32));
+            self.free_frames += 1;
+            self.reference_counts[frame] = 0;
+        }
+    }
+
+    /// Mark frames which are completely inside the range as free.
+    fn add_memory(&mut self, start: u64, end: u64) {
+        let first = (start as usize + FRAME_SIZE - 1) / FRAME_SIZE;
+        let end = core::cmp::min(end, (MAX_FRAME_COUNT * FRAME_SIZE) as u64) as usize / FRAME_SIZE;
+
+        for frame in first..end {
+            if self.is_used(frame) {
+                self.total_frames += 1;
+                self.set_used(frame, false);
+            }
+        }
+    }
+
+    /// Mark frames which overlap with the range as used.
+    fn reserve(&mut self, start: usize, end: usize) {
+        let first = start / FRAME_SIZE;
+        let end = (end + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        for frame in first..core::cmp::min(end, MAX_FRAME_COUNT) {
+            self.set_used(frame, true);
+        }
+    }
+
+    fn allocate(&mut self) -> Option<Frame> {
+        for offset in 0..BITMAP_SIZE {
+            let i = (self.next_index + offset) % BITMAP_SIZE;
+            let bits = self.bitmap[i];
+
+            if bits != u32::max_value() {
+                let frame = i * 32 + (!bits).trailing_zeros() as usize;
+                self.set_used(frame, true);
+                self.next_index = i;
+                return Some(Frame(frame));
+            }
+        }
+
+        None
+    }
+
+    /// Allocate `count` physically contiguous frames.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<Frame> {
+        let mut run_start = 0;
+        let mut run_length = 0;
+
+        for frame in 0..MAX_FRAME_COUNT {
+            if self.is_used(frame) {
+                run_length = 0;
+                continue;
+            }
+
+            if run_length == 0 {
+                run_start = frame;
+            }
+            run_length += 1;
+
+            if run_length == count {
+                for frame in run_start..run_start + count {
+                    self.set_used(frame, true);
+                }
+                return Some(Frame(run_start));
+            }
+        }
+
+        None
+    }
+}
+
+/// Initialize the frame allocator using the memory map from the boot loader.
+///
+/// Kernel image, boot information and boot modules are reserved.
+pub fn init(boot_info: &BootInformation) -> Result<(), ()> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+
+    let memory_map = boot_info.memory_map_tag().ok_or(())?;
+    for area in memory_map.memory_areas() {
+        allocator.add_memory(area.start_address(), area.end_address());
+    }
+
+    let kernel_end = unsafe { &KERNEL_END_LOCATION as *const () as usize };
+    allocator.reserve(0, LOW_MEMORY_END);
+    allocator.reserve(LOW_MEMORY_END, kernel_end);
+    allocator.reserve(boot_info.start_address(), boot_info.end_address());
+
+    for module in boot_info.module_tags() {
+        allocator.reserve(module.start_address() as usize, module.end_address() as usize);
+    }
+
+    Ok(())
+}
+
+pub fn allocate_frame() -> Option<Frame> {
+    FRAME_ALLOCATOR.lock().allocate()
+}
+
+/// Allocate a frame and fill it with zeros.
+pub fn allocate_zeroed_frame() -> Option<Frame> {
+    let frame = allocate_frame()?;
+    unsafe {
+        for byte in frame.data_mut().iter_mut() {
+            *byte = 0;
+        }
+    }
+    Some(frame)
+}
+
+/// Allocate `count` physically contiguous frames. Returns the first frame.
+pub fn allocate_contiguous_frames(count: usize) -> Option<Frame> {
+    if count == 0 {
+        return None;
+    }
+
+    FRAME_ALLOCATOR.lock().allocate_contiguous(count)
+}
+
+/// Free frames allocated with `allocate_contiguous_frames`.
+pub fn free_contiguous_frames(first: Frame, count: usize) {
+    for number in first.0..first.0 + count {
+        free_frame(Frame(number));
+    }
+}
+
+/// Remove one reference to the frame. Frame is freed when there are no
+/// more references.
+pub fn free_frame(frame: Frame) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+
+    if !allocator.is_used(frame.0) {
+        panic!("double free of frame {:#x}", frame.start_address());
+    }
+
+    let count = &mut allocator.reference_counts[frame.0];
+    if *count > 1 {
+        *count -= 1;
+    } else {
+        allocator.set_used(frame.0, false);
+    }
+}
+
+/// Add a reference to an allocated frame.
+pub fn share_frame(frame: Frame) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+
+    if !allocator.is_used(frame.0) {
+        panic!("sharing free frame {:#x}", frame.start_address());
+    }
+
+    let count = &mut allocator.reference_counts[frame.0];
+    *count = count.checked_add(1).expect("frame reference count overflow");
+}
+
+pub fn reference_count(frame: Frame) -> usize {
+    FRAME_ALLOCATOR.lock().reference_counts.get(frame.0).cloned().unwrap_or(0) as usize
+}
+
+/// Mark memory range `start..end` as used.
+pub fn reserve(start: usize, end: usize) {
+    FRAME_ALLOCATOR.lock().reserve(start, end)
+}
+
+pub fn total_frames() -> usize {
+    FRAME_ALLOCATOR.lock().total_frames
+}
+
+pub fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_frames
+}
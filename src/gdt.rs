@@ -1,56 +1,66 @@
-
 use x86::segmentation::*;
 use x86::dtables::*;
 
 
+use crate::sync::IrqSpinlock;
 use crate::tss::{TSS_DATA, TSS};
 
+// Segment selector values. User mode selectors have requested
+// privilege level 3.
+//
+// Descriptor order matters for SYSENTER and SYSEXIT, which compute
+// other selectors from the kernel code selector.
+pub const KERNEL_CODE_SELECTOR: u16 = 1 << 3;
+pub const KERNEL_DATA_SELECTOR: u16 = 2 << 3;
+pub const USER_CODE_SELECTOR: u16 = 3 << 3 | 3;
+pub const USER_DATA_SELECTOR: u16 = 4 << 3 | 3;
+pub const TSS_SELECTOR: u16 = 5 << 3;
+
 #[repr(C, packed)]
 pub struct GDT {
     _null: Descriptor,
     code: Descriptor,
     data: Descriptor,
+    user_code: Descriptor,
+    user_data: Descriptor,
     task: Descriptor,
 }
 
 #[used]
-static mut GDT_DATA: GDT = GDT {
+static GDT_DATA: IrqSpinlock<GDT> = IrqSpinlock::new("gdt", GDT {
     _null: Descriptor::NULL,
     code: Descriptor::NULL,
     data: Descriptor::NULL,
+    user_code: Descriptor::NULL,
+    user_data: Descriptor::NULL,
     task: Descriptor::NULL,
-};
+});
 
 impl GDT {
     pub fn load_gdt() {
-        let code = DescriptorBuilder::code_descriptor(0, u32::max_value(), CodeSegmentType::ExecuteRead)
-            .limit_granularity_4kb()
-            .present()
-            .db()
-            .dpl(x86::Ring::Ring0)
-            .finish();
-        let data = DescriptorBuilder::data_descriptor(0, u32::max_value(), DataSegmentType::ReadWrite)
-            .limit_granularity_4kb()
-            .present()
-            .db()
-            .dpl(x86::Ring::Ring0)
-            .finish();
-        let task = <DescriptorBuilder as GateDescriptorBuilder<u32>>::tss_descriptor(&TSS_DATA as *const _ as u64, core::mem::size_of::<TSS>() as u64, true)
+        let code = Self::code_descriptor(x86::Ring::Ring0);
+        let data = Self::data_descriptor(x86::Ring::Ring0);
+        let user_code = Self::code_descriptor(x86::Ring::Ring3);
+        let user_data = Self::data_descriptor(x86::Ring::Ring3);
+        let task = <DescriptorBuilder as GateDescriptorBuilder<u32>>::tss_descriptor(TSS_DATA.as_ptr() as u64, core::mem::size_of::<TSS>() as u64, true)
             .present()
             .dpl(x86::Ring::Ring0)
             .finish();
 
-        unsafe {
-            GDT_DATA.code = code;
-            GDT_DATA.data = data;
-            GDT_DATA.task = task;
-        }
+        let mut gdt = GDT_DATA.lock();
+        gdt.code = code;
+        gdt.data = data;
+        gdt.user_code = user_code;
+        gdt.user_data = user_data;
+        gdt.task = task;
 
-        let code_segment_selector = SegmentSelector::new(1, x86::Ring::Ring0);
-        let data_and_stack_segment_selector = SegmentSelector::new(2, x86::Ring::Ring0);
+        let code_segment_selector = SegmentSelector::from_raw(KERNEL_CODE_SELECTOR);
+        let data_and_stack_segment_selector = SegmentSelector::from_raw(KERNEL_DATA_SELECTOR);
 
         unsafe {
-            let pointer = DescriptorTablePointer::new(&GDT_DATA);
+            // GDT is in a static, so the address stays valid after
+            // the lock is released.
+            let pointer = DescriptorTablePointer::new(&*gdt);
             lgdt(&pointer);
 
             // Set LDTR to null, because it is not used.
@@ -65,4 +75,24 @@ impl GDT {
         }
     }
 
+    /// Flat 4 GiB code segment.
+    fn code_descriptor(dpl: x86::Ring) -> Descriptor {
+        DescriptorBuilder::code_descriptor(0, u32::max_value(), CodeSegmentType::ExecuteRead)
+            .limit_granularity_4kb()
+            .present()
+            .db()
+            .dpl(dpl)
+            .finish()
+    }
+
+    /// Flat 4 GiB data segment.
+    fn data_descriptor(dpl: x86::Ring) -> Descriptor {
+        DescriptorBuilder::data_descriptor(0, u32::max_value(), DataSegmentType::ReadWrite)
+            .limit_granularity_4kb()
+            .present()
+            .db()
+            .dpl(dpl)
+            .finish()
+    }
+
 }
@@ -1,5 +1,5 @@
 
-use core::sync::atomic::{AtomicUsize, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, AtomicU32, Ordering};
 use core::num::Wrapping;
 
 use x86::dtables::*;
@@ -9,6 +9,8 @@ use arraydeque::{ArrayDeque, Saturating};
 
 use seq_macro::seq;
 
+use crate::sync::{self, IrqSpinlock, WaitQueue};
+
 seq!(N in 18..=255 {
     extern "C" {
         fn interrupt_0();
@@ -35,6 +37,10 @@ seq!(N in 18..=255 {
     }
 });
 
+extern "C" {
+    fn syscall_interrupt();
+}
+
 seq!(N in 18..=255 {
     const INTERRUPT_HANDLERS: [unsafe extern "C" fn (); 256] = [
         interrupt_0,
@@ -70,9 +76,9 @@ pub struct IDT {
     entries: [Descriptor; 256],
 }
 
-static mut IDT_DATA: IDT = IDT {
+static IDT_DATA: IrqSpinlock<IDT> = IrqSpinlock::new("idt", IDT {
     entries: [Descriptor::NULL; 256],
-};
+});
 
 use pc_at_pic8259a::*;
 
@@ -97,36 +103,69 @@ const MASTER_PIC_SPURIOUS_INTERRUPT: u8 = MASTER_PIC_INTERRUPT_OFFSET + 7;
 const SLAVE_PIC_SPURIOUS_INTERRUPT: u8 = SLAVE_PIC_INTERRUPT_OFFSET + 7;
 
 static RECEIVED_HARDWARE_INTERRUPT_BITFLAGS: AtomicU32 = AtomicU32::new(0);
-static mut INTERRUPT_DEQUE: Option<ArrayDeque<[HardwareInterrupt; 32], Saturating>> = None;
+static INTERRUPT_DEQUE: IrqSpinlock<Option<ArrayDeque<[HardwareInterrupt; 32], Saturating>>> = IrqSpinlock::new("interrupt deque", None);
 
-static mut PIC: Option<Pic<PicPortIO>> = None;
+/// Threads waiting for hardware interrupts.
+static INTERRUPT_WAITERS: WaitQueue = WaitQueue::new("interrupt waiters");
+
+/// Set by `request_wake_up`.
+static WAKE_UP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Make `wait_for_interrupt` return even if there isn't a hardware
+/// interrupt. Console uses this when other threads write output.
+pub fn request_wake_up() {
+    WAKE_UP_REQUESTED.store(true, Ordering::SeqCst);
+    INTERRUPT_WAITERS.wake_all();
+}
+
+static PIC: IrqSpinlock<Option<Pic<PicPortIO>>> = IrqSpinlock::new("pic", None);
 
 static MASTER_PIC_SPURIOUS_INTERRUPT_COUNT: AtomicUsize = AtomicUsize::new(0);
 static SLAVE_PIC_SPURIOUS_INTERRUPT_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 pub static TIME_MILLISECONDS: AtomicUsize = AtomicUsize::new(0);
 
+/// Interrupt counts for every interrupt vector. Only interrupt handlers
+/// modify the counts.
+static INTERRUPT_COUNTS: IrqSpinlock<[usize; 256]> = IrqSpinlock::new("interrupt counts", [0; 256]);
+
+pub fn interrupt_count(interrupt_number: u8) -> usize {
+    INTERRUPT_COUNTS.lock()[interrupt_number as usize]
+}
+
 impl IDTHandler {
     pub fn new() -> Self {
-        unsafe {
-            for (i, entry) in IDT_DATA.entries.iter_mut().enumerate() {
+        {
+            let mut idt = IDT_DATA.lock();
+
+            for (i, entry) in idt.entries.iter_mut().enumerate() {
                 let function_position = INTERRUPT_HANDLERS[i] as u32;
 
-                let descriptor = DescriptorBuilder::interrupt_descriptor(SegmentSelector::new(1, x86::Ring::Ring0), function_position)
+                let descriptor = DescriptorBuilder::interrupt_descriptor(SegmentSelector::from_raw(crate::gdt::KERNEL_CODE_SELECTOR), function_position)
                     .present()
                     .finish();
 
                 *entry = descriptor;
             }
-            let idt_pointer = DescriptorTablePointer::new(&IDT_DATA);
 
-            lidt(&idt_pointer);
-        }
+            // System call gate which user mode can use.
+            let syscall_gate = DescriptorBuilder::trap_gate_descriptor(SegmentSelector::from_raw(crate::gdt::KERNEL_CODE_SELECTOR), syscall_interrupt as u32)
+                .present()
+                .dpl(x86::Ring::Ring3)
+                .finish();
+            idt.entries[crate::syscall::SYSCALL_INTERRUPT as usize] = syscall_gate;
 
-        unsafe {
-            INTERRUPT_DEQUE = Some(ArrayDeque::new());
+            // IDT is in a static, so the address stays valid after
+            // the lock is released.
+            let idt_pointer = DescriptorTablePointer::new(&*idt);
+
+            unsafe {
+                lidt(&idt_pointer);
+            }
         }
 
+        *INTERRUPT_DEQUE.lock() = Some(ArrayDeque::new());
+
         let mut pic = PicInit::send_icw1(PicPortIO, InterruptTriggerMode::EdgeTriggered)
             .send_icw2_and_icw3(MASTER_PIC_INTERRUPT_OFFSET, SLAVE_PIC_INTERRUPT_OFFSET)
             .send_icw4();
@@ -136,9 +175,7 @@ impl IDTHandler {
         pic.set_master_mask(LAST_IRQ_LINE);
         pic.set_slave_mask(LAST_IRQ_LINE);
 
-        unsafe {
-            PIC = Some(pic);
-        }
+        *PIC.lock() = Some(pic);
 
         IDTHandler
     }
@@ -150,16 +187,21 @@ impl IDTHandler {
     }
 
     pub fn handle_interrupt(&mut self) -> Option<HardwareInterrupt> {
-        unsafe {
-            x86::irq::disable();
-            let interrupt = INTERRUPT_DEQUE.as_mut().unwrap().pop_front();
-            if let Some(hardware_interrupt) = &interrupt {
-                let new = RECEIVED_HARDWARE_INTERRUPT_BITFLAGS.load(Ordering::Relaxed) & !(1 << *hardware_interrupt as u8);
-                RECEIVED_HARDWARE_INTERRUPT_BITFLAGS.store(new, Ordering::Relaxed);
-            }
-            x86::irq::enable();
-            interrupt
+        let mut deque = INTERRUPT_DEQUE.lock();
+        let interrupt = deque.as_mut().unwrap().pop_front();
+        if let Some(hardware_interrupt) = &interrupt {
+            let new = RECEIVED_HARDWARE_INTERRUPT_BITFLAGS.load(Ordering::Relaxed) & !(1 << *hardware_interrupt as u8);
+            RECEIVED_HARDWARE_INTERRUPT_BITFLAGS.store(new, Ordering::Relaxed);
         }
+        interrupt
+    }
+
+    /// Wait until there is a hardware interrupt to handle or a wake up
+    /// is requested. Other threads run while the current thread waits.
+    pub fn wait_for_interrupt(&mut self) {
+        INTERRUPT_WAITERS.wait_until(|| {
+            WAKE_UP_REQUESTED.swap(false, Ordering::SeqCst) || !INTERRUPT_DEQUE.lock().as_ref().unwrap().is_empty()
+        });
     }
 
     pub fn master_pic_spurious_interrupts_count() -> usize {
@@ -170,7 +212,30 @@ impl IDTHandler {
     }
 }
 
-#[derive(Debug)]
+/// Stack frame which CPU pushes when an interrupt happens.
+#[repr(C)]
+pub struct InterruptFrame {
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+    /// Only valid if the interrupt happened in user mode.
+    pub user_esp: u32,
+    /// Only valid if the interrupt happened in user mode.
+    pub user_ss: u32,
+}
+
+impl InterruptFrame {
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+
+    /// True if interrupts were enabled when the interrupt happened.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.eflags & (1 << 9) != 0
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
 pub enum Exception {
     DivideByZero = 0,
     Debug,
@@ -196,10 +261,10 @@ pub enum Exception {
 }
 
 #[derive(Debug)]
-struct UnknownInterrupt;
+pub struct UnknownInterrupt;
 
 impl Exception {
-    fn from_interrupt_number(interrupt_number: u8) -> Result<Self, UnknownInterrupt> {
+    pub fn from_interrupt_number(interrupt_number: u8) -> Result<Self, UnknownInterrupt> {
         use self::Exception::*;
         let exception = match interrupt_number {
             0 => DivideByZero,
@@ -247,7 +312,7 @@ pub enum HardwareInterrupt {
 }
 
 impl HardwareInterrupt {
-    fn from_interrupt_number(interrupt_number: u8) -> Result<Self, UnknownInterrupt> {
+    pub fn from_interrupt_number(interrupt_number: u8) -> Result<Self, UnknownInterrupt> {
         use self::HardwareInterrupt::*;
         let interrupt = match interrupt_number {
             32 => Timer,
@@ -269,16 +334,16 @@ impl HardwareInterrupt {
 }
 
 #[no_mangle]
-extern "C" fn rust_interrupt_handler(interrupt_number: u32) {
+extern "C" fn rust_interrupt_handler(interrupt_number: u32, frame: &InterruptFrame) {
     let interrupt_number: u8 = interrupt_number as u8;
 
-    use core::fmt::Write;
-
-    let text_mode = unsafe {
-        crate::vga_text::new_vga_text_mode_unsafe()
-    };
+    INTERRUPT_COUNTS.lock()[interrupt_number as usize] += 1;
 
-    let mut terminal = crate::terminal::Terminal::new(text_mode, false);
+    if let Ok(exception) = Exception::from_interrupt_number(interrupt_number) {
+        if frame.from_user_mode() {
+            crate::usermode::kill_current_task(exception, None, frame);
+        }
+    }
 
     let exception = Exception::from_interrupt_number(interrupt_number);
 
@@ -295,17 +360,15 @@ extern "C" fn rust_interrupt_handler(interrupt_number: u32) {
                 let flag = 1 << interrupt as u8;
                 let interrupt_received_bitflags = RECEIVED_HARDWARE_INTERRUPT_BITFLAGS.load(Ordering::Relaxed);
                 if flag & interrupt_received_bitflags == 0 {
-                    unsafe {
-                        INTERRUPT_DEQUE.as_mut().unwrap().push_back(interrupt).unwrap();
-                    }
+                    INTERRUPT_DEQUE.lock().as_mut().unwrap().push_back(interrupt).unwrap();
                     RECEIVED_HARDWARE_INTERRUPT_BITFLAGS.store(interrupt_received_bitflags | flag, Ordering::Relaxed);
                 }
+                INTERRUPT_WAITERS.wake_all();
             }
         }
 
-        let pic = unsafe {
-            PIC.as_mut().unwrap()
-        };
+        let mut pic_guard = PIC.lock();
+        let pic = pic_guard.as_mut().unwrap();
 
         if MASTER_PIC_INTERRUPT_OFFSET <= interrupt_number && interrupt_number < MASTER_PIC_SPURIOUS_INTERRUPT {
             pic.send_eoi_to_master();
@@ -315,27 +378,65 @@ extern "C" fn rust_interrupt_handler(interrupt_number: u32) {
         }
 
         if interrupt_number == MASTER_PIC_SPURIOUS_INTERRUPT {
-            let _ = writeln!(terminal, "Spurious interrupt from master PIC");
+            crate::kmsg::write(b"Spurious interrupt from master PIC\n");
 
             MASTER_PIC_SPURIOUS_INTERRUPT_COUNT.fetch_add(1, Ordering::Relaxed);
         }
 
         if interrupt_number == SLAVE_PIC_SPURIOUS_INTERRUPT {
-            let _ = writeln!(terminal, "Spurious interrupt from slave PIC");
+            crate::kmsg::write(b"Spurious interrupt from slave PIC\n");
 
             SLAVE_PIC_SPURIOUS_INTERRUPT_COUNT.fetch_add(1, Ordering::Relaxed);
 
             pic.send_eoi_to_master();
         }
+
+        drop(pic_guard);
+
+        // Preemption may switch to another thread, so end of interrupt
+        // must be sent before this.
+        if interrupt_number == MASTER_PIC_INTERRUPT_OFFSET {
+            crate::scheduler::timer_tick();
+        }
+
+        if frame.from_user_mode() {
+            crate::process::handle_pending_kill();
+        }
     }
 }
 
 #[no_mangle]
 extern "C" fn rust_interrupt_handler_with_error(
     interrupt_number: u32,
-    error_code: u32
+    error_code: u32,
+    frame: &InterruptFrame,
 ) {
+    INTERRUPT_COUNTS.lock()[interrupt_number as u8 as usize] += 1;
+
     let exception = Exception::from_interrupt_number(interrupt_number as u8);
+
+    if let Ok(exception) = exception {
+        // Kernel might fault too when it accesses user memory, because
+        // pages can be swapped out after the kernel has checked them.
+        if let Exception::PageFault = exception {
+            let address = unsafe { x86::controlregs::cr2() };
+
+            // Resolving the fault might read a swapped out page or a
+            // file, which is done with interrupts enabled if the faulting
+            // code had them enabled.
+            sync::restore_interrupts(frame.interrupts_enabled());
+            let handled = crate::process::handle_page_fault(address, error_code);
+            sync::disable_interrupts();
+
+            if handled {
+                return;
+            }
+        }
+
+        if frame.from_user_mode() {
+            crate::usermode::kill_current_task(exception, Some(error_code), frame);
+        }
+    }
     panic!("Interrupt {:?}, number: {}, error: {:#08x}",
         exception, interrupt_number, error_code);
 }
@@ -0,0 +1,304 @@
+//! Initial RAM filesystem from Multiboot2 boot modules.
+//!
+//! Boot modules which are tar (ustar) or cpio (newc) archives are unpacked
+//! to the root filesystem. Other modules are added as files to `/modules`.
+//! File contents are not copied, so boot module memory must stay reserved.
+
+use core::fmt::Write;
+
+use multiboot2::BootInformation;
+
+use crate::ramfs::RamFs;
+use crate::vfs::{FileSystem, FileType, FsError, InodeNumber};
+
+const TAR_BLOCK_SIZE: usize = 512;
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+const MODE_PERMISSIONS_MASK: u32 = 0o7777;
+
+const MODULES_DIRECTORY: &str = "modules";
+
+#[derive(Debug)]
+pub enum InitrdError {
+    Corrupted,
+    /// Entry path has an empty or `..` component.
+    InvalidPath,
+    FileSystem(FsError),
+}
+
+impl From<FsError> for InitrdError {
+    fn from(e: FsError) -> Self {
+        InitrdError::FileSystem(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum ArchiveFormat {
+    Tar,
+    Cpio,
+}
+
+impl ArchiveFormat {
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.len() >= TAR_BLOCK_SIZE && &data[257..262] == b"ustar" {
+            Some(ArchiveFormat::Tar)
+        } else if data.starts_with(b"070701") {
+            Some(ArchiveFormat::Cpio)
+        } else {
+            None
+        }
+    }
+}
+
+enum Entry<'a> {
+    Directory,
+    Regular(&'static [u8]),
+    Symlink(&'a str),
+    /// Hard links, device files and other entries which are not supported.
+    Unsupported,
+}
+
+/// Load all boot modules to `fs`.
+pub fn load_boot_modules(fs: &mut RamFs, boot_info: &BootInformation, log: &mut impl Write) {
+    for (i, module) in boot_info.module_tags().enumerate() {
+        let data = unsafe {
+            let start = module.start_address() as usize;
+            let end = module.end_address() as usize;
+            core::slice::from_raw_parts(start as *const u8, end - start)
+        };
+
+        let result = match ArchiveFormat::detect(data) {
+            Some(format) => unpack(fs, format, data).map(|count| {
+                let _ = writeln!(log, "Boot module {}: {:?} archive, {} entries", i, format, count);
+            }),
+            None => add_module_file(fs, i, module.name(), data).map(|_| {
+                let _ = writeln!(log, "Boot module {}: added to /{}", i, MODULES_DIRECTORY);
+            }),
+        };
+
+        if let Err(e) = result {
+            let _ = writeln!(log, "Boot module {} '{}' loading failed: {:?}", i, module.name(), e);
+        }
+    }
+}
+
+/// Unpack archive to `fs` and return the number of unpacked entries.
+pub fn unpack(fs: &mut RamFs, format: ArchiveFormat, data: &'static [u8]) -> Result<usize, InitrdError> {
+    match format {
+        ArchiveFormat::Tar => unpack_tar(fs, data),
+        ArchiveFormat::Cpio => unpack_cpio(fs, data),
+    }
+}
+
+/// Module file name is the last path component of the first word in the module command line.
+fn add_module_file(fs: &mut RamFs, index: usize, command_line: &str, data: &'static [u8]) -> Result<(), InitrdError> {
+    let root = fs.root();
+    let directory = match fs.lookup(root, MODULES_DIRECTORY) {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) => fs.create(root, MODULES_DIRECTORY, FileType::Directory, 0o755)?,
+        Err(e) => return Err(e.into()),
+    };
+
+    let name = command_line
+        .split_whitespace()
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..");
+
+    let mut default_name = crate::vfs::FileName::new();
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let _ = write!(default_name, "module{}", index);
+            default_name.as_str()
+        }
+    };
+
+    fs.create_file_with_data(directory, name, 0o644, data)?;
+    Ok(())
+}
+
+fn unpack_tar(fs: &mut RamFs, data: &'static [u8]) -> Result<usize, InitrdError> {
+    let mut offset = 0;
+    let mut count = 0;
+
+    while offset + TAR_BLOCK_SIZE <= data.len() {
+        let header = &data[offset..offset + TAR_BLOCK_SIZE];
+
+        // Archive ends with zero blocks.
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let size = parse_octal(&header[124..136])? as usize;
+        let mode = parse_octal(&header[100..108])? & MODE_PERMISSIONS_MASK;
+        let type_flag = header[156];
+
+        let prefix = null_terminated(&header[345..500])?;
+        let name = null_terminated(&header[0..100])?;
+        let link_name = null_terminated(&header[157..257])?;
+
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let data_end = data_start.checked_add(size).filter(|&end| end <= data.len()).ok_or(InitrdError::Corrupted)?;
+
+        let entry = match type_flag {
+            b'0' | b'\0' | b'7' => Entry::Regular(&data[data_start..data_end]),
+            b'5' => Entry::Directory,
+            b'2' => Entry::Symlink(link_name),
+            _ => Entry::Unsupported,
+        };
+
+        let mut path = crate::vfs::PathBuf::new();
+        path.try_push_str(prefix).map_err(|_| FsError::PathTooLong)?;
+        if !prefix.is_empty() {
+            path.try_push('/').map_err(|_| FsError::PathTooLong)?;
+        }
+        path.try_push_str(name).map_err(|_| FsError::PathTooLong)?;
+
+        if add_entry(fs, &path, mode as u16, entry)? {
+            count += 1;
+        }
+
+        offset = data_start + round_up(size, TAR_BLOCK_SIZE);
+    }
+
+    Ok(count)
+}
+
+fn unpack_cpio(fs: &mut RamFs, data: &'static [u8]) -> Result<usize, InitrdError> {
+    let mut offset = 0;
+    let mut count = 0;
+
+    loop {
+        let header = data.get(offset..offset + CPIO_HEADER_SIZE).ok_or(InitrdError::Corrupted)?;
+
+        if &header[0..6] != b"070701" {
+            return Err(InitrdError::Corrupted);
+        }
+
+        let field = |i: usize| parse_hex(&header[6 + i * 8..6 + (i + 1) * 8]);
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name_end = name_start.checked_add(name_size).ok_or(InitrdError::Corrupted)?;
+        let name_data = data.get(name_start..name_end).ok_or(InitrdError::Corrupted)?;
+        let name = null_terminated(name_data)?;
+
+        if name == CPIO_TRAILER {
+            break;
+        }
+
+        let data_start = round_up(name_end, 4);
+        let data_end = data_start.checked_add(size).filter(|&end| end <= data.len()).ok_or(InitrdError::Corrupted)?;
+        let contents = &data[data_start..data_end];
+
+        let entry = match mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => Entry::Directory,
+            MODE_REGULAR => Entry::Regular(contents),
+            MODE_SYMLINK => Entry::Symlink(core::str::from_utf8(contents).map_err(|_| InitrdError::Corrupted)?),
+            _ => Entry::Unsupported,
+        };
+
+        if add_entry(fs, name, (mode & MODE_PERMISSIONS_MASK) as u16, entry)? {
+            count += 1;
+        }
+
+        offset = round_up(data_end, 4);
+    }
+
+    Ok(count)
+}
+
+/// Create archive entry and missing parent directories. Returns false if entry was skipped.
+/// Paths with `..` or empty components are rejected, so entries stay inside the root directory.
+fn add_entry(fs: &mut RamFs, path: &str, mode: u16, entry: Entry) -> Result<bool, InitrdError> {
+    let path = path.trim_start_matches("./").trim_matches('/');
+
+    if path.is_empty() || path == "." {
+        return Ok(false);
+    }
+
+    if path.split('/').any(|component| component.is_empty() || component == "..") {
+        return Err(InitrdError::InvalidPath);
+    }
+
+    if let Entry::Unsupported = entry {
+        return Ok(false);
+    }
+
+    let (parent_path, name) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i+1..]),
+        None => ("", path),
+    };
+
+    let mut directory = fs.root();
+    for component in parent_path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        directory = lookup_or_create_directory(fs, directory, component, 0o755)?;
+    }
+
+    match entry {
+        Entry::Directory => {
+            lookup_or_create_directory(fs, directory, name, mode)?;
+        }
+        Entry::Regular(data) => {
+            fs.create_file_with_data(directory, name, mode, data)?;
+        }
+        Entry::Symlink(target) => {
+            fs.symlink(directory, name, target)?;
+        }
+        Entry::Unsupported => (),
+    }
+
+    Ok(true)
+}
+
+fn lookup_or_create_directory(fs: &mut RamFs, directory: InodeNumber, name: &str, mode: u16) -> Result<InodeNumber, FsError> {
+    match fs.lookup(directory, name) {
+        Ok(inode) => {
+            if fs.metadata(inode)?.file_type != FileType::Directory {
+                return Err(FsError::NotDirectory);
+            }
+            Ok(inode)
+        }
+        Err(FsError::NotFound) => fs.create(directory, name, FileType::Directory, mode),
+        Err(e) => Err(e),
+    }
+}
+
+fn round_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn null_terminated(bytes: &[u8]) -> Result<&str, InitrdError> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).map_err(|_| InitrdError::Corrupted)
+}
+
+/// Tar header numbers are octal and end with a space or null.
+fn parse_octal(bytes: &[u8]) -> Result<u32, InitrdError> {
+    let mut value: u32 = 0;
+
+    for &b in bytes.iter().skip_while(|&&b| b == b' ') {
+        match b {
+            b'0'..=b'7' => {
+                value = value.checked_mul(8).ok_or(InitrdError::Corrupted)? + (b - b'0') as u32;
+            }
+            b' ' | b'\0' => break,
+            _ => return Err(InitrdError::Corrupted),
+        }
+    }
+
+    Ok(value)
+}
+
+fn parse_hex(bytes: &[u8]) -> Result<u32, InitrdError> {
+    let text = core::str::from_utf8(bytes).map_err(|_| InitrdError::Corrupted)?;
+    u32::from_str_radix(text, 16).map_err(|_| InitrdError::Corrupted)
+}
@@ -58,6 +58,8 @@ pub struct Input {
     ps2_controller: EnabledDevices<PS2ControllerIO, InterruptsEnabled>,
     keyevent_decoder: pc_keyboard::Keyboard<Us104Key, ScancodeSet2>,
     keyboard_driver: Keyboard<[Command; 8]>,
+    shift_pressed: bool,
+    alt_pressed: bool,
 }
 
 #[derive(Debug)]
@@ -73,7 +75,7 @@ impl Input {
         controller.self_test().map_err(|e| InputError::ControllerSelfTestError(e))?;
         controller.scancode_translation(false);
         let mut controller = controller.enable_devices_and_interrupts(EnableDevice::Keyboard).map_err(|(_, e)| InputError::KeyboardConnectionError(e))?;
-        let keyevent_decoder = pc_keyboard::Keyboard::new(Us104Key, ScancodeSet2, HandleControl::Ignore);
+        let keyevent_decoder = pc_keyboard::Keyboard::new(Us104Key, ScancodeSet2, HandleControl::MapLettersToUnicode);
         let mut keyboard_driver = Keyboard::new(&mut ToKeyboard(&mut controller)).unwrap();
         keyboard_driver.enable(&mut ToKeyboard(&mut controller)).unwrap();
 
@@ -81,6 +83,8 @@ impl Input {
             ps2_controller: controller,
             keyevent_decoder,
             keyboard_driver,
+            shift_pressed: false,
+            alt_pressed: false,
         };
 
         Ok(input)
@@ -104,6 +108,15 @@ impl Input {
     }
 
     fn keyboard_event_to_key_press(&mut self, key_event: KeyEvent) -> Option<KeyPress> {
+        // The decoder also tracks Shift, but it doesn't tell the state.
+        if let KeyCode::ShiftLeft | KeyCode::ShiftRight = key_event.code {
+            self.shift_pressed = if let KeyState::Down = key_event.state { true } else { false };
+        }
+
+        if let KeyCode::AltLeft | KeyCode::AltRight = key_event.code {
+            self.alt_pressed = if let KeyState::Down = key_event.state { true } else { false };
+        }
+
         let converted = match key_event.code {
             KeyCode::ArrowUp => KeyPress::Up,
             KeyCode::ArrowDown => KeyPress::Down,
@@ -115,8 +128,20 @@ impl Input {
             KeyCode::Delete => KeyPress::Delete,
             KeyCode::Home => KeyPress::Home,
             KeyCode::End => KeyPress::End,
+            KeyCode::Tab => KeyPress::Tab,
+            KeyCode::PageUp if self.shift_pressed => KeyPress::ShiftPageUp,
+            KeyCode::PageDown if self.shift_pressed => KeyPress::ShiftPageDown,
+            KeyCode::F1 if self.alt_pressed => KeyPress::AltFunction(1),
+            KeyCode::F2 if self.alt_pressed => KeyPress::AltFunction(2),
+            KeyCode::F3 if self.alt_pressed => KeyPress::AltFunction(3),
+            KeyCode::F4 if self.alt_pressed => KeyPress::AltFunction(4),
+            KeyCode::F5 if self.alt_pressed => KeyPress::AltFunction(5),
+            KeyCode::F6 if self.alt_pressed => KeyPress::AltFunction(6),
             _ => {
                 return match self.keyevent_decoder.process_keyevent(key_event) {
+                    Some(DecodedKey::Unicode(c)) if ('\u{1}'..='\u{1A}').contains(&c) => {
+                        Some(KeyPress::Ctrl((b'a' + c as u8 - 1) as char))
+                    }
                     Some(DecodedKey::Unicode(c)) => Some(KeyPress::Unicode(c)),
                     _ => None,
                 };
@@ -135,6 +160,14 @@ impl Input {
     }
 }
 
+/// Reset the CPU with the PS/2 controller. Shell commands use this,
+/// because they don't have access to `Input`.
+pub fn reset_cpu() {
+    unsafe {
+        x86::io::outb(0x64, 0xFE);
+    }
+}
+
 pub enum KeyPress {
     Up,
     Down,
@@ -146,5 +179,13 @@ pub enum KeyPress {
     Delete,
     Home,
     End,
+    Tab,
+    /// Scroll the terminal output.
+    ShiftPageUp,
+    ShiftPageDown,
+    /// Alt and function key F1-F6 switch the virtual console.
+    AltFunction(usize),
+    /// Control key and a lowercase letter.
+    Ctrl(char),
     Unicode(char)
 }
@@ -0,0 +1,75 @@
+//! Kernel message log which is readable from `/dev/kmsg`.
+//!
+//! The log keeps the latest messages and drops the oldest bytes when it
+//! is full.
+
+use core::fmt::{self, Write};
+
+use crate::devfs::CharDevice;
+use crate::ring_buffer::ByteRing;
+use crate::sync::IrqSpinlock;
+use crate::vfs::FsError;
+
+/// Interrupt handlers write to the log too, so it is behind an
+/// `IrqSpinlock`.
+static KMSG: IrqSpinlock<ByteRing> = IrqSpinlock::new("kernel log", ByteRing::new());
+
+pub fn write(data: &[u8]) {
+    let mut log = KMSG.lock();
+
+    for &byte in data {
+        log.push_overwrite(byte);
+    }
+}
+
+/// Copy log bytes from stream position `position` and move the position
+/// after them. Dropped bytes are skipped.
+pub fn read(position: &mut u64, buffer: &mut [u8]) -> usize {
+    let log = KMSG.lock();
+    *position = (*position).max(log.start_position());
+    let count = log.read_at(*position, buffer);
+    *position += count as u64;
+    count
+}
+
+/// Writes messages to the kernel log and to another output.
+pub struct KernelLog<'a, W: Write> {
+    output: &'a mut W,
+}
+
+impl<'a, W: Write> KernelLog<'a, W> {
+    pub fn new(output: &'a mut W) -> Self {
+        Self {
+            output,
+        }
+    }
+}
+
+impl<W: Write> Write for KernelLog<'_, W> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        write(text.as_bytes());
+        self.output.write_str(text)
+    }
+}
+
+pub struct KmsgDevice;
+
+impl CharDevice for KmsgDevice {
+    /// File offset is a position in the log stream. The VFS moves
+    /// offsets of dropped messages to `first_offset`, so readers skip
+    /// them.
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(KMSG.lock().read_at(offset, buffer))
+    }
+
+    fn first_offset(&self) -> u64 {
+        KMSG.lock().start_position()
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        write(data);
+        Ok(data.len())
+    }
+}
+
+pub static mut KMSG_DEVICE: KmsgDevice = KmsgDevice;
@@ -4,11 +4,44 @@
 
 pub mod vga_text;
 pub mod terminal;
+pub mod ansi;
 pub mod page_table;
 pub mod gdt;
 pub mod idt;
 pub mod tss;
 pub mod input;
+pub mod vfs;
+pub mod ramfs;
+pub mod shell;
+pub mod frame_allocator;
+pub mod initrd;
+pub mod block;
+pub mod ata;
+pub mod fat;
+pub mod ext2;
+pub mod procfs;
+pub mod ring_buffer;
+pub mod console;
+pub mod kmsg;
+pub mod serial;
+pub mod devfs;
+pub mod usermode;
+pub mod syscall;
+pub mod elf;
+pub mod scheduler;
+pub mod sync;
+pub mod process;
+pub mod vma;
+pub mod swap;
+pub mod pipe;
+pub mod shell_parser;
+pub mod shell_command;
+pub mod shell_history;
+pub mod shell_completion;
+pub mod virtual_console;
+pub mod cp437;
+pub mod utf8;
+pub mod vga_font;
 
 use self::terminal::{Terminal};
 use self::gdt::GDT;
@@ -26,6 +59,7 @@ extern "C" {
 extern "C" fn kernel_main(eax: u32, ebx: u32) -> ! {
     let mut vga_handle = vga_text::new_vga_text_mode().unwrap();
     vga_handle.clear_screen(vga::driver::text::VgaChar::empty());
+    vga_font::init();
 
     let mut terminal = Terminal::new(vga_handle, true);
 
@@ -76,25 +110,72 @@ extern "C" fn kernel_main(eax: u32, ebx: u32) -> ! {
 
     let _ = writeln!(terminal, "{:?}", boot_info);
 
+    procfs::init(ebx as usize);
+
+    frame_allocator::init(&boot_info).expect("Boot information doesn't contain a memory map");
+
+    let _ = writeln!(terminal, "Free memory: {} KiB", frame_allocator::free_frames() * frame_allocator::FRAME_SIZE / 1024);
+
     check_cpu_features(&mut terminal).expect("error: CPU is not compatible");
 
     enable_cpu_features();
 
     GDT::load_gdt();
 
+    syscall::init();
+
     let mut idt_handler = IDTHandler::new();
 
     let mut page_table = page_table::GlobalPageTable::new().expect("Page table handle loading failed");
     page_table.load_identity_map();
 
     unsafe {
-        let cr3_data = page_table::pae_cr3_format(page_table.level3_start_address(), false, false);
-        x86::controlregs::cr3_write(cr3_data as u64);
+        page_table::load_cr3(page_table.level3_start_address());
         x86::controlregs::cr0_write(x86::controlregs::Cr0::CR0_EMULATE_COPROCESSOR | x86::controlregs::Cr0::CR0_WRITE_PROTECT | x86::controlregs::Cr0::CR0_ENABLE_PAGING | x86::controlregs::cr0());
     }
 
     let _task = KernelTask::load();
 
+    scheduler::init().expect("Scheduler initialization failed");
+
+    let root_fs = ramfs::RamFs::new_instance().expect("Root filesystem creation failed");
+    initrd::load_boot_modules(root_fs, &boot_info, &mut terminal);
+    vfs::init(root_fs);
+    vfs::vfs().register_file_system_type(ramfs::RAMFS_TYPE);
+    vfs::vfs().register_file_system_type(fat::FAT_TYPE);
+    vfs::vfs().register_file_system_type(ext2::EXT2_TYPE);
+    vfs::vfs().register_file_system_type(procfs::PROCFS_TYPE);
+    vfs::vfs().register_file_system_type(devfs::DEVFS_TYPE);
+    pipe::init().expect("Pipe filesystem creation failed");
+
+    {
+        let mut log = kmsg::KernelLog::new(&mut terminal);
+
+        ata::init(&mut log);
+
+        if let Err(e) = register_char_devices() {
+            let _ = writeln!(log, "Character device registration failed: {:?}", e);
+        }
+    }
+
+    if let Err(e) = register_shell_commands() {
+        let _ = writeln!(terminal, "Shell command registration failed: {:?}", e);
+    }
+
+    let shell_context = vfs::Context::new();
+
+    for directory in &["/mnt", "/tmp", "/proc", "/dev"] {
+        if let Err(e) = vfs::vfs().create_directory(&shell_context, directory) {
+            let _ = writeln!(terminal, "Couldn't create directory {}: {:?}", directory, e);
+        }
+    }
+
+    for &(fs_type, path) in &[("proc", "/proc"), ("devfs", "/dev")] {
+        if let Err(e) = vfs::vfs().mount(&shell_context, fs_type, path, fs_type) {
+            let _ = writeln!(terminal, "Couldn't mount {}: {:?}", path, e);
+        }
+    }
+
     let mut input_module = match self::input::Input::init() {
         Ok(input) => {
             Some(input)
@@ -107,7 +188,7 @@ extern "C" fn kernel_main(eax: u32, ebx: u32) -> ! {
 
     idt_handler.enable_interrupts();
 
-    let mut cmd_store = terminal::CommandStore::new();
+    let mut consoles = virtual_console::VirtualConsoles::new(terminal, shell_context).expect("Virtual console creation failed");
 
     loop {
         while let Some(hardware_interrupt) = idt_handler.handle_interrupt() {
@@ -118,45 +199,53 @@ extern "C" fn kernel_main(eax: u32, ebx: u32) -> ! {
                         let key = input.handle_keyboard_interrupt();
 
                         match key {
-                            Ok(Some(k)) => {
-                                if let Some(cmd) = terminal.update_command_line(k, &mut cmd_store) {
-                                    match cmd.name {
-                                        "echo" => {
-                                            for arg in cmd.arguments {
-                                                write!(terminal, "{} ", arg).unwrap();
-                                            }
-                                            writeln!(terminal, "").unwrap();
-                                        }
-                                        "reboot" => {
-                                            input.reboot_computer()
-                                        }
-                                        "" => (),
-                                        unknown_cmd => writeln!(terminal, "Unknown command '{}'", unknown_cmd).unwrap(),
-                                    }
-                                    writeln!(terminal, "").unwrap();
-                                }
-                            }
+                            Ok(Some(k)) => consoles.handle_key(k),
                             Ok(None) => (),
                             Err(e) => {
-                                let _ = writeln!(terminal, "Keyboard error: {:?}", e);
+                                let mut log = kmsg::KernelLog::new(consoles.active_terminal());
+                                let _ = writeln!(log, "Keyboard error: {:?}", e);
                             }
                         }
                     }
                 },
                 hardware_interrupt => {
-                    let _ = writeln!(terminal, "HardwareInterrupt: {:?}", hardware_interrupt);
+                    let mut log = kmsg::KernelLog::new(consoles.active_terminal());
+                    let _ = writeln!(log, "HardwareInterrupt: {:?}", hardware_interrupt);
                 }
             }
         }
 
-        // TODO: Here is a possible deadlock if IDT interrupt handler runs just before
-        //       x86::halt() and if there won't be more interrupts until a device driver
-        //       handles the received interrupt.
+        // Console output of session threads and kernel messages.
+        consoles.flush();
+
+        idt_handler.wait_for_interrupt();
+    }
+}
+
+fn register_char_devices() -> Result<(), vfs::FsError> {
+    devfs::register_memory_devices()?;
+
+    unsafe {
+        devfs::register_char_device("console", 0o600, &mut console::CONSOLE_DEVICE)?;
+        devfs::register_char_device("kmsg", 0o600, &mut kmsg::KMSG_DEVICE)?;
+    }
 
+    if serial::init() {
         unsafe {
-            x86::halt()
+            devfs::register_char_device("ttyS0", 0o660, &mut serial::COM1_DEVICE)?;
         }
     }
+
+    Ok(())
+}
+
+fn register_shell_commands() -> Result<(), shell_command::RegistryError> {
+    shell::register_commands()?;
+    shell_history::register_commands()?;
+    process::register_commands()?;
+    scheduler::register_commands()?;
+    swap::register_commands()?;
+    vga_font::register_commands()
 }
 
 fn check_cpu_features(log: &mut impl Write) -> Result<(), ()> {
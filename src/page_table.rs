@@ -4,10 +4,40 @@ use bitflags::bitflags;
 const GIBIBYTE: u64 = MIBIBYTE*1024;
 const MIBIBYTE: u64 = 1024*1024;
 
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::frame_allocator::{self, Frame};
+use crate::swap;
+use crate::vma::MemoryMap;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// User mode code can only access virtual addresses in this range.
+/// Rest of the address space is identity mapped for the kernel.
+pub const USER_SPACE_START: usize = GIBIBYTE as usize;
+pub const USER_SPACE_END: usize = (GIBIBYTE * 3) as usize;
+
+/// Kernel memory which is mapped page by page, shared by every address
+/// space. `vma::KernelBuffer` reserves ranges from here. The range is
+/// not identity mapped.
+pub const KERNEL_BUFFER_START: usize = (GIBIBYTE * 3) as usize;
+pub const KERNEL_BUFFER_END: usize = KERNEL_BUFFER_START + (MIBIBYTE * 256) as usize;
+
+const LEVEL1_TABLE_COVERAGE: usize = (MIBIBYTE * 2) as usize;
+
+#[derive(Debug)]
+pub enum MapError {
+    NotUserAddress,
+    NotKernelBufferAddress,
+    AlreadyMapped,
+    OutOfMemory,
+}
 
 static PAGE_TABLE_HANDLE_CREATED: AtomicBool = AtomicBool::new(false);
 
+/// Address of the level 3 table which is currently loaded to CR3.
+static ACTIVE_LEVEL3_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
 extern "C" {
     #[allow(improper_ctypes)]
     pub static READ_WRITE_PAGE_START_LOCATION: ();
@@ -17,8 +47,6 @@ extern "C" {
 pub struct PageTableData {
     level3: [L3PageTableEntry; 512],
     level2_1: [L2PageTableEntry2MB; 512],
-    level2_2: [L2PageTableEntry2MB; 512],
-    level2_3: [L2PageTableEntry2MB; 512],
     level2_4: [L2PageTableEntry2MB; 512],
 }
 
@@ -26,12 +54,11 @@ pub struct PageTableData {
 static mut PAGE_TABLE_DATA: PageTableData = PageTableData {
     level3: [L3PageTableEntry::zero(); 512],
     level2_1: [L2PageTableEntry2MB::zero(); 512],
-    level2_2: [L2PageTableEntry2MB::zero(); 512],
-    level2_3: [L2PageTableEntry2MB::zero(); 512],
     level2_4: [L2PageTableEntry2MB::zero(); 512],
 };
 
-
+/// Kernel page table. User space is not mapped. Every `AddressSpace`
+/// shares the kernel mappings from this table.
 pub struct GlobalPageTable {
     data: &'static mut PageTableData,
 }
@@ -55,8 +82,6 @@ impl GlobalPageTable {
 
     pub fn load_identity_map(&mut self) {
         self.data.level3[0] = L3PageTableEntry::new(self.data.level2_1.as_ptr() as u64, L3Flags::PRESENT);
-        self.data.level3[1] = L3PageTableEntry::new(self.data.level2_2.as_ptr() as u64, L3Flags::PRESENT);
-        self.data.level3[2] = L3PageTableEntry::new(self.data.level2_3.as_ptr() as u64, L3Flags::PRESENT);
         self.data.level3[3] = L3PageTableEntry::new(self.data.level2_4.as_ptr() as u64, L3Flags::PRESENT);
 
         fn fill_page_table<F: EntryFlags>(mut flags: F, mut start_address: u64, table: &mut [GenericPageTableEntry<F, PhysicalAddressHandler2MBytesPDE>; 512], address_offset: u64, read_write_flag: F) {
@@ -71,15 +96,21 @@ impl GlobalPageTable {
                 start_address += address_offset;
             }
         }
-        let flags = L2Flags2MB::PRESENT | L2Flags2MB::USER_SUPERVISOR;
+        // Kernel pages are not accessible from user mode.
+        let flags = L2Flags2MB::PRESENT;
         let stack_and_data_flags = L2Flags2MB::READ_WRITE | L2Flags2MB::NO_EXECUTE;
         fill_page_table(flags, 0, &mut self.data.level2_1, MIBIBYTE*2, stack_and_data_flags);
-        fill_page_table(flags, GIBIBYTE, &mut self.data.level2_2, MIBIBYTE*2, stack_and_data_flags);
-        fill_page_table(flags, GIBIBYTE*2, &mut self.data.level2_3, MIBIBYTE*2, stack_and_data_flags);
         fill_page_table(flags, GIBIBYTE*3, &mut self.data.level2_4, MIBIBYTE*2, stack_and_data_flags);
 
         // Allow writing to VGA text buffer.
         self.data.level2_1[0] = <GenericPageTableEntry<_, _>>::new(0, flags | L2Flags2MB::READ_WRITE | L2Flags2MB::PAGE_LEVEL_CACHE_DISABLE);
+
+        // Level 1 tables of kernel buffers are allocated when pages are
+        // mapped.
+        let buffer_directory_entries = (KERNEL_BUFFER_END - KERNEL_BUFFER_START) / LEVEL1_TABLE_COVERAGE;
+        for entry in self.data.level2_4[..buffer_directory_entries].iter_mut() {
+            *entry = L2PageTableEntry2MB::zero();
+        }
     }
 
     pub fn level3_start_address(&self) -> usize {
@@ -87,6 +118,480 @@ impl GlobalPageTable {
     }
 }
 
+/// Address of the level 3 table of the kernel page table.
+pub fn kernel_level3_address() -> usize {
+    unsafe { PAGE_TABLE_DATA.level3.as_ptr() as usize }
+}
+
+/// Load the kernel page table which doesn't have user space mappings.
+pub fn activate_kernel_page_table() {
+    unsafe {
+        load_cr3(kernel_level3_address());
+    }
+}
+
+/// Page tables of one user space. Level 3 table entries 0 and 3 point
+/// to the kernel page directories and entries 1 and 2 to the user space
+/// page directories of this address space.
+///
+/// Frames of the page tables and frames mapped to user space are freed
+/// when the address space is dropped.
+pub struct AddressSpace {
+    level3: Frame,
+    directories: [Frame; 2],
+    /// Memory areas which the page fault handler maps on demand.
+    pub memory_map: MemoryMap,
+}
+
+impl AddressSpace {
+    /// Create an address space with empty user space.
+    pub fn new() -> Result<Self, MapError> {
+        let level3 = frame_allocator::allocate_zeroed_frame().ok_or(MapError::OutOfMemory)?;
+        let directory1 = match frame_allocator::allocate_zeroed_frame() {
+            Some(frame) => frame,
+            None => {
+                frame_allocator::free_frame(level3);
+                return Err(MapError::OutOfMemory);
+            }
+        };
+        let directory2 = match frame_allocator::allocate_zeroed_frame() {
+            Some(frame) => frame,
+            None => {
+                frame_allocator::free_frame(level3);
+                frame_allocator::free_frame(directory1);
+                return Err(MapError::OutOfMemory);
+            }
+        };
+
+        let address_space = Self {
+            level3,
+            directories: [directory1, directory2],
+            memory_map: MemoryMap::new(),
+        };
+
+        let kernel_level3 = unsafe { &PAGE_TABLE_DATA.level3 };
+        let level3_table = address_space.level3_table();
+        level3_table[0] = kernel_level3[0];
+        level3_table[1] = L3PageTableEntry::new(directory1.start_address() as u64, L3Flags::PRESENT);
+        level3_table[2] = L3PageTableEntry::new(directory2.start_address() as u64, L3Flags::PRESENT);
+        level3_table[3] = kernel_level3[3];
+
+        Ok(address_space)
+    }
+
+    fn level3_table(&self) -> &'static mut [L3PageTableEntry; 512] {
+        unsafe { &mut *(self.level3.start_address() as *mut [L3PageTableEntry; 512]) }
+    }
+
+    fn directory(&self, index: usize) -> &'static mut [L2PageTableEntry; 512] {
+        unsafe { &mut *(self.directories[index].start_address() as *mut [L2PageTableEntry; 512]) }
+    }
+
+    pub fn level3_start_address(&self) -> usize {
+        self.level3.start_address()
+    }
+
+    pub fn is_active(&self) -> bool {
+        ACTIVE_LEVEL3_ADDRESS.load(Ordering::SeqCst) == self.level3_start_address()
+    }
+
+    /// Load this address space to CR3.
+    pub fn activate(&self) {
+        if !self.is_active() {
+            unsafe {
+                load_cr3(self.level3_start_address());
+            }
+        }
+    }
+
+    fn user_directory_entry(&mut self, address: usize) -> Result<&mut L2PageTableEntry, MapError> {
+        if address < USER_SPACE_START || address >= USER_SPACE_END {
+            return Err(MapError::NotUserAddress);
+        }
+
+        let offset = address - USER_SPACE_START;
+        let directory = self.directory(offset / GIBIBYTE as usize);
+
+        Ok(&mut directory[(offset / LEVEL1_TABLE_COVERAGE) % 512])
+    }
+
+    fn flush(&self, address: usize) {
+        if self.is_active() {
+            unsafe {
+                x86::tlb::flush(address);
+            }
+        }
+    }
+
+    /// Map user page containing `address` to `frame`. Page table
+    /// frames are allocated from the frame allocator.
+    pub fn map_user_page(&mut self, address: usize, frame: Frame, flags: L1Flags) -> Result<(), MapError> {
+        let entry = self.create_user_entry(address)?;
+
+        if entry.flags().intersects(L1Flags::PRESENT | L1Flags::SWAPPED) {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        *entry = L1PageTableEntry::new(frame.start_address() as u64, flags | L1Flags::PRESENT | L1Flags::USER_SUPERVISOR);
+        self.flush(address);
+
+        Ok(())
+    }
+
+    /// Page table entry of the user page containing `address`. Level 1
+    /// table is allocated if it doesn't exist.
+    fn create_user_entry(&mut self, address: usize) -> Result<&mut L1PageTableEntry, MapError> {
+        let directory_entry = self.user_directory_entry(address)?;
+
+        if !directory_entry.flags().contains(L2Flags::PRESENT) {
+            let table = frame_allocator::allocate_zeroed_frame().ok_or(MapError::OutOfMemory)?;
+            let table_flags = L2Flags::PRESENT | L2Flags::READ_WRITE | L2Flags::USER_SUPERVISOR;
+            *directory_entry = L2PageTableEntry::new(table.start_address() as u64, table_flags);
+        }
+
+        Ok(&mut level1_table(directory_entry)[(address / PAGE_SIZE) % 512])
+    }
+
+    /// Remove mapping of the user page containing `address`. Returns the
+    /// frame which was mapped. If the page is swapped out, its swap slot
+    /// is freed.
+    pub fn unmap_user_page(&mut self, address: usize) -> Option<Frame> {
+        let entry = self.user_entry_mut(address)?;
+
+        if let Some(slot) = swap::swapped_slot(entry) {
+            *entry = L1PageTableEntry::zero();
+            swap::free_slot(slot);
+            return None;
+        }
+
+        if !entry.flags().contains(L1Flags::PRESENT) {
+            return None;
+        }
+
+        let frame = Frame::containing_address(entry.address() as usize);
+        *entry = L1PageTableEntry::zero();
+        self.flush(address);
+
+        Some(frame)
+    }
+
+    /// Page table entry of a user page which might not be present.
+    /// Returns `None` if the level 1 table doesn't exist.
+    pub fn user_entry_mut(&mut self, address: usize) -> Option<&mut L1PageTableEntry> {
+        let directory_entry = self.user_directory_entry(address).ok()?;
+
+        if directory_entry.flags().contains(L2Flags::PRESENT) {
+            Some(&mut level1_table(directory_entry)[(address / PAGE_SIZE) % 512])
+        } else {
+            None
+        }
+    }
+
+    /// Page table entry of a mapped user page. Flush the TLB with
+    /// `flush_user_page` after modifying the entry.
+    pub fn user_page_mut(&mut self, address: usize) -> Option<&mut L1PageTableEntry> {
+        let entry = self.user_entry_mut(address)?;
+
+        if entry.flags().contains(L1Flags::PRESENT) {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    pub fn flush_user_page(&self, address: usize) {
+        self.flush(address);
+    }
+
+    /// Iterate mapped user pages. Items are the virtual address of the
+    /// page and the page table entry.
+    pub fn user_pages(&self) -> impl Iterator<Item=(usize, L1PageTableEntry)> + '_ {
+        (0..2).flat_map(move |directory_index| {
+            let directory: &[L2PageTableEntry; 512] = self.directory(directory_index);
+            (0..512).filter(move |&i| directory[i].flags().contains(L2Flags::PRESENT))
+                .flat_map(move |i| {
+                    let table: &[L1PageTableEntry; 512] = level1_table(&directory[i]);
+                    let table_address = USER_SPACE_START + directory_index * GIBIBYTE as usize + i * LEVEL1_TABLE_COVERAGE;
+                    (0..512).filter(move |&j| table[j].flags().contains(L1Flags::PRESENT))
+                        .map(move |j| (table_address + j * PAGE_SIZE, table[j]))
+                })
+        })
+    }
+
+    /// Address of the first mapped user page at or after `address`.
+    pub fn next_user_page(&self, address: usize) -> Option<usize> {
+        let mut address = core::cmp::max(address, USER_SPACE_START) & !(PAGE_SIZE - 1);
+
+        while address < USER_SPACE_END {
+            let offset = address - USER_SPACE_START;
+            let directory_entry = &self.directory(offset / GIBIBYTE as usize)[(offset / LEVEL1_TABLE_COVERAGE) % 512];
+            let table_address = address & !(LEVEL1_TABLE_COVERAGE - 1);
+
+            if directory_entry.flags().contains(L2Flags::PRESENT) {
+                let table = level1_table(directory_entry);
+                for i in (address / PAGE_SIZE) % 512..512 {
+                    if table[i].flags().contains(L1Flags::PRESENT) {
+                        return Some(table_address + i * PAGE_SIZE);
+                    }
+                }
+            }
+
+            address = table_address + LEVEL1_TABLE_COVERAGE;
+        }
+
+        None
+    }
+
+    /// Unmap all user pages and free mapped frames and level 1 tables.
+    pub fn clear_user_space(&mut self) {
+        for directory_index in 0..2 {
+            for directory_entry in self.directory(directory_index).iter_mut() {
+                if !directory_entry.flags().contains(L2Flags::PRESENT) {
+                    continue;
+                }
+
+                for entry in level1_table(directory_entry).iter_mut() {
+                    if entry.flags().contains(L1Flags::PRESENT) {
+                        frame_allocator::free_frame(Frame::containing_address(entry.address() as usize));
+                    } else if let Some(slot) = swap::swapped_slot(entry) {
+                        swap::free_slot(slot);
+                    }
+                    *entry = L1PageTableEntry::zero();
+                }
+
+                frame_allocator::free_frame(Frame::containing_address(directory_entry.address() as usize));
+                *directory_entry = L2PageTableEntry::zero();
+            }
+        }
+
+        // Flush TLB by reloading CR3.
+        if self.is_active() {
+            unsafe {
+                load_cr3(self.level3_start_address());
+            }
+        }
+    }
+
+    /// Create a new address space which shares all user pages with this
+    /// address space. Writable pages become copy-on-write pages in both
+    /// address spaces. Swapped out pages share the swap slot.
+    pub fn clone_copy_on_write(&mut self) -> Result<Self, MapError> {
+        let mut address_space = Self::new()?;
+        address_space.memory_map = self.memory_map.clone();
+
+        let result = self.share_user_pages(&mut address_space);
+
+        // Flush TLB by reloading CR3, because pages became read-only.
+        if self.is_active() {
+            unsafe {
+                load_cr3(self.level3_start_address());
+            }
+        }
+
+        result.map(|_| address_space)
+    }
+
+    fn share_user_pages(&mut self, target: &mut Self) -> Result<(), MapError> {
+        for directory_index in 0..2 {
+            for (i, directory_entry) in self.directory(directory_index).iter().enumerate() {
+                if !directory_entry.flags().contains(L2Flags::PRESENT) {
+                    continue;
+                }
+
+                let table_address = USER_SPACE_START + directory_index * GIBIBYTE as usize + i * LEVEL1_TABLE_COVERAGE;
+
+                for (j, entry) in level1_table(directory_entry).iter_mut().enumerate() {
+                    if entry.flags().contains(L1Flags::PRESENT) {
+                        let target_entry = target.create_user_entry(table_address + j * PAGE_SIZE)?;
+                        entry.flags_mut(copy_on_write_flags(entry.flags()));
+                        frame_allocator::share_frame(Frame::containing_address(entry.address() as usize));
+                        *target_entry = *entry;
+                    } else if let Some(slot) = swap::swapped_slot(entry) {
+                        let target_entry = target.create_user_entry(table_address + j * PAGE_SIZE)?;
+                        swap::share_slot(slot);
+                        *target_entry = *entry;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make the copy-on-write page containing `address` writable. Frame
+    /// is copied if other address spaces still use it. Returns false if
+    /// the page is not a copy-on-write page.
+    pub fn handle_copy_on_write(&mut self, address: usize) -> Result<bool, MapError> {
+        let entry = match self.user_page_mut(address) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        let mut flags = entry.flags();
+        if !flags.contains(L1Flags::COPY_ON_WRITE) {
+            return Ok(false);
+        }
+        flags.remove(L1Flags::COPY_ON_WRITE);
+        flags.insert(L1Flags::READ_WRITE);
+
+        let frame = Frame::containing_address(entry.address() as usize);
+
+        if frame_allocator::reference_count(frame) == 1 {
+            entry.flags_mut(flags);
+        } else {
+            let copy = swap::allocate_frame().ok_or(MapError::OutOfMemory)?;
+            unsafe {
+                copy.data_mut().copy_from_slice(frame.data_mut());
+            }
+            *entry = L1PageTableEntry::new(copy.start_address() as u64, flags);
+            frame_allocator::free_frame(frame);
+        }
+
+        self.flush(address);
+
+        Ok(true)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel_page_table();
+        }
+
+        self.clear_user_space();
+
+        frame_allocator::free_frame(self.directories[0]);
+        frame_allocator::free_frame(self.directories[1]);
+        frame_allocator::free_frame(self.level3);
+    }
+}
+
+/// Load page table starting from level 3 table at `level3_address`.
+///
+/// # Safety
+/// Page table must map the kernel.
+pub unsafe fn load_cr3(level3_address: usize) {
+    let cr3_data = pae_cr3_format(level3_address, false, false);
+    x86::controlregs::cr3_write(cr3_data as u64);
+    ACTIVE_LEVEL3_ADDRESS.store(level3_address, Ordering::SeqCst);
+}
+
+/// Address of the level 3 table which is currently loaded to CR3.
+pub fn active_level3_address() -> usize {
+    ACTIVE_LEVEL3_ADDRESS.load(Ordering::SeqCst)
+}
+
+/// Flags of the page containing `address` in the currently loaded page
+/// table. Returns `None` if the page is not mapped or it is not
+/// accessible from user mode.
+pub fn user_page_flags(address: usize) -> Option<L1Flags> {
+    if address < USER_SPACE_START || address >= USER_SPACE_END {
+        return None;
+    }
+
+    let level3_address = ACTIVE_LEVEL3_ADDRESS.load(Ordering::SeqCst);
+    if level3_address == 0 {
+        return None;
+    }
+
+    let level3 = unsafe { &*(level3_address as *const [L3PageTableEntry; 512]) };
+    let level3_entry = &level3[address / GIBIBYTE as usize];
+    if !level3_entry.flags().contains(L3Flags::PRESENT) {
+        return None;
+    }
+
+    let level2 = unsafe { &*(level3_entry.address() as usize as *const [L2PageTableEntry; 512]) };
+    let directory_entry = &level2[(address / LEVEL1_TABLE_COVERAGE) % 512];
+    if !directory_entry.flags().contains(L2Flags::PRESENT | L2Flags::USER_SUPERVISOR) {
+        return None;
+    }
+
+    let entry = &level1_table(directory_entry)[(address / PAGE_SIZE) % 512];
+    let flags = entry.flags();
+    if flags.contains(L1Flags::PRESENT | L1Flags::USER_SUPERVISOR) {
+        Some(flags)
+    } else {
+        None
+    }
+}
+
+/// Directory entry of a kernel buffer address. Entries of the kernel
+/// buffer range point to level 1 tables instead of 2 MiB pages.
+fn kernel_buffer_directory_entry(address: usize) -> Result<&'static mut L2PageTableEntry, MapError> {
+    if address < KERNEL_BUFFER_START || address >= KERNEL_BUFFER_END {
+        return Err(MapError::NotKernelBufferAddress);
+    }
+
+    let index = (address - KERNEL_BUFFER_START) / LEVEL1_TABLE_COVERAGE;
+    unsafe {
+        Ok(&mut *(&mut PAGE_TABLE_DATA.level2_4[index] as *mut L2PageTableEntry2MB as *mut L2PageTableEntry))
+    }
+}
+
+/// Map kernel buffer page containing `address` to `frame`. Call with
+/// interrupts disabled. Level 1 tables are never freed.
+pub fn map_kernel_buffer_page(address: usize, frame: Frame) -> Result<(), MapError> {
+    let directory_entry = kernel_buffer_directory_entry(address)?;
+
+    if !directory_entry.flags().contains(L2Flags::PRESENT) {
+        let table = frame_allocator::allocate_zeroed_frame().ok_or(MapError::OutOfMemory)?;
+        *directory_entry = L2PageTableEntry::new(table.start_address() as u64, L2Flags::PRESENT | L2Flags::READ_WRITE);
+    }
+
+    let entry = &mut level1_table(directory_entry)[(address / PAGE_SIZE) % 512];
+    if entry.flags().contains(L1Flags::PRESENT) {
+        return Err(MapError::AlreadyMapped);
+    }
+
+    *entry = L1PageTableEntry::new(frame.start_address() as u64, L1Flags::PRESENT | L1Flags::READ_WRITE | L1Flags::NO_EXECUTE);
+    unsafe {
+        x86::tlb::flush(address);
+    }
+
+    Ok(())
+}
+
+/// Remove mapping of the kernel buffer page containing `address`.
+/// Returns the frame which was mapped.
+pub fn unmap_kernel_buffer_page(address: usize) -> Option<Frame> {
+    let directory_entry = kernel_buffer_directory_entry(address).ok()?;
+
+    if !directory_entry.flags().contains(L2Flags::PRESENT) {
+        return None;
+    }
+
+    let entry = &mut level1_table(directory_entry)[(address / PAGE_SIZE) % 512];
+    if !entry.flags().contains(L1Flags::PRESENT) {
+        return None;
+    }
+
+    let frame = Frame::containing_address(entry.address() as usize);
+    *entry = L1PageTableEntry::zero();
+    unsafe {
+        x86::tlb::flush(address);
+    }
+
+    Some(frame)
+}
+
+/// Flags of a shared page. Writable pages become copy-on-write pages.
+fn copy_on_write_flags(mut flags: L1Flags) -> L1Flags {
+    if flags.contains(L1Flags::READ_WRITE) {
+        flags.remove(L1Flags::READ_WRITE);
+        flags.insert(L1Flags::COPY_ON_WRITE);
+    }
+    flags
+}
+
+/// Level 1 table which a present level 2 entry points to. Page tables
+/// are accessed using the identity mapping.
+fn level1_table(entry: &L2PageTableEntry) -> &'static mut [L1PageTableEntry; 512] {
+    unsafe {
+        &mut *(entry.address() as usize as *mut [L1PageTableEntry; 512])
+    }
+}
+
 // Availible to software BITS 9-11
 
 use core::marker::PhantomData;
@@ -125,7 +630,7 @@ pub trait EntryFlags: Sized + Copy + Clone + core::ops::Not<Output=Self> + core:
 }
 
 impl <F: EntryFlags, A: PhysicalAddressHandler> GenericPageTableEntry<F, A> {
-    fn new(address: u64, flags: F) -> Self {
+    pub fn new(address: u64, flags: F) -> Self {
         let entry = A::to_entry_format(&address) | flags.bits();
         GenericPageTableEntry(entry, PhantomData, PhantomData)
     }
@@ -226,6 +731,12 @@ bitflags! {
         const DIRTY = 1 << 6;
         const PAGE_ATTRIBUTE_TABLE = 1 << 7;
         const GLOBAL_PAGE = 1 << 8;
+        /// Software bit. Page is shared and read-only until a write
+        /// fault copies it.
+        const COPY_ON_WRITE = 1 << 9;
+        /// Software bit. Page is not present and the address bits
+        /// contain a swap slot.
+        const SWAPPED = 1 << 10;
         const NO_EXECUTE = 1 << 63;
     }
 }
@@ -236,11 +747,14 @@ impl EntryFlags for L1Flags {
     fn from_bits_truncate(value: u64) -> Self { <Self>::from_bits_truncate(value)}
 }
 
-const PHYSICAL_ADDRESS_MASK_STR: &str = "(u64::max_value() << 12) >> 12";
-const PHYSICAL_ADDRESS_MASK: u64 = (u64::max_value() << 12) >> 12;
+// Physical address bits 12-51. Flags and software bits are not part of
+// the address.
+const PHYSICAL_ADDRESS_MASK_STR: &str = "((u64::max_value() << 12) >> 12) & !0xFFF";
+const PHYSICAL_ADDRESS_MASK: u64 = ((u64::max_value() << 12) >> 12) & !0xFFF;
 
-const PHYSICAL_ADDRESS_MASK_2MB_PDE_STR: &str = "(u64::max_value() << 21) >> 12";
-const PHYSICAL_ADDRESS_MASK_2MB_PDE: u64 = (u64::max_value() << 21) >> 12;
+// Physical address bits 21-51.
+const PHYSICAL_ADDRESS_MASK_2MB_PDE_STR: &str = "((u64::max_value() << 12) >> 12) & !0x1F_FFFF";
+const PHYSICAL_ADDRESS_MASK_2MB_PDE: u64 = ((u64::max_value() << 12) >> 12) & !0x1F_FFFF;
 
 #[derive(Copy, Clone)]
 pub struct PhysicalAddressHandlerNormal;
@@ -0,0 +1,315 @@
+//! Kernel pipes.
+//!
+//! A pipe is a one frame byte buffer with a read end and a write end.
+//! Reads block while the pipe is empty and writes block while it is
+//! full. Reading an empty pipe returns 0 (end of file) when all write
+//! ends are closed, and writing fails with `FsError::BrokenPipe` when
+//! all read ends are closed.
+//!
+//! Pipes are inodes of `PipeFs`, an internal filesystem which isn't in
+//! the directory tree, so pipe ends are ordinary file descriptors for
+//! shell commands and processes. The read end is a file descriptor with
+//! `OpenFlags::READ` and the write end one with `OpenFlags::WRITE`. The
+//! pipe is freed when the last file descriptor is closed.
+
+use core::cmp::min;
+
+use crate::frame_allocator::{self, Frame, FRAME_SIZE};
+use crate::process;
+use crate::sync::{IrqSpinlock, WaitQueue};
+use crate::vfs::{self, Context, DirEntry, FileDescriptor, FileSystem, FileType, FsError, InodeNumber, Metadata, OpenFlags, VNode};
+
+pub const MAX_PIPES: usize = 16;
+pub const PIPE_BUFFER_SIZE: usize = FRAME_SIZE;
+
+const ROOT_INODE: InodeNumber = 1;
+const PIPE_INODE_START: InodeNumber = 2;
+
+struct Pipe {
+    buffer: Frame,
+    start: usize,
+    length: usize,
+    readers: usize,
+    writers: usize,
+}
+
+impl Pipe {
+    fn data(&mut self) -> &mut [u8; PIPE_BUFFER_SIZE] {
+        unsafe { &mut *(self.buffer.start_address() as *mut [u8; PIPE_BUFFER_SIZE]) }
+    }
+
+    /// Move buffered bytes to `buffer`.
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let count = min(buffer.len(), self.length);
+        for (i, byte) in buffer[..count].iter_mut().enumerate() {
+            let index = (self.start + i) % PIPE_BUFFER_SIZE;
+            *byte = self.data()[index];
+        }
+
+        self.start = (self.start + count) % PIPE_BUFFER_SIZE;
+        self.length -= count;
+        count
+    }
+
+    /// Buffer as many bytes of `data` as there is space for.
+    fn write(&mut self, data: &[u8]) -> usize {
+        let count = min(data.len(), PIPE_BUFFER_SIZE - self.length);
+        let end = self.start + self.length;
+        for (i, &byte) in data[..count].iter().enumerate() {
+            self.data()[(end + i) % PIPE_BUFFER_SIZE] = byte;
+        }
+
+        self.length += count;
+        count
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        frame_allocator::free_frame(self.buffer);
+    }
+}
+
+static PIPES: IrqSpinlock<Option<[Option<Pipe>; MAX_PIPES]>> = IrqSpinlock::new("pipes", None);
+
+/// Readers and writers which wait for a pipe to change.
+static PIPE_CHANGED: WaitQueue = WaitQueue::new("pipe changed");
+
+/// Wake up all readers and writers, so they check if their process has
+/// been killed.
+pub fn wake_waiters() {
+    PIPE_CHANGED.wake_all();
+}
+
+/// Mount index of `PipeFs`.
+static mut PIPE_MOUNT: Option<usize> = None;
+
+static mut PIPE_FS: PipeFs = PipeFs;
+
+/// Add `PipeFs` to the VFS.
+pub fn init() -> Result<(), FsError> {
+    let mount = vfs::vfs().mount_internal(unsafe { &mut PIPE_FS })?;
+    unsafe {
+        PIPE_MOUNT = Some(mount);
+    }
+    Ok(())
+}
+
+fn with_pipe<T>(inode: InodeNumber, function: impl FnOnce(&mut Pipe) -> T) -> Result<T, FsError> {
+    let mut pipes = PIPES.lock();
+    let pipes = pipes.get_or_insert_with(Default::default);
+
+    inode.checked_sub(PIPE_INODE_START)
+        .and_then(|index| pipes.get_mut(index as usize))
+        .and_then(|pipe| pipe.as_mut())
+        .map(function)
+        .ok_or(FsError::NotFound)
+}
+
+/// Create a pipe and open its ends in `ctx`. Returns file descriptors
+/// of the read end and the write end.
+pub fn create(ctx: &mut Context) -> Result<(FileDescriptor, FileDescriptor), FsError> {
+    let mount = unsafe { PIPE_MOUNT }.ok_or(FsError::NotSupported)?;
+    let buffer = frame_allocator::allocate_frame().ok_or(FsError::NoSpace)?;
+
+    let inode = {
+        let mut pipes = PIPES.lock();
+        let pipes = pipes.get_or_insert_with(Default::default);
+
+        match pipes.iter().position(|pipe| pipe.is_none()) {
+            Some(index) => {
+                pipes[index] = Some(Pipe {
+                    buffer,
+                    start: 0,
+                    length: 0,
+                    readers: 0,
+                    writers: 0,
+                });
+                index as InodeNumber + PIPE_INODE_START
+            }
+            None => {
+                frame_allocator::free_frame(buffer);
+                return Err(FsError::TooManyOpenFiles);
+            }
+        }
+    };
+
+    let vfs = vfs::vfs();
+    let vnode = VNode { mount, inode };
+
+    let read_fd = match vfs.open_vnode(ctx, vnode, OpenFlags::READ) {
+        Ok(fd) => fd,
+        Err(e) => {
+            free_unused(inode);
+            return Err(e);
+        }
+    };
+
+    match vfs.open_vnode(ctx, vnode, OpenFlags::WRITE) {
+        Ok(write_fd) => Ok((read_fd, write_fd)),
+        Err(e) => {
+            // Closing the only end frees the pipe.
+            let _ = vfs.close(ctx, read_fd);
+            Err(e)
+        }
+    }
+}
+
+/// Free the pipe if it doesn't have any open ends.
+fn free_unused(inode: InodeNumber) {
+    let mut pipes = PIPES.lock();
+    let pipes = pipes.get_or_insert_with(Default::default);
+
+    if let Some(slot) = inode.checked_sub(PIPE_INODE_START).and_then(|index| pipes.get_mut(index as usize)) {
+        let unused = slot.as_ref().map(|pipe| pipe.readers == 0 && pipe.writers == 0).unwrap_or(false);
+        if unused {
+            *slot = None;
+        }
+    }
+}
+
+/// Filesystem which contains all pipes.
+pub struct PipeFs;
+
+impl FileSystem for PipeFs {
+    fn name(&self) -> &'static str {
+        "pipefs"
+    }
+
+    /// Pipe data is behind `PIPES`, and readers wait for writers.
+    fn locks_internally(&self) -> bool {
+        true
+    }
+
+    fn root(&self) -> InodeNumber {
+        ROOT_INODE
+    }
+
+    fn lookup(&mut self, _directory: InodeNumber, _name: &str) -> Result<InodeNumber, FsError> {
+        Err(FsError::NotFound)
+    }
+
+    fn metadata(&mut self, inode: InodeNumber) -> Result<Metadata, FsError> {
+        let (file_type, mode, size) = if inode == ROOT_INODE {
+            (FileType::Directory, 0o555, 0)
+        } else {
+            (FileType::Pipe, 0o600, with_pipe(inode, |pipe| pipe.length as u64)?)
+        };
+
+        Ok(Metadata {
+            inode,
+            file_type,
+            size,
+            mode,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            modified: 0,
+        })
+    }
+
+    /// Wait until the pipe has data or all write ends are closed.
+    fn read(&mut self, inode: InodeNumber, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let mut result = Ok(0);
+
+        PIPE_CHANGED.wait_until(|| {
+            if process::kill_pending() {
+                result = Err(FsError::Interrupted);
+                return true;
+            }
+
+            let done = with_pipe(inode, |pipe| {
+                if pipe.length > 0 {
+                    result = Ok(pipe.read(buffer));
+                    true
+                } else {
+                    pipe.writers == 0
+                }
+            });
+
+            done.unwrap_or_else(|e| {
+                result = Err(e);
+                true
+            })
+        });
+
+        PIPE_CHANGED.wake_all();
+        result
+    }
+
+    /// Wait until all of `data` is in the pipe or all read ends are
+    /// closed.
+    fn write(&mut self, inode: InodeNumber, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut count = 0;
+        let mut result = Ok(());
+
+        PIPE_CHANGED.wait_until(|| {
+            if process::kill_pending() {
+                result = Err(FsError::Interrupted);
+                return true;
+            }
+
+            let done = with_pipe(inode, |pipe| {
+                if pipe.readers == 0 {
+                    return Err(FsError::BrokenPipe);
+                }
+
+                let written = pipe.write(&data[count..]);
+                if written > 0 {
+                    count += written;
+                    PIPE_CHANGED.wake_all();
+                }
+
+                Ok(count == data.len())
+            });
+
+            match done.and_then(|done| done) {
+                Ok(done) => done,
+                Err(e) => {
+                    result = Err(e);
+                    true
+                }
+            }
+        });
+
+        match result {
+            Err(e) if count == 0 => Err(e),
+            _ => Ok(count),
+        }
+    }
+
+    fn read_dir(&mut self, _directory: InodeNumber, _index: usize) -> Result<Option<DirEntry>, FsError> {
+        Ok(None)
+    }
+
+    fn open(&mut self, inode: InodeNumber, flags: OpenFlags) {
+        let _ = with_pipe(inode, |pipe| {
+            if flags.contains(OpenFlags::READ) {
+                pipe.readers += 1;
+            }
+            if flags.contains(OpenFlags::WRITE) {
+                pipe.writers += 1;
+            }
+        });
+    }
+
+    /// Wake up the other end, which might be waiting for end of file
+    /// or a broken pipe.
+    fn close(&mut self, inode: InodeNumber, flags: OpenFlags) {
+        let _ = with_pipe(inode, |pipe| {
+            if flags.contains(OpenFlags::READ) {
+                pipe.readers -= 1;
+            }
+            if flags.contains(OpenFlags::WRITE) {
+                pipe.writers -= 1;
+            }
+        });
+
+        free_unused(inode);
+        PIPE_CHANGED.wake_all();
+    }
+}
@@ -0,0 +1,574 @@
+//! Processes.
+//!
+//! Every process runs in its own kernel thread, which enters user mode
+//! and returns when the process exits. Process table contains process
+//! IDs, parent processes and exit reasons of zombie processes. Address
+//! space, open files and working directory are in a `Task`, which only
+//! the thread of the process accesses.
+//!
+//! Processes which the kernel starts have parent PID 0. When a process
+//! exits, its running children become orphans, which are removed from
+//! the process table when they exit.
+
+use core::fmt::Write;
+
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::elf::{self, ElfError};
+use crate::page_table::{self, AddressSpace, MapError};
+use crate::pipe;
+use crate::scheduler::{self, Priority, ThreadError, ThreadId, MAX_THREADS};
+use crate::shell::Io;
+use crate::shell_command::{self, Builtin, CommandError, RegistryError};
+use crate::shell_completion::{ArgumentCompletion, Completions};
+use crate::shell_parser::Arguments;
+use crate::sync::{self, IrqSpinlock, WaitQueue};
+use crate::syscall::SyscallFrame;
+use crate::usermode::{self, ExitReason, KernelStack, UserRegisters};
+use crate::vfs::{self, Context, OpenFlags};
+use crate::vma;
+
+pub const MAX_PROCESSES: usize = 32;
+
+pub type Pid = usize;
+pub type ProcessName = ArrayString<[u8; 32]>;
+
+#[derive(Debug)]
+pub enum ProcessError {
+    TooManyProcesses,
+    OutOfMemory,
+    Map(MapError),
+    Thread(ThreadError),
+    NoSuchProcess,
+    NoChildren,
+    /// Current thread doesn't belong to a process.
+    NotProcess,
+    /// Process was killed while it waited.
+    Interrupted,
+}
+
+impl From<MapError> for ProcessError {
+    fn from(error: MapError) -> Self {
+        ProcessError::Map(error)
+    }
+}
+
+impl From<ThreadError> for ProcessError {
+    fn from(error: ThreadError) -> Self {
+        ProcessError::Thread(error)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum ProcessState {
+    Running,
+    /// Process has exited, but its parent hasn't waited for it yet.
+    Zombie(ExitReason),
+}
+
+impl ProcessState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcessState::Running => "running",
+            ProcessState::Zombie(_) => "zombie",
+        }
+    }
+}
+
+/// Process table entry.
+#[derive(Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: ProcessName,
+    pub state: ProcessState,
+    /// Thread which runs the process. Zombies don't have a thread.
+    pub thread: Option<ThreadId>,
+    /// Nobody waits for an orphan.
+    orphan: bool,
+    kill_requested: bool,
+}
+
+struct ProcessTable {
+    processes: [Option<ProcessInfo>; MAX_PROCESSES],
+    next_pid: Pid,
+}
+
+impl ProcessTable {
+    fn new() -> Self {
+        Self {
+            processes: Default::default(),
+            next_pid: 1,
+        }
+    }
+
+    fn get_mut(&mut self, pid: Pid) -> Option<&mut ProcessInfo> {
+        self.processes.iter_mut()
+            .filter_map(|process| process.as_mut())
+            .find(|process| process.pid == pid)
+    }
+
+    fn find_thread(&self, thread: ThreadId) -> Option<&ProcessInfo> {
+        self.processes.iter()
+            .filter_map(|process| process.as_ref())
+            .find(|process| process.thread == Some(thread))
+    }
+
+    fn insert(&mut self, parent: Pid, name: &str) -> Result<Pid, ProcessError> {
+        let slot = self.processes.iter_mut()
+            .find(|process| process.is_none())
+            .ok_or(ProcessError::TooManyProcesses)?;
+
+        let pid = self.next_pid;
+        self.next_pid += 1;
+
+        *slot = Some(ProcessInfo {
+            pid,
+            parent,
+            name: process_name(name),
+            state: ProcessState::Running,
+            thread: None,
+            orphan: false,
+            kill_requested: false,
+        });
+
+        Ok(pid)
+    }
+
+    fn remove(&mut self, pid: Pid) {
+        for slot in self.processes.iter_mut() {
+            if slot.as_ref().map(|process| process.pid) == Some(pid) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+static PROCESS_TABLE: IrqSpinlock<Option<ProcessTable>> = IrqSpinlock::new("process table", None);
+
+/// Parents which wait for their children to exit.
+static CHILD_EXITED: WaitQueue = WaitQueue::new("child exited");
+
+fn with_table<T>(function: impl FnOnce(&mut ProcessTable) -> T) -> T {
+    let mut table = PROCESS_TABLE.lock();
+    function(table.get_or_insert_with(ProcessTable::new))
+}
+
+/// Resources of a process.
+pub struct Task {
+    pub pid: Pid,
+    /// Working directory and open files.
+    pub context: Context,
+    pub address_space: AddressSpace,
+    /// Stack for interrupts and system calls from user mode.
+    interrupt_stack: KernelStack,
+    registers: UserRegisters,
+}
+
+/// Tasks indexed by thread ID. Only the thread of the task accesses the
+/// task after the thread is started.
+static mut TASKS: Option<[Option<Task>; MAX_THREADS]> = None;
+
+fn tasks() -> &'static mut [Option<Task>; MAX_THREADS] {
+    unsafe { TASKS.get_or_insert_with(Default::default) }
+}
+
+/// Process ID and address space of the process which `thread` runs.
+/// Swap uses this to evict pages of all processes, so it must be called
+/// with interrupts disabled.
+pub fn address_space(thread: ThreadId) -> Option<(Pid, &'static mut AddressSpace)> {
+    unsafe { TASKS.as_mut() }
+        .and_then(|tasks| tasks.get_mut(thread))
+        .and_then(|task| task.as_mut())
+        .map(|task| (task.pid, &mut task.address_space))
+}
+
+/// Task of the process which the current thread runs.
+pub fn current_task() -> Option<&'static mut Task> {
+    unsafe { TASKS.as_mut() }.and_then(|tasks| tasks[scheduler::current_thread_id()].as_mut())
+}
+
+fn process_name(name: &str) -> ProcessName {
+    let mut process_name = ProcessName::new();
+    for c in name.chars() {
+        if process_name.try_push(c).is_err() {
+            break;
+        }
+    }
+    process_name
+}
+
+/// Start a new process in a new thread. Files of `context` are closed
+/// if the process can't be started.
+fn start_process(parent: Pid, name: &str, mut context: Context, address_space: AddressSpace, registers: UserRegisters) -> Result<Pid, ProcessError> {
+    let interrupt_stack = match KernelStack::new() {
+        Some(stack) => stack,
+        None => {
+            vfs::vfs().close_all(&mut context);
+            return Err(ProcessError::OutOfMemory);
+        }
+    };
+
+    // New thread must not run before its task is stored.
+    let interrupts = sync::disable_interrupts();
+
+    let result = with_table(|table| table.insert(parent, name)).and_then(|pid| {
+        match scheduler::spawn("process", Priority::Normal, process_thread, 0) {
+            Ok(thread) => {
+                scheduler::detach(thread)?;
+                Ok((pid, thread))
+            }
+            Err(e) => {
+                with_table(|table| table.remove(pid));
+                Err(e.into())
+            }
+        }
+    });
+
+    let result = match result {
+        Ok((pid, thread)) => {
+            tasks()[thread] = Some(Task {
+                pid,
+                context,
+                address_space,
+                interrupt_stack,
+                registers,
+            });
+
+            with_table(|table| {
+                if let Some(process) = table.get_mut(pid) {
+                    process.thread = Some(thread);
+                }
+            });
+
+            Ok(pid)
+        }
+        Err(e) => {
+            vfs::vfs().close_all(&mut context);
+            Err(e)
+        }
+    };
+
+    sync::restore_interrupts(interrupts);
+    result
+}
+
+fn process_thread(_argument: usize) -> usize {
+    let registers = {
+        let task = current_task().expect("process thread without a task");
+        task.address_space.activate();
+        scheduler::set_user_interrupt_stack(task.interrupt_stack.top());
+        task.registers
+    };
+
+    let reason = usermode::enter(&registers);
+
+    scheduler::set_user_interrupt_stack(0);
+    page_table::activate_kernel_page_table();
+
+    // Address space, open files and the interrupt stack are freed here.
+    let interrupts = sync::disable_interrupts();
+    let mut task = tasks()[scheduler::current_thread_id()].take().expect("process task is missing");
+    sync::restore_interrupts(interrupts);
+    let pid = task.pid;
+    vfs::vfs().close_all(&mut task.context);
+    drop(task);
+
+    exit_process(pid, reason);
+
+    0
+}
+
+/// Make the process a zombie or remove it if it is an orphan.
+fn exit_process(pid: Pid, reason: ExitReason) {
+    with_table(|table| {
+        for slot in table.processes.iter_mut() {
+            let remove = match slot {
+                Some(child) if child.parent == pid => match child.state {
+                    ProcessState::Zombie(_) => true,
+                    ProcessState::Running => {
+                        child.parent = 0;
+                        child.orphan = true;
+                        false
+                    }
+                },
+                _ => false,
+            };
+
+            if remove {
+                *slot = None;
+            }
+        }
+
+        let remove = match table.get_mut(pid) {
+            Some(process) => {
+                process.thread = None;
+                process.state = ProcessState::Zombie(reason);
+                process.orphan
+            }
+            None => false,
+        };
+
+        if remove {
+            table.remove(pid);
+        }
+    });
+
+    CHILD_EXITED.wake_all();
+}
+
+/// Start a process from the kernel. The process gets the working
+/// directory and the open files of `context`.
+pub fn spawn(name: &str, address_space: AddressSpace, registers: &UserRegisters, context: Context) -> Result<Pid, ProcessError> {
+    start_process(0, name, context, address_space, *registers)
+}
+
+/// Start a process from the kernel and wait until it exits.
+pub fn run(name: &str, address_space: AddressSpace, registers: &UserRegisters, context: Context) -> Result<ExitReason, ProcessError> {
+    let pid = spawn(name, address_space, registers, context)?;
+    wait(0, Some(pid)).map(|(_, reason)| reason)
+}
+
+/// Context with file descriptors 0, 1 and 2 opened to `/dev/console`.
+pub fn console_context() -> Context {
+    let mut context = Context::new();
+    for _ in 0..3 {
+        let _ = vfs::vfs().open(&mut context, "/dev/console", OpenFlags::READ | OpenFlags::WRITE);
+    }
+    context
+}
+
+/// Copy the current process. Memory is shared with copy-on-write
+/// pages. The child returns from the system call with return value 0.
+pub fn fork(frame: &SyscallFrame) -> Result<Pid, ProcessError> {
+    let task = current_task().ok_or(ProcessError::NotProcess)?;
+    let interrupts = sync::disable_interrupts();
+    let address_space = task.address_space.clone_copy_on_write();
+    sync::restore_interrupts(interrupts);
+    let address_space = address_space?;
+
+    let registers = UserRegisters {
+        eax: 0,
+        ebx: frame.ebx,
+        ecx: frame.ecx,
+        edx: frame.edx,
+        esi: frame.esi,
+        edi: frame.edi,
+        ebp: frame.ebp,
+        eip: frame.eip,
+        esp: frame.esp,
+    };
+
+    let pid = task.pid;
+    let name = with_table(|table| table.get_mut(pid).map(|process| process.name))
+        .ok_or(ProcessError::NoSuchProcess)?;
+
+    start_process(pid, &name, vfs::vfs().clone_context(&task.context), address_space, registers)
+}
+
+/// Replace the program of the current process. System call returns to
+/// the entry point of the new program.
+pub fn execve(path: &str, arguments: &[&str], environment: &[&str], frame: &mut SyscallFrame) -> Result<(), ElfError> {
+    let task = current_task().ok_or(ElfError::Process(ProcessError::NotProcess))?;
+    let executable = elf::load(&mut task.context, path, arguments, environment)?;
+
+    // Old address space is freed when it is replaced.
+    executable.address_space.activate();
+    let interrupts = sync::disable_interrupts();
+    task.address_space = executable.address_space;
+    sync::restore_interrupts(interrupts);
+
+    let pid = task.pid;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    with_table(|table| {
+        if let Some(process) = table.get_mut(pid) {
+            process.name = process_name(name);
+        }
+    });
+
+    *frame = SyscallFrame {
+        eip: executable.entry as u32,
+        esp: executable.stack_pointer as u32,
+        ..SyscallFrame::default()
+    };
+
+    Ok(())
+}
+
+/// Wait until a child of `parent` exits and remove it from the process
+/// table. If `pid` is `None`, any child is accepted.
+pub fn wait(parent: Pid, pid: Option<Pid>) -> Result<(Pid, ExitReason), ProcessError> {
+    let mut result = Err(ProcessError::NoChildren);
+
+    CHILD_EXITED.wait_until(|| match try_wait(parent, pid) {
+        _ if kill_pending() => {
+            result = Err(ProcessError::Interrupted);
+            true
+        }
+        Ok(Some(child)) => {
+            result = Ok(child);
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            result = Err(e);
+            true
+        }
+    });
+
+    result
+}
+
+/// Like `wait`, but returns `None` instead of waiting if the child is
+/// still running.
+pub fn try_wait(parent: Pid, pid: Option<Pid>) -> Result<Option<(Pid, ExitReason)>, ProcessError> {
+    with_table(|table| {
+        let mut child_found = false;
+
+        for slot in table.processes.iter_mut() {
+            let (child_pid, state) = match slot {
+                Some(process) if process.parent == parent && !process.orphan && pid.map(|pid| pid == process.pid).unwrap_or(true) => {
+                    (process.pid, process.state)
+                }
+                _ => continue,
+            };
+
+            child_found = true;
+
+            if let ProcessState::Zombie(reason) = state {
+                *slot = None;
+                return Ok(Some((child_pid, reason)));
+            }
+        }
+
+        if child_found {
+            Ok(None)
+        } else {
+            Err(ProcessError::NoChildren)
+        }
+    })
+}
+
+/// Request process `pid` to stop. Process stops when it returns to
+/// user mode. Waiting system calls are woken up, and they return
+/// `EINTR` when they see the request.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let thread = with_table(|table| -> Result<_, ProcessError> {
+        let process = table.get_mut(pid).ok_or(ProcessError::NoSuchProcess)?;
+        if let ProcessState::Running = process.state {
+            process.kill_requested = true;
+        }
+        Ok(process.thread)
+    })?;
+
+    CHILD_EXITED.wake_all();
+    pipe::wake_waiters();
+    if let Some(thread) = thread {
+        scheduler::wake_sleeping(thread);
+    }
+
+    Ok(())
+}
+
+/// True if the process of the current thread has been killed. Waiting
+/// in the kernel stops when this becomes true.
+pub fn kill_pending() -> bool {
+    let thread = scheduler::current_thread_id();
+    with_table(|table| {
+        table.find_thread(thread).map(|process| process.kill_requested).unwrap_or(false)
+    })
+}
+
+/// Stop the current process if it has been killed. Called before
+/// returning to user mode.
+pub fn handle_pending_kill() {
+    if kill_pending() {
+        usermode::exit_current_task(ExitReason::Killed);
+    }
+}
+
+/// Handle a page fault of the current process. Returns false if the
+/// fault is an error.
+pub fn handle_page_fault(address: usize, error_code: u32) -> bool {
+    const WRITE: u32 = 1 << 1;
+
+    resolve_page_fault(address, error_code & WRITE != 0)
+}
+
+/// Map the page containing `address` or copy it if it is a
+/// copy-on-write page, if the memory map of the current process allows
+/// the access. Pages of kernel buffers are mapped too.
+pub fn resolve_page_fault(address: usize, write: bool) -> bool {
+    if vma::is_kernel_buffer_address(address) {
+        return vma::handle_kernel_page_fault(address);
+    }
+
+    match current_task() {
+        Some(task) => vma::handle_page_fault(&mut task.address_space, address, write),
+        None => false,
+    }
+}
+
+/// Parent of the current process.
+pub fn current_parent() -> Option<Pid> {
+    let thread = scheduler::current_thread_id();
+    with_table(|table| table.find_thread(thread).map(|process| process.parent))
+}
+
+pub fn processes() -> ArrayVec<[ProcessInfo; MAX_PROCESSES]> {
+    with_table(|table| {
+        table.processes.iter()
+            .filter_map(|process| process.clone())
+            .collect()
+    })
+}
+
+static COMMANDS: [Builtin; 2] = [
+    Builtin {
+        name: "ps",
+        usage: "",
+        help: "List processes",
+        run: ps,
+        complete: ArgumentCompletion::None,
+    },
+    Builtin {
+        name: "kill",
+        usage: "<pid>",
+        help: "Stop a process when it next returns to user mode",
+        run: kill_command,
+        complete: ArgumentCompletion::Function(complete_pids),
+    },
+];
+
+pub fn register_commands() -> Result<(), RegistryError> {
+    shell_command::register_builtins(&COMMANDS)
+}
+
+fn ps(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    args.end()?;
+
+    let _ = writeln!(io, "  PID  PPID STATE    NAME");
+    for process in processes() {
+        let _ = writeln!(io, "{:>5} {:>5} {:<8} {}", process.pid, process.parent, process.state.name(), process.name);
+    }
+
+    Ok(())
+}
+
+fn complete_pids(argument: usize, completions: &mut Completions) {
+    if argument != 0 {
+        return;
+    }
+
+    for process in processes() {
+        let mut pid = ArrayString::<[u8; 10]>::new();
+        let _ = write!(pid, "{}", process.pid);
+        completions.add(&pid, false);
+    }
+}
+
+fn kill_command(mut args: Arguments, _io: &mut Io) -> Result<(), CommandError> {
+    let pid = args.number()?;
+    args.end()?;
+    Ok(kill(pid)?)
+}
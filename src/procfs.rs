@@ -0,0 +1,415 @@
+//! Process information filesystem, usually mounted at `/proc`.
+//!
+//! File contents are generated when the file is read, so files don't
+//! have a size and reading always returns current values.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::idt::{self, IDTHandler, Exception, HardwareInterrupt};
+use crate::scheduler::{self, ThreadInfo};
+use crate::vfs::{self, FileSystem, FileSystemType, InodeNumber, Metadata, DirEntry, FileType, FsError, FileName};
+
+const ROOT_INODE: InodeNumber = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ProcFile {
+    Uptime,
+    Interrupts,
+    MemInfo,
+    CpuInfo,
+    CommandLine,
+    Mounts,
+    BootInfo,
+    Swaps,
+}
+
+const FILES: [(&str, ProcFile); 8] = [
+    ("uptime", ProcFile::Uptime),
+    ("interrupts", ProcFile::Interrupts),
+    ("meminfo", ProcFile::MemInfo),
+    ("cpuinfo", ProcFile::CpuInfo),
+    ("cmdline", ProcFile::CommandLine),
+    ("mounts", ProcFile::Mounts),
+    ("bootinfo", ProcFile::BootInfo),
+    ("swaps", ProcFile::Swaps),
+];
+
+/// Files in the global file list have inode numbers starting from this.
+const FILE_INODE_START: InodeNumber = 2;
+
+/// Task directory inode is `TASK_INODE_START + task_id * TASK_INODE_STRIDE`
+/// and the files inside it follow the directory inode.
+const TASK_INODE_START: InodeNumber = 0x1_0000;
+const TASK_INODE_STRIDE: InodeNumber = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TaskFile {
+    Status,
+}
+
+const TASK_FILES: [(&str, TaskFile); 1] = [
+    ("status", TaskFile::Status),
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Node {
+    Root,
+    File(ProcFile),
+    TaskDirectory(usize),
+    TaskFile(usize, TaskFile),
+}
+
+impl Node {
+    fn from_inode(inode: InodeNumber) -> Result<Self, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(Node::Root);
+        }
+
+        if inode >= FILE_INODE_START && inode < FILE_INODE_START + FILES.len() as InodeNumber {
+            return Ok(Node::File(FILES[(inode - FILE_INODE_START) as usize].1));
+        }
+
+        if inode >= TASK_INODE_START {
+            let task_id = ((inode - TASK_INODE_START) / TASK_INODE_STRIDE) as usize;
+            let file_index = ((inode - TASK_INODE_START) % TASK_INODE_STRIDE) as usize;
+
+            if tasks().any(|task| task.id == task_id) {
+                return match file_index {
+                    0 => Ok(Node::TaskDirectory(task_id)),
+                    i if i <= TASK_FILES.len() => Ok(Node::TaskFile(task_id, TASK_FILES[i - 1].1)),
+                    _ => Err(FsError::NotFound),
+                };
+            }
+        }
+
+        Err(FsError::NotFound)
+    }
+}
+
+fn task_directory_inode(task_id: usize) -> InodeNumber {
+    TASK_INODE_START + task_id as InodeNumber * TASK_INODE_STRIDE
+}
+
+/// Every kernel thread has a task directory.
+fn tasks() -> impl Iterator<Item=ThreadInfo> {
+    scheduler::threads().into_iter()
+}
+
+static BOOT_INFO_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set Multiboot2 boot information address. Frame allocator keeps the
+/// boot information memory reserved.
+pub fn init(boot_info_address: usize) {
+    BOOT_INFO_ADDRESS.store(boot_info_address, Ordering::SeqCst);
+}
+
+fn boot_info() -> Option<multiboot2::BootInformation> {
+    match BOOT_INFO_ADDRESS.load(Ordering::SeqCst) {
+        0 => None,
+        address => Some(unsafe { multiboot2::load(address) }),
+    }
+}
+
+pub const PROCFS_TYPE: FileSystemType = FileSystemType {
+    name: "proc",
+    mount: mount_procfs,
+};
+
+/// Procfs doesn't have any state, so all mounts use the same instance.
+static mut PROCFS: ProcFs = ProcFs;
+
+fn mount_procfs(_source: &str) -> Result<&'static mut dyn FileSystem, FsError> {
+    unsafe {
+        Ok(&mut PROCFS)
+    }
+}
+
+/// Writes only bytes which are inside the read window to the buffer.
+struct ReadWindow<'a> {
+    offset: u64,
+    buffer: &'a mut [u8],
+    position: u64,
+    written: usize,
+}
+
+impl Write for ReadWindow<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let bytes = text.as_bytes();
+        let start = self.position;
+        self.position += bytes.len() as u64;
+
+        if self.position <= self.offset || self.written == self.buffer.len() {
+            return Ok(());
+        }
+
+        let skip = self.offset.saturating_sub(start) as usize;
+        let count = core::cmp::min(bytes.len() - skip, self.buffer.len() - self.written);
+        self.buffer[self.written..self.written + count].copy_from_slice(&bytes[skip..skip + count]);
+        self.written += count;
+
+        Ok(())
+    }
+}
+
+pub struct ProcFs;
+
+impl ProcFs {
+    fn write_file(&self, out: &mut impl Write, file: ProcFile) -> fmt::Result {
+        match file {
+            ProcFile::Uptime => {
+                let time = idt::time_in_milliseconds();
+                writeln!(out, "{}.{:03}", time / 1000, time % 1000)
+            }
+            ProcFile::Interrupts => write_interrupts(out),
+            ProcFile::MemInfo => {
+                let kib = |frames: usize| frames * crate::frame_allocator::FRAME_SIZE / 1024;
+                writeln!(out, "MemTotal: {} KiB", kib(crate::frame_allocator::total_frames()))?;
+                writeln!(out, "MemFree: {} KiB", kib(crate::frame_allocator::free_frames()))?;
+                let swap = crate::swap::statistics();
+                writeln!(out, "SwapTotal: {} KiB", kib(swap.total_slots))?;
+                writeln!(out, "SwapFree: {} KiB", kib(swap.total_slots - swap.used_slots))
+            }
+            ProcFile::CpuInfo => write_cpu_info(out),
+            ProcFile::CommandLine => {
+                let boot_info = boot_info();
+                let command_line = boot_info.as_ref()
+                    .and_then(|info| info.command_line_tag())
+                    .map(|tag| tag.command_line())
+                    .unwrap_or("");
+                writeln!(out, "{}", command_line)
+            }
+            ProcFile::Mounts => {
+                for mount in vfs::vfs().mounts() {
+                    writeln!(out, "{} {} {}", mount.source, mount.path, mount.file_system_type())?;
+                }
+                Ok(())
+            }
+            ProcFile::BootInfo => write_boot_info(out),
+            ProcFile::Swaps => {
+                let swap = crate::swap::statistics();
+                writeln!(out, "Filename Size Used PagesIn PagesOut")?;
+                if let Some(name) = crate::swap::swap_name() {
+                    writeln!(out, "{} {} {} {} {}", name, swap.total_slots, swap.used_slots, swap.pages_in, swap.pages_out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn write_task_file(&self, out: &mut impl Write, task_id: usize, file: TaskFile) -> fmt::Result {
+        let task = match tasks().find(|task| task.id == task_id) {
+            Some(task) => task,
+            None => return Ok(()),
+        };
+
+        match file {
+            TaskFile::Status => {
+                writeln!(out, "Name: {}", task.name)?;
+                writeln!(out, "Id: {}", task.id)?;
+                writeln!(out, "State: {}", task.state.name())?;
+                writeln!(out, "Priority: {:?}", task.priority)
+            }
+        }
+    }
+}
+
+fn write_interrupts(out: &mut impl Write) -> fmt::Result {
+    for vector in 0..=255u8 {
+        let count = idt::interrupt_count(vector);
+        if count == 0 {
+            continue;
+        }
+
+        write!(out, "{:3}: {:10} ", vector, count)?;
+
+        if let Ok(exception) = Exception::from_interrupt_number(vector) {
+            writeln!(out, "{:?}", exception)?;
+        } else if let Ok(interrupt) = HardwareInterrupt::from_interrupt_number(vector) {
+            writeln!(out, "{:?}", interrupt)?;
+        } else {
+            writeln!(out)?;
+        }
+    }
+
+    writeln!(out, "Master PIC spurious: {}", IDTHandler::master_pic_spurious_interrupts_count())?;
+    writeln!(out, "Slave PIC spurious: {}", IDTHandler::slave_pic_spurious_interrupts_count())
+}
+
+fn write_cpu_info(out: &mut impl Write) -> fmt::Result {
+    use x86::cpuid::CpuId;
+
+    let cpu_id = CpuId::new();
+
+    if let Some(vendor_info) = cpu_id.get_vendor_info() {
+        writeln!(out, "vendor: {}", vendor_info.as_string())?;
+    }
+
+    if let Some(brand) = cpu_id.get_extended_function_info().as_ref().and_then(|info| info.processor_brand_string()) {
+        writeln!(out, "model name: {}", brand.trim())?;
+    }
+
+    if let Some(features) = cpu_id.get_feature_info() {
+        writeln!(out, "family: {}", features.family_id())?;
+        writeln!(out, "model: {}", features.model_id())?;
+        writeln!(out, "stepping: {}", features.stepping_id())?;
+
+        write!(out, "flags:")?;
+        let flags = [
+            (features.has_fpu(), "fpu"),
+            (features.has_tsc(), "tsc"),
+            (features.has_pae(), "pae"),
+            (features.has_apic(), "apic"),
+            (features.has_sysenter_sysexit(), "sep"),
+            (features.has_pge(), "pge"),
+            (features.has_cmov(), "cmov"),
+            (features.has_mmx(), "mmx"),
+            (features.has_sse(), "sse"),
+            (features.has_sse2(), "sse2"),
+            (features.has_rdrand(), "rdrand"),
+        ];
+        for (_, name) in flags.iter().filter(|(supported, _)| *supported) {
+            write!(out, " {}", name)?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+fn write_boot_info(out: &mut impl Write) -> fmt::Result {
+    let boot_info = match boot_info() {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+
+    writeln!(out, "address: {:#x}-{:#x}", boot_info.start_address(), boot_info.end_address())?;
+
+    if let Some(tag) = boot_info.boot_loader_name_tag() {
+        writeln!(out, "boot loader: {}", tag.name())?;
+    }
+
+    if let Some(tag) = boot_info.command_line_tag() {
+        writeln!(out, "command line: {}", tag.command_line())?;
+    }
+
+    if let Some(tag) = boot_info.memory_map_tag() {
+        writeln!(out, "memory map:")?;
+        for area in tag.memory_areas() {
+            writeln!(out, "  {:#010x}-{:#010x} available", area.start_address(), area.end_address())?;
+        }
+    }
+
+    for (i, module) in boot_info.module_tags().enumerate() {
+        writeln!(out, "module {}: {:#010x}-{:#010x} {}", i, module.start_address(), module.end_address(), module.name())?;
+    }
+
+    Ok(())
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> InodeNumber {
+        ROOT_INODE
+    }
+
+    fn lookup(&mut self, directory: InodeNumber, name: &str) -> Result<InodeNumber, FsError> {
+        match Node::from_inode(directory)? {
+            Node::Root => {
+                if let Some(i) = FILES.iter().position(|(file_name, _)| *file_name == name) {
+                    return Ok(FILE_INODE_START + i as InodeNumber);
+                }
+
+                let task_id = name.parse::<usize>().map_err(|_| FsError::NotFound)?;
+                if tasks().any(|task| task.id == task_id) {
+                    Ok(task_directory_inode(task_id))
+                } else {
+                    Err(FsError::NotFound)
+                }
+            }
+            Node::TaskDirectory(task_id) => {
+                TASK_FILES.iter()
+                    .position(|(file_name, _)| *file_name == name)
+                    .map(|i| task_directory_inode(task_id) + 1 + i as InodeNumber)
+                    .ok_or(FsError::NotFound)
+            }
+            Node::File(_) | Node::TaskFile(..) => Err(FsError::NotDirectory),
+        }
+    }
+
+    fn metadata(&mut self, inode: InodeNumber) -> Result<Metadata, FsError> {
+        let (file_type, mode) = match Node::from_inode(inode)? {
+            Node::Root | Node::TaskDirectory(_) => (FileType::Directory, 0o555),
+            Node::File(_) | Node::TaskFile(..) => (FileType::Regular, 0o444),
+        };
+
+        Ok(Metadata {
+            inode,
+            file_type,
+            size: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            modified: (idt::time_in_milliseconds() / 1000) as u32,
+        })
+    }
+
+    fn read(&mut self, inode: InodeNumber, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let mut window = ReadWindow {
+            offset,
+            buffer,
+            position: 0,
+            written: 0,
+        };
+
+        let _ = match Node::from_inode(inode)? {
+            Node::Root | Node::TaskDirectory(_) => return Err(FsError::IsDirectory),
+            Node::File(file) => self.write_file(&mut window, file),
+            Node::TaskFile(task_id, file) => self.write_task_file(&mut window, task_id, file),
+        };
+
+        Ok(window.written)
+    }
+
+    fn read_dir(&mut self, directory: InodeNumber, index: usize) -> Result<Option<DirEntry>, FsError> {
+        match Node::from_inode(directory)? {
+            Node::Root => {
+                if let Some((name, _)) = FILES.get(index) {
+                    return Ok(Some(DirEntry {
+                        name: FileName::from(name).unwrap_or_default(),
+                        inode: FILE_INODE_START + index as InodeNumber,
+                        file_type: FileType::Regular,
+                    }));
+                }
+
+                let entry = tasks().nth(index - FILES.len()).map(|task| {
+                    let mut name = FileName::new();
+                    let _ = write!(name, "{}", task.id);
+                    DirEntry {
+                        name,
+                        inode: task_directory_inode(task.id),
+                        file_type: FileType::Directory,
+                    }
+                });
+
+                Ok(entry)
+            }
+            Node::TaskDirectory(task_id) => {
+                let entry = TASK_FILES.get(index).map(|(name, _)| {
+                    DirEntry {
+                        name: FileName::from(name).unwrap_or_default(),
+                        inode: task_directory_inode(task_id) + 1 + index as InodeNumber,
+                        file_type: FileType::Regular,
+                    }
+                });
+
+                Ok(entry)
+            }
+            Node::File(_) | Node::TaskFile(..) => Err(FsError::NotDirectory),
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! In-memory filesystem.
+//!
+//! All instances live in static memory, so the maximum number of files and
+//! the total size of file contents are fixed at compile time.
+
+use crate::sync::IrqSpinlock;
+use crate::vfs::{FileSystem, FileSystemType, InodeNumber, Metadata, DirEntry, FileType, FsError, FileName, PathBuf, FILE_NAME_MAX_LENGTH};
+
+const RAMFS_INSTANCE_COUNT: usize = 4;
+const NODE_COUNT: usize = 128;
+const BLOCK_SIZE: usize = 1024;
+const BLOCK_COUNT: usize = 256;
+
+const ROOT_NODE: usize = 0;
+const NO_BLOCK: u16 = u16::max_value();
+
+/// Instance is in use if its root node is used.
+static RAMFS_INSTANCES: IrqSpinlock<[RamFs; RAMFS_INSTANCE_COUNT]> = IrqSpinlock::new("ramfs instances", [
+    RamFs::new(),
+    RamFs::new(),
+    RamFs::new(),
+    RamFs::new(),
+]);
+
+pub const RAMFS_TYPE: FileSystemType = FileSystemType {
+    name: "ramfs",
+    mount: mount_ramfs,
+};
+
+fn mount_ramfs(_source: &str) -> Result<&'static mut dyn FileSystem, FsError> {
+    match RamFs::new_instance() {
+        Some(fs) => Ok(fs),
+        None => Err(FsError::NoSpace),
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Node {
+    used: bool,
+    file_type: FileType,
+    mode: u16,
+    parent: u16,
+    name: [u8; FILE_NAME_MAX_LENGTH],
+    name_length: u8,
+    size: u32,
+    first_block: u16,
+    modified: u32,
+    /// File contents which are not stored in blocks, for example
+    /// from a boot module. Contents are copied to blocks on first write.
+    external: Option<&'static [u8]>,
+}
+
+impl Node {
+    const EMPTY: Node = Node {
+        used: false,
+        file_type: FileType::Regular,
+        mode: 0,
+        parent: 0,
+        name: [0; FILE_NAME_MAX_LENGTH],
+        name_length: 0,
+        size: 0,
+        first_block: NO_BLOCK,
+        modified: 0,
+        external: None,
+    };
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length as usize]).unwrap_or_default()
+    }
+}
+
+pub struct RamFs {
+    nodes: [Node; NODE_COUNT],
+    /// Next block of the file, like in a FAT.
+    block_next: [u16; BLOCK_COUNT],
+    block_used: [bool; BLOCK_COUNT],
+    blocks: [[u8; BLOCK_SIZE]; BLOCK_COUNT],
+}
+
+impl RamFs {
+    const fn new() -> Self {
+        Self {
+            nodes: [Node::EMPTY; NODE_COUNT],
+            block_next: [NO_BLOCK; BLOCK_COUNT],
+            block_used: [false; BLOCK_COUNT],
+            blocks: [[0; BLOCK_SIZE]; BLOCK_COUNT],
+        }
+    }
+
+    /// Get an unused instance with an empty root directory. Instances
+    /// are never freed, and the VFS locks the mount for every call to
+    /// the instance.
+    pub fn new_instance() -> Option<&'static mut RamFs> {
+        let mut instances = RAMFS_INSTANCES.lock();
+        let fs = instances.iter_mut().find(|fs| !fs.nodes[ROOT_NODE].used)?;
+
+        fs.nodes[ROOT_NODE] = Node {
+            used: true,
+            file_type: FileType::Directory,
+            mode: 0o755,
+            ..Node::EMPTY
+        };
+        Some(unsafe { &mut *(fs as *mut RamFs) })
+    }
+
+    fn node(&self, inode: InodeNumber) -> Result<&Node, FsError> {
+        match self.nodes.get(inode as usize) {
+            Some(node) if node.used => Ok(node),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn directory(&self, inode: InodeNumber) -> Result<&Node, FsError> {
+        let node = self.node(inode)?;
+        if node.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(node)
+    }
+
+    fn children(&self, directory: InodeNumber) -> impl Iterator<Item=(usize, &Node)> {
+        self.nodes.iter()
+            .enumerate()
+            .filter(move |(i, n)| n.used && *i != ROOT_NODE && n.parent as u32 == directory)
+    }
+
+    fn allocate_block(&mut self) -> Result<u16, FsError> {
+        let i = self.block_used.iter().position(|used| !used).ok_or(FsError::NoSpace)?;
+        self.block_used[i] = true;
+        self.block_next[i] = NO_BLOCK;
+        for byte in self.blocks[i].iter_mut() {
+            *byte = 0;
+        }
+        Ok(i as u16)
+    }
+
+    fn free_blocks(&mut self, mut block: u16) {
+        while block != NO_BLOCK {
+            let next = self.block_next[block as usize];
+            self.block_used[block as usize] = false;
+            self.block_next[block as usize] = NO_BLOCK;
+            block = next;
+        }
+    }
+
+    /// Find block number `index` of the file. Missing blocks are allocated if `allocate` is true.
+    fn file_block(&mut self, inode: InodeNumber, index: usize, allocate: bool) -> Result<Option<u16>, FsError> {
+        let mut block = self.node(inode)?.first_block;
+
+        if block == NO_BLOCK {
+            if !allocate {
+                return Ok(None);
+            }
+            block = self.allocate_block()?;
+            self.nodes[inode as usize].first_block = block;
+        }
+
+        for _ in 0..index {
+            let mut next = self.block_next[block as usize];
+            if next == NO_BLOCK {
+                if !allocate {
+                    return Ok(None);
+                }
+                next = self.allocate_block()?;
+                self.block_next[block as usize] = next;
+            }
+            block = next;
+        }
+
+        Ok(Some(block))
+    }
+
+    fn read_data(&mut self, inode: InodeNumber, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node(inode)?;
+        let size = node.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let count = core::cmp::min(buffer.len() as u64, size - offset) as usize;
+
+        if let Some(data) = node.external {
+            let offset = offset as usize;
+            buffer[..count].copy_from_slice(&data[offset..offset + count]);
+            return Ok(count);
+        }
+
+        let mut done = 0;
+
+        while done < count {
+            let position = offset as usize + done;
+            let block_offset = position % BLOCK_SIZE;
+            let chunk = core::cmp::min(BLOCK_SIZE - block_offset, count - done);
+
+            match self.file_block(inode, position / BLOCK_SIZE, false)? {
+                Some(block) => buffer[done..done + chunk].copy_from_slice(&self.blocks[block as usize][block_offset..block_offset + chunk]),
+                None => {
+                    for byte in buffer[done..done + chunk].iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+
+            done += chunk;
+        }
+
+        Ok(count)
+    }
+
+    /// Move external file contents to blocks.
+    fn materialize(&mut self, inode: InodeNumber) -> Result<(), FsError> {
+        if let Some(data) = self.node(inode)?.external {
+            let size = self.nodes[inode as usize].size;
+            self.nodes[inode as usize].external = None;
+            self.nodes[inode as usize].size = 0;
+
+            if let Err(e) = self.write_data(inode, 0, data) {
+                let node = &mut self.nodes[inode as usize];
+                let first_block = node.first_block;
+                node.first_block = NO_BLOCK;
+                node.external = Some(data);
+                node.size = size;
+                self.free_blocks(first_block);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create a regular file which uses `data` as its initial contents
+    /// without copying it.
+    pub fn create_file_with_data(&mut self, directory: InodeNumber, name: &str, mode: u16, data: &'static [u8]) -> Result<InodeNumber, FsError> {
+        let inode = self.create(directory, name, FileType::Regular, mode)?;
+        let node = &mut self.nodes[inode as usize];
+        node.external = Some(data);
+        node.size = data.len() as u32;
+        Ok(inode)
+    }
+
+    fn write_data(&mut self, inode: InodeNumber, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if offset + data.len() as u64 > (BLOCK_SIZE * BLOCK_COUNT) as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        let mut done = 0;
+
+        while done < data.len() {
+            let position = offset as usize + done;
+            let block_offset = position % BLOCK_SIZE;
+            let chunk = core::cmp::min(BLOCK_SIZE - block_offset, data.len() - done);
+
+            let block = match self.file_block(inode, position / BLOCK_SIZE, true) {
+                Ok(Some(block)) => block,
+                Ok(None) | Err(FsError::NoSpace) if done > 0 => break,
+                Ok(None) => return Err(FsError::NoSpace),
+                Err(e) => return Err(e),
+            };
+
+            self.blocks[block as usize][block_offset..block_offset + chunk].copy_from_slice(&data[done..done + chunk]);
+            done += chunk;
+        }
+
+        let node = &mut self.nodes[inode as usize];
+        node.size = core::cmp::max(node.size, (offset as usize + done) as u32);
+        node.modified = current_time();
+
+        Ok(done)
+    }
+}
+
+fn current_time() -> u32 {
+    (crate::idt::time_in_milliseconds() / 1000) as u32
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> InodeNumber {
+        ROOT_NODE as InodeNumber
+    }
+
+    fn lookup(&mut self, directory: InodeNumber, name: &str) -> Result<InodeNumber, FsError> {
+        self.directory(directory)?;
+        self.children(directory)
+            .find(|(_, n)| n.name() == name)
+            .map(|(i, _)| i as InodeNumber)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn metadata(&mut self, inode: InodeNumber) -> Result<Metadata, FsError> {
+        let node = self.node(inode)?;
+
+        let links = if node.file_type == FileType::Directory {
+            2 + self.children(inode).filter(|(_, n)| n.file_type == FileType::Directory).count() as u16
+        } else {
+            1
+        };
+
+        Ok(Metadata {
+            inode,
+            file_type: node.file_type,
+            size: node.size as u64,
+            mode: node.mode,
+            uid: 0,
+            gid: 0,
+            links,
+            modified: node.modified,
+        })
+    }
+
+    fn read(&mut self, inode: InodeNumber, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match self.node(inode)?.file_type {
+            FileType::Directory => Err(FsError::IsDirectory),
+            _ => self.read_data(inode, offset, buffer),
+        }
+    }
+
+    fn read_dir(&mut self, directory: InodeNumber, index: usize) -> Result<Option<DirEntry>, FsError> {
+        self.directory(directory)?;
+
+        let entry = self.children(directory).nth(index).map(|(i, n)| {
+            DirEntry {
+                name: FileName::from(n.name()).unwrap_or_default(),
+                inode: i as InodeNumber,
+                file_type: n.file_type,
+            }
+        });
+
+        Ok(entry)
+    }
+
+    fn write(&mut self, inode: InodeNumber, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        match self.node(inode)?.file_type {
+            FileType::Regular => {
+                self.materialize(inode)?;
+                self.write_data(inode, offset, data)
+            }
+            FileType::Directory => Err(FsError::IsDirectory),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn read_link(&mut self, inode: InodeNumber, target: &mut PathBuf) -> Result<(), FsError> {
+        let node = self.node(inode)?;
+        if node.file_type != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut buffer = [0u8; crate::vfs::PATH_MAX_LENGTH];
+        let count = self.read_data(inode, 0, &mut buffer)?;
+        let text = core::str::from_utf8(&buffer[..count]).map_err(|_| FsError::Corrupted)?;
+
+        target.clear();
+        target.try_push_str(text).map_err(|_| FsError::PathTooLong)
+    }
+
+    fn create(&mut self, directory: InodeNumber, name: &str, file_type: FileType, mode: u16) -> Result<InodeNumber, FsError> {
+        if name.len() > FILE_NAME_MAX_LENGTH {
+            return Err(FsError::NameTooLong);
+        }
+
+        if self.lookup(directory, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let i = self.nodes.iter().position(|n| !n.used).ok_or(FsError::NoSpace)?;
+
+        let mut node = Node {
+            used: true,
+            file_type,
+            mode,
+            parent: directory as u16,
+            name_length: name.len() as u8,
+            modified: current_time(),
+            ..Node::EMPTY
+        };
+        node.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.nodes[i] = node;
+
+        Ok(i as InodeNumber)
+    }
+
+    fn symlink(&mut self, directory: InodeNumber, name: &str, target: &str) -> Result<InodeNumber, FsError> {
+        let inode = self.create(directory, name, FileType::Symlink, 0o777)?;
+
+        if let Err(e) = self.write_data(inode, 0, target.as_bytes()) {
+            self.nodes[inode as usize] = Node::EMPTY;
+            return Err(e);
+        }
+
+        Ok(inode)
+    }
+
+    fn truncate(&mut self, inode: InodeNumber, size: u64) -> Result<(), FsError> {
+        if self.node(inode)?.file_type != FileType::Regular {
+            return Err(FsError::InvalidArgument);
+        }
+
+        self.materialize(inode)?;
+        let node = *self.node(inode)?;
+
+        if size > node.size as u64 {
+            // Extending reads back as zeros, because missing blocks are read as zeros.
+            self.nodes[inode as usize].size = size as u32;
+            return Ok(());
+        }
+
+        let keep_blocks = (size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        if keep_blocks == 0 {
+            self.free_blocks(node.first_block);
+            self.nodes[inode as usize].first_block = NO_BLOCK;
+        } else if let Some(last) = self.file_block(inode, keep_blocks - 1, false)? {
+            let next = self.block_next[last as usize];
+            self.block_next[last as usize] = NO_BLOCK;
+            self.free_blocks(next);
+
+            // Clear the tail of the last block, so that extending the file later reads zeros.
+            let tail = size as usize % BLOCK_SIZE;
+            if tail != 0 {
+                for byte in self.blocks[last as usize][tail..].iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+
+        let node = &mut self.nodes[inode as usize];
+        node.size = size as u32;
+        node.modified = current_time();
+        Ok(())
+    }
+
+    fn remove(&mut self, directory: InodeNumber, name: &str) -> Result<(), FsError> {
+        let inode = self.lookup(directory, name)?;
+
+        if self.children(inode).next().is_some() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        let first_block = self.nodes[inode as usize].first_block;
+        self.free_blocks(first_block);
+        self.nodes[inode as usize] = Node::EMPTY;
+        Ok(())
+    }
+}
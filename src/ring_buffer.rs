@@ -0,0 +1,92 @@
+//! Fixed size byte ring buffer.
+
+pub const RING_BUFFER_SIZE: usize = 4096;
+
+pub struct ByteRing {
+    data: [u8; RING_BUFFER_SIZE],
+    start: usize,
+    length: usize,
+    /// Number of bytes which have been removed from the buffer.
+    removed: u64,
+}
+
+impl ByteRing {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; RING_BUFFER_SIZE],
+            start: 0,
+            length: 0,
+            removed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.length == RING_BUFFER_SIZE
+    }
+
+    /// Stream position of the first byte in the buffer.
+    pub fn start_position(&self) -> u64 {
+        self.removed
+    }
+
+    /// Returns false if the buffer is full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+
+        self.data[(self.start + self.length) % RING_BUFFER_SIZE] = byte;
+        self.length += 1;
+        true
+    }
+
+    /// Push byte and remove the oldest byte if the buffer is full.
+    pub fn push_overwrite(&mut self, byte: u8) {
+        if self.is_full() {
+            self.pop();
+        }
+        self.push(byte);
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % RING_BUFFER_SIZE;
+        self.length -= 1;
+        self.removed += 1;
+        Some(byte)
+    }
+
+    pub fn get(&self, index: usize) -> Option<u8> {
+        if index < self.length {
+            Some(self.data[(self.start + index) % RING_BUFFER_SIZE])
+        } else {
+            None
+        }
+    }
+
+    /// Copy bytes starting from stream position `position`. Bytes which
+    /// are already removed are skipped.
+    pub fn read_at(&self, position: u64, buffer: &mut [u8]) -> usize {
+        let first = position.saturating_sub(self.removed) as usize;
+        let mut count = 0;
+
+        for (target, i) in buffer.iter_mut().zip(first..self.length) {
+            *target = self.data[(self.start + i) % RING_BUFFER_SIZE];
+            count += 1;
+        }
+
+        count
+    }
+}
@@ -0,0 +1,662 @@
+//! Kernel threads and a preemptive round-robin scheduler.
+//!
+//! Every thread has its own kernel stack. Context switch saves
+//! callee-saved registers and EFLAGS to the stack of the current thread
+//! and restores them from the stack of the next thread.
+//!
+//! Ready threads are in run queues, one queue for every priority.
+//! Scheduler runs the first thread from the highest priority queue which
+//! is not empty. Timer interrupt preempts the running thread when its
+//! time slice ends or when a higher priority thread is ready.
+//!
+//! Thread 0 is the thread which runs `kernel_main`. The idle thread runs
+//! when there are no other ready threads.
+
+use arraydeque::ArrayDeque;
+use arrayvec::ArrayVec;
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::page_table;
+use crate::shell::Io;
+use crate::shell_command::{self, Builtin, CommandError, RegistryError};
+use crate::shell_completion::ArgumentCompletion;
+use crate::shell_parser::Arguments;
+use crate::sync::{disable_interrupts, restore_interrupts};
+use crate::syscall;
+use crate::tss;
+use crate::usermode::KernelStack;
+
+extern "C" {
+    fn switch_context(old_stack_pointer: *mut usize, new_stack_pointer: usize);
+}
+
+pub const MAX_THREADS: usize = 32;
+const PRIORITY_COUNT: usize = 4;
+
+/// Time slice length in timer interrupts.
+const TIME_SLICE_TICKS: usize = 2;
+
+/// Initial EFLAGS of a new thread. Interrupts stay disabled until
+/// `thread_start` enables them.
+const INITIAL_EFLAGS: usize = 0x2;
+
+pub type ThreadId = usize;
+pub type ThreadFunction = fn(argument: usize) -> usize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Idle = 0,
+    Low = 1,
+    Normal = 2,
+    High = 3,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Sleeping until the time in milliseconds.
+    Sleeping(usize),
+    Joining(ThreadId),
+    /// Waiting in a wait queue.
+    Blocked,
+    /// Thread has exited, but it is not joined yet.
+    Finished(usize),
+}
+
+impl ThreadState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Sleeping(_) => "sleeping",
+            ThreadState::Joining(_) => "joining",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Finished(_) => "finished",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ThreadError {
+    TooManyThreads,
+    OutOfMemory,
+    NoSuchThread,
+    /// Thread can't join itself.
+    Deadlock,
+    /// Another thread is already joining the thread.
+    AlreadyJoined,
+    Detached,
+    NotInitialized,
+}
+
+struct Thread {
+    name: &'static str,
+    state: ThreadState,
+    priority: Priority,
+    saved_stack_pointer: usize,
+    /// Thread 0 uses the boot stack. Stack is freed when the thread is
+    /// joined.
+    _kernel_stack: Option<KernelStack>,
+    /// Stack for interrupts from user mode. Zero if the thread doesn't
+    /// run user mode code.
+    user_interrupt_stack: usize,
+    /// Page table which was loaded when the thread was switched out.
+    level3_address: usize,
+    entry: Option<(ThreadFunction, usize)>,
+    joined: bool,
+    /// Detached thread is removed when it exits.
+    detached: bool,
+    /// Virtual console which gets the console output of the thread. New
+    /// threads inherit it from the thread which starts them.
+    console: usize,
+}
+
+/// Information about a thread for displaying.
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub priority: Priority,
+}
+
+type RunQueue = ArrayDeque<[ThreadId; MAX_THREADS]>;
+
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    run_queues: [RunQueue; PRIORITY_COUNT],
+    current: ThreadId,
+    time_slice_left: usize,
+    /// Detached thread which exited. Its stack is freed after switching
+    /// to the next thread.
+    exited_detached: Option<ThreadId>,
+}
+
+static SCHEDULER_INITIALIZED: AtomicBool = AtomicBool::new(false);
+static mut SCHEDULER: Option<Scheduler> = None;
+
+fn scheduler() -> Option<&'static mut Scheduler> {
+    if SCHEDULER_INITIALIZED.load(Ordering::SeqCst) {
+        unsafe { SCHEDULER.as_mut() }
+    } else {
+        None
+    }
+}
+
+/// Make the current code thread 0 and start the idle thread.
+pub fn init() -> Result<(), ThreadError> {
+    let mut threads: [Option<Thread>; MAX_THREADS] = Default::default();
+    threads[0] = Some(Thread {
+        name: "kernel",
+        state: ThreadState::Running,
+        priority: Priority::Normal,
+        saved_stack_pointer: 0,
+        _kernel_stack: None,
+        user_interrupt_stack: 0,
+        level3_address: page_table::kernel_level3_address(),
+        entry: None,
+        joined: false,
+        detached: false,
+        console: 0,
+    });
+
+    unsafe {
+        SCHEDULER = Some(Scheduler {
+            threads,
+            run_queues: [RunQueue::new(), RunQueue::new(), RunQueue::new(), RunQueue::new()],
+            current: 0,
+            time_slice_left: TIME_SLICE_TICKS,
+            exited_detached: None,
+        });
+    }
+
+    SCHEDULER_INITIALIZED.store(true, Ordering::SeqCst);
+
+    spawn("idle", Priority::Idle, idle_thread, 0)?;
+
+    Ok(())
+}
+
+fn idle_thread(_argument: usize) -> usize {
+    loop {
+        unsafe {
+            x86::halt();
+        }
+    }
+}
+
+/// Start a new thread which runs `function(argument)`. Finished threads
+/// keep their thread table slot until they are joined.
+pub fn spawn(name: &'static str, priority: Priority, function: ThreadFunction, argument: usize) -> Result<ThreadId, ThreadError> {
+    let kernel_stack = KernelStack::new().ok_or(ThreadError::OutOfMemory)?;
+
+    // Initial stack for `switch_context`: EDI, ESI, EBX, EBP, EFLAGS,
+    // return address and a return address for `thread_start`.
+    let initial_stack = [0, 0, 0, 0, INITIAL_EFLAGS, thread_start as usize, 0];
+    let saved_stack_pointer = kernel_stack.top() - initial_stack.len() * 4;
+    for (i, &value) in initial_stack.iter().enumerate() {
+        unsafe {
+            *((saved_stack_pointer + i * 4) as *mut usize) = value;
+        }
+    }
+
+    let interrupts = disable_interrupts();
+
+    let result = match scheduler() {
+        Some(scheduler) => {
+            let console = scheduler.current_thread().console;
+            scheduler.add_thread(Thread {
+                name,
+                state: ThreadState::Ready,
+                priority,
+                saved_stack_pointer,
+                _kernel_stack: Some(kernel_stack),
+                user_interrupt_stack: 0,
+                level3_address: page_table::kernel_level3_address(),
+                entry: Some((function, argument)),
+                joined: false,
+                detached: false,
+                console,
+            })
+        }
+        None => Err(ThreadError::NotInitialized),
+    };
+
+    restore_interrupts(interrupts);
+    result
+}
+
+/// First code which a new thread runs.
+extern "C" fn thread_start() -> ! {
+    let scheduler = scheduler().expect("scheduler is not initialized");
+    scheduler.finish_switch();
+    let entry = scheduler.current_thread().entry.expect("thread entry is missing");
+
+    unsafe {
+        x86::irq::enable();
+    }
+
+    let (function, argument) = entry;
+    exit(function(argument))
+}
+
+/// Stop the current thread. Return value is available with `join`.
+pub fn exit(value: usize) -> ! {
+    disable_interrupts();
+
+    let scheduler = scheduler().expect("scheduler is not initialized");
+    let current = scheduler.current;
+
+    if current == 0 {
+        panic!("kernel thread 0 exited");
+    }
+
+    scheduler.current_thread().state = ThreadState::Finished(value);
+
+    if scheduler.current_thread().detached {
+        scheduler.exited_detached = Some(current);
+    } else {
+        scheduler.wake(|state| state == ThreadState::Joining(current));
+    }
+
+    scheduler.schedule();
+
+    unreachable!("finished thread was scheduled")
+}
+
+/// Wait until thread `id` exits and return its return value.
+pub fn join(id: ThreadId) -> Result<usize, ThreadError> {
+    let interrupts = disable_interrupts();
+    let result = join_with_interrupts_disabled(id);
+    restore_interrupts(interrupts);
+    result
+}
+
+fn join_with_interrupts_disabled(id: ThreadId) -> Result<usize, ThreadError> {
+    let scheduler = scheduler().ok_or(ThreadError::NotInitialized)?;
+
+    if id == scheduler.current {
+        return Err(ThreadError::Deadlock);
+    }
+
+    {
+        let thread = scheduler.thread(id)?;
+        if thread.joined {
+            return Err(ThreadError::AlreadyJoined);
+        }
+        if thread.detached {
+            return Err(ThreadError::Detached);
+        }
+        thread.joined = true;
+    }
+
+    loop {
+        if let ThreadState::Finished(value) = scheduler.thread(id)?.state {
+            // Stack frames are freed when the thread is dropped.
+            scheduler.threads[id] = None;
+            return Ok(value);
+        }
+
+        scheduler.current_thread().state = ThreadState::Joining(id);
+        scheduler.schedule();
+    }
+}
+
+/// Remove thread `id` when it exits. Detached thread can't be joined.
+pub fn detach(id: ThreadId) -> Result<(), ThreadError> {
+    let interrupts = disable_interrupts();
+
+    let result = scheduler().ok_or(ThreadError::NotInitialized).and_then(|scheduler| {
+        let thread = scheduler.thread(id)?;
+        if thread.joined {
+            return Err(ThreadError::AlreadyJoined);
+        }
+
+        if let ThreadState::Finished(_) = thread.state {
+            scheduler.threads[id] = None;
+        } else {
+            thread.detached = true;
+        }
+
+        Ok(())
+    });
+
+    restore_interrupts(interrupts);
+    result
+}
+
+/// Let other threads with the same or higher priority run.
+pub fn yield_now() {
+    let interrupts = disable_interrupts();
+
+    if let Some(scheduler) = scheduler() {
+        scheduler.schedule();
+    }
+
+    restore_interrupts(interrupts);
+}
+
+/// Sleep at least `milliseconds`.
+pub fn sleep(milliseconds: usize) {
+    let interrupts = disable_interrupts();
+
+    match scheduler() {
+        Some(scheduler) => {
+            let wake_time = crate::idt::time_in_milliseconds().wrapping_add(milliseconds);
+            scheduler.current_thread().state = ThreadState::Sleeping(wake_time);
+            scheduler.schedule();
+        }
+        None => {
+            let start = crate::idt::time_in_milliseconds();
+            while crate::idt::time_in_milliseconds().wrapping_sub(start) < milliseconds {
+                unsafe {
+                    x86::irq::enable();
+                    x86::halt();
+                    x86::irq::disable();
+                }
+            }
+        }
+    }
+
+    restore_interrupts(interrupts);
+}
+
+/// Block the current thread. Interrupts must be disabled. The thread
+/// runs again after `unblock` is called. Before the scheduler is
+/// initialized this waits for the next interrupt.
+pub fn block_current() {
+    match scheduler() {
+        Some(scheduler) => {
+            scheduler.current_thread().state = ThreadState::Blocked;
+            scheduler.schedule();
+        }
+        None => unsafe {
+            // Enabling interrupts is delayed by one instruction, so
+            // interrupt can't happen before the halt.
+            x86::irq::enable();
+            x86::halt();
+            x86::irq::disable();
+        }
+    }
+}
+
+/// Wake a sleeping thread before its wake up time. Killing a process
+/// uses this, so `sleep` can return early.
+pub fn wake_sleeping(id: ThreadId) {
+    let interrupts = disable_interrupts();
+
+    if let Some(scheduler) = scheduler() {
+        if let Ok(thread) = scheduler.thread(id) {
+            if let ThreadState::Sleeping(_) = thread.state {
+                scheduler.make_ready(id);
+            }
+        }
+    }
+
+    restore_interrupts(interrupts);
+}
+
+/// Make a blocked thread ready. Interrupts must be disabled.
+pub fn unblock(id: ThreadId) {
+    if let Some(scheduler) = scheduler() {
+        if let Ok(thread) = scheduler.thread(id) {
+            if thread.state == ThreadState::Blocked {
+                scheduler.make_ready(id);
+            }
+        }
+    }
+}
+
+pub fn current_thread_id() -> ThreadId {
+    scheduler().map(|scheduler| scheduler.current).unwrap_or(0)
+}
+
+/// Virtual console of the current thread.
+pub fn console() -> usize {
+    let interrupts = disable_interrupts();
+    let console = scheduler().map(|scheduler| scheduler.current_thread().console).unwrap_or(0);
+    restore_interrupts(interrupts);
+    console
+}
+
+/// Send console output of the current thread and the threads which it
+/// starts to virtual console `console`.
+pub fn set_console(console: usize) {
+    let interrupts = disable_interrupts();
+
+    if let Some(scheduler) = scheduler() {
+        scheduler.current_thread().console = console;
+    }
+
+    restore_interrupts(interrupts);
+}
+
+/// Set the stack which CPU uses for interrupts and system calls when
+/// the current thread is in user mode. Zero means that the thread
+/// doesn't run user mode code anymore.
+pub fn set_user_interrupt_stack(stack_top: usize) {
+    let interrupts = disable_interrupts();
+
+    if let Some(scheduler) = scheduler() {
+        scheduler.current_thread().user_interrupt_stack = stack_top;
+    }
+
+    if stack_top != 0 {
+        tss::set_kernel_stack(stack_top);
+        syscall::set_sysenter_stack(stack_top);
+    }
+
+    restore_interrupts(interrupts);
+}
+
+/// Called from the timer interrupt handler after end of interrupt is
+/// sent to the PIC.
+pub fn timer_tick() {
+    let scheduler = match scheduler() {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
+
+    let time = crate::idt::time_in_milliseconds();
+    scheduler.wake(|state| match state {
+        ThreadState::Sleeping(wake_time) => time.wrapping_sub(wake_time) as isize >= 0,
+        _ => false,
+    });
+
+    scheduler.time_slice_left = scheduler.time_slice_left.saturating_sub(1);
+
+    let current_priority = scheduler.current_thread().priority;
+    if scheduler.time_slice_left == 0 || scheduler.higher_priority_ready(current_priority) {
+        scheduler.schedule();
+    }
+}
+
+static COMMANDS: [Builtin; 1] = [
+    Builtin {
+        name: "threadtest",
+        usage: "",
+        help: "Run and join a few test threads",
+        run: threadtest,
+        complete: ArgumentCompletion::None,
+    },
+];
+
+pub fn register_commands() -> Result<(), RegistryError> {
+    shell_command::register_builtins(&COMMANDS)
+}
+
+/// Start a few kernel threads which compute sums, yielding between the
+/// steps, and print the results after joining them.
+fn threadtest(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    const THREADS: [(&str, Priority); 3] = [
+        ("test-low", Priority::Low),
+        ("test-normal-1", Priority::Normal),
+        ("test-normal-2", Priority::Normal),
+    ];
+
+    args.end()?;
+
+    let mut ids: ArrayVec<[(&str, ThreadId); 3]> = ArrayVec::new();
+
+    for (i, &(name, priority)) in THREADS.iter().enumerate() {
+        match spawn(name, priority, sum_thread, (i + 1) * 1000) {
+            Ok(id) => ids.push((name, id)),
+            Err(e) => {
+                let _ = writeln!(io, "threadtest: spawning {} failed: {:?}", name, e);
+            }
+        }
+    }
+
+    for (name, id) in ids {
+        match join(id) {
+            Ok(value) => {
+                let _ = writeln!(io, "Thread {} ({}) returned {}", id, name, value);
+            }
+            Err(e) => {
+                let _ = writeln!(io, "threadtest: joining {} failed: {:?}", name, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sum of numbers from 1 to `count`.
+fn sum_thread(count: usize) -> usize {
+    let mut sum = 0;
+    for i in 1..=count {
+        sum += i;
+        if i % 100 == 0 {
+            yield_now();
+        }
+    }
+    sum
+}
+
+pub fn threads() -> ArrayVec<[ThreadInfo; MAX_THREADS]> {
+    let interrupts = disable_interrupts();
+    let mut list = ArrayVec::new();
+
+    if let Some(scheduler) = scheduler() {
+        for (id, thread) in scheduler.threads.iter().enumerate() {
+            if let Some(thread) = thread {
+                list.push(ThreadInfo {
+                    id,
+                    name: thread.name,
+                    state: thread.state,
+                    priority: thread.priority,
+                });
+            }
+        }
+    }
+
+    restore_interrupts(interrupts);
+    list
+}
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> Result<&mut Thread, ThreadError> {
+        self.threads.get_mut(id).and_then(|thread| thread.as_mut()).ok_or(ThreadError::NoSuchThread)
+    }
+
+    fn current_thread(&mut self) -> &mut Thread {
+        self.threads[self.current].as_mut().expect("current thread is missing")
+    }
+
+    fn add_thread(&mut self, thread: Thread) -> Result<ThreadId, ThreadError> {
+        let id = self.threads.iter().position(|thread| thread.is_none()).ok_or(ThreadError::TooManyThreads)?;
+        self.threads[id] = Some(thread);
+        self.make_ready(id);
+        Ok(id)
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        let thread = self.threads[id].as_mut().expect("thread is missing");
+        thread.state = ThreadState::Ready;
+        let priority = thread.priority as usize;
+        // Queue can't be full, because a thread is in a queue only once.
+        let _ = self.run_queues[priority].push_back(id);
+    }
+
+    fn wake(&mut self, condition: impl Fn(ThreadState) -> bool) {
+        for id in 0..MAX_THREADS {
+            let wake = match &self.threads[id] {
+                Some(thread) => condition(thread.state),
+                None => false,
+            };
+
+            if wake {
+                self.make_ready(id);
+            }
+        }
+    }
+
+    fn higher_priority_ready(&self, priority: Priority) -> bool {
+        self.run_queues[priority as usize + 1..].iter().any(|queue| !queue.is_empty())
+    }
+
+    fn pop_ready_thread(&mut self) -> Option<ThreadId> {
+        for queue in self.run_queues.iter_mut().rev() {
+            if let Some(id) = queue.pop_front() {
+                return Some(id);
+            }
+        }
+
+        None
+    }
+
+    /// Switch to the next ready thread. Current thread is added to a run
+    /// queue if it is still running. Interrupts must be disabled.
+    fn schedule(&mut self) {
+        let current = self.current;
+
+        if self.current_thread().state == ThreadState::Running {
+            self.make_ready(current);
+        }
+
+        // Idle thread is always running or ready.
+        let next = self.pop_ready_thread().expect("no ready threads");
+        self.time_slice_left = TIME_SLICE_TICKS;
+
+        self.threads[next].as_mut().expect("thread is missing").state = ThreadState::Running;
+
+        if next == current {
+            return;
+        }
+
+        self.current_thread().level3_address = page_table::active_level3_address();
+        self.current = next;
+
+        let old_stack_pointer = &mut self.threads[current].as_mut().expect("thread is missing").saved_stack_pointer as *mut usize;
+
+        let next_thread = self.current_thread();
+        if next_thread.level3_address != page_table::active_level3_address() {
+            unsafe {
+                page_table::load_cr3(next_thread.level3_address);
+            }
+        }
+
+        if next_thread.user_interrupt_stack != 0 {
+            tss::set_kernel_stack(next_thread.user_interrupt_stack);
+            syscall::set_sysenter_stack(next_thread.user_interrupt_stack);
+        }
+
+        let new_stack_pointer = next_thread.saved_stack_pointer;
+
+        unsafe {
+            switch_context(old_stack_pointer, new_stack_pointer);
+        }
+
+        self.finish_switch();
+    }
+
+    /// Called by the next thread after a context switch.
+    fn finish_switch(&mut self) {
+        if let Some(id) = self.exited_detached.take() {
+            // Stack frames are freed when the thread is dropped.
+            self.threads[id] = None;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Serial port (16550 UART) driver.
+
+use core::fmt;
+
+use crate::devfs::CharDevice;
+use crate::sync::IrqSpinlock;
+use crate::vfs::FsError;
+
+pub const COM1_IO_BASE: u16 = 0x3F8;
+
+// Register offsets from IO base.
+const REGISTER_DATA: u16 = 0;
+const REGISTER_INTERRUPT_ENABLE: u16 = 1;
+const REGISTER_FIFO_CONTROL: u16 = 2;
+const REGISTER_LINE_CONTROL: u16 = 3;
+const REGISTER_MODEM_CONTROL: u16 = 4;
+const REGISTER_LINE_STATUS: u16 = 5;
+
+/// When set, data and interrupt enable registers are the baud rate divisor.
+const LINE_CONTROL_DIVISOR_LATCH: u8 = 0x80;
+const LINE_CONTROL_8N1: u8 = 0x03;
+/// Enable and clear FIFOs with 14 byte interrupt threshold.
+const FIFO_CONTROL_ENABLE: u8 = 0xC7;
+const MODEM_CONTROL_NORMAL: u8 = 0x0F;
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1E;
+
+const LINE_STATUS_DATA_READY: u8 = 1;
+const LINE_STATUS_TRANSMITTER_EMPTY: u8 = 1 << 5;
+
+/// 115200 baud.
+const BAUD_RATE_DIVISOR: u16 = 1;
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+const TRANSMIT_TIMEOUT: usize = 100_000;
+
+pub struct SerialPort {
+    io_base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(io_base: u16) -> Self {
+        Self {
+            io_base,
+        }
+    }
+
+    fn read_register(&self, register: u16) -> u8 {
+        unsafe { x86::io::inb(self.io_base + register) }
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        unsafe { x86::io::outb(self.io_base + register, value) }
+    }
+
+    /// Configure port to 115200 baud 8N1. Returns false if the port
+    /// doesn't pass the loopback test.
+    pub fn init(&mut self) -> bool {
+        self.write_register(REGISTER_INTERRUPT_ENABLE, 0);
+        self.write_register(REGISTER_LINE_CONTROL, LINE_CONTROL_DIVISOR_LATCH);
+        self.write_register(REGISTER_DATA, BAUD_RATE_DIVISOR as u8);
+        self.write_register(REGISTER_INTERRUPT_ENABLE, (BAUD_RATE_DIVISOR >> 8) as u8);
+        self.write_register(REGISTER_LINE_CONTROL, LINE_CONTROL_8N1);
+        self.write_register(REGISTER_FIFO_CONTROL, FIFO_CONTROL_ENABLE);
+
+        self.write_register(REGISTER_MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+        self.write_register(REGISTER_DATA, LOOPBACK_TEST_BYTE);
+        if self.read_register(REGISTER_DATA) != LOOPBACK_TEST_BYTE {
+            return false;
+        }
+
+        self.write_register(REGISTER_MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+        true
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        for _ in 0..TRANSMIT_TIMEOUT {
+            if self.read_register(REGISTER_LINE_STATUS) & LINE_STATUS_TRANSMITTER_EMPTY != 0 {
+                break;
+            }
+        }
+
+        self.write_register(REGISTER_DATA, byte);
+    }
+
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.read_register(REGISTER_LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+            Some(self.read_register(REGISTER_DATA))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for byte in text.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// The lock is taken for each byte, so interrupts are not kept disabled
+/// for a whole write.
+static COM1: IrqSpinlock<Option<SerialPort>> = IrqSpinlock::new("com1", None);
+
+/// Initialize COM1 if it exists. Returns false if it doesn't.
+pub fn init() -> bool {
+    let mut port = SerialPort::new(COM1_IO_BASE);
+
+    if !port.init() {
+        return false;
+    }
+
+    *COM1.lock() = Some(port);
+    true
+}
+
+/// Device file of COM1.
+pub struct Com1Device;
+
+impl CharDevice for Com1Device {
+    /// Returns received bytes without waiting.
+    fn read(&mut self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let mut count = 0;
+
+        for byte in buffer.iter_mut() {
+            match COM1.lock().as_mut().and_then(|port| port.read_byte()) {
+                Some(received) => *byte = received,
+                None => break,
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        for &byte in data {
+            if let Some(port) = COM1.lock().as_mut() {
+                port.write_byte(byte);
+            }
+        }
+        Ok(data.len())
+    }
+}
+
+pub static mut COM1_DEVICE: Com1Device = Com1Device;
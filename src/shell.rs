@@ -0,0 +1,843 @@
+//! Shell command execution and file commands.
+//!
+//! Command lines are parsed with `shell_parser` and commands are found
+//! from the `shell_command` registry. A pipeline with one command runs
+//! in the shell thread. Commands of longer pipelines run in their own
+//! threads, connected with pipes, so that a command which fills a pipe
+//! doesn't block the command which reads it. A list which ends with `&`
+//! runs in a background thread, which has its own working directory.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use arrayvec::ArrayVec;
+
+use crate::ansi;
+use crate::elf;
+use crate::input;
+use crate::pipe;
+use crate::process::{self, Pid, ProcessError};
+use crate::scheduler::{self, Priority, ThreadId};
+use crate::shell_command::{self, Builtin, CommandError, RegistryError};
+use crate::shell_completion::{ArgumentCompletion, Completions};
+use crate::shell_history::{self, ExpansionError, HistoryLine};
+use crate::shell_parser::{self, Arguments, Command, ParsedLine, Separator, MAX_COMMANDS};
+use crate::sync::WaitQueue;
+use crate::usermode::{self, ExitReason};
+use crate::utf8::Utf8Decoder;
+use crate::vfs::{self, Context, FileDescriptor, OpenFlags, FileType, FsError};
+
+pub const STDIN: FileDescriptor = 0;
+pub const STDOUT: FileDescriptor = 1;
+
+const CONSOLE_PATH: &str = "/dev/console";
+
+/// Threads which wait for a started command thread to copy its
+/// arguments.
+static COMMAND_THREAD_STARTED: WaitQueue = WaitQueue::new("command thread started");
+
+/// Standard streams and context of a shell command.
+///
+/// File descriptors 0, 1 and 2 of `ctx` are standard input, output and
+/// error, and programs which the command starts inherit them. Commands
+/// write their output to `Io`. In the shell thread output to the
+/// console is written directly to the output of `run_command_line`.
+/// Files are closed when `Io` is dropped.
+pub struct Io<'a> {
+    /// Console output of the shell thread.
+    terminal: Option<&'a mut dyn Write>,
+    /// Standard output is the console.
+    console_output: bool,
+    /// Working directory of the command. A command which runs in the
+    /// shell thread without a pipeline changes the working directory of
+    /// the shell.
+    pub ctx: Context,
+}
+
+impl<'a> Io<'a> {
+    /// Streams which are all the console.
+    fn console(terminal: Option<&'a mut dyn Write>, ctx: &Context) -> Self {
+        let mut files = ctx.without_files();
+        for _ in 0..3 {
+            let _ = vfs::vfs().open(&mut files, CONSOLE_PATH, OpenFlags::READ | OpenFlags::WRITE);
+        }
+
+        Self {
+            terminal,
+            console_output: true,
+            ctx: files,
+        }
+    }
+
+    /// Read standard input.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        vfs::vfs().read(&mut self.ctx, STDIN, buffer)
+    }
+
+    /// Wait until process `pid` exits.
+    pub fn wait(&mut self, pid: Pid) -> Result<ExitReason, ProcessError> {
+        process::wait(0, Some(pid)).map(|(_, reason)| reason)
+    }
+}
+
+impl Write for Io<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match &mut self.terminal {
+            Some(terminal) if self.console_output => terminal.write_str(s),
+            _ => write_all(&mut self.ctx, STDOUT, s.as_bytes()).map_err(|_| fmt::Error),
+        }
+    }
+}
+
+impl Drop for Io<'_> {
+    fn drop(&mut self) {
+        vfs::vfs().close_all(&mut self.ctx);
+    }
+}
+
+/// Expand history references, add the line to the history and run it.
+pub fn run_command_line(out: &mut impl Write, ctx: &mut Context, line: &str) {
+    let expanded = shell_history::expand(line).and_then(|expanded| match expanded {
+        Some(expanded) => {
+            let _ = writeln!(out, "{}", expanded);
+            Ok(expanded)
+        }
+        None => HistoryLine::from(line).map_err(|_| ExpansionError::LineTooLong),
+    });
+
+    let expanded = match expanded {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            let _ = writeln!(out, "history: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = shell_history::add(&expanded) {
+        let _ = writeln!(out, "history: {:?}", e);
+    }
+
+    execute(out, ctx, &expanded);
+}
+
+/// Parse and run a command line.
+pub fn execute(out: &mut impl Write, ctx: &mut Context, line: &str) {
+    let line = match shell_parser::parse(line) {
+        Ok(line) => line,
+        Err(e) => {
+            let _ = writeln!(out, "syntax error: {:?}", e);
+            return;
+        }
+    };
+
+    let mut terminal: Option<&mut dyn Write> = Some(out);
+    let mut first = 0;
+
+    for (last, command) in line.commands().iter().enumerate() {
+        match command.separator {
+            Separator::Sequence => {
+                run_list(&mut terminal, ctx, &line, first, last);
+            }
+            Separator::Background => start_background_list(&mut terminal, ctx, &line, first, last),
+            _ => continue,
+        }
+
+        first = last + 1;
+    }
+}
+
+/// Run commands from `first` to `last`, which are pipelines separated
+/// by `&&` and `||`. Returns true if the last pipeline which ran
+/// succeeded.
+fn run_list(terminal: &mut Option<&mut dyn Write>, ctx: &mut Context, line: &ParsedLine, first: usize, last: usize) -> bool {
+    let commands = line.commands();
+    let mut success = true;
+    let mut run = true;
+    let mut start = first;
+
+    while start <= last {
+        let end = (start..last).find(|&i| commands[i].separator != Separator::Pipe).unwrap_or(last);
+
+        if run {
+            success = run_pipeline(terminal, ctx, line, start, end);
+        }
+
+        run = match commands[end].separator {
+            Separator::And => success,
+            Separator::Or => !success,
+            _ => true,
+        };
+
+        start = end + 1;
+    }
+
+    success
+}
+
+/// Start a thread which runs commands from `first` to `last` and print
+/// its thread ID.
+fn start_background_list(terminal: &mut Option<&mut dyn Write>, ctx: &Context, line: &ParsedLine, first: usize, last: usize) {
+    let start = ThreadStart {
+        line,
+        ctx,
+        first,
+        last,
+        input: None,
+        output: None,
+        started: AtomicBool::new(false),
+    };
+
+    let result = start_thread("shell-job", list_thread, &start)
+        .and_then(|thread| scheduler::detach(thread).map(|_| thread));
+
+    let mut io = Io::console(terminal.as_mut().map(|terminal| &mut **terminal as &mut dyn Write), ctx);
+    match result {
+        Ok(thread) => {
+            let _ = writeln!(io, "[{}]", thread);
+        }
+        Err(e) => {
+            let _ = writeln!(io, "{}: {:?}", line.name(&line.commands()[first]), e);
+        }
+    }
+}
+
+/// Arguments of a command thread. The thread which starts a command
+/// thread waits until `started` is true, so the references stay valid
+/// until then.
+struct ThreadStart<'a> {
+    line: &'a ParsedLine,
+    /// Context with the working directory of the command.
+    ctx: &'a Context,
+    first: usize,
+    last: usize,
+    /// Pipe ends for standard input and output.
+    input: Option<(&'a Context, FileDescriptor)>,
+    output: Option<(&'a Context, FileDescriptor)>,
+    started: AtomicBool,
+}
+
+fn start_thread(name: &'static str, function: fn(usize) -> usize, start: &ThreadStart) -> Result<ThreadId, scheduler::ThreadError> {
+    let thread = scheduler::spawn(name, Priority::Normal, function, start as *const ThreadStart as usize)?;
+    COMMAND_THREAD_STARTED.wait_until(|| start.started.load(Ordering::SeqCst));
+    Ok(thread)
+}
+
+fn list_thread(argument: usize) -> usize {
+    let start = unsafe { &*(argument as *const ThreadStart) };
+    let line = start.line.clone();
+    let mut ctx = start.ctx.without_files();
+    let (first, last) = (start.first, start.last);
+    start.started.store(true, Ordering::SeqCst);
+    COMMAND_THREAD_STARTED.wake_all();
+
+    run_list(&mut None, &mut ctx, &line, first, last) as usize
+}
+
+/// Thread of a pipeline command. The line stays valid until the thread
+/// exits, because the thread which started it waits for it.
+fn pipeline_command_thread(argument: usize) -> usize {
+    let start = unsafe { &*(argument as *const ThreadStart) };
+    let line = start.line;
+    let command = &line.commands()[start.first];
+    let io = open_io(None, start.ctx, line, command, start.input, start.output);
+    start.started.store(true, Ordering::SeqCst);
+    COMMAND_THREAD_STARTED.wake_all();
+
+    match io {
+        Some(mut io) => shell_command::run(&mut io, line.name(command), line.arguments(command)) as usize,
+        None => false as usize,
+    }
+}
+
+/// Run commands from `first` to `last`, which are connected with pipes.
+/// Returns true if the last command succeeded.
+fn run_pipeline(terminal: &mut Option<&mut dyn Write>, ctx: &mut Context, line: &ParsedLine, first: usize, last: usize) -> bool {
+    let commands = line.commands();
+    let terminal = terminal.as_mut().map(|terminal| &mut **terminal as &mut dyn Write);
+
+    if first == last {
+        let command = &commands[first];
+        return match open_io(terminal, ctx, line, command, None, None) {
+            Some(mut io) => {
+                let success = shell_command::run(&mut io, line.name(command), line.arguments(command));
+                ctx.copy_current_directory(&io.ctx);
+                success
+            }
+            None => false,
+        };
+    }
+
+    let vfs = vfs::vfs();
+    // Errors are printed with this.
+    let mut io = Io::console(terminal, ctx);
+    // Pipe ends which are open while the commands are started.
+    let mut pipes = ctx.without_files();
+    let mut threads: ArrayVec<[ThreadId; MAX_COMMANDS]> = ArrayVec::new();
+    let mut input = None;
+
+    for (index, command) in commands.iter().enumerate().take(last + 1).skip(first) {
+        let pipe = if index < last {
+            match pipe::create(&mut pipes) {
+                Ok(pipe) => Some(pipe),
+                Err(e) => {
+                    let _ = writeln!(io, "{}: {:?}", line.name(command), e);
+                    break;
+                }
+            }
+        } else {
+            None
+        };
+
+        let start = ThreadStart {
+            line,
+            ctx,
+            first: index,
+            last: index,
+            input: input.map(|fd| (&pipes, fd)),
+            output: pipe.map(|(_, write_fd)| (&pipes, write_fd)),
+            started: AtomicBool::new(false),
+        };
+
+        let result = start_thread("shell-pipeline", pipeline_command_thread, &start);
+
+        // Commands have their own copies of the pipe ends, so a reader
+        // gets end of file when its writer exits.
+        if let Some(fd) = input {
+            let _ = vfs.close(&mut pipes, fd);
+        }
+        if let Some((_, write_fd)) = pipe {
+            let _ = vfs.close(&mut pipes, write_fd);
+        }
+        input = pipe.map(|(read_fd, _)| read_fd);
+
+        match result {
+            Ok(thread) => threads.push(thread),
+            Err(e) => {
+                let _ = writeln!(io, "{}: {:?}", line.name(command), e);
+                break;
+            }
+        }
+    }
+
+    vfs.close_all(&mut pipes);
+
+    let mut success = false;
+    for (i, &thread) in threads.iter().enumerate() {
+        let status = scheduler::join(thread).unwrap_or(0);
+        if i == last - first {
+            success = status != 0;
+        }
+    }
+
+    success
+}
+
+/// Open standard streams of `command`. Redirections override the pipe
+/// ends `input` and `output`, and without either a stream is the
+/// console. If a file can't be opened, an error is printed and `None`
+/// is returned.
+fn open_io<'a>(
+    terminal: Option<&'a mut dyn Write>,
+    ctx: &Context,
+    line: &ParsedLine,
+    command: &Command,
+    input: Option<(&Context, FileDescriptor)>,
+    output: Option<(&Context, FileDescriptor)>,
+) -> Option<Io<'a>> {
+    let mut files = ctx.without_files();
+
+    match open_streams(&mut files, line, command, input, output) {
+        Ok(console_output) => Some(Io {
+            terminal,
+            console_output,
+            ctx: files,
+        }),
+        Err(e) => {
+            vfs::vfs().close_all(&mut files);
+            let mut io = Io::console(terminal, ctx);
+            let _ = writeln!(io, "{}: {:?}", line.name(command), e);
+            None
+        }
+    }
+}
+
+/// Returns true if standard output is the console.
+fn open_streams(
+    files: &mut Context,
+    line: &ParsedLine,
+    command: &Command,
+    input: Option<(&Context, FileDescriptor)>,
+    output: Option<(&Context, FileDescriptor)>,
+) -> Result<bool, FsError> {
+    let vfs = vfs::vfs();
+
+    // Files are opened in order, so they get descriptors 0, 1 and 2.
+    match (line.input(command), input) {
+        (Some(path), _) => {
+            vfs.open(files, path, OpenFlags::READ)?;
+        }
+        (None, Some((pipes, fd))) => vfs.duplicate_into(pipes, fd, files, STDIN)?,
+        (None, None) => {
+            vfs.open(files, CONSOLE_PATH, OpenFlags::READ)?;
+        }
+    }
+
+    let console_output = match (line.output(command), output) {
+        (Some((path, append)), _) => {
+            let mode = if append { OpenFlags::APPEND } else { OpenFlags::TRUNCATE };
+            vfs.open(files, path, OpenFlags::WRITE | OpenFlags::CREATE | mode)?;
+            false
+        }
+        (None, Some((pipes, fd))) => {
+            vfs.duplicate_into(pipes, fd, files, STDOUT)?;
+            false
+        }
+        (None, None) => {
+            vfs.open(files, CONSOLE_PATH, OpenFlags::WRITE)?;
+            true
+        }
+    };
+
+    vfs.open(files, CONSOLE_PATH, OpenFlags::WRITE)?;
+
+    Ok(console_output)
+}
+
+static COMMANDS: [Builtin; 15] = [
+    Builtin {
+        name: "echo",
+        usage: "[-e] [words]",
+        help: "Print arguments, -e handles backslash escapes",
+        run: echo,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "reboot",
+        usage: "",
+        help: "Reset the computer",
+        run: reboot,
+        complete: ArgumentCompletion::None,
+    },
+    Builtin {
+        name: "ls",
+        usage: "[path]",
+        help: "List a directory",
+        run: ls,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "cat",
+        usage: "[paths]",
+        help: "Print files or standard input",
+        run: cat,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "cd",
+        usage: "[path]",
+        help: "Change the working directory",
+        run: cd,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "pwd",
+        usage: "",
+        help: "Print the working directory",
+        run: pwd,
+        complete: ArgumentCompletion::None,
+    },
+    Builtin {
+        name: "mount",
+        usage: "[<type> <path> [source]]",
+        help: "Mount a filesystem or list mounts",
+        run: mount,
+        complete: ArgumentCompletion::Function(complete_mount),
+    },
+    Builtin {
+        name: "stat",
+        usage: "<paths>",
+        help: "Print file metadata",
+        run: stat,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "mkdir",
+        usage: "<paths>",
+        help: "Create directories",
+        run: mkdir,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "rm",
+        usage: "<paths>",
+        help: "Remove files and empty directories",
+        run: rm,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "touch",
+        usage: "<paths>",
+        help: "Create empty files",
+        run: touch,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "write",
+        usage: "<path> [text]",
+        help: "Replace file contents with text",
+        run: write,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "sync",
+        usage: "",
+        help: "Write cached data to disks",
+        run: sync,
+        complete: ArgumentCompletion::None,
+    },
+    Builtin {
+        name: "run",
+        usage: "<path> [args]",
+        help: "Run an ELF executable in user mode",
+        run: run_program,
+        complete: ArgumentCompletion::Paths,
+    },
+    Builtin {
+        name: "usertest",
+        usage: "[fault]",
+        help: "Run the built-in user mode test program",
+        run: usertest,
+        complete: ArgumentCompletion::Words(&["fault"]),
+    },
+];
+
+pub fn register_commands() -> Result<(), RegistryError> {
+    shell_command::register_builtins(&COMMANDS)
+}
+
+/// Complete filesystem types and paths.
+fn complete_mount(argument: usize, completions: &mut Completions) {
+    if argument == 0 {
+        for name in vfs::vfs().file_system_types() {
+            completions.add(name, false);
+        }
+    } else {
+        completions.add_paths();
+    }
+}
+
+/// Print arguments separated by spaces.
+fn echo(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let mut args = args.peekable();
+    let escapes = args.peek() == Some(&"-e");
+    if escapes {
+        args.next();
+    }
+
+    for (i, arg) in args.enumerate() {
+        let separator = if i == 0 { "" } else { " " };
+        let _ = write!(io, "{}", separator);
+        if escapes {
+            write_escaped(io, arg);
+        } else {
+            let _ = write!(io, "{}", arg);
+        }
+    }
+    let _ = writeln!(io);
+    Ok(())
+}
+
+/// Write text with `\e`, `\n`, `\t` and `\\` replaced with the
+/// characters. Other backslashes are written as is.
+fn write_escaped(io: &mut Io, text: &str) {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let escaped = match c {
+            '\\' => match chars.clone().next() {
+                Some('e') => Some(ansi::ESCAPE),
+                Some('n') => Some('\n'),
+                Some('t') => Some('\t'),
+                Some('\\') => Some('\\'),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let _ = match escaped {
+            Some(escaped) => {
+                chars.next();
+                io.write_char(escaped)
+            }
+            None => io.write_char(c),
+        };
+    }
+}
+
+fn reboot(args: Arguments, _io: &mut Io) -> Result<(), CommandError> {
+    args.end()?;
+    input::reset_cpu();
+    Ok(())
+}
+
+fn ls(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let path = args.next().unwrap_or(".");
+    args.end()?;
+    let vfs = vfs::vfs();
+
+    let metadata = vfs.stat(&io.ctx, path)?;
+    if metadata.file_type != FileType::Directory {
+        let _ = writeln!(io, "{}", path);
+        return Ok(());
+    }
+
+    let fd = vfs.open(&mut io.ctx, path, OpenFlags::READ | OpenFlags::DIRECTORY)?;
+
+    loop {
+        match vfs.read_dir(&mut io.ctx, fd) {
+            Ok(Some(entry)) => {
+                let suffix = match entry.file_type {
+                    FileType::Directory => "/",
+                    FileType::Symlink => "@",
+                    FileType::Pipe => "|",
+                    _ => "",
+                };
+                let _ = writeln!(io, "{}{}", entry.name, suffix);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                let _ = vfs.close(&mut io.ctx, fd);
+                return Err(e.into());
+            }
+        }
+    }
+
+    Ok(vfs.close(&mut io.ctx, fd)?)
+}
+
+/// Print files, or standard input without arguments.
+fn cat(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let vfs = vfs::vfs();
+    let mut buffer = [0u8; 512];
+    let mut decoder = Utf8Decoder::new();
+    let mut args = args.peekable();
+
+    if args.peek().is_none() {
+        loop {
+            match io.read(&mut buffer)? {
+                0 => return Ok(()),
+                count => write_bytes(io, &mut decoder, &buffer[..count]),
+            }
+        }
+    }
+
+    for path in args {
+        let fd = vfs.open(&mut io.ctx, path, OpenFlags::READ)?;
+
+        loop {
+            match vfs.read(&mut io.ctx, fd, &mut buffer) {
+                Ok(0) => break,
+                Ok(count) => write_bytes(io, &mut decoder, &buffer[..count]),
+                Err(e) => {
+                    let _ = vfs.close(&mut io.ctx, fd);
+                    return Err(e.into());
+                }
+            }
+        }
+
+        vfs.close(&mut io.ctx, fd)?;
+    }
+
+    Ok(())
+}
+
+fn cd(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let path = args.next().unwrap_or("/");
+    args.end()?;
+    Ok(vfs::vfs().change_directory(&mut io.ctx, path)?)
+}
+
+fn pwd(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    args.end()?;
+    let mut directory = vfs::PathBuf::new();
+    directory.push_str(io.ctx.current_directory());
+    let _ = writeln!(io, "{}", directory);
+    Ok(())
+}
+
+/// Without arguments list mounts, otherwise mount a filesystem.
+fn mount(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let vfs = vfs::vfs();
+
+    match args.next() {
+        Some(fs_type) => {
+            let path = match args.next() {
+                Some(path) => path,
+                None => {
+                    let _ = write!(io, "Filesystem types:");
+                    for name in vfs.file_system_types() {
+                        let _ = write!(io, " {}", name);
+                    }
+                    let _ = writeln!(io);
+                    return Err(CommandError::Usage);
+                }
+            };
+            let source = args.next().unwrap_or("none");
+            args.end()?;
+            Ok(vfs.mount(&io.ctx, fs_type, path, source)?)
+        }
+        None => {
+            for mount in vfs.mounts() {
+                let _ = writeln!(io, "{} on {} type {}", mount.source, mount.path, mount.file_system_type());
+            }
+            Ok(())
+        }
+    }
+}
+
+fn stat(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let vfs = vfs::vfs();
+
+    if args.clone().next().is_none() {
+        return Err(CommandError::Usage);
+    }
+
+    for path in args {
+        let metadata = vfs.lstat(&io.ctx, path)?;
+
+        let _ = writeln!(io, "  File: {}", path);
+        let _ = writeln!(io, "  Type: {:?}", metadata.file_type);
+        let _ = writeln!(io, "  Size: {}", metadata.size);
+        let _ = writeln!(io, " Inode: {}  Links: {}", metadata.inode, metadata.links);
+        let _ = writeln!(io, "Access: {:04o}  Uid: {}  Gid: {}", metadata.mode, metadata.uid, metadata.gid);
+        let _ = writeln!(io, "Modify: {}", metadata.modified);
+
+        if metadata.file_type == FileType::Symlink {
+            let mut target = vfs::PathBuf::new();
+            vfs.read_link(&io.ctx, path, &mut target)?;
+            let _ = writeln!(io, "Target: {}", target);
+        }
+    }
+
+    Ok(())
+}
+
+fn mkdir(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    for_each_path(args, io, |ctx, path| vfs::vfs().create_directory(ctx, path))
+}
+
+fn rm(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    for_each_path(args, io, |ctx, path| vfs::vfs().remove(ctx, path))
+}
+
+/// Create empty files if they don't exist.
+fn touch(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    for_each_path(args, io, |ctx, path| {
+        let vfs = vfs::vfs();
+        let fd = vfs.open(ctx, path, OpenFlags::WRITE | OpenFlags::CREATE)?;
+        vfs.close(ctx, fd)
+    })
+}
+
+/// Run `function` for every argument. At least one argument is
+/// required.
+fn for_each_path(args: Arguments, io: &mut Io, mut function: impl FnMut(&mut Context, &str) -> Result<(), FsError>) -> Result<(), CommandError> {
+    if args.clone().next().is_none() {
+        return Err(CommandError::Usage);
+    }
+
+    for path in args {
+        function(&mut io.ctx, path)?;
+    }
+
+    Ok(())
+}
+
+/// Replace file contents with text and a newline.
+fn write(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let path = args.required()?;
+    let vfs = vfs::vfs();
+    let ctx = &mut io.ctx;
+
+    let fd = vfs.open(ctx, path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+
+    let mut result = Ok(());
+    for (i, word) in args.enumerate() {
+        let separator: &[u8] = if i == 0 { b"" } else { b" " };
+        result = write_all(ctx, fd, separator).and_then(|_| write_all(ctx, fd, word.as_bytes()));
+        if result.is_err() {
+            break;
+        }
+    }
+    let result = result.and_then(|_| write_all(ctx, fd, b"\n"));
+
+    vfs.close(ctx, fd)?;
+    Ok(result?)
+}
+
+/// Write all of `data` to a file.
+pub fn write_all(ctx: &mut Context, fd: vfs::FileDescriptor, mut data: &[u8]) -> Result<(), FsError> {
+    while !data.is_empty() {
+        match vfs::vfs().write(ctx, fd, data)? {
+            0 => return Err(FsError::NoSpace),
+            count => data = &data[count..],
+        }
+    }
+
+    Ok(())
+}
+
+fn sync(args: Arguments, _io: &mut Io) -> Result<(), CommandError> {
+    args.end()?;
+    Ok(vfs::vfs().sync()?)
+}
+
+/// Run the built-in user mode test program. Argument `fault` selects
+/// a program which causes a page fault.
+fn usertest(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let fault = match args.next() {
+        Some("fault") => true,
+        Some(_) => return Err(CommandError::InvalidArgument),
+        None => false,
+    };
+    args.end()?;
+
+    let result = usermode::run_test_program(fault);
+
+    print_exit_reason(io, result?)
+}
+
+/// Run an ELF executable in user mode with the standard streams of the
+/// command.
+fn run_program(args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let arguments: ArrayVec<[&str; elf::MAX_ARGUMENTS]> = args.take(elf::MAX_ARGUMENTS).collect();
+
+    if arguments.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let pid = elf::spawn(&mut io.ctx, arguments[0], &arguments, &[])?;
+    let reason = io.wait(pid)?;
+    print_exit_reason(io, reason)
+}
+
+/// Print the reason unless the task exited with status 0.
+fn print_exit_reason(out: &mut impl Write, reason: ExitReason) -> Result<(), CommandError> {
+    match reason {
+        ExitReason::Exit(0) => return Ok(()),
+        ExitReason::Exit(status) => {
+            let _ = writeln!(out, "User task exited with status {}", status);
+        }
+        reason => {
+            let _ = writeln!(out, "User task killed: {:?}", reason);
+        }
+    }
+
+    Err(CommandError::Failed)
+}
+
+/// Write file contents, which are UTF-8.
+fn write_bytes(out: &mut impl Write, decoder: &mut Utf8Decoder, bytes: &[u8]) {
+    decoder.push_bytes(bytes, |c| {
+        let _ = out.write_char(c);
+    });
+}
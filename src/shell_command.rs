@@ -0,0 +1,263 @@
+//! Shell command registry.
+//!
+//! Commands implement `ShellCommand` and subsystems add their commands
+//! with `register` during boot. Most commands are functions, which
+//! `Builtin` wraps. Commands also complete their arguments. Errors which
+//! commands return are printed in the same format for every command, and
+//! `CommandError::Usage` prints the usage of the command.
+
+use core::fmt::{self, Write};
+
+use arrayvec::ArrayVec;
+
+use crate::elf::ElfError;
+use crate::process::ProcessError;
+use crate::scheduler::ThreadError;
+use crate::shell::Io;
+use crate::shell_completion::{ArgumentCompletion, Completions};
+use crate::shell_parser::Arguments;
+use crate::swap::SwapError;
+use crate::sync::IrqSpinlock;
+use crate::vfs::FsError;
+use crate::vga_font::FontError;
+
+pub const MAX_SHELL_COMMANDS: usize = 48;
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// Missing or extra arguments.
+    Usage,
+    /// Argument couldn't be parsed.
+    InvalidArgument,
+    /// Command has printed an error message already.
+    Failed,
+    Fs(FsError),
+    Process(ProcessError),
+    Elf(ElfError),
+    Thread(ThreadError),
+    Swap(SwapError),
+    Font(FontError),
+}
+
+impl From<FsError> for CommandError {
+    fn from(error: FsError) -> Self {
+        CommandError::Fs(error)
+    }
+}
+
+impl From<ProcessError> for CommandError {
+    fn from(error: ProcessError) -> Self {
+        CommandError::Process(error)
+    }
+}
+
+impl From<ElfError> for CommandError {
+    fn from(error: ElfError) -> Self {
+        CommandError::Elf(error)
+    }
+}
+
+impl From<ThreadError> for CommandError {
+    fn from(error: ThreadError) -> Self {
+        CommandError::Thread(error)
+    }
+}
+
+impl From<SwapError> for CommandError {
+    fn from(error: SwapError) -> Self {
+        CommandError::Swap(error)
+    }
+}
+
+impl From<FontError> for CommandError {
+    fn from(error: FontError) -> Self {
+        CommandError::Font(error)
+    }
+}
+
+/// Error without the variant name, for example `NotFound` instead of
+/// `Fs(NotFound)`.
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Usage => write!(f, "invalid usage"),
+            CommandError::InvalidArgument => write!(f, "invalid argument"),
+            CommandError::Failed => write!(f, "failed"),
+            CommandError::Fs(e) => write!(f, "{:?}", e),
+            CommandError::Process(e) => write!(f, "{:?}", e),
+            CommandError::Elf(e) => write!(f, "{:?}", e),
+            CommandError::Thread(e) => write!(f, "{:?}", e),
+            CommandError::Swap(e) => write!(f, "{:?}", e),
+            CommandError::Font(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    TooManyCommands,
+    NameInUse,
+}
+
+pub trait ShellCommand: Sync {
+    fn name(&self) -> &'static str;
+
+    /// One line description for `help`.
+    fn help(&self) -> &'static str;
+
+    /// Arguments of the command, for example `<path> [text]`.
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    fn run(&self, args: Arguments, io: &mut Io) -> Result<(), CommandError>;
+
+    /// Add completions of argument `argument` to `completions`. Paths
+    /// are completed by default.
+    fn complete(&self, _argument: usize, completions: &mut Completions) {
+        completions.add_paths();
+    }
+}
+
+/// Command which is a function.
+pub struct Builtin {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(Arguments, &mut Io) -> Result<(), CommandError>,
+    pub complete: ArgumentCompletion,
+}
+
+impl ShellCommand for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn usage(&self) -> &'static str {
+        self.usage
+    }
+
+    fn run(&self, args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+        (self.run)(args, io)
+    }
+
+    fn complete(&self, argument: usize, completions: &mut Completions) {
+        self.complete.complete(argument, completions);
+    }
+}
+
+type CommandList = ArrayVec<[&'static dyn ShellCommand; MAX_SHELL_COMMANDS]>;
+
+static COMMANDS: IrqSpinlock<Option<CommandList>> = IrqSpinlock::new("shell commands", None);
+
+static HELP_COMMAND: Builtin = Builtin {
+    name: "help",
+    usage: "[command]",
+    help: "List commands or show usage of a command",
+    run: help,
+    complete: ArgumentCompletion::Commands,
+};
+
+/// Command list which contains at least `help`.
+fn command_list(commands: &mut Option<CommandList>) -> &mut CommandList {
+    commands.get_or_insert_with(|| {
+        let mut commands = ArrayVec::new();
+        commands.push(&HELP_COMMAND as &dyn ShellCommand);
+        commands
+    })
+}
+
+pub fn register(command: &'static dyn ShellCommand) -> Result<(), RegistryError> {
+    let mut commands = COMMANDS.lock();
+    let commands = command_list(&mut commands);
+
+    if commands.iter().any(|registered| registered.name() == command.name()) {
+        return Err(RegistryError::NameInUse);
+    }
+
+    commands.try_push(command).map_err(|_| RegistryError::TooManyCommands)
+}
+
+pub fn register_builtins(commands: &'static [Builtin]) -> Result<(), RegistryError> {
+    for command in commands {
+        register(command)?;
+    }
+
+    Ok(())
+}
+
+pub fn find(name: &str) -> Option<&'static dyn ShellCommand> {
+    let mut commands = COMMANDS.lock();
+    command_list(&mut commands).iter().find(|command| command.name() == name).cloned()
+}
+
+/// Registered commands sorted by name.
+pub fn commands() -> CommandList {
+    let mut commands = command_list(&mut COMMANDS.lock()).clone();
+    commands.sort_unstable_by_key(|command| command.name());
+    commands
+}
+
+/// Run a command and print its error. Returns true if the command
+/// succeeded.
+pub fn run(io: &mut Io, name: &str, args: Arguments) -> bool {
+    let command = match find(name) {
+        Some(command) => command,
+        None => {
+            let _ = writeln!(io, "Unknown command '{}'", name);
+            return false;
+        }
+    };
+
+    match command.run(args, io) {
+        Ok(()) => true,
+        Err(CommandError::Failed) => false,
+        Err(CommandError::Usage) => {
+            print_usage(io, command);
+            false
+        }
+        Err(e @ CommandError::InvalidArgument) => {
+            let _ = writeln!(io, "{}: {}", name, e);
+            print_usage(io, command);
+            false
+        }
+        Err(e) => {
+            let _ = writeln!(io, "{}: {}", name, e);
+            false
+        }
+    }
+}
+
+fn print_usage(out: &mut impl Write, command: &dyn ShellCommand) {
+    let _ = writeln!(out, "usage: {} {}", command.name(), command.usage());
+}
+
+fn help(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let name = args.next();
+    args.end()?;
+
+    match name {
+        Some(name) => {
+            let command = match find(name) {
+                Some(command) => command,
+                None => {
+                    let _ = writeln!(io, "help: unknown command '{}'", name);
+                    return Err(CommandError::Failed);
+                }
+            };
+            print_usage(io, command);
+            let _ = writeln!(io, "{}", command.help());
+        }
+        None => {
+            for command in commands() {
+                let _ = writeln!(io, "{:<10} {}", command.name(), command.help());
+            }
+        }
+    }
+
+    Ok(())
+}
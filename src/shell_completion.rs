@@ -0,0 +1,207 @@
+//! Tab completion of the shell command line.
+//!
+//! The word before the cursor is completed. First word of a command is
+//! a command name from the registry, a word after a redirection is a
+//! path, and other words are completed by the command, which completes
+//! paths by default. Words end at whitespace and operators, quotes are
+//! not handled.
+
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::shell_command;
+use crate::shell_parser::MAX_LINE_LENGTH;
+use crate::vfs::{self, Context, FileType, OpenFlags, PATH_MAX_LENGTH};
+
+/// How many matches are kept for listing.
+pub const MAX_LISTED_MATCHES: usize = 32;
+
+pub type Match = ArrayString<[u8; PATH_MAX_LENGTH]>;
+
+/// Completion of a command argument.
+#[derive(Copy, Clone)]
+pub enum ArgumentCompletion {
+    None,
+    Paths,
+    Commands,
+    Words(&'static [&'static str]),
+    /// Function which gets the argument index and adds matches.
+    Function(fn(usize, &mut Completions)),
+}
+
+impl ArgumentCompletion {
+    pub fn complete(self, argument: usize, completions: &mut Completions) {
+        match self {
+            ArgumentCompletion::None => (),
+            ArgumentCompletion::Paths => completions.add_paths(),
+            ArgumentCompletion::Commands => {
+                for command in shell_command::commands() {
+                    completions.add(command.name(), false);
+                }
+            }
+            ArgumentCompletion::Words(words) => {
+                for word in words {
+                    completions.add(word, false);
+                }
+            }
+            ArgumentCompletion::Function(function) => function(argument, completions),
+        }
+    }
+}
+
+/// Matches for the word which is completed.
+pub struct Completions<'a> {
+    ctx: &'a Context,
+    word: &'a str,
+    /// Longest common prefix of the matches.
+    common: ArrayString<[u8; MAX_LINE_LENGTH]>,
+    /// Matches without the directory part of the word.
+    listed: ArrayVec<[Match; MAX_LISTED_MATCHES]>,
+    count: usize,
+    /// The only match is a directory.
+    directory: bool,
+}
+
+impl<'a> Completions<'a> {
+    /// Working directory for path completion.
+    pub fn ctx(&self) -> &Context {
+        self.ctx
+    }
+
+    /// The word which is completed.
+    pub fn word(&self) -> &str {
+        self.word
+    }
+
+    /// Add a match if it starts with the word. Directories don't get a
+    /// space after the completion.
+    pub fn add(&mut self, candidate: &str, directory: bool) {
+        if !candidate.starts_with(self.word) {
+            return;
+        }
+
+        if self.count == 0 {
+            self.common.clear();
+            let _ = self.common.try_push_str(candidate);
+        } else {
+            let length = self.common.char_indices()
+                .zip(candidate.chars())
+                .find(|&((_, a), b)| a != b)
+                .map(|((i, _), _)| i)
+                .unwrap_or_else(|| self.common.len().min(candidate.len()));
+            self.common.truncate(length);
+        }
+
+        let name_start = self.word.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let mut listed = Match::new();
+        let _ = listed.try_push_str(&candidate[name_start..]);
+        if directory {
+            let _ = listed.try_push('/');
+        }
+        let _ = self.listed.try_push(listed);
+
+        self.count += 1;
+        self.directory = directory;
+    }
+
+    /// Add files of the directory in the word.
+    pub fn add_paths(&mut self) {
+        let (directory, name_start) = match self.word.rfind('/') {
+            Some(0) => ("/", 1),
+            Some(i) => (&self.word[..i], i + 1),
+            None => (".", 0),
+        };
+
+        let vfs = vfs::vfs();
+        let mut ctx = self.ctx.without_files();
+        let fd = match vfs.open(&mut ctx, directory, OpenFlags::READ | OpenFlags::DIRECTORY) {
+            Ok(fd) => fd,
+            Err(_) => return,
+        };
+
+        let show_hidden = self.word[name_start..].starts_with('.');
+
+        while let Ok(Some(entry)) = vfs.read_dir(&mut ctx, fd) {
+            if entry.name.starts_with('.') && !show_hidden {
+                continue;
+            }
+
+            let mut candidate = Match::new();
+            if candidate.try_push_str(&self.word[..name_start]).is_err() || candidate.try_push_str(&entry.name).is_err() {
+                continue;
+            }
+
+            self.add(&candidate, entry.file_type == FileType::Directory);
+        }
+
+        let _ = vfs.close(&mut ctx, fd);
+    }
+
+    /// Number of matches.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Text which replaces the word. A single match which is not a
+    /// directory is followed by a space.
+    pub fn completion(&self) -> Option<Match> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mut completion = Match::new();
+        let _ = completion.try_push_str(&self.common);
+        if self.count == 1 {
+            let _ = completion.try_push(if self.directory { '/' } else { ' ' });
+        }
+        Some(completion)
+    }
+
+    /// Matches for listing, at most `MAX_LISTED_MATCHES`.
+    pub fn matches(&self) -> &[Match] {
+        &self.listed
+    }
+}
+
+fn is_operator(c: char) -> bool {
+    match c {
+        '|' | '&' | ';' | '<' | '>' => true,
+        _ => false,
+    }
+}
+
+/// Complete the last word of `line`, which is the text before the
+/// cursor. Returns the byte offset where the word starts and the matches.
+pub fn complete<'a>(ctx: &'a Context, line: &'a str) -> (usize, Completions<'a>) {
+    let word_start = line.rfind(|c: char| c.is_whitespace() || is_operator(c)).map(|i| i + 1).unwrap_or(0);
+    let word = &line[word_start..];
+
+    // Words of the command before the completed word.
+    let command_start = line[..word_start].rfind(|c| c == '|' || c == '&' || c == ';').map(|i| i + 1).unwrap_or(0);
+    let before = &line[command_start..word_start];
+    let after_redirection = before.trim_end().ends_with(|c| c == '<' || c == '>');
+
+    let mut words = before.split(|c: char| c.is_whitespace() || c == '<' || c == '>').filter(|word| !word.is_empty());
+    let command = words.next();
+    let argument = words.count();
+
+    let mut completions = Completions {
+        ctx,
+        word,
+        common: ArrayString::new(),
+        listed: ArrayVec::new(),
+        count: 0,
+        directory: false,
+    };
+
+    match command {
+        _ if after_redirection => completions.add_paths(),
+        None => ArgumentCompletion::Commands.complete(0, &mut completions),
+        Some(name) => {
+            if let Some(command) = shell_command::find(name) {
+                command.complete(argument, &mut completions);
+            }
+        }
+    }
+
+    (word_start, completions)
+}
@@ -0,0 +1,268 @@
+//! Shell command history.
+//!
+//! History keeps the last `HISTORY_SIZE` command lines. Lines are
+//! numbered from 1 and the numbers don't change when old lines are
+//! dropped. `!!` expands to the previous line and `!n` to line `n`,
+//! except inside single quotes.
+//!
+//! `history -f <path>` loads history from a file and appends every new
+//! line to it, so history persists on a writable disk filesystem. A
+//! relative path is resolved against the working directory of the shell
+//! when `-f` is given.
+
+use core::fmt::Write;
+
+use arraydeque::{ArrayDeque, Wrapping};
+use arrayvec::ArrayString;
+
+use crate::shell::{self, Io};
+use crate::shell_command::{self, Builtin, CommandError, RegistryError};
+use crate::shell_completion::{ArgumentCompletion, Completions};
+use crate::shell_parser::{Arguments, MAX_LINE_LENGTH};
+use crate::sync::IrqSpinlock;
+use crate::utf8::Utf8Decoder;
+use crate::vfs::{self, Context, FsError, OpenFlags, PathBuf};
+
+pub const HISTORY_SIZE: usize = 64;
+
+pub type HistoryLine = ArrayString<[u8; MAX_LINE_LENGTH]>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExpansionError {
+    /// Line with the number doesn't exist.
+    EventNotFound,
+    LineTooLong,
+}
+
+struct History {
+    lines: ArrayDeque<[HistoryLine; HISTORY_SIZE], Wrapping>,
+    /// Number of the first line in `lines`.
+    first: usize,
+    /// File where new lines are appended.
+    file: Option<PathBuf>,
+}
+
+impl History {
+    fn new() -> Self {
+        Self {
+            lines: ArrayDeque::new(),
+            first: 1,
+            file: None,
+        }
+    }
+
+    /// Number of the next line.
+    fn end(&self) -> usize {
+        self.first + self.lines.len()
+    }
+
+    fn get(&self, number: usize) -> Option<HistoryLine> {
+        number.checked_sub(self.first).and_then(|i| self.lines.get(i)).cloned()
+    }
+
+    fn push(&mut self, line: &str) {
+        let mut history_line = HistoryLine::new();
+        for c in line.chars() {
+            if history_line.try_push(c).is_err() {
+                break;
+            }
+        }
+
+        if self.lines.push_back(history_line).is_some() {
+            self.first += 1;
+        }
+    }
+}
+
+static HISTORY: IrqSpinlock<Option<History>> = IrqSpinlock::new("shell history", None);
+
+fn with_history<T>(function: impl FnOnce(&mut History) -> T) -> T {
+    let mut history = HISTORY.lock();
+    function(history.get_or_insert_with(History::new))
+}
+
+/// Add a line to the history. Empty lines and lines which are the
+/// same as the previous line are skipped. If there is a history file,
+/// the line is appended to it.
+pub fn add(line: &str) -> Result<(), FsError> {
+    let line = line.trim();
+
+    let file = with_history(|history| {
+        let previous = history.end() - 1;
+        if line.is_empty() || history.get(previous).map(|previous| previous.as_str() == line).unwrap_or(false) {
+            return None;
+        }
+
+        history.push(line);
+        history.file
+    });
+
+    match file {
+        Some(path) => append_to_file(&path, line),
+        None => Ok(()),
+    }
+}
+
+/// Returns the line with `number`.
+pub fn get(number: usize) -> Option<HistoryLine> {
+    with_history(|history| history.get(number))
+}
+
+/// Returns the numbers of the first line and the next line.
+pub fn range() -> (usize, usize) {
+    with_history(|history| (history.first, history.end()))
+}
+
+/// Find the newest line which is older than line `before` and contains
+/// `pattern`.
+pub fn search(pattern: &str, before: usize) -> Option<(usize, HistoryLine)> {
+    with_history(|history| {
+        (history.first..before.min(history.end()))
+            .rev()
+            .filter_map(|number| history.get(number).map(|line| (number, line)))
+            .find(|(_, line)| line.contains(pattern))
+    })
+}
+
+/// Expand `!!` and `!n`. Returns `None` if the line doesn't contain
+/// history references.
+pub fn expand(line: &str) -> Result<Option<HistoryLine>, ExpansionError> {
+    let mut expanded = HistoryLine::new();
+    let mut chars = line.char_indices().peekable();
+    let mut single_quote = false;
+    let mut expansion = false;
+
+    while let Some((i, c)) = chars.next() {
+        let reference = match (c, chars.peek()) {
+            ('\'', _) => {
+                single_quote = !single_quote;
+                None
+            }
+            ('!', Some(&(_, '!'))) if !single_quote => {
+                chars.next();
+                Some(range().1 - 1)
+            }
+            ('!', Some(&(_, digit))) if !single_quote && digit.is_ascii_digit() => {
+                let digits = &line[i + 1..];
+                let length = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| digits.len());
+                for _ in 0..length {
+                    chars.next();
+                }
+                Some(digits[..length].parse().map_err(|_| ExpansionError::EventNotFound)?)
+            }
+            _ => None,
+        };
+
+        match reference {
+            Some(number) => {
+                let history_line = get(number).ok_or(ExpansionError::EventNotFound)?;
+                expanded.try_push_str(&history_line).map_err(|_| ExpansionError::LineTooLong)?;
+                expansion = true;
+            }
+            None => expanded.try_push(c).map_err(|_| ExpansionError::LineTooLong)?,
+        }
+    }
+
+    Ok(if expansion { Some(expanded) } else { None })
+}
+
+fn append_to_file(path: &str, line: &str) -> Result<(), FsError> {
+    let vfs = vfs::vfs();
+    let mut ctx = Context::new();
+    let fd = vfs.open(&mut ctx, path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::APPEND)?;
+    let result = shell::write_all(&mut ctx, fd, line.as_bytes()).and_then(|_| shell::write_all(&mut ctx, fd, b"\n"));
+    vfs.close(&mut ctx, fd)?;
+    result
+}
+
+/// Add lines of a file to the history. Too long lines are truncated.
+fn load(path: &str) -> Result<(), FsError> {
+    let vfs = vfs::vfs();
+    let mut ctx = Context::new();
+    let fd = vfs.open(&mut ctx, path, OpenFlags::READ)?;
+    let mut buffer = [0u8; 512];
+    let mut decoder = Utf8Decoder::new();
+    let mut line = HistoryLine::new();
+
+    let result = loop {
+        let count = match vfs.read(&mut ctx, fd, &mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(count) => count,
+            Err(e) => break Err(e),
+        };
+
+        decoder.push_bytes(&buffer[..count], |c| {
+            if c == '\n' {
+                if !line.is_empty() {
+                    with_history(|history| history.push(&line));
+                }
+                line.clear();
+            } else {
+                let _ = line.try_push(c);
+            }
+        });
+    };
+
+    if !line.is_empty() {
+        with_history(|history| history.push(&line));
+    }
+
+    vfs.close(&mut ctx, fd)?;
+    result
+}
+
+static COMMANDS: [Builtin; 1] = [
+    Builtin {
+        name: "history",
+        usage: "[-c | -f <path>]",
+        help: "List history, clear it or save it to a file",
+        run: history,
+        complete: ArgumentCompletion::Function(complete_history),
+    },
+];
+
+pub fn register_commands() -> Result<(), RegistryError> {
+    shell_command::register_builtins(&COMMANDS)
+}
+
+fn complete_history(argument: usize, completions: &mut Completions) {
+    match argument {
+        0 => ArgumentCompletion::Words(&["-c", "-f"]).complete(argument, completions),
+        _ => completions.add_paths(),
+    }
+}
+
+fn history(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    match args.next() {
+        None => {
+            let (first, end) = range();
+            for number in first..end {
+                if let Some(line) = get(number) {
+                    let _ = writeln!(io, "{:>5}  {}", number, line);
+                }
+            }
+        }
+        Some("-c") => {
+            args.end()?;
+            with_history(|history| {
+                history.first = history.end();
+                history.lines.clear();
+            });
+        }
+        Some("-f") => {
+            let path = args.required()?;
+            args.end()?;
+            let file = io.ctx.absolute_path(path)?;
+
+            match load(&file) {
+                Ok(()) | Err(FsError::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+
+            with_history(|history| history.file = Some(file));
+        }
+        Some(_) => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
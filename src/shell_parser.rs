@@ -0,0 +1,346 @@
+//! Shell command line grammar.
+//!
+//! ```text
+//! line        = [list]
+//! list        = and_or { (";" | "&") and_or } [";" | "&"]
+//! and_or      = pipeline { ("&&" | "||") pipeline }
+//! pipeline    = command { "|" command }
+//! command     = { word | redirection }
+//! redirection = ("<" | ">" | ">>") word
+//! ```
+//!
+//! Command must have at least one word. Words are separated by
+//! whitespace and operators. Characters between single quotes are
+//! literal. Between double quotes a backslash escapes only `"` and `\`,
+//! and outside quotes it escapes any character. Empty quotes are an
+//! empty word.
+//!
+//! Parsed line doesn't borrow the input, so background jobs can keep a
+//! copy of it.
+
+use core::iter::Peekable;
+use core::slice;
+use core::str::{Chars, FromStr};
+
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::shell_command::CommandError;
+
+pub const MAX_LINE_LENGTH: usize = 256;
+pub const MAX_WORDS: usize = 32;
+pub const MAX_COMMANDS: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyntaxError {
+    LineTooLong,
+    UnterminatedQuote,
+    /// Backslash at the end of the line.
+    TrailingBackslash,
+    /// Operator without a command before or after it.
+    MissingCommand,
+    /// Redirection without a file name.
+    MissingFileName,
+    TooManyWords,
+    TooManyCommands,
+}
+
+/// Operator after a command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Separator {
+    /// `|`, output of the command is input of the next command.
+    Pipe,
+    /// `&&`, next pipeline runs if this pipeline succeeds.
+    And,
+    /// `||`, next pipeline runs if this pipeline fails.
+    Or,
+    /// `;` or the end of the line.
+    Sequence,
+    /// `&`, list which ends here runs in the background.
+    Background,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Redirection {
+    Input,
+    Output,
+    Append,
+}
+
+/// Byte range of a word in `ParsedLine::text`.
+#[derive(Debug, Copy, Clone)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Command {
+    /// Range of `ParsedLine::words`. First word is the command name.
+    first_word: usize,
+    end_word: usize,
+    input: Option<Span>,
+    output: Option<Span>,
+    append: bool,
+    pub separator: Separator,
+}
+
+impl Command {
+    fn new(first_word: usize) -> Self {
+        Self {
+            first_word,
+            end_word: first_word,
+            input: None,
+            output: None,
+            append: false,
+            separator: Separator::Sequence,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.first_word == self.end_word && self.input.is_none() && self.output.is_none()
+    }
+}
+
+#[derive(Clone)]
+pub struct ParsedLine {
+    /// Words without quotes and escapes.
+    text: ArrayString<[u8; MAX_LINE_LENGTH]>,
+    words: ArrayVec<[Span; MAX_WORDS]>,
+    commands: ArrayVec<[Command; MAX_COMMANDS]>,
+}
+
+impl ParsedLine {
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn name(&self, command: &Command) -> &str {
+        self.word(self.words[command.first_word])
+    }
+
+    /// Words after the command name.
+    pub fn arguments(&self, command: &Command) -> Arguments {
+        Arguments {
+            text: &self.text,
+            words: self.words[command.first_word + 1..command.end_word].iter(),
+        }
+    }
+
+    /// File from `<` redirection.
+    pub fn input(&self, command: &Command) -> Option<&str> {
+        command.input.map(|span| self.word(span))
+    }
+
+    /// File from `>` or `>>` redirection. Boolean is true for `>>`.
+    pub fn output(&self, command: &Command) -> Option<(&str, bool)> {
+        command.output.map(|span| (self.word(span), command.append))
+    }
+
+    fn word(&self, span: Span) -> &str {
+        &self.text[span.start..span.end]
+    }
+
+    fn push_command(&mut self, mut command: Command, separator: Separator) -> Result<(), SyntaxError> {
+        if command.first_word == command.end_word {
+            return Err(SyntaxError::MissingCommand);
+        }
+
+        command.separator = separator;
+        self.commands.try_push(command).map_err(|_| SyntaxError::TooManyCommands)
+    }
+}
+
+/// Arguments of a command.
+#[derive(Clone)]
+pub struct Arguments<'a> {
+    text: &'a str,
+    words: slice::Iter<'a, Span>,
+}
+
+impl<'a> Arguments<'a> {
+    /// Next argument, or `CommandError::Usage` if there isn't one.
+    pub fn required(&mut self) -> Result<&'a str, CommandError> {
+        self.next().ok_or(CommandError::Usage)
+    }
+
+    /// Parse the next argument, which is required.
+    pub fn number<T: FromStr>(&mut self) -> Result<T, CommandError> {
+        self.required()?.parse().map_err(|_| CommandError::InvalidArgument)
+    }
+
+    /// Returns `CommandError::Usage` if there are arguments left.
+    pub fn end(mut self) -> Result<(), CommandError> {
+        match self.next() {
+            Some(_) => Err(CommandError::Usage),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> Iterator for Arguments<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let text = self.text;
+        self.words.next().map(|span| &text[span.start..span.end])
+    }
+}
+
+pub fn parse(line: &str) -> Result<ParsedLine, SyntaxError> {
+    if line.len() > MAX_LINE_LENGTH {
+        return Err(SyntaxError::LineTooLong);
+    }
+
+    let mut parsed = ParsedLine {
+        text: ArrayString::new(),
+        words: ArrayVec::new(),
+        commands: ArrayVec::new(),
+    };
+
+    let mut tokenizer = Tokenizer { chars: line.chars().peekable() };
+    let mut command = Command::new(0);
+    // Redirection operator which waits for a file name.
+    let mut redirection = None;
+    let mut last_separator = None;
+
+    while let Some(token) = tokenizer.next_token(&mut parsed.text)? {
+        match (token, redirection.take()) {
+            (Token::Word(span), Some(Redirection::Input)) => command.input = Some(span),
+            (Token::Word(span), Some(kind)) => {
+                command.output = Some(span);
+                command.append = kind == Redirection::Append;
+            }
+            (Token::Word(span), None) => {
+                parsed.words.try_push(span).map_err(|_| SyntaxError::TooManyWords)?;
+                command.end_word = parsed.words.len();
+            }
+            (_, Some(_)) => return Err(SyntaxError::MissingFileName),
+            (Token::Redirection(kind), None) => redirection = Some(kind),
+            (Token::Separator(separator), None) => {
+                parsed.push_command(command, separator)?;
+                command = Command::new(parsed.words.len());
+                last_separator = Some(separator);
+            }
+        }
+    }
+
+    if redirection.is_some() {
+        return Err(SyntaxError::MissingFileName);
+    }
+
+    if !command.is_empty() {
+        parsed.push_command(command, Separator::Sequence)?;
+    } else {
+        match last_separator {
+            Some(Separator::Pipe) | Some(Separator::And) | Some(Separator::Or) => return Err(SyntaxError::MissingCommand),
+            _ => (),
+        }
+    }
+
+    Ok(parsed)
+}
+
+enum Token {
+    Word(Span),
+    Separator(Separator),
+    Redirection(Redirection),
+}
+
+struct Tokenizer<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Tokenizer<'_> {
+    /// Returns the next token. Word characters are appended to `text`.
+    fn next_token(&mut self, text: &mut ArrayString<[u8; MAX_LINE_LENGTH]>) -> Result<Option<Token>, SyntaxError> {
+        while self.chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.chars.next();
+        }
+
+        let c = match self.chars.peek() {
+            Some(&c) => c,
+            None => return Ok(None),
+        };
+
+        if !is_operator(c) {
+            return self.word(text).map(Some);
+        }
+
+        self.chars.next();
+
+        let doubled = c != ';' && c != '<' && self.next_if(c);
+
+        let token = match (c, doubled) {
+            ('|', true) => Token::Separator(Separator::Or),
+            ('|', false) => Token::Separator(Separator::Pipe),
+            ('&', true) => Token::Separator(Separator::And),
+            ('&', false) => Token::Separator(Separator::Background),
+            (';', _) => Token::Separator(Separator::Sequence),
+            ('<', _) => Token::Redirection(Redirection::Input),
+            (_, true) => Token::Redirection(Redirection::Append),
+            (_, false) => Token::Redirection(Redirection::Output),
+        };
+
+        Ok(Some(token))
+    }
+
+    fn next_if(&mut self, expected: char) -> bool {
+        if self.chars.peek() == Some(&expected) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn word(&mut self, text: &mut ArrayString<[u8; MAX_LINE_LENGTH]>) -> Result<Token, SyntaxError> {
+        let start = text.len();
+        let mut push = |c| text.try_push(c).map_err(|_| SyntaxError::LineTooLong);
+
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || is_operator(c) {
+                break;
+            }
+
+            self.chars.next();
+
+            match c {
+                '\'' => loop {
+                    match self.chars.next() {
+                        Some('\'') => break,
+                        Some(c) => push(c)?,
+                        None => return Err(SyntaxError::UnterminatedQuote),
+                    }
+                },
+                '"' => loop {
+                    match self.chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match self.chars.peek() {
+                            Some(&escaped) if escaped == '"' || escaped == '\\' => {
+                                self.chars.next();
+                                push(escaped)?;
+                            }
+                            _ => push('\\')?,
+                        },
+                        Some(c) => push(c)?,
+                        None => return Err(SyntaxError::UnterminatedQuote),
+                    }
+                },
+                '\\' => match self.chars.next() {
+                    Some(c) => push(c)?,
+                    None => return Err(SyntaxError::TrailingBackslash),
+                },
+                c => push(c)?,
+            }
+        }
+
+        Ok(Token::Word(Span { start, end: text.len() }))
+    }
+}
+
+fn is_operator(c: char) -> bool {
+    match c {
+        '|' | '&' | ';' | '<' | '>' => true,
+        _ => false,
+    }
+}
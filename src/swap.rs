@@ -0,0 +1,535 @@
+//! Swap space for user pages.
+//!
+//! When free memory runs low, pages of user memory areas are written to
+//! a swap device or a swap file. Pages are selected with the clock
+//! algorithm: the clock hand walks the pages of all processes and a page
+//! is evicted if its accessed bit is still clear when the hand visits it
+//! the next time. Page table entry of a swapped out page is not present
+//! and it contains the swap slot number in place of the frame address.
+//! The page fault handler reads the page back.
+//!
+//! Frames which address spaces share are not evicted. Swapped out pages
+//! are shared with reference counts of swap slots, so `fork` doesn't
+//! need to read them.
+//!
+//! Eviction and swap-in hold the swap `Mutex` while they read and write
+//! the swap space, so the I/O runs with interrupts enabled. Page tables
+//! are changed only with interrupts disabled. A page is copied to a
+//! buffer before it is written, and it is evicted only if it wasn't
+//! written while the copy was written to swap.
+
+use core::fmt::Write;
+
+use crate::block::{self, BlockDeviceId, BlockError, SECTOR_SIZE};
+use crate::frame_allocator::{self, Frame};
+use crate::page_table::{AddressSpace, L1Flags, L1PageTableEntry, PAGE_SIZE, USER_SPACE_START};
+use crate::process::{self, Pid};
+use crate::scheduler::{ThreadId, MAX_THREADS};
+use crate::shell::Io;
+use crate::shell_command::{self, Builtin, CommandError, RegistryError};
+use crate::shell_completion::ArgumentCompletion;
+use crate::shell_parser::Arguments;
+use crate::sync::{self, IrqSpinlock, Mutex};
+use crate::vfs::{self, Context, FileType, FsError, OpenFlags, PathBuf, VNode};
+
+/// Maximum swap size is 64 MiB.
+pub const MAX_SWAP_SLOTS: usize = 16384;
+
+/// Pages are evicted when fewer frames than this are free.
+const LOW_FREE_FRAMES: usize = 64;
+/// Number of pages which one eviction tries to evict.
+const EVICT_BATCH: usize = 16;
+
+const SECTORS_PER_SLOT: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+pub type SwapSlot = usize;
+
+#[derive(Debug)]
+pub enum SwapError {
+    AlreadyEnabled,
+    /// Swap space is smaller than one page.
+    TooSmall,
+    NotRegularFile,
+    Block(BlockError),
+    Fs(FsError),
+}
+
+impl From<BlockError> for SwapError {
+    fn from(error: BlockError) -> Self {
+        SwapError::Block(error)
+    }
+}
+
+impl From<FsError> for SwapError {
+    fn from(error: FsError) -> Self {
+        SwapError::Fs(error)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum SwapTarget {
+    Device(BlockDeviceId),
+    /// The VFS keeps a reference to the file while swap is on.
+    File(VNode),
+}
+
+struct Swap {
+    target: SwapTarget,
+    /// Clock hand is at this user page of this thread's process.
+    hand_thread: ThreadId,
+    hand_address: usize,
+}
+
+struct SwapState {
+    swap: Option<Swap>,
+    /// Copy of the page which is being written.
+    buffer: [u8; PAGE_SIZE],
+}
+
+static SWAP: Mutex<SwapState> = Mutex::new("swap", SwapState {
+    swap: None,
+    buffer: [0; PAGE_SIZE],
+});
+
+/// Slot allocation and statistics. Page tables are copied and freed
+/// with interrupts disabled, so these are behind a spinlock.
+struct Slots {
+    name: Option<PathBuf>,
+    slot_count: usize,
+    used_slots: usize,
+    /// Search for free slots starts from this slot.
+    next_slot: usize,
+    /// Number of page table entries which refer to a slot. Zero means
+    /// that the slot is free.
+    references: [u8; MAX_SWAP_SLOTS],
+    pages_in: usize,
+    pages_out: usize,
+}
+
+static SLOTS: IrqSpinlock<Slots> = IrqSpinlock::new("swap slots", Slots {
+    name: None,
+    slot_count: 0,
+    used_slots: 0,
+    next_slot: 0,
+    references: [0; MAX_SWAP_SLOTS],
+    pages_in: 0,
+    pages_out: 0,
+});
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SwapStatistics {
+    pub total_slots: usize,
+    pub used_slots: usize,
+    pub pages_in: usize,
+    pub pages_out: usize,
+}
+
+/// Start swapping to a block device or to a regular file. Size of the
+/// file doesn't change, so the file must be large enough already.
+pub fn swapon(ctx: &mut Context, path: &str) -> Result<usize, SwapError> {
+    let mut state = SWAP.lock();
+
+    if state.swap.is_some() {
+        return Err(SwapError::AlreadyEnabled);
+    }
+
+    let (target, size) = match block::find(path) {
+        Some(device) => (SwapTarget::Device(device), block::sector_count(device)? * SECTOR_SIZE as u64),
+        None => {
+            let vfs = vfs::vfs();
+            let fd = vfs.open(ctx, path, OpenFlags::READ | OpenFlags::WRITE)?;
+            let result = vfs.fstat(ctx, fd).and_then(|metadata| {
+                let vnode = ctx.files.get(fd)?.vnode;
+                Ok((metadata, vnode))
+            });
+            vfs.close(ctx, fd)?;
+            let (metadata, vnode) = result?;
+
+            if metadata.file_type != FileType::Regular {
+                return Err(SwapError::NotRegularFile);
+            }
+
+            (SwapTarget::File(vnode), metadata.size)
+        }
+    };
+
+    let slot_count = core::cmp::min((size / PAGE_SIZE as u64) as usize, MAX_SWAP_SLOTS);
+    if slot_count == 0 {
+        return Err(SwapError::TooSmall);
+    }
+
+    if let SwapTarget::File(vnode) = target {
+        vfs::vfs().retain_vnode(vnode)?;
+    }
+
+    let mut name = PathBuf::new();
+    let _ = name.try_push_str(path);
+
+    {
+        let mut slots = SLOTS.lock();
+        slots.name = Some(name);
+        slots.slot_count = slot_count;
+    }
+
+    state.swap = Some(Swap {
+        target,
+        hand_thread: 0,
+        hand_address: USER_SPACE_START,
+    });
+
+    Ok(slot_count)
+}
+
+/// Path of the swap device or file.
+pub fn swap_name() -> Option<PathBuf> {
+    SLOTS.lock().name
+}
+
+pub fn statistics() -> SwapStatistics {
+    let slots = SLOTS.lock();
+
+    SwapStatistics {
+        total_slots: slots.slot_count,
+        used_slots: slots.used_slots,
+        pages_in: slots.pages_in,
+        pages_out: slots.pages_out,
+    }
+}
+
+static COMMANDS: [Builtin; 1] = [
+    Builtin {
+        name: "swapon",
+        usage: "[path]",
+        help: "Swap to a block device or a file, or print swap statistics",
+        run: swapon_command,
+        complete: ArgumentCompletion::Paths,
+    },
+];
+
+pub fn register_commands() -> Result<(), RegistryError> {
+    shell_command::register_builtins(&COMMANDS)
+}
+
+fn swapon_command(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let path = args.next();
+    args.end()?;
+
+    if let Some(path) = path {
+        let slots = swapon(&mut io.ctx, path)?;
+        let _ = writeln!(io, "Swapping to {}, {} KiB", path, slots * PAGE_SIZE / 1024);
+        return Ok(());
+    }
+
+    let statistics = statistics();
+    match swap_name() {
+        Some(name) => {
+            let _ = writeln!(io, "Swap: {}", name);
+        }
+        None => {
+            let _ = writeln!(io, "Swap is not enabled");
+        }
+    }
+    let _ = writeln!(io, "Size: {} KiB", statistics.total_slots * PAGE_SIZE / 1024);
+    let _ = writeln!(io, "Used: {} KiB", statistics.used_slots * PAGE_SIZE / 1024);
+    let _ = writeln!(io, "Pages in: {}", statistics.pages_in);
+    let _ = writeln!(io, "Pages out: {}", statistics.pages_out);
+    Ok(())
+}
+
+/// Allocate a frame for user memory. Pages are evicted if there are
+/// only a few free frames. Eviction waits for the swap space, so this
+/// can't be called with spinlocks held.
+pub fn allocate_frame() -> Option<Frame> {
+    if frame_allocator::free_frames() < LOW_FREE_FRAMES {
+        evict(EVICT_BATCH);
+    }
+
+    frame_allocator::allocate_frame().or_else(|| {
+        evict(EVICT_BATCH);
+        frame_allocator::allocate_frame()
+    })
+}
+
+/// Allocate a frame for user memory and fill it with zeros.
+pub fn allocate_zeroed_frame() -> Option<Frame> {
+    let frame = allocate_frame()?;
+    unsafe {
+        for byte in frame.data_mut().iter_mut() {
+            *byte = 0;
+        }
+    }
+    Some(frame)
+}
+
+/// Swap slot of a swapped out page table entry.
+pub fn swapped_slot(entry: &L1PageTableEntry) -> Option<SwapSlot> {
+    let flags = entry.flags();
+    if !flags.contains(L1Flags::PRESENT) && flags.contains(L1Flags::SWAPPED) {
+        Some(entry.address() as usize / PAGE_SIZE)
+    } else {
+        None
+    }
+}
+
+fn swapped_entry(slot: SwapSlot) -> L1PageTableEntry {
+    L1PageTableEntry::new((slot * PAGE_SIZE) as u64, L1Flags::SWAPPED)
+}
+
+/// Add a reference to a slot when a swapped out page table entry is
+/// copied.
+pub fn share_slot(slot: SwapSlot) {
+    let mut slots = SLOTS.lock();
+    let count = &mut slots.references[slot];
+    *count = count.checked_add(1).expect("swap slot reference count overflow");
+}
+
+/// Remove a reference to a slot. Slot is free when there are no more
+/// references.
+pub fn free_slot(slot: SwapSlot) {
+    let mut slots = SLOTS.lock();
+
+    let count = &mut slots.references[slot];
+    if *count == 0 {
+        panic!("double free of swap slot {}", slot);
+    }
+
+    *count -= 1;
+    if *count == 0 {
+        slots.used_slots -= 1;
+    }
+}
+
+fn allocate_slot() -> Option<SwapSlot> {
+    let mut slots = SLOTS.lock();
+
+    for i in 0..slots.slot_count {
+        let slot = (slots.next_slot + i) % slots.slot_count;
+
+        if slots.references[slot] == 0 {
+            slots.references[slot] = 1;
+            slots.used_slots += 1;
+            slots.next_slot = (slot + 1) % slots.slot_count;
+            return Some(slot);
+        }
+    }
+
+    None
+}
+
+/// Read the swapped out page at `page` back to memory and map it with
+/// `flags`. Returns false if the page is not swapped out or it can't be
+/// read. `address_space` must be the address space of the current
+/// process.
+pub fn swap_in(address_space: &mut AddressSpace, page: usize, flags: L1Flags) -> bool {
+    // Allocation may evict pages, so it is done before the swap is
+    // locked.
+    let frame = match allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let state = SWAP.lock();
+
+    let interrupts = sync::disable_interrupts();
+    let slot = address_space.user_entry_mut(page).as_ref().and_then(|entry| swapped_slot(entry));
+    sync::restore_interrupts(interrupts);
+
+    let (target, slot) = match (state.swap.as_ref(), slot) {
+        (Some(swap), Some(slot)) => (swap.target, slot),
+        _ => {
+            frame_allocator::free_frame(frame);
+            return false;
+        }
+    };
+
+    if read_slot(target, slot, unsafe { frame.data_mut() }).is_err() {
+        frame_allocator::free_frame(frame);
+        return false;
+    }
+
+    // Only this thread changes swapped out entries of its address space,
+    // so the entry still refers to the same slot.
+    let interrupts = sync::disable_interrupts();
+    if let Some(entry) = address_space.user_entry_mut(page) {
+        *entry = L1PageTableEntry::new(frame.start_address() as u64, flags | L1Flags::PRESENT | L1Flags::USER_SUPERVISOR);
+    }
+    address_space.flush_user_page(page);
+    sync::restore_interrupts(interrupts);
+
+    free_slot(slot);
+    SLOTS.lock().pages_in += 1;
+
+    true
+}
+
+/// Evict up to `count` pages. Returns the number of evicted pages.
+fn evict(count: usize) -> usize {
+    let mut state = SWAP.lock();
+    let SwapState { swap, buffer } = &mut *state;
+
+    let swap = match swap {
+        Some(swap) => swap,
+        None => return 0,
+    };
+
+    let mut evicted = 0;
+
+    // First round over all processes clears accessed bits, so the
+    // second round finds pages to evict if there are any.
+    let mut visited_threads = 0;
+
+    while evicted < count && visited_threads <= 2 * MAX_THREADS {
+        let thread = swap.hand_thread;
+
+        let interrupts = sync::disable_interrupts();
+        let next_page = process::address_space(thread)
+            .and_then(|(pid, address_space)| {
+                let page = address_space.next_user_page(swap.hand_address)?;
+                Some((pid, address_space, page))
+            });
+
+        let candidate = match next_page {
+            Some((pid, address_space, page)) => {
+                swap.hand_address = page + PAGE_SIZE;
+                select_page(address_space, page, buffer).map(|frame| (pid, page, frame))
+            }
+            None => {
+                swap.hand_thread = (swap.hand_thread + 1) % MAX_THREADS;
+                swap.hand_address = USER_SPACE_START;
+                visited_threads += 1;
+                None
+            }
+        };
+        sync::restore_interrupts(interrupts);
+
+        if let Some((pid, page, frame)) = candidate {
+            if evict_page(swap.target, buffer, thread, pid, page, frame) {
+                evicted += 1;
+            }
+        }
+    }
+
+    evicted
+}
+
+/// Called with interrupts disabled. Clear the accessed bit of the page,
+/// or copy the page to `buffer` if it hasn't been accessed since the
+/// previous visit of the clock hand. Returns the frame of a copied page.
+fn select_page(address_space: &mut AddressSpace, page: usize, buffer: &mut [u8; PAGE_SIZE]) -> Option<Frame> {
+    address_space.memory_map.find(page)?;
+
+    let entry = address_space.user_page_mut(page)?;
+    let flags = entry.flags();
+    let frame = Frame::containing_address(entry.address() as usize);
+
+    if !flags.contains(L1Flags::USER_SUPERVISOR) ||
+        flags.contains(L1Flags::COPY_ON_WRITE) ||
+        frame_allocator::reference_count(frame) != 1 {
+        return None;
+    }
+
+    if flags.contains(L1Flags::ACCESSED) {
+        entry.flags_mut(flags - L1Flags::ACCESSED);
+        address_space.flush_user_page(page);
+        return None;
+    }
+
+    // Dirty bit tells if the page is written while the copy is written
+    // to swap.
+    entry.flags_mut(flags - L1Flags::DIRTY);
+    address_space.flush_user_page(page);
+    buffer.copy_from_slice(unsafe { frame.data_mut() });
+
+    Some(frame)
+}
+
+/// Write the copy of `page` to swap and replace the page with the swap
+/// slot if the process hasn't changed the page meanwhile.
+fn evict_page(target: SwapTarget, buffer: &[u8; PAGE_SIZE], thread: ThreadId, pid: Pid, page: usize, frame: Frame) -> bool {
+    let slot = match allocate_slot() {
+        Some(slot) => slot,
+        None => return false,
+    };
+
+    if write_slot(target, slot, buffer).is_err() {
+        free_slot(slot);
+        return false;
+    }
+
+    let interrupts = sync::disable_interrupts();
+    let evicted = replace_page(thread, pid, page, frame, slot);
+    sync::restore_interrupts(interrupts);
+
+    if evicted {
+        SLOTS.lock().pages_out += 1;
+    } else {
+        free_slot(slot);
+    }
+
+    evicted
+}
+
+/// Called with interrupts disabled. Replace `page` with `slot` if the
+/// page still maps `frame` and it hasn't been written.
+fn replace_page(thread: ThreadId, pid: Pid, page: usize, frame: Frame, slot: SwapSlot) -> bool {
+    // Another process might run in the thread now.
+    let address_space = match process::address_space(thread) {
+        Some((current_pid, address_space)) => {
+            if current_pid != pid {
+                return false;
+            }
+            address_space
+        }
+        None => return false,
+    };
+
+    let entry = match address_space.user_page_mut(page) {
+        Some(entry) => entry,
+        None => return false,
+    };
+
+    if entry.address() as usize != frame.start_address() ||
+        entry.flags().intersects(L1Flags::DIRTY | L1Flags::COPY_ON_WRITE) ||
+        frame_allocator::reference_count(frame) != 1 {
+        return false;
+    }
+
+    *entry = swapped_entry(slot);
+    address_space.flush_user_page(page);
+    frame_allocator::free_frame(frame);
+    true
+}
+
+fn read_slot(target: SwapTarget, slot: SwapSlot, data: &mut [u8]) -> Result<(), SwapError> {
+    match target {
+        SwapTarget::Device(device) => block::read_sectors(device, slot as u64 * SECTORS_PER_SLOT, data)?,
+        SwapTarget::File(vnode) => {
+            let mut count = 0;
+            while count < data.len() {
+                match vfs::vfs().read_vnode(vnode, (slot * PAGE_SIZE + count) as u64, &mut data[count..])? {
+                    0 => return Err(SwapError::Fs(FsError::IoError)),
+                    read_count => count += read_count,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_slot(target: SwapTarget, slot: SwapSlot, data: &[u8]) -> Result<(), SwapError> {
+    match target {
+        SwapTarget::Device(device) => block::write_sectors(device, slot as u64 * SECTORS_PER_SLOT, data)?,
+        SwapTarget::File(vnode) => {
+            let mut count = 0;
+            while count < data.len() {
+                match vfs::vfs().write_vnode(vnode, (slot * PAGE_SIZE + count) as u64, &data[count..])? {
+                    0 => return Err(SwapError::Fs(FsError::IoError)),
+                    write_count => count += write_count,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,494 @@
+//! Synchronization primitives.
+//!
+//! `IrqSpinlock` disables interrupts while the lock is held, so it can be
+//! used to share data with interrupt handlers. Lock holder must not
+//! sleep.
+//!
+//! `Mutex`, `Semaphore` and `Condvar` put the waiting thread to a
+//! `WaitQueue`, so other threads run while the thread waits. These
+//! can't be used from interrupt handlers.
+//!
+//! Debug builds check that spinlocks are always acquired in the same
+//! order and panic if a thread would deadlock with itself.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+
+use crate::scheduler::{self, ThreadId, MAX_THREADS};
+
+/// Disable interrupts and return true if they were enabled.
+pub fn disable_interrupts() -> bool {
+    let enabled = unsafe {
+        x86::bits32::eflags::read().contains(x86::bits32::eflags::EFlags::FLAGS_IF)
+    };
+
+    unsafe {
+        x86::irq::disable();
+    }
+
+    enabled
+}
+
+/// Enable interrupts if `enabled` is true.
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe {
+            x86::irq::enable();
+        }
+    }
+}
+
+/// Spinlock which disables interrupts while it is locked. Interrupt
+/// flag is restored when the lock is released, so locks can be nested.
+pub struct IrqSpinlock<T> {
+    name: &'static str,
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSpinlock<T> {}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Self {
+            name,
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+        let interrupts = disable_interrupts();
+
+        lock_debug::acquire(self.name, self.address());
+
+        while self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            spin_loop_hint();
+        }
+
+        IrqSpinlockGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    /// Pointer to the data without locking. Use only when the hardware
+    /// needs the address of the data, for example with `lidt`.
+    pub fn as_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    fn address(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    lock: &'a IrqSpinlock<T>,
+    interrupts: bool,
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        lock_debug::release(self.lock.address());
+        restore_interrupts(self.interrupts);
+    }
+}
+
+/// FIFO queue of thread IDs. Thread can wait in only one queue at a
+/// time, so the queue can't become full.
+struct ThreadQueue {
+    ids: [ThreadId; MAX_THREADS],
+    start: usize,
+    length: usize,
+}
+
+impl ThreadQueue {
+    const fn new() -> Self {
+        Self {
+            ids: [0; MAX_THREADS],
+            start: 0,
+            length: 0,
+        }
+    }
+
+    fn push_back(&mut self, id: ThreadId) {
+        assert!(self.length < MAX_THREADS, "thread queue is full");
+        self.ids[(self.start + self.length) % MAX_THREADS] = id;
+        self.length += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<ThreadId> {
+        if self.length == 0 {
+            return None;
+        }
+
+        let id = self.ids[self.start];
+        self.start = (self.start + 1) % MAX_THREADS;
+        self.length -= 1;
+        Some(id)
+    }
+}
+
+/// Threads which are waiting for something to happen.
+pub struct WaitQueue {
+    threads: IrqSpinlock<ThreadQueue>,
+}
+
+impl WaitQueue {
+    /// `name` is used by lock debugging for the spinlock of the queue.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            threads: IrqSpinlock::new(name, ThreadQueue::new()),
+        }
+    }
+
+    /// Block the current thread until it is woken up. Interrupts should
+    /// be disabled while checking the condition and calling this, so
+    /// that the wake up is not lost.
+    pub fn wait(&self) {
+        lock_debug::check_can_sleep(self.threads.name);
+
+        let interrupts = disable_interrupts();
+        self.threads.lock().push_back(scheduler::current_thread_id());
+        scheduler::block_current();
+        restore_interrupts(interrupts);
+    }
+
+    /// Wait until `condition` returns true.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let interrupts = disable_interrupts();
+
+        while !condition() {
+            self.wait();
+        }
+
+        restore_interrupts(interrupts);
+    }
+
+    /// Wake the thread which has waited longest. Returns false if there
+    /// are no waiting threads.
+    pub fn wake_one(&self) -> bool {
+        match self.threads.lock().pop_front() {
+            Some(id) => {
+                scheduler::unblock(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    owner: ThreadId,
+}
+
+/// Mutual exclusion lock which blocks the thread while it waits for the
+/// lock.
+pub struct Mutex<T> {
+    name: &'static str,
+    state: IrqSpinlock<MutexState>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Spinlocks inside the mutex have the same name as the mutex, so
+    /// lock debugging reports them by the mutex.
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Self {
+            name,
+            state: IrqSpinlock::new(name, MutexState {
+                locked: false,
+                owner: 0,
+            }),
+            waiters: WaitQueue::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        lock_debug::check_can_sleep(self.name);
+
+        let current = scheduler::current_thread_id();
+        let interrupts = disable_interrupts();
+
+        loop {
+            {
+                let mut state = self.state.lock();
+
+                if !state.locked {
+                    state.locked = true;
+                    state.owner = current;
+                    break;
+                }
+
+                if cfg!(debug_assertions) && state.owner == current {
+                    panic!("Deadlock: thread {} locked mutex {} twice", current, self.name);
+                }
+            }
+
+            self.waiters.wait();
+        }
+
+        restore_interrupts(interrupts);
+
+        MutexGuard {
+            mutex: self,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let mut state = self.state.lock();
+
+        if state.locked {
+            return None;
+        }
+
+        state.locked = true;
+        state.owner = scheduler::current_thread_id();
+
+        Some(MutexGuard {
+            mutex: self,
+        })
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        state.locked = false;
+        self.waiters.wake_one();
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Counting semaphore.
+pub struct Semaphore {
+    count: IrqSpinlock<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(name: &'static str, count: usize) -> Self {
+        Self {
+            count: IrqSpinlock::new(name, count),
+            waiters: WaitQueue::new(name),
+        }
+    }
+
+    /// Decrement the count. Waits until the count is not zero.
+    pub fn down(&self) {
+        let interrupts = disable_interrupts();
+
+        loop {
+            {
+                let mut count = self.count.lock();
+                if *count > 0 {
+                    *count -= 1;
+                    break;
+                }
+            }
+
+            self.waiters.wait();
+        }
+
+        restore_interrupts(interrupts);
+    }
+
+    /// Decrement the count if it is not zero.
+    pub fn try_down(&self) -> bool {
+        let mut count = self.count.lock();
+
+        if *count > 0 {
+            *count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Increment the count and wake one waiting thread. Can be used from
+    /// interrupt handlers.
+    pub fn up(&self) {
+        let mut count = self.count.lock();
+        *count += 1;
+        self.waiters.wake_one();
+    }
+}
+
+/// Condition variable which is used with a `Mutex`.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            waiters: WaitQueue::new(name),
+        }
+    }
+
+    /// Unlock the mutex, wait until notified and lock the mutex again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+
+        // Other threads can't run before this thread is in the wait
+        // queue, so notifications are not lost.
+        let interrupts = disable_interrupts();
+        drop(guard);
+        self.waiters.wait();
+        restore_interrupts(interrupts);
+
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+/// Lock order checking for debug builds.
+///
+/// Spinlocks are identified by their names, so every lock with the same
+/// name must follow the same order. Order of two locks is recorded when
+/// one is acquired while holding the other. Each thread has its own list
+/// of held locks, so a thread is not blamed for locks of other threads.
+#[cfg(debug_assertions)]
+mod lock_debug {
+    use crate::scheduler::{self, MAX_THREADS};
+
+    const MAX_HELD_LOCKS: usize = 16;
+    const MAX_LOCK_ORDERS: usize = 64;
+
+    // Locks are held with interrupts disabled, so there is no concurrent
+    // access to these. Interrupt handlers use the list of the thread
+    // they interrupted.
+    static mut HELD_LOCKS: [[(&str, usize); MAX_HELD_LOCKS]; MAX_THREADS] = [[("", 0); MAX_HELD_LOCKS]; MAX_THREADS];
+    static mut HELD_LOCK_COUNTS: [usize; MAX_THREADS] = [0; MAX_THREADS];
+    static mut LOCK_ORDERS: [(&str, &str); MAX_LOCK_ORDERS] = [("", ""); MAX_LOCK_ORDERS];
+    static mut LOCK_ORDER_COUNT: usize = 0;
+
+    /// Called with interrupts disabled before spinning.
+    pub fn acquire(name: &'static str, address: usize) {
+        let thread = scheduler::current_thread_id();
+
+        unsafe {
+            let count = HELD_LOCK_COUNTS[thread];
+
+            for &(held_name, held_address) in &HELD_LOCKS[thread][..count] {
+                if held_address == address {
+                    panic!("Deadlock: spinlock {} is already held", name);
+                }
+
+                if held_name == name {
+                    continue;
+                }
+
+                let orders = &LOCK_ORDERS[..LOCK_ORDER_COUNT];
+
+                if orders.contains(&(name, held_name)) {
+                    panic!("Lock order violation: {} acquired while holding {}, but earlier {} was acquired while holding {}",
+                        name, held_name, held_name, name);
+                }
+
+                if !orders.contains(&(held_name, name)) && LOCK_ORDER_COUNT < MAX_LOCK_ORDERS {
+                    LOCK_ORDERS[LOCK_ORDER_COUNT] = (held_name, name);
+                    LOCK_ORDER_COUNT += 1;
+                }
+            }
+
+            if count == MAX_HELD_LOCKS {
+                panic!("Too many spinlocks held when acquiring {}", name);
+            }
+
+            HELD_LOCKS[thread][count] = (name, address);
+            HELD_LOCK_COUNTS[thread] += 1;
+        }
+    }
+
+    /// Locks may be released in any order.
+    pub fn release(address: usize) {
+        let thread = scheduler::current_thread_id();
+
+        unsafe {
+            let held = &mut HELD_LOCKS[thread][..HELD_LOCK_COUNTS[thread]];
+
+            if let Some(i) = held.iter().rposition(|&(_, held_address)| held_address == address) {
+                for j in i..held.len() - 1 {
+                    held[j] = held[j + 1];
+                }
+                HELD_LOCK_COUNTS[thread] -= 1;
+            }
+        }
+    }
+
+    /// Thread can't sleep while holding a spinlock.
+    pub fn check_can_sleep(name: &str) {
+        let interrupts = super::disable_interrupts();
+        let thread = scheduler::current_thread_id();
+        let held = unsafe { HELD_LOCKS[thread][..HELD_LOCK_COUNTS[thread]].last().map(|&(held_name, _)| held_name) };
+        super::restore_interrupts(interrupts);
+
+        if let Some(held_name) = held {
+            panic!("Waiting for {} while holding spinlock {}", name, held_name);
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod lock_debug {
+    pub fn acquire(_name: &'static str, _address: usize) {}
+
+    pub fn release(_address: usize) {}
+
+    pub fn check_can_sleep(_name: &str) {}
+}
@@ -0,0 +1,653 @@
+//! System call interface.
+//!
+//! User mode calls the kernel with `int 0x80` or with `sysenter`.
+//! System call number is in EAX and the return value is returned in
+//! EAX. Return values from -4095 to -1 are error numbers (`-Errno`).
+//!
+//! | Number | Name   | Arguments                      |
+//! |--------|--------|--------------------------------|
+//! | 0      | exit   | status                         |
+//! | 1      | read   | fd, buffer, length             |
+//! | 2      | write  | fd, buffer, length             |
+//! | 3      | open   | path, path length, `OpenFlags` |
+//! | 4      | close  | fd                             |
+//! | 5      | getpid |                                |
+//! | 6      | sleep  | milliseconds                   |
+//! | 7      | fork   |                                |
+//! | 8      | execve | path, path length, argv, envp  |
+//! | 9      | waitpid| pid or -1, status address      |
+//! | 10     | kill   | pid                            |
+//! | 11     | getppid|                                |
+//! | 12     | chdir  | path, path length              |
+//! | 13     | getcwd | buffer, length                 |
+//! | 14     | mmap   | address, length, protection, flags, fd, offset |
+//! | 15     | munmap | address, length                |
+//! | 16     | mprotect | address, length, protection  |
+//! | 17     | brk    | address                        |
+//! | 18     | pipe   | address of two file descriptors |
+//! | 19     | dup2   | fd, new fd                     |
+//!
+//! `argv` and `envp` are null terminated arrays of pointers to null
+//! terminated strings. `waitpid` stores exit status `status << 8` or
+//! signal number 9 for killed processes and 11 for processes killed by
+//! an exception.
+//!
+//! `pipe` stores the read end and the write end as two 32-bit file
+//! descriptors. Reads from an empty pipe and writes to a full pipe
+//! block, see `pipe` module.
+//!
+//! `mmap` supports only private mappings. Protection and flag values are
+//! the same as in Linux. `mmap` needs six arguments, so it can't be
+//! called with `sysenter`.
+
+use x86::msr::{wrmsr, IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use arrayvec::ArrayVec;
+
+use crate::elf::{self, ElfError};
+use crate::gdt::KERNEL_CODE_SELECTOR;
+use crate::page_table::{self, L1Flags, PAGE_SIZE};
+use crate::pipe;
+use crate::process::{self, ProcessError};
+use crate::usermode::{self, ExitReason};
+use crate::vfs::{self, FileType, FsError, OpenFlags, PATH_MAX_LENGTH};
+use crate::vma::{self, Backing, Protection, VmaError};
+
+pub const SYSCALL_INTERRUPT: u8 = 0x80;
+
+pub const SYS_EXIT: u32 = 0;
+pub const SYS_READ: u32 = 1;
+pub const SYS_WRITE: u32 = 2;
+pub const SYS_OPEN: u32 = 3;
+pub const SYS_CLOSE: u32 = 4;
+pub const SYS_GETPID: u32 = 5;
+pub const SYS_SLEEP: u32 = 6;
+pub const SYS_FORK: u32 = 7;
+pub const SYS_EXECVE: u32 = 8;
+pub const SYS_WAITPID: u32 = 9;
+pub const SYS_KILL: u32 = 10;
+pub const SYS_GETPPID: u32 = 11;
+pub const SYS_CHDIR: u32 = 12;
+pub const SYS_GETCWD: u32 = 13;
+pub const SYS_MMAP: u32 = 14;
+pub const SYS_MUNMAP: u32 = 15;
+pub const SYS_MPROTECT: u32 = 16;
+pub const SYS_BRK: u32 = 17;
+pub const SYS_PIPE: u32 = 18;
+pub const SYS_DUP2: u32 = 19;
+
+const MAP_SHARED: u32 = 0x01;
+const MAP_PRIVATE: u32 = 0x02;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+/// Size of the kernel buffer which `read` and `write` use for copying.
+const COPY_BUFFER_SIZE: usize = 512;
+
+/// Size of the kernel buffer for `execve` arguments and environment.
+const EXECVE_STRINGS_SIZE: usize = 1024;
+
+const SIGKILL: u32 = 9;
+const SIGSEGV: u32 = 11;
+
+extern "C" {
+    fn sysenter_entry();
+}
+
+static SYSENTER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOSPC = 28,
+    EROFS = 30,
+    EPIPE = 32,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+}
+
+impl From<FsError> for Errno {
+    fn from(error: FsError) -> Self {
+        match error {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::NotDirectory => Errno::ENOTDIR,
+            FsError::IsDirectory => Errno::EISDIR,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::DirectoryNotEmpty => Errno::ENOTEMPTY,
+            FsError::InvalidPath |
+            FsError::InvalidArgument |
+            FsError::UnknownFileSystemType => Errno::EINVAL,
+            FsError::NameTooLong |
+            FsError::PathTooLong => Errno::ENAMETOOLONG,
+            FsError::TooManySymlinks => Errno::ELOOP,
+            FsError::BadFileDescriptor => Errno::EBADF,
+            FsError::TooManyOpenFiles |
+            FsError::MountTableFull => Errno::EMFILE,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::PermissionDenied => Errno::EACCES,
+            FsError::NotSupported => Errno::ENOSYS,
+            FsError::IoError |
+            FsError::Corrupted => Errno::EIO,
+            FsError::BrokenPipe => Errno::EPIPE,
+            FsError::Busy => Errno::EBUSY,
+            FsError::Interrupted => Errno::EINTR,
+        }
+    }
+}
+
+impl From<ProcessError> for Errno {
+    fn from(error: ProcessError) -> Self {
+        match error {
+            ProcessError::TooManyProcesses |
+            ProcessError::Thread(_) => Errno::EAGAIN,
+            ProcessError::OutOfMemory |
+            ProcessError::Map(_) => Errno::ENOMEM,
+            ProcessError::NoSuchProcess => Errno::ESRCH,
+            ProcessError::NoChildren => Errno::ECHILD,
+            ProcessError::NotProcess => Errno::EPERM,
+            ProcessError::Interrupted => Errno::EINTR,
+        }
+    }
+}
+
+impl From<ElfError> for Errno {
+    fn from(error: ElfError) -> Self {
+        match error {
+            ElfError::Fs(error) => error.into(),
+            ElfError::NotElf |
+            ElfError::Unsupported |
+            ElfError::InvalidProgramHeader |
+            ElfError::TooManyProgramHeaders |
+            ElfError::SegmentOutOfRange => Errno::ENOEXEC,
+            ElfError::TooManyArguments |
+            ElfError::ArgumentsTooLarge => Errno::E2BIG,
+            ElfError::OutOfMemory |
+            ElfError::Map(_) => Errno::ENOMEM,
+            ElfError::Process(error) => error.into(),
+            ElfError::Vma(error) => error.into(),
+        }
+    }
+}
+
+impl From<VmaError> for Errno {
+    fn from(error: VmaError) -> Self {
+        match error {
+            VmaError::InvalidRange => Errno::EINVAL,
+            VmaError::NotMapped |
+            VmaError::NoFreeRange |
+            VmaError::TooManyAreas |
+            VmaError::OutOfMemory |
+            VmaError::Map(_) => Errno::ENOMEM,
+            VmaError::Fs(error) => error.into(),
+        }
+    }
+}
+
+/// User registers which the system call entry points save to the
+/// kernel stack. Registers are restored from the frame when the system
+/// call returns.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SyscallFrame {
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub ebp: u32,
+    pub eip: u32,
+    pub esp: u32,
+}
+
+type SyscallFunction = fn(arguments: &[u32; 6], frame: &mut SyscallFrame) -> Result<u32, Errno>;
+
+/// System call functions indexed by system call number.
+const SYSCALL_TABLE: [SyscallFunction; 20] = [
+    sys_exit,
+    sys_read,
+    sys_write,
+    sys_open,
+    sys_close,
+    sys_getpid,
+    sys_sleep,
+    sys_fork,
+    sys_execve,
+    sys_waitpid,
+    sys_kill,
+    sys_getppid,
+    sys_chdir,
+    sys_getcwd,
+    sys_mmap,
+    sys_munmap,
+    sys_mprotect,
+    sys_brk,
+    sys_pipe,
+    sys_dup2,
+];
+
+/// Configure SYSENTER if CPU supports it. The `int 0x80` gate is
+/// configured when the IDT is loaded.
+pub fn init() {
+    let features = x86::cpuid::CpuId::new().get_feature_info();
+
+    if !features.map(|f| f.has_sysenter_sysexit()).unwrap_or(false) {
+        return;
+    }
+
+    unsafe {
+        wrmsr(IA32_SYSENTER_CS, KERNEL_CODE_SELECTOR as u64);
+        wrmsr(IA32_SYSENTER_EIP, sysenter_entry as usize as u64);
+    }
+
+    SYSENTER_ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn sysenter_enabled() -> bool {
+    SYSENTER_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Set the kernel stack for SYSENTER.
+pub fn set_sysenter_stack(stack_top: usize) {
+    if sysenter_enabled() {
+        unsafe {
+            wrmsr(IA32_SYSENTER_ESP, stack_top as u64);
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn rust_syscall_handler(number: u32, arguments: *const [u32; 6], frame: *mut SyscallFrame) -> u32 {
+    // Arguments array and the frame can overlap, so arguments are
+    // copied before creating a reference to the frame.
+    let arguments = unsafe { *arguments };
+    let frame = unsafe { &mut *frame };
+
+    if process::current_task().is_none() {
+        return -(Errno::EPERM as i32) as u32;
+    }
+
+    let result = match SYSCALL_TABLE.get(number as usize) {
+        Some(function) => function(&arguments, frame),
+        None => Err(Errno::ENOSYS),
+    };
+
+    process::handle_pending_kill();
+
+    match result {
+        Ok(value) => value,
+        Err(errno) => -(errno as i32) as u32,
+    }
+}
+
+/// Check that user mode can access the memory range.
+fn check_user_range(address: usize, length: usize, write: bool) -> Result<(), Errno> {
+    if length == 0 {
+        return Ok(());
+    }
+
+    let last = address.checked_add(length - 1).ok_or(Errno::EFAULT)?;
+    let mut page = address & !(PAGE_SIZE - 1);
+
+    loop {
+        let accessible = match page_table::user_page_flags(page) {
+            Some(flags) => !write || flags.contains(L1Flags::READ_WRITE),
+            None => false,
+        };
+
+        // Kernel accesses don't cause page faults which would map the
+        // page or copy a copy-on-write page.
+        if !accessible && !process::resolve_page_fault(page, write) {
+            return Err(Errno::EFAULT);
+        }
+
+        match page.checked_add(PAGE_SIZE) {
+            Some(next) if next <= last => page = next,
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Copy `buffer.len()` bytes from user memory at `address`.
+pub fn copy_from_user(buffer: &mut [u8], address: usize) -> Result<(), Errno> {
+    check_user_range(address, buffer.len(), false)?;
+
+    unsafe {
+        let source = core::slice::from_raw_parts(address as *const u8, buffer.len());
+        buffer.copy_from_slice(source);
+    }
+
+    Ok(())
+}
+
+/// Copy a null terminated string from user memory at `address` to
+/// `buffer`. Returns the string length without the null byte.
+fn copy_string_from_user(buffer: &mut [u8], address: usize) -> Result<usize, Errno> {
+    for i in 0..buffer.len() {
+        copy_from_user(&mut buffer[i..i + 1], address.wrapping_add(i))?;
+        if buffer[i] == 0 {
+            return Ok(i);
+        }
+    }
+
+    Err(Errno::E2BIG)
+}
+
+/// Copy path which is `length` bytes long from user memory.
+fn copy_path_from_user(buffer: &mut [u8; PATH_MAX_LENGTH], address: u32, length: u32) -> Result<&str, Errno> {
+    if length as usize > PATH_MAX_LENGTH {
+        return Err(Errno::ENAMETOOLONG);
+    }
+
+    let path = &mut buffer[..length as usize];
+    copy_from_user(path, address as usize)?;
+    core::str::from_utf8(path).map_err(|_| Errno::EINVAL)
+}
+
+/// Copy strings of a null terminated pointer array to `buffer` starting
+/// from `*used`. String ranges are added to `ranges`.
+fn copy_string_array_from_user(
+    address: u32,
+    buffer: &mut [u8],
+    used: &mut usize,
+    ranges: &mut ArrayVec<[(usize, usize); elf::MAX_ARGUMENTS]>,
+) -> Result<(), Errno> {
+    if address == 0 {
+        return Ok(());
+    }
+
+    for i in 0.. {
+        let mut pointer = [0u8; 4];
+        copy_from_user(&mut pointer, (address as usize).wrapping_add(i * 4))?;
+        let pointer = u32::from_le_bytes(pointer);
+
+        if pointer == 0 {
+            return Ok(());
+        }
+
+        let length = copy_string_from_user(&mut buffer[*used..], pointer as usize)?;
+        ranges.try_push((*used, length)).map_err(|_| Errno::E2BIG)?;
+        *used += length + 1;
+    }
+
+    Ok(())
+}
+
+/// Copy `data` to user memory at `address`.
+pub fn copy_to_user(address: usize, data: &[u8]) -> Result<(), Errno> {
+    check_user_range(address, data.len(), true)?;
+
+    unsafe {
+        let target = core::slice::from_raw_parts_mut(address as *mut u8, data.len());
+        target.copy_from_slice(data);
+    }
+
+    Ok(())
+}
+
+fn sys_exit(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    usermode::exit_current_task(ExitReason::Exit(arguments[0] as i32))
+}
+
+fn sys_read(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let (fd, address, length) = (arguments[0], arguments[1], arguments[2]);
+    let context = &mut process::current_task().ok_or(Errno::EPERM)?.context;
+    let mut buffer = [0u8; COPY_BUFFER_SIZE];
+    let mut count = 0;
+
+    while count < length as usize {
+        let chunk_length = core::cmp::min(COPY_BUFFER_SIZE, length as usize - count);
+        let read_count = vfs::vfs().read(context, fd as usize, &mut buffer[..chunk_length])?;
+        copy_to_user((address as usize).wrapping_add(count), &buffer[..read_count])?;
+        count += read_count;
+
+        if read_count < chunk_length {
+            break;
+        }
+    }
+
+    Ok(count as u32)
+}
+
+fn sys_write(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let (fd, address, length) = (arguments[0], arguments[1], arguments[2]);
+    let context = &mut process::current_task().ok_or(Errno::EPERM)?.context;
+    let mut buffer = [0u8; COPY_BUFFER_SIZE];
+    let mut count = 0;
+
+    while count < length as usize {
+        let chunk_length = core::cmp::min(COPY_BUFFER_SIZE, length as usize - count);
+        copy_from_user(&mut buffer[..chunk_length], (address as usize).wrapping_add(count))?;
+        let write_count = vfs::vfs().write(context, fd as usize, &buffer[..chunk_length])?;
+        count += write_count;
+
+        if write_count < chunk_length {
+            break;
+        }
+    }
+
+    Ok(count as u32)
+}
+
+fn sys_open(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let (address, length, flags) = (arguments[0], arguments[1], arguments[2]);
+    let context = &mut process::current_task().ok_or(Errno::EPERM)?.context;
+
+    let mut buffer = [0u8; PATH_MAX_LENGTH];
+    let path = copy_path_from_user(&mut buffer, address, length)?;
+    let flags = OpenFlags::from_bits(flags).ok_or(Errno::EINVAL)?;
+
+    let fd = vfs::vfs().open(context, path, flags)?;
+    Ok(fd as u32)
+}
+
+fn sys_close(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let context = &mut process::current_task().ok_or(Errno::EPERM)?.context;
+    vfs::vfs().close(context, arguments[0] as usize)?;
+    Ok(0)
+}
+
+fn sys_getpid(_arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let task = process::current_task().ok_or(Errno::EPERM)?;
+    Ok(task.pid as u32)
+}
+
+fn sys_sleep(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    if process::kill_pending() {
+        return Err(Errno::EINTR);
+    }
+
+    crate::scheduler::sleep(arguments[0] as usize);
+
+    if process::kill_pending() {
+        Err(Errno::EINTR)
+    } else {
+        Ok(0)
+    }
+}
+
+fn sys_fork(_arguments: &[u32; 6], frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let pid = process::fork(frame)?;
+    Ok(pid as u32)
+}
+
+fn sys_execve(arguments: &[u32; 6], frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let mut path_buffer = [0u8; PATH_MAX_LENGTH];
+    let path = copy_path_from_user(&mut path_buffer, arguments[0], arguments[1])?;
+
+    let mut strings = [0u8; EXECVE_STRINGS_SIZE];
+    let mut used = 0;
+    let mut ranges = ArrayVec::new();
+    copy_string_array_from_user(arguments[2], &mut strings, &mut used, &mut ranges)?;
+    let argument_count = ranges.len();
+    copy_string_array_from_user(arguments[3], &mut strings, &mut used, &mut ranges)?;
+
+    let mut string_slices: ArrayVec<[&str; elf::MAX_ARGUMENTS]> = ArrayVec::new();
+    for &(start, length) in &ranges {
+        let text = core::str::from_utf8(&strings[start..start + length]).map_err(|_| Errno::EINVAL)?;
+        string_slices.push(text);
+    }
+
+    let (argv, envp) = string_slices.split_at(argument_count);
+    process::execve(path, argv, envp, frame)?;
+    Ok(0)
+}
+
+fn sys_waitpid(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let (pid, status_address) = (arguments[0] as i32, arguments[1]);
+    let task = process::current_task().ok_or(Errno::EPERM)?;
+
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(pid as usize),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let (child, reason) = process::wait(task.pid, pid)?;
+
+    let status = match reason {
+        ExitReason::Exit(status) => (status as u32 & 0xFF) << 8,
+        ExitReason::Exception { .. } => SIGSEGV,
+        ExitReason::Killed => SIGKILL,
+    };
+
+    if status_address != 0 {
+        copy_to_user(status_address as usize, &status.to_le_bytes())?;
+    }
+
+    Ok(child as u32)
+}
+
+fn sys_kill(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    process::kill(arguments[0] as usize)?;
+    Ok(0)
+}
+
+fn sys_getppid(_arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let parent = process::current_parent().ok_or(Errno::EPERM)?;
+    Ok(parent as u32)
+}
+
+fn sys_chdir(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let context = &mut process::current_task().ok_or(Errno::EPERM)?.context;
+
+    let mut buffer = [0u8; PATH_MAX_LENGTH];
+    let path = copy_path_from_user(&mut buffer, arguments[0], arguments[1])?;
+
+    vfs::vfs().change_directory(context, path)?;
+    Ok(0)
+}
+
+/// Copies the working directory and a null byte to the buffer. Returns
+/// the path length.
+fn sys_getcwd(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let (address, length) = (arguments[0], arguments[1]);
+    let context = &process::current_task().ok_or(Errno::EPERM)?.context;
+    let path = context.current_directory();
+
+    if path.len() + 1 > length as usize {
+        return Err(Errno::ERANGE);
+    }
+
+    copy_to_user(address as usize, path.as_bytes())?;
+    copy_to_user((address as usize).wrapping_add(path.len()), &[0])?;
+    Ok(path.len() as u32)
+}
+
+fn sys_mmap(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let (address, length, protection, flags, fd, offset) =
+        (arguments[0], arguments[1], arguments[2], arguments[3], arguments[4], arguments[5]);
+    let task = process::current_task().ok_or(Errno::EPERM)?;
+
+    let protection = Protection::from_bits(protection).ok_or(Errno::EINVAL)?;
+
+    if flags & MAP_PRIVATE == 0 || flags & MAP_SHARED != 0 || flags & !(MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let backing = if flags & MAP_ANONYMOUS != 0 {
+        Backing::Anonymous
+    } else {
+        let file = *task.context.files.get(fd as usize)?;
+        let regular = vfs::vfs().fstat(&task.context, fd as usize)?.file_type == FileType::Regular;
+        if !file.flags.contains(OpenFlags::READ) || !regular {
+            return Err(Errno::EACCES);
+        }
+        Backing::File {
+            vnode: file.vnode,
+            offset: offset as u64,
+        }
+    };
+
+    let address = if flags & MAP_FIXED != 0 { Some(address as usize) } else { None };
+    let start = vma::map(&mut task.address_space, address, length as usize, protection, backing)?;
+    Ok(start as u32)
+}
+
+fn sys_munmap(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let task = process::current_task().ok_or(Errno::EPERM)?;
+    vma::unmap(&mut task.address_space, arguments[0] as usize, arguments[1] as usize)?;
+    Ok(0)
+}
+
+fn sys_mprotect(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let task = process::current_task().ok_or(Errno::EPERM)?;
+    let protection = Protection::from_bits(arguments[2]).ok_or(Errno::EINVAL)?;
+    vma::protect(&mut task.address_space, arguments[0] as usize, arguments[1] as usize, protection)?;
+    Ok(0)
+}
+
+/// Returns the new program break or the current program break if it
+/// can't be changed. Address 0 returns the current program break.
+fn sys_brk(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let task = process::current_task().ok_or(Errno::EPERM)?;
+    Ok(vma::brk(&mut task.address_space, arguments[0] as usize) as u32)
+}
+
+fn sys_pipe(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let context = &mut process::current_task().ok_or(Errno::EPERM)?.context;
+    let (read_fd, write_fd) = pipe::create(context)?;
+
+    let mut fds = [0u8; 8];
+    fds[..4].copy_from_slice(&(read_fd as u32).to_le_bytes());
+    fds[4..].copy_from_slice(&(write_fd as u32).to_le_bytes());
+
+    if let Err(e) = copy_to_user(arguments[0] as usize, &fds) {
+        let _ = vfs::vfs().close(context, read_fd);
+        let _ = vfs::vfs().close(context, write_fd);
+        return Err(e);
+    }
+
+    Ok(0)
+}
+
+fn sys_dup2(arguments: &[u32; 6], _frame: &mut SyscallFrame) -> Result<u32, Errno> {
+    let (fd, new_fd) = (arguments[0] as usize, arguments[1] as usize);
+    let context = &mut process::current_task().ok_or(Errno::EPERM)?.context;
+    vfs::vfs().duplicate(context, fd, new_fd)?;
+    Ok(new_fd as u32)
+}
@@ -1,21 +1,248 @@
 
 
+use crate::ansi::{self, Action};
+use crate::cp437;
 use crate::input::KeyPress;
+use crate::shell_completion;
+use crate::shell_history::{self, HistoryLine};
+use crate::shell_parser::MAX_LINE_LENGTH;
+use crate::vfs::Context;
+use crate::vga_font::{self, MAX_ROWS};
+use crate::vma::{KernelBuffer, VmaError};
+
+use core::fmt::Write;
+use core::mem;
+use core::ops::{Deref, DerefMut, Range};
 
 use arrayvec::{ArrayString, ArrayVec};
 
-use crate::vga_text::{VgaTextMode};
+use crate::vga_text::{self, VgaTextMode};
 
-use vga::driver::text::{ VGA_TEXT_WIDTH, VGA_TEXT_HEIGHT, VgaChar, Colour };
+use vga::driver::text::{ VGA_TEXT_WIDTH, VgaChar, Colour };
 
-const COMMAND_HISTORY_LAST_LINE_INDEX: usize = VGA_TEXT_HEIGHT - 2;
-const COMMAND_HISTORY_LINE_COUNT: usize = VGA_TEXT_HEIGHT - 1;
-const COMMAND_LINE_INDEX_Y: usize = VGA_TEXT_HEIGHT - 1;
 const COMMAND_LENGTH: usize = VGA_TEXT_WIDTH - 1;
+const SEARCH_PATTERN_LENGTH: usize = 32;
+
+/// Output lines of a terminal which can be viewed with Shift+PageUp.
+pub const SCROLLBACK_LINES: usize = 4096;
+
+/// White text on black background.
+const DEFAULT_ATTRIBUTE: u8 = 0x0F;
+const DEFAULT_FOREGROUND: u8 = 0xF;
+const DEFAULT_BACKGROUND: u8 = 0;
+const BRIGHT: u8 = 0x8;
+/// Glyph bit 8 of a cell in 512 glyph mode, which is attribute bit 3.
+const GLYPH_BIT_8: Cell = (BRIGHT as Cell) << 8;
+const BLINK: u8 = 0x80;
+const TAB_WIDTH: usize = 8;
+
+/// VGA colours of ANSI colours black, red, green, yellow, blue, magenta,
+/// cyan and white.
+const ANSI_TO_VGA_COLOUR: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// Output cell in the format of the VGA text buffer. Low byte is the
+/// glyph of the loaded font and high byte is the VGA attribute. Zero is a
+/// space with the default attribute.
+type Cell = u16;
 
-fn write_char_to_vga_text_buffer(text_mode: &mut VgaTextMode, x: usize, y: usize, c: char, blink: bool) {
-    let vga_char = VgaChar::new(c).blink(blink).foreground_color(Colour::White);
-    text_mode.lines_mut().nth(y).unwrap().iter_mut().nth(x).unwrap().write(vga_char);
+/// Ring buffer of output lines. Zero cells are spaces.
+type Scrollback = [[Cell; VGA_TEXT_WIDTH]; SCROLLBACK_LINES];
+
+/// Scrollback buffer of the first virtual console, whose terminal is
+/// created before paging is enabled. The terminal of the panic handler
+/// reuses it, which doesn't matter because the kernel stops after the
+/// panic.
+static mut SCROLLBACK: Scrollback = [[0; VGA_TEXT_WIDTH]; SCROLLBACK_LINES];
+
+/// Scrollback of a terminal. Hidden terminals use a `KernelBuffer`, so
+/// memory is allocated only for lines which have been written.
+enum ScrollbackBuffer {
+    Static(&'static mut Scrollback),
+    Kernel(KernelBuffer),
+}
+
+impl ScrollbackBuffer {
+    fn allocate() -> Result<Self, VmaError> {
+        KernelBuffer::new(mem::size_of::<Scrollback>()).map(ScrollbackBuffer::Kernel)
+    }
+}
+
+impl Deref for ScrollbackBuffer {
+    type Target = Scrollback;
+
+    fn deref(&self) -> &Scrollback {
+        match self {
+            ScrollbackBuffer::Static(lines) => lines,
+            ScrollbackBuffer::Kernel(buffer) => unsafe { &*(buffer.address() as *const Scrollback) },
+        }
+    }
+}
+
+impl DerefMut for ScrollbackBuffer {
+    fn deref_mut(&mut self) -> &mut Scrollback {
+        match self {
+            ScrollbackBuffer::Static(lines) => lines,
+            ScrollbackBuffer::Kernel(buffer) => unsafe { &mut *(buffer.address() as *mut Scrollback) },
+        }
+    }
+}
+
+/// Cell of `c` with the glyph of the loaded font. In 512 glyph mode
+/// attribute bit 3 is glyph bit 8 instead of a bright foreground.
+fn glyph_cell(c: char, attribute: u8) -> Cell {
+    let glyph = vga_font::glyph(c);
+    let attribute = if !vga_font::has_512_glyphs() {
+        attribute
+    } else if glyph > 0xFF {
+        attribute | BRIGHT
+    } else {
+        attribute & !BRIGHT
+    };
+
+    Cell::from(attribute) << 8 | glyph & 0xFF
+}
+
+/// Convert a cell of the other glyph mode. Glyphs 256-511 can't be shown
+/// in 256 glyph mode and become `cp437::REPLACEMENT`. Bright foregrounds
+/// of 256 glyph mode become normal foregrounds in 512 glyph mode.
+fn convert_cell(cell: Cell, glyphs_512: bool) -> Cell {
+    if cell & GLYPH_BIT_8 == 0 {
+        cell
+    } else if glyphs_512 {
+        cell & !GLYPH_BIT_8
+    } else {
+        cell & !GLYPH_BIT_8 & 0xFF00 | Cell::from(cp437::REPLACEMENT)
+    }
+}
+
+/// Screen contents of a terminal. Only the visible screen is drawn to
+/// VGA memory, and a screen which becomes visible is drawn again. The
+/// last row is the command line and the rows above it are output.
+pub struct Screen {
+    text_mode: VgaTextMode,
+    cells: [[Cell; VGA_TEXT_WIDTH]; MAX_ROWS],
+    /// Row count of the font which the cells are for.
+    rows: usize,
+    glyphs_512: bool,
+    /// VGA value of zero cells.
+    blank: Cell,
+    /// Character index of the cursor, `None` if the cursor is hidden.
+    cursor: Option<usize>,
+    visible: bool,
+}
+
+impl Screen {
+    fn new(text_mode: VgaTextMode, visible: bool) -> Self {
+        let mut screen = Self {
+            text_mode,
+            cells: [[0; VGA_TEXT_WIDTH]; MAX_ROWS],
+            rows: 0,
+            glyphs_512: false,
+            blank: 0,
+            cursor: None,
+            visible,
+        };
+        screen.update_font();
+        screen
+    }
+
+    /// Take the row count and glyph mode of the loaded font and clear
+    /// the screen.
+    fn update_font(&mut self) {
+        self.rows = vga_font::rows();
+        self.glyphs_512 = vga_font::has_512_glyphs();
+        self.blank = glyph_cell(' ', DEFAULT_ATTRIBUTE);
+        self.cells = [[0; VGA_TEXT_WIDTH]; MAX_ROWS];
+        self.cursor = None;
+    }
+
+    fn output_lines(&self) -> usize {
+        self.rows - 1
+    }
+
+    fn last_output_line(&self) -> usize {
+        self.rows - 2
+    }
+
+    fn command_line_y(&self) -> usize {
+        self.rows - 1
+    }
+
+    fn draw_cell(&self, x: usize, y: usize) {
+        let cell = match self.cells[y][x] {
+            0 => self.blank,
+            cell => cell,
+        };
+        vga_text::write_cell(y * VGA_TEXT_WIDTH + x, cell);
+    }
+
+    fn write_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        self.cells[y][x] = cell;
+
+        if self.visible {
+            self.draw_cell(x, y);
+        }
+    }
+
+    fn write_char(&mut self, x: usize, y: usize, c: char, attribute: u8) {
+        self.write_cell(x, y, glyph_cell(c, attribute));
+    }
+
+    fn clear(&mut self, y: usize, columns: Range<usize>) {
+        for x in columns {
+            self.write_cell(x, y, 0);
+        }
+    }
+
+    /// Move lines of `lines` up by one and clear the last line.
+    fn scroll(&mut self, lines: Range<usize>) {
+        let last = lines.end - 1;
+        self.cells[lines.clone()].rotate_left(1);
+        self.cells[last] = [0; VGA_TEXT_WIDTH];
+
+        if self.visible {
+            for y in lines {
+                for x in 0..VGA_TEXT_WIDTH {
+                    self.draw_cell(x, y);
+                }
+            }
+        }
+    }
+
+    fn set_cursor(&mut self, cursor: Option<usize>) {
+        self.cursor = cursor;
+
+        if self.visible {
+            self.draw_cursor();
+        }
+    }
+
+    fn draw_cursor(&mut self) {
+        match self.cursor {
+            Some(index) => {
+                vga_text::set_cursor_index(index);
+                self.text_mode.set_cursor_visibility(true);
+            }
+            None => self.text_mode.set_cursor_visibility(false),
+        }
+    }
+
+    /// Draw the screen to VGA memory and keep drawing it.
+    fn show(&mut self) {
+        self.visible = true;
+
+        for y in 0..self.rows {
+            for x in 0..VGA_TEXT_WIDTH {
+                self.draw_cell(x, y);
+            }
+        }
+
+        self.draw_cursor();
+    }
+
+    fn hide(&mut self) {
+        self.visible = false;
+    }
 }
 
 pub struct DebugLine<'a> {
@@ -33,7 +260,7 @@ impl core::fmt::Write for DebugLine<'_> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         let mut line = self.text_mode.lines_mut().nth(0).expect("DebugLine error");
         for (c, mut vga) in s.chars().zip(line.iter_mut().skip(self.position)) {
-            let vga_char = VgaChar::new(c).foreground_color(Colour::White);
+            let vga_char = VgaChar::new(cp437::from_char(c) as char).foreground_color(Colour::White);
             vga.write(vga_char);
             self.position += 1;
         }
@@ -43,95 +270,509 @@ impl core::fmt::Write for DebugLine<'_> {
 }
 
 pub struct Terminal {
-    text_mode: VgaTextMode,
+    screen: Screen,
     history: CommandHistory,
     command_line: CommandLine,
 }
 
 
 impl Terminal {
+    /// Visible terminal which uses the scrollback buffer of the first
+    /// virtual console.
     pub fn new(text_mode: VgaTextMode, init_cmd: bool) -> Self {
+        let scrollback = ScrollbackBuffer::Static(unsafe { &mut SCROLLBACK });
+        Self::with_screen(Screen::new(text_mode, true), scrollback, init_cmd)
+    }
+
+    /// Hidden terminal of another virtual console. Its scrollback buffer
+    /// is a `KernelBuffer`, so paging must be enabled.
+    pub fn new_hidden(text_mode: VgaTextMode, init_cmd: bool) -> Result<Self, VmaError> {
+        let scrollback = ScrollbackBuffer::allocate()?;
+        Ok(Self::with_screen(Screen::new(text_mode, false), scrollback, init_cmd))
+    }
+
+    fn with_screen(screen: Screen, scrollback: ScrollbackBuffer, init_cmd: bool) -> Self {
+        let history = CommandHistory::new(scrollback, screen.output_lines());
         let mut terminal = Self {
-            text_mode,
-            history: CommandHistory::new(),
+            screen,
+            history,
             command_line: CommandLine::new(),
         };
 
         if init_cmd {
-            terminal.command_line.clear_line(&mut terminal.text_mode);
-            terminal.command_line.add_char(&mut terminal.text_mode, '>');
-            terminal.text_mode.set_cursor_height(13, 14);
-            terminal.text_mode.set_cursor_visibility(true);
+            terminal.command_line.clear_line(&mut terminal.screen);
+            terminal.command_line.add_char(&mut terminal.screen, '>');
+            let height = vga_font::height() as u8;
+            terminal.screen.text_mode.set_cursor_height(height - 3, height - 2);
         }
 
         terminal
     }
 
-    pub fn update_command_line<'a>(&mut self, key: KeyPress, cmd_store: &'a mut CommandStore) -> Option<ParsedCommand<'a>> {
-        self.command_line.update_command_line(key, &mut self.text_mode, &mut self.history, cmd_store)
+    /// Draw the terminal to the VGA text buffer and keep drawing it.
+    pub fn show(&mut self) {
+        self.screen.show();
+    }
+
+    /// Stop drawing the terminal.
+    pub fn hide(&mut self) {
+        self.screen.hide();
+    }
+
+    /// Convert the glyphs and change the row count after `vga_font` has
+    /// loaded a font, and draw the terminal again.
+    pub fn update_font(&mut self) {
+        let glyphs_512 = vga_font::has_512_glyphs();
+        if glyphs_512 != self.screen.glyphs_512 {
+            // Unused lines are not touched, so their memory is not
+            // allocated.
+            for back in 0..self.history.line_count {
+                let index = self.history.line_index(back);
+                for cell in self.history.lines[index].iter_mut() {
+                    *cell = convert_cell(*cell, glyphs_512);
+                }
+            }
+        }
+
+        self.screen.update_font();
+        self.history.resize(&mut self.screen);
+        self.command_line.redraw(&mut self.screen);
+
+        if self.screen.visible {
+            self.screen.show();
+        }
+    }
+
+    /// Scroll the output with Shift+PageUp and Shift+PageDown. Returns
+    /// false for other keys, which scroll the output back to the bottom.
+    pub fn scroll_output(&mut self, key: &KeyPress) -> bool {
+        let page_lines = self.screen.output_lines() - 1;
+        match key {
+            KeyPress::ShiftPageUp => self.history.scroll_up(&mut self.screen, page_lines),
+            KeyPress::ShiftPageDown => self.history.scroll_down(&mut self.screen, page_lines),
+            _ => {
+                self.history.scroll_to_bottom(&mut self.screen);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns the command line without the prompt when Enter is pressed.
+    /// Paths are completed relative to the working directory of `ctx`.
+    /// Other keys than Shift+PageUp and Shift+PageDown scroll the output
+    /// back to the bottom.
+    pub fn update_command_line<'a>(&mut self, key: KeyPress, cmd_store: &'a mut CommandStore, ctx: &Context) -> Option<&'a str> {
+        if self.scroll_output(&key) {
+            return None;
+        }
+
+        self.command_line.update_command_line(key, &mut self.screen, &mut self.history, cmd_store, ctx)
     }
 
     pub fn new_command_line(&mut self, cmd_store: &mut CommandStore) {
-        self.command_line.new_command_line(&mut self.text_mode, &mut self.history, cmd_store);
+        self.command_line.new_command_line(&mut self.screen, &mut self.history, cmd_store);
     }
 }
 
 impl core::fmt::Write for Terminal {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        self.history.add_text(&mut self.text_mode, s.chars());
+        self.history.add_text(&mut self.screen, s.chars());
         Ok(())
     }
 }
 
-#[derive(Debug)]
+/// Text attributes which SGR escape sequences select.
+#[derive(Debug, Copy, Clone)]
+struct Attributes {
+    foreground: u8,
+    background: u8,
+    bold: bool,
+    blink: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    fn new() -> Self {
+        Self {
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+            blink: false,
+            reverse: false,
+        }
+    }
+
+    /// Handle parameters of `ESC [ ... m`. Bold text has a bright
+    /// colour. 256 colour and RGB colour parameters are skipped.
+    fn select_graphic_rendition(&mut self, parameters: &[u16]) {
+        if parameters.is_empty() {
+            *self = Self::new();
+        }
+
+        let mut parameters = parameters.iter();
+        while let Some(&parameter) = parameters.next() {
+            let colour = |first| ANSI_TO_VGA_COLOUR[usize::from(parameter - first)];
+            match parameter {
+                0 => *self = Self::new(),
+                1 => self.bold = true,
+                5 | 6 => self.blink = true,
+                7 => self.reverse = true,
+                22 => self.bold = false,
+                25 => self.blink = false,
+                27 => self.reverse = false,
+                30..=37 => self.foreground = colour(30),
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = colour(40),
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = colour(90) | BRIGHT,
+                100..=107 => self.background = colour(100) | BRIGHT,
+                38 | 48 => {
+                    let skip = match parameters.next() {
+                        Some(5) => 1,
+                        Some(2) => 3,
+                        _ => 0,
+                    };
+                    for _ in 0..skip {
+                        parameters.next();
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// VGA attribute byte. Bit 7 is blink, so bright background colours
+    /// are shown as normal colours.
+    fn vga_attribute(self) -> u8 {
+        let mut foreground = self.foreground;
+        if self.bold {
+            foreground |= BRIGHT;
+        }
+        let mut background = self.background;
+        if self.reverse {
+            core::mem::swap(&mut foreground, &mut background);
+        }
+
+        let blink = if self.blink { BLINK } else { 0 };
+        blink | (background & 0x7) << 4 | foreground & 0xF
+    }
+}
+
+/// Output lines above the command line. Lines are also saved to the
+/// scrollback buffer. When the view is scrolled up, new output is only
+/// saved and the view keeps showing the same lines.
+///
+/// Output is interpreted as ANSI/VT100 text. Cursor movement and erase
+/// sequences apply to the lines above the command line, and rows of
+/// `ESC [ row ; column H` start from the top of that area.
 pub struct CommandHistory {
     position: usize,
     scroll_next: bool,
+    /// Scrollback index of the current line.
+    current: usize,
+    /// Number of saved lines, including the current line.
+    line_count: usize,
+    /// How many lines the view is scrolled up from the bottom.
+    view_offset: usize,
+    /// How many lines the cursor is above the current line.
+    cursor_line: usize,
+    /// Position and line which `ESC 7` and `ESC [ s` save.
+    saved_cursor: (usize, usize),
+    attributes: Attributes,
+    parser: ansi::Parser,
+    lines: ScrollbackBuffer,
 }
 
 impl CommandHistory {
-    fn new() -> Self {
+    fn new(mut lines: ScrollbackBuffer, output_lines: usize) -> Self {
+        // Lines of the output area exist from the start, so the cursor
+        // can move to any of them.
+        for line in lines.iter_mut().take(output_lines) {
+            *line = [0; VGA_TEXT_WIDTH];
+        }
+
         Self {
             position: 0,
             scroll_next: false,
+            current: output_lines - 1,
+            line_count: output_lines,
+            view_offset: 0,
+            cursor_line: 0,
+            saved_cursor: (0, 0),
+            attributes: Attributes::new(),
+            parser: ansi::Parser::new(),
+            lines,
+        }
+    }
+
+    fn write_char(&mut self, screen: &mut Screen, c: char) {
+        match self.parser.advance(c) {
+            Some(Action::Print(c)) => self.print(screen, c),
+            Some(Action::Control(c)) => self.control(screen, c),
+            Some(Action::Csi { parameters, private: false, command }) => self.control_sequence(screen, &parameters, command),
+            Some(Action::Escape(command)) => self.escape(screen, command),
+            Some(Action::Csi { private: true, .. }) | None => (),
+        }
+    }
+
+    fn print(&mut self, screen: &mut Screen, c: char) {
+        self.finish_line(screen);
+
+        let cell = glyph_cell(c, self.attributes.vga_attribute());
+        self.set_cell(screen, self.position, self.cursor_line, cell);
+
+        self.position += 1;
+
+        if self.position >= VGA_TEXT_WIDTH {
+            self.line_feed(screen);
+        }
+    }
+
+    fn control(&mut self, screen: &mut Screen, c: char) {
+        match c {
+            // Scrolling is delayed until the next character, so the last
+            // output line is not followed by an empty line.
+            '\n' if self.cursor_line == 0 => {
+                self.finish_line(screen);
+                self.scroll_next = true;
+            }
+            '\n' => self.line_feed(screen),
+            '\r' if !self.scroll_next => self.position = 0,
+            '\x08' if !self.scroll_next => self.position = self.position.saturating_sub(1),
+            '\t' => {
+                self.finish_line(screen);
+                self.position = ((self.position / TAB_WIDTH + 1) * TAB_WIDTH).min(VGA_TEXT_WIDTH - 1);
+            }
+            _ => (),
+        }
+    }
+
+    fn control_sequence(&mut self, screen: &mut Screen, parameters: &[u16], command: char) {
+        // Missing and zero counts are 1.
+        let count = |i: usize| parameters.get(i).map(|&count| usize::from(count).max(1)).unwrap_or(1);
+        let mode = parameters.get(0).cloned().unwrap_or(0);
+
+        if command != 'm' {
+            self.finish_line(screen);
+        }
+
+        let last_line = screen.last_output_line();
+
+        match command {
+            'A' => self.cursor_line = (self.cursor_line + count(0)).min(last_line),
+            'B' => self.cursor_line = self.cursor_line.saturating_sub(count(0)),
+            'C' => self.position = (self.position + count(0)).min(VGA_TEXT_WIDTH - 1),
+            'D' => self.position = self.position.saturating_sub(count(0)),
+            'E' => {
+                self.cursor_line = self.cursor_line.saturating_sub(count(0));
+                self.position = 0;
+            }
+            'F' => {
+                self.cursor_line = (self.cursor_line + count(0)).min(last_line);
+                self.position = 0;
+            }
+            'G' => self.position = (count(0) - 1).min(VGA_TEXT_WIDTH - 1),
+            'H' | 'f' => {
+                self.cursor_line = last_line - (count(0) - 1).min(last_line);
+                self.position = (count(1) - 1).min(VGA_TEXT_WIDTH - 1);
+            }
+            'J' => self.erase_in_display(screen, mode),
+            'K' => self.erase_in_line(screen, mode),
+            'm' => self.attributes.select_graphic_rendition(parameters),
+            's' => self.saved_cursor = (self.position, self.cursor_line),
+            'u' => {
+                let (position, cursor_line) = self.saved_cursor;
+                self.position = position;
+                self.cursor_line = cursor_line;
+            }
+            _ => (),
         }
     }
 
-    fn write_char(&mut self, text_mode: &mut VgaTextMode, c: char) {
+    fn escape(&mut self, screen: &mut Screen, command: char) {
+        match command {
+            '7' => self.control_sequence(screen, &[], 's'),
+            '8' => self.control_sequence(screen, &[], 'u'),
+            // Reset
+            'c' => {
+                self.attributes = Attributes::new();
+                self.control_sequence(screen, &[2], 'J');
+                self.control_sequence(screen, &[], 'H');
+            }
+            _ => (),
+        }
+    }
+
+    /// Mode 0 erases from the cursor to the end of the area, 1 from the
+    /// start of the area to the cursor and 2 the whole area.
+    fn erase_in_display(&mut self, screen: &mut Screen, mode: u16) {
+        let lines = match mode {
+            0 => 0..self.cursor_line,
+            1 => self.cursor_line + 1..screen.output_lines(),
+            2 => 0..screen.output_lines(),
+            _ => return,
+        };
+
+        for cursor_line in lines {
+            self.erase(screen, cursor_line, 0..VGA_TEXT_WIDTH);
+        }
+
+        if mode != 2 {
+            self.erase_in_line(screen, mode);
+        }
+    }
+
+    /// Mode 0 erases from the cursor to the end of the line, 1 from the
+    /// start of the line to the cursor and 2 the whole line.
+    fn erase_in_line(&mut self, screen: &mut Screen, mode: u16) {
+        let columns = match mode {
+            0 => self.position..VGA_TEXT_WIDTH,
+            1 => 0..(self.position + 1).min(VGA_TEXT_WIDTH),
+            2 => 0..VGA_TEXT_WIDTH,
+            _ => return,
+        };
+
+        self.erase(screen, self.cursor_line, columns);
+    }
+
+    fn erase(&mut self, screen: &mut Screen, cursor_line: usize, columns: Range<usize>) {
+        for x in columns {
+            self.set_cell(screen, x, cursor_line, 0);
+        }
+    }
+
+    /// Scrollback index of the line which is `lines_up` lines above the
+    /// current line.
+    fn line_index(&self, lines_up: usize) -> usize {
+        (self.current + SCROLLBACK_LINES - lines_up) % SCROLLBACK_LINES
+    }
+
+    fn set_cell(&mut self, screen: &mut Screen, x: usize, lines_up: usize, cell: Cell) {
+        let index = self.line_index(lines_up);
+        self.lines[index][x] = cell;
+
+        if self.view_offset == 0 {
+            screen.write_cell(x, screen.last_output_line() - lines_up, cell);
+        }
+    }
+
+    /// Do the delayed scrolling of `\n`.
+    fn finish_line(&mut self, screen: &mut Screen) {
         if self.scroll_next {
             self.scroll_next = false;
+            self.new_line(screen);
+        }
+    }
 
-            text_mode.scroll_range(0..COMMAND_HISTORY_LINE_COUNT);
-            text_mode.lines_mut().nth(COMMAND_HISTORY_LINE_COUNT - 1).unwrap().clear_with(VgaChar::empty());
+    /// Move the cursor to the start of the next line.
+    fn line_feed(&mut self, screen: &mut Screen) {
+        if self.cursor_line > 0 {
+            self.cursor_line -= 1;
             self.position = 0;
+        } else {
+            self.new_line(screen);
         }
+    }
 
-        if c == '\n' {
-            self.scroll_next = true;
-            return;
+    fn new_line(&mut self, screen: &mut Screen) {
+        self.current = (self.current + 1) % SCROLLBACK_LINES;
+        self.line_count = (self.line_count + 1).min(SCROLLBACK_LINES);
+        self.position = 0;
+
+        self.lines[self.current] = [0; VGA_TEXT_WIDTH];
+
+        if self.view_offset == 0 {
+            screen.scroll(0..screen.output_lines());
+        } else {
+            self.view_offset = (self.view_offset + 1).min(self.max_view_offset(screen));
         }
+    }
 
-        write_char_to_vga_text_buffer(text_mode, self.position, COMMAND_HISTORY_LAST_LINE_INDEX, c, false);
+    fn max_view_offset(&self, screen: &Screen) -> usize {
+        self.line_count.saturating_sub(screen.output_lines())
+    }
 
-        self.position += 1;
+    fn scroll_up(&mut self, screen: &mut Screen, lines: usize) {
+        let offset = (self.view_offset + lines).min(self.max_view_offset(screen));
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw(screen);
+        }
+    }
 
-        if self.position >= VGA_TEXT_WIDTH {
-            self.position = 0;
-            text_mode.scroll_range(0..COMMAND_HISTORY_LINE_COUNT);
-            text_mode.lines_mut().nth(COMMAND_HISTORY_LINE_COUNT - 1).unwrap().clear_with(VgaChar::empty());
+    fn scroll_down(&mut self, screen: &mut Screen, lines: usize) {
+        let offset = self.view_offset.saturating_sub(lines);
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw(screen);
+        }
+    }
+
+    fn scroll_to_bottom(&mut self, screen: &mut Screen) {
+        self.scroll_down(screen, self.view_offset);
+    }
+
+    /// Draw the lines which the view offset selects.
+    fn redraw(&self, screen: &mut Screen) {
+        for y in 0..screen.output_lines() {
+            // Distance from the current line.
+            let back = screen.last_output_line() - y + self.view_offset;
+            let line = if back < self.line_count {
+                Some(&self.lines[self.line_index(back)])
+            } else {
+                None
+            };
+
+            for x in 0..VGA_TEXT_WIDTH {
+                let cell = line.map(|line| line[x]).unwrap_or(0);
+                screen.write_cell(x, y, cell);
+            }
+        }
+    }
+
+    /// Fit the cursor and the view to the output area of `screen` and
+    /// draw the output.
+    fn resize(&mut self, screen: &mut Screen) {
+        let output_lines = screen.output_lines();
+        self.cursor_line = self.cursor_line.min(output_lines - 1);
+        self.saved_cursor.1 = self.saved_cursor.1.min(output_lines - 1);
+
+        // Lines of a larger output area exist too.
+        while self.line_count < output_lines {
+            let index = self.line_index(self.line_count);
+            self.lines[index] = [0; VGA_TEXT_WIDTH];
+            self.line_count += 1;
         }
+
+        self.view_offset = self.view_offset.min(self.max_view_offset(screen));
+        self.redraw(screen);
     }
 
-    fn add_text(&mut self, text_mode: &mut VgaTextMode, chars: impl Iterator<Item=char>) {
+    fn add_text(&mut self, screen: &mut Screen, chars: impl Iterator<Item=char>) {
         for character in chars {
-            self.write_char(text_mode, character);
+            self.write_char(screen, character);
         }
     }
 }
 
+/// State of reverse incremental history search.
+struct Search {
+    pattern: ArrayString<[u8; SEARCH_PATTERN_LENGTH]>,
+    /// Number and text of the matching history line.
+    found: Option<(usize, HistoryLine)>,
+}
+
 pub struct CommandLine {
     editable_command: ArrayVec<[char; COMMAND_LENGTH]>,
     position: usize,
+    /// Number of the history line which is shown. `None` when the line
+    /// is not from the history.
+    history_position: Option<usize>,
+    /// Line which was edited when history navigation started.
+    saved_command: ArrayVec<[char; COMMAND_LENGTH]>,
+    search: Option<Search>,
 }
 
 impl CommandLine {
@@ -139,108 +780,321 @@ impl CommandLine {
         Self {
             editable_command: ArrayVec::new(),
             position: 0,
+            history_position: None,
+            saved_command: ArrayVec::new(),
+            search: None,
         }
     }
 
-    fn draw_command_line(&mut self, text_mode: &mut VgaTextMode) {
-        for (i, &c) in self.editable_command.iter().enumerate() {
-            write_char_to_vga_text_buffer(text_mode, i, COMMAND_LINE_INDEX_Y, c, false);
+    fn draw_command_line(&mut self, screen: &mut Screen) {
+        Self::draw_text(screen, self.editable_command.iter().copied());
+        self.update_cursor_position(screen);
+    }
+
+    /// Draw the command line again, for example on another row.
+    /// Terminals without a command line have no prompt, and nothing is
+    /// drawn.
+    fn redraw(&mut self, screen: &mut Screen) {
+        if self.search.is_some() {
+            self.draw_search(screen);
+        } else if !self.editable_command.is_empty() {
+            self.draw_command_line(screen);
         }
+    }
 
-        let cmd_len = self.editable_command.iter().count();
+    /// Write text to the command line and clear the rest of the line.
+    /// Returns the number of written characters.
+    fn draw_text(screen: &mut Screen, text: impl Iterator<Item=char>) -> usize {
+        let mut length = 0;
+        for (i, c) in text.take(VGA_TEXT_WIDTH).enumerate() {
+            screen.write_char(i, screen.command_line_y(), c, DEFAULT_ATTRIBUTE);
+            length += 1;
+        }
 
         // Clear the end of the command line. Character deleting support requires this.
-        for mut c in text_mode.lines_mut().nth(COMMAND_LINE_INDEX_Y).unwrap().iter_mut().skip(cmd_len) {
-            c.write(Self::whitespace_character());
+        screen.clear(screen.command_line_y(), length..VGA_TEXT_WIDTH);
+
+        length
+    }
+
+    /// Replace the command after the prompt.
+    fn set_command(&mut self, screen: &mut Screen, command: impl Iterator<Item=char>) {
+        self.editable_command.truncate(1);
+        for c in command {
+            if self.editable_command.try_push(c).is_err() {
+                break;
+            }
         }
+        self.position = self.editable_command.len();
+        self.draw_command_line(screen);
+    }
+
+    fn history_up(&mut self, screen: &mut Screen) {
+        let (first, end) = shell_history::range();
+        let number = match self.history_position {
+            None if first < end => {
+                self.saved_command = self.editable_command.clone();
+                end - 1
+            }
+            Some(number) if number > first => number - 1,
+            _ => return,
+        };
 
-        self.update_cursor_position(text_mode);
+        if let Some(line) = shell_history::get(number) {
+            self.history_position = Some(number);
+            self.set_command(screen, line.chars());
+        }
+    }
+
+    fn history_down(&mut self, screen: &mut Screen) {
+        let number = match self.history_position {
+            Some(number) => number + 1,
+            None => return,
+        };
+
+        match shell_history::get(number) {
+            Some(line) => {
+                self.history_position = Some(number);
+                self.set_command(screen, line.chars());
+            }
+            None => {
+                self.history_position = None;
+                self.editable_command = self.saved_command.clone();
+                self.position = self.editable_command.len();
+                self.draw_command_line(screen);
+            }
+        }
+    }
+
+    fn draw_search(&self, screen: &mut Screen) {
+        let search = match &self.search {
+            Some(search) => search,
+            None => return,
+        };
+
+        let mut text = ArrayString::<[u8; 512]>::new();
+        let failed = if search.found.is_none() && !search.pattern.is_empty() { "failed " } else { "" };
+        let line = search.found.as_ref().map(|(_, line)| line.as_str()).unwrap_or("");
+        let _ = write!(text, "({}reverse-i-search)'{}': {}", failed, search.pattern, line);
+
+        let length = Self::draw_text(screen, text.chars());
+        screen.set_cursor(Some(length.min(VGA_TEXT_WIDTH - 1) + screen.command_line_y() * VGA_TEXT_WIDTH));
+    }
+
+    /// Find the pattern from lines older than `before`.
+    fn search_history(&mut self, before: usize) {
+        if let Some(search) = &mut self.search {
+            search.found = if search.pattern.is_empty() {
+                None
+            } else {
+                shell_history::search(&search.pattern, before)
+            };
+        }
+    }
+
+    /// Handle a key in search mode. Returns the key if search ended and
+    /// the key should be handled as a normal key.
+    fn update_search(&mut self, key: KeyPress, screen: &mut Screen) -> Option<KeyPress> {
+        let (current, end) = match &self.search {
+            Some(search) => (search.found.as_ref().map(|&(number, _)| number), shell_history::range().1),
+            None => return Some(key),
+        };
+
+        match key {
+            KeyPress::Unicode(c) if !c.is_control() => {
+                if let Some(search) = &mut self.search {
+                    let _ = search.pattern.try_push(c);
+                }
+                // Current line still matches if it contains the longer pattern.
+                self.search_history(current.map(|number| number + 1).unwrap_or(end));
+            }
+            KeyPress::Backspace => {
+                if let Some(search) = &mut self.search {
+                    search.pattern.pop();
+                }
+                self.search_history(end);
+            }
+            KeyPress::Ctrl('r') => {
+                self.search_history(current.unwrap_or(end));
+            }
+            KeyPress::Ctrl('g') | KeyPress::Escape => {
+                self.search = None;
+                self.draw_command_line(screen);
+                return None;
+            }
+            key => {
+                if let Some((number, line)) = self.search.take().and_then(|search| search.found) {
+                    self.history_position = Some(number);
+                    self.set_command(screen, line.chars());
+                } else {
+                    self.draw_command_line(screen);
+                }
+                return Some(key);
+            }
+        }
+
+        self.draw_search(screen);
+        None
     }
 
-    fn add_char(&mut self, text_mode: &mut VgaTextMode, c: char) {
+    fn add_char(&mut self, screen: &mut Screen, c: char) {
         if let Ok(()) = self.editable_command.try_insert(self.position, c) {
             self.position += 1;
-            self.draw_command_line(text_mode);
+            self.draw_command_line(screen);
+        }
+    }
+
+    /// Complete the word before the cursor. If there are many matches
+    /// and the word can't be extended, the matches are listed.
+    fn complete(&mut self, screen: &mut Screen, command_history: &mut CommandHistory, ctx: &Context) {
+        let mut line = ArrayString::<[u8; MAX_LINE_LENGTH]>::new();
+        for &c in &self.editable_command[1..self.position] {
+            let _ = line.try_push(c);
+        }
+
+        let (word_start, completions) = shell_completion::complete(ctx, &line);
+        let completion = match completions.completion() {
+            Some(completion) => completion,
+            None => return,
+        };
+
+        if completions.count() > 1 && completion.len() == line.len() - word_start {
+            Self::list_matches(screen, command_history, completions.matches(), completions.count());
+            return;
+        }
+
+        // Prompt is the first character.
+        let start = line[..word_start].chars().count() + 1;
+        for _ in start..self.position {
+            self.editable_command.remove(start);
+        }
+        self.position = start;
+
+        for c in completion.chars() {
+            if self.editable_command.try_insert(self.position, c).is_err() {
+                break;
+            }
+            self.position += 1;
+        }
+
+        self.draw_command_line(screen);
+    }
+
+    /// Write matches in lines above the command line.
+    fn list_matches(screen: &mut Screen, command_history: &mut CommandHistory, matches: &[shell_completion::Match], count: usize) {
+        let mut column = 0;
+
+        for name in matches {
+            let width = name.len() + 2;
+            if column > 0 && column + width > VGA_TEXT_WIDTH {
+                command_history.add_text(screen, "\n".chars());
+                column = 0;
+            }
+            command_history.add_text(screen, name.chars().chain("  ".chars()).take(VGA_TEXT_WIDTH - column));
+            column += width;
         }
+
+        if count > matches.len() {
+            let mut more = ArrayString::<[u8; 32]>::new();
+            let _ = write!(more, "\n... {} more", count - matches.len());
+            command_history.add_text(screen, more.chars());
+        }
+
+        command_history.add_text(screen, "\n".chars());
     }
 
-    pub fn update_command_line<'a>(&mut self, key: KeyPress, text_mode: &mut VgaTextMode, command_history: &mut CommandHistory, cmd_store: &'a mut CommandStore) -> Option<ParsedCommand<'a>> {
+    pub fn update_command_line<'a>(&mut self, key: KeyPress, screen: &mut Screen, command_history: &mut CommandHistory, cmd_store: &'a mut CommandStore, ctx: &Context) -> Option<&'a str> {
+        let key = if self.search.is_some() {
+            self.update_search(key, screen)?
+        } else {
+            key
+        };
+
         match key {
             KeyPress::Enter => {
-                self.new_command_line(text_mode, command_history, cmd_store);
-                return Some(ParsedCommand::parse(&cmd_store.cmd));
+                self.new_command_line(screen, command_history, cmd_store);
+                return Some(&cmd_store.cmd[1..]);
+            }
+            KeyPress::Tab => self.complete(screen, command_history, ctx),
+            KeyPress::Up => self.history_up(screen),
+            KeyPress::Down => self.history_down(screen),
+            KeyPress::Ctrl('r') => {
+                self.search = Some(Search {
+                    pattern: ArrayString::new(),
+                    found: None,
+                });
+                self.draw_search(screen);
             }
             KeyPress::Unicode(c) => {
-                if c.is_ascii() {
-                    self.add_char(text_mode, c);
+                if !c.is_control() {
+                    self.add_char(screen, c);
                 }
             },
             KeyPress::Left => {
                 if self.position > 1 {
                     self.position -= 1;
                 }
-                self.update_cursor_position(text_mode);
+                self.update_cursor_position(screen);
             },
             KeyPress::Right => {
                 if self.position < self.editable_command.len() {
                     self.position += 1;
                 }
-                self.update_cursor_position(text_mode);
+                self.update_cursor_position(screen);
             },
             KeyPress::Backspace => {
                 if self.editable_command.len() > 1 && self.position > 1 {
                     self.editable_command.remove(self.position - 1);
                     self.position -= 1;
-                    self.draw_command_line(text_mode);
+                    self.draw_command_line(screen);
                 }
             },
             KeyPress::Delete => {
                 if self.editable_command.len() > self.position && self.position > 0 {
                     self.editable_command.remove(self.position);
-                    self.draw_command_line(text_mode);
+                    self.draw_command_line(screen);
                 }
             }
             KeyPress::Home => {
                 self.position = 1;
-                self.update_cursor_position(text_mode);
+                self.update_cursor_position(screen);
             }
             KeyPress::End => {
                 self.position = self.editable_command.len();
-                self.update_cursor_position(text_mode);
+                self.update_cursor_position(screen);
             }
             _ => (),
         }
         None
     }
 
-    pub fn new_command_line(&mut self, text_mode: &mut VgaTextMode, command_history: &mut CommandHistory, cmd_store: &mut CommandStore) {
-        command_history.add_text(text_mode, self.editable_command.iter().copied());
-        command_history.add_text(text_mode, "\n".chars());
+    pub fn new_command_line(&mut self, screen: &mut Screen, command_history: &mut CommandHistory, cmd_store: &mut CommandStore) {
+        command_history.add_text(screen, self.editable_command.iter().copied());
+        command_history.add_text(screen, "\n".chars());
         cmd_store.replace_cmd(self.editable_command.iter().copied());
 
         self.editable_command.clear();
         self.position = 0;
+        self.history_position = None;
+        self.search = None;
 
-        self.clear_line(text_mode);
-
-        self.add_char(text_mode, '>');
-    }
+        self.clear_line(screen);
 
-    pub fn clear_line(&self, text_mode: &mut VgaTextMode) {
-        text_mode.lines_mut().nth(COMMAND_LINE_INDEX_Y).expect("new_command_line, line not found").clear_with(Self::whitespace_character());
+        self.add_char(screen, '>');
     }
 
-    pub fn whitespace_character() -> VgaChar {
-        VgaChar::new(' ').foreground_color(Colour::White)
+    pub fn clear_line(&self, screen: &mut Screen) {
+        screen.clear(screen.command_line_y(), 0..VGA_TEXT_WIDTH);
     }
 
-    pub fn update_cursor_position(&self, text_mode: &mut VgaTextMode) {
-        text_mode.set_cursor_character_index(self.position + COMMAND_LINE_INDEX_Y * VGA_TEXT_WIDTH);
+    pub fn update_cursor_position(&self, screen: &mut Screen) {
+        screen.set_cursor(Some(self.position + screen.command_line_y() * VGA_TEXT_WIDTH));
     }
 }
 
+/// Command line as UTF-8.
 pub struct CommandStore {
-    pub cmd: ArrayString<[u8; COMMAND_LENGTH]>,
+    pub cmd: ArrayString<[u8; MAX_LINE_LENGTH]>,
 }
 
 impl CommandStore {
@@ -253,27 +1107,10 @@ impl CommandStore {
     fn replace_cmd(&mut self, chars: impl Iterator<Item=char>) {
         self.cmd.clear();
         for character in chars {
-            self.cmd.try_push(character).expect("Multibyte characters are not supported currently.");
+            if self.cmd.try_push(character).is_err() {
+                break;
+            }
         }
     }
 }
 
-
-pub struct ParsedCommand<'a> {
-    pub name: &'a str,
-    pub arguments: core::str::SplitWhitespace<'a>,
-}
-
-impl <'a> ParsedCommand<'a> {
-    pub fn parse(cmd_line: &'a str) -> Self {
-        let cmd = cmd_line[1..].trim();
-
-        let mut iter = cmd.split_whitespace();
-        let name = iter.next().unwrap_or_default();
-
-        Self {
-            name,
-            arguments: iter,
-        }
-    }
-}
@@ -2,23 +2,33 @@ use x86::bits32::task::TaskStateSegment;
 use x86::segmentation::*;
 use x86::task::load_tr;
 
+use crate::gdt::{KERNEL_DATA_SELECTOR, TSS_SELECTOR};
+use crate::sync::IrqSpinlock;
+
 #[repr(transparent)]
 pub struct TSS {
     _start: TaskStateSegment,
 }
 
 #[used]
-pub static TSS_DATA: TSS = TSS {
+pub static TSS_DATA: IrqSpinlock<TSS> = IrqSpinlock::new("tss", TSS {
     _start: TaskStateSegment::new()
-};
+});
 
+/// Set the stack which CPU switches to when an interrupt or
+/// exception happens in user mode.
+pub fn set_kernel_stack(stack_top: usize) {
+    let mut tss = TSS_DATA.lock();
+    tss._start.esp0 = stack_top as u32;
+    tss._start.ss0 = KERNEL_DATA_SELECTOR;
+}
 
 pub struct KernelTask;
 
 impl KernelTask {
     pub fn load() -> Self {
         unsafe {
-            load_tr(SegmentSelector::new(3, x86::Ring::Ring0));
+            load_tr(SegmentSelector::from_raw(TSS_SELECTOR));
         }
         KernelTask
     }
@@ -0,0 +1,192 @@
+//! Running code in ring 3.
+//!
+//! A kernel thread enters user mode with `iret` and the user code runs
+//! until it calls `exit`, causes an exception or is killed. Kernel then
+//! switches back to the kernel stack which was saved when the thread
+//! entered user mode.
+
+use crate::frame_allocator::{self, Frame, FRAME_SIZE};
+use crate::idt::{Exception, InterruptFrame};
+use crate::page_table::{AddressSpace, L1Flags, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START};
+use crate::process::{self, ProcessError};
+use crate::scheduler::{self, MAX_THREADS};
+
+extern "C" {
+    fn enter_user_mode(saved_kernel_stack: *mut usize, registers: &UserRegisters);
+    fn leave_user_mode(saved_kernel_stack: usize) -> !;
+}
+
+/// Size of the stack which is used when an interrupt happens in user mode.
+const KERNEL_STACK_FRAMES: usize = 4;
+
+pub const USER_CODE_ADDRESS: usize = USER_SPACE_START;
+pub const USER_STACK_TOP: usize = USER_SPACE_END;
+
+// Every thread accesses only its own entry, so these don't need locks.
+static mut SAVED_KERNEL_STACKS: [usize; MAX_THREADS] = [0; MAX_THREADS];
+static mut EXIT_REASONS: [Option<ExitReason>; MAX_THREADS] = [None; MAX_THREADS];
+
+#[derive(Debug, Copy, Clone)]
+pub enum ExitReason {
+    /// Task called `exit`.
+    Exit(i32),
+    Exception {
+        exception: Exception,
+        error_code: Option<u32>,
+        eip: u32,
+        /// Value of CR2 if the exception is a page fault.
+        fault_address: Option<u32>,
+    },
+    /// Task was killed with `kill`.
+    Killed,
+}
+
+/// Register values when entering user mode. EFLAGS is always `0x202`,
+/// so interrupts are enabled.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UserRegisters {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub ebp: u32,
+    pub eip: u32,
+    pub esp: u32,
+}
+
+impl UserRegisters {
+    /// Registers for starting a program at `entry`.
+    pub fn new(entry: usize, stack_pointer: usize) -> Self {
+        Self {
+            eip: entry as u32,
+            esp: stack_pointer as u32,
+            ..Self::default()
+        }
+    }
+}
+
+/// Physically contiguous stack for a task.
+pub struct KernelStack {
+    first_frame: Frame,
+}
+
+impl KernelStack {
+    pub fn new() -> Option<Self> {
+        let first_frame = frame_allocator::allocate_contiguous_frames(KERNEL_STACK_FRAMES)?;
+        Some(Self {
+            first_frame,
+        })
+    }
+
+    pub fn top(&self) -> usize {
+        self.first_frame.start_address() + KERNEL_STACK_FRAMES * FRAME_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        frame_allocator::free_contiguous_frames(self.first_frame, KERNEL_STACK_FRAMES);
+    }
+}
+
+/// Run user mode code in the current thread until it exits.
+///
+/// Address space must be active and the interrupt stack of the thread
+/// must be set with `scheduler::set_user_interrupt_stack`.
+pub fn enter(registers: &UserRegisters) -> ExitReason {
+    let thread = scheduler::current_thread_id();
+
+    unsafe {
+        enter_user_mode(&mut SAVED_KERNEL_STACKS[thread], registers);
+
+        SAVED_KERNEL_STACKS[thread] = 0;
+        EXIT_REASONS[thread].take().expect("user task exited without a reason")
+    }
+}
+
+/// Called from an exception handler when the exception happened in
+/// user mode. Returns to the kernel code which started the task.
+pub fn kill_current_task(exception: Exception, error_code: Option<u32>, frame: &InterruptFrame) -> ! {
+    if unsafe { SAVED_KERNEL_STACKS[scheduler::current_thread_id()] } == 0 {
+        panic!("Exception {:?} from user mode without a user task", exception);
+    }
+
+    let fault_address = match exception {
+        Exception::PageFault => Some(unsafe { x86::controlregs::cr2() } as u32),
+        _ => None,
+    };
+
+    exit_current_task(ExitReason::Exception {
+        exception,
+        error_code,
+        eip: frame.eip,
+        fault_address,
+    })
+}
+
+/// Stop the current task and return to the kernel code which started it.
+pub fn exit_current_task(reason: ExitReason) -> ! {
+    let thread = scheduler::current_thread_id();
+
+    unsafe {
+        EXIT_REASONS[thread] = Some(reason);
+        leave_user_mode(SAVED_KERNEL_STACKS[thread])
+    }
+}
+
+/// Writes a message to standard output and exits.
+const TEST_PROGRAM: &[u8] = &[
+    0xB8, 0x02, 0x00, 0x00, 0x00, // mov $SYS_WRITE, %eax
+    0xBB, 0x01, 0x00, 0x00, 0x00, // mov $1, %ebx
+    0xB9, 0x22, 0x00, 0x00, 0x40, // mov $message, %ecx
+    0xBA, 0x15, 0x00, 0x00, 0x00, // mov $message_length, %edx
+    0xCD, 0x80,                   // int $0x80
+    0xB8, 0x00, 0x00, 0x00, 0x00, // mov $SYS_EXIT, %eax
+    0xBB, 0x00, 0x00, 0x00, 0x00, // mov $0, %ebx
+    0xCD, 0x80,                   // int $0x80
+    // message:
+    b'H', b'e', b'l', b'l', b'o', b' ', b'f', b'r', b'o', b'm', b' ',
+    b'u', b's', b'e', b'r', b' ', b'm', b'o', b'd', b'e', b'\n',
+];
+
+/// Pushes a value to the user stack and then reads kernel memory, which
+/// causes a page fault.
+const FAULT_TEST_PROGRAM: &[u8] = &[
+    0x68, 0x78, 0x56, 0x34, 0x12, // push $0x12345678
+    0x58,                         // pop %eax
+    0xA1, 0x00, 0x00, 0x20, 0x00, // mov 0x200000, %eax
+    0xEB, 0xFE,                   // jmp .
+];
+
+/// Run a small built-in program in a new process with console file
+/// descriptors. If `fault` is true, the program causes a page fault.
+pub fn run_test_program(fault: bool) -> Result<ExitReason, ProcessError> {
+    let program = if fault { FAULT_TEST_PROGRAM } else { TEST_PROGRAM };
+    let mut address_space = AddressSpace::new()?;
+    map_test_program(&mut address_space, program)?;
+
+    let registers = UserRegisters::new(USER_CODE_ADDRESS, USER_STACK_TOP);
+    process::run("usertest", address_space, &registers, process::console_context())
+}
+
+fn map_test_program(address_space: &mut AddressSpace, program: &[u8]) -> Result<(), ProcessError> {
+    let code = frame_allocator::allocate_zeroed_frame().ok_or(ProcessError::OutOfMemory)?;
+    unsafe {
+        code.data_mut()[..program.len()].copy_from_slice(program);
+    }
+    if let Err(e) = address_space.map_user_page(USER_CODE_ADDRESS, code, L1Flags::empty()) {
+        frame_allocator::free_frame(code);
+        return Err(e.into());
+    }
+
+    let stack = frame_allocator::allocate_zeroed_frame().ok_or(ProcessError::OutOfMemory)?;
+    if let Err(e) = address_space.map_user_page(USER_STACK_TOP - PAGE_SIZE, stack, L1Flags::READ_WRITE | L1Flags::NO_EXECUTE) {
+        frame_allocator::free_frame(stack);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! UTF-8 decoding of byte streams.
+
+use core::char::REPLACEMENT_CHARACTER;
+
+/// Decodes UTF-8 one byte at a time, so a character can be split between
+/// reads. Invalid bytes and sequences are decoded as U+FFFD.
+#[derive(Debug)]
+pub struct Utf8Decoder {
+    code_point: u32,
+    /// Continuation bytes which are missing from the current character.
+    remaining: u8,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Self {
+            code_point: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Decode `byte` and call `output` with the decoded characters.
+    pub fn push(&mut self, byte: u8, mut output: impl FnMut(char)) {
+        if self.remaining > 0 {
+            if byte & 0xC0 == 0x80 {
+                self.code_point = self.code_point << 6 | u32::from(byte & 0x3F);
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    output(core::char::from_u32(self.code_point).unwrap_or(REPLACEMENT_CHARACTER));
+                }
+                return;
+            }
+
+            // Sequence ended too early, decode the byte as a new character.
+            self.remaining = 0;
+            output(REPLACEMENT_CHARACTER);
+        }
+
+        let (code_point, remaining) = match byte {
+            0x00..=0x7F => return output(byte as char),
+            0xC2..=0xDF => (byte & 0x1F, 1),
+            0xE0..=0xEF => (byte & 0x0F, 2),
+            0xF0..=0xF4 => (byte & 0x07, 3),
+            _ => return output(REPLACEMENT_CHARACTER),
+        };
+
+        self.code_point = u32::from(code_point);
+        self.remaining = remaining;
+    }
+
+    /// Decode `bytes` and call `output` with the decoded characters.
+    pub fn push_bytes(&mut self, bytes: &[u8], mut output: impl FnMut(char)) {
+        for &byte in bytes {
+            self.push(byte, &mut output);
+        }
+    }
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,846 @@
+//! Virtual filesystem layer.
+//!
+//! Concrete filesystems implement the `FileSystem` trait and are attached to
+//! the directory tree with `Vfs::mount`. Paths are resolved by walking the
+//! tree one `Dentry` at a time, which makes `..` and mount point crossing
+//! simple: the walk keeps every visited directory in a stack.
+
+use arrayvec::{ArrayString, ArrayVec};
+use bitflags::bitflags;
+
+use crate::sync::{IrqSpinlock, Mutex};
+
+pub const FILE_NAME_MAX_LENGTH: usize = 64;
+pub const PATH_MAX_LENGTH: usize = 256;
+pub const FILE_DESCRIPTOR_TABLE_SIZE: usize = 16;
+
+const MOUNT_TABLE_SIZE: usize = 8;
+const FILE_SYSTEM_TYPE_COUNT: usize = 8;
+const MAX_PATH_DEPTH: usize = 32;
+const MAX_SYMLINK_DEPTH: usize = 8;
+const RETAINED_VNODE_COUNT: usize = 32;
+
+pub type FileName = ArrayString<[u8; FILE_NAME_MAX_LENGTH]>;
+pub type PathBuf = ArrayString<[u8; PATH_MAX_LENGTH]>;
+pub type InodeNumber = u32;
+pub type FileDescriptor = usize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidPath,
+    NameTooLong,
+    PathTooLong,
+    TooManySymlinks,
+    BadFileDescriptor,
+    TooManyOpenFiles,
+    MountTableFull,
+    UnknownFileSystemType,
+    ReadOnly,
+    NoSpace,
+    PermissionDenied,
+    InvalidArgument,
+    NotSupported,
+    IoError,
+    Corrupted,
+    /// Pipe doesn't have any read ends.
+    BrokenPipe,
+    /// File is in use, for example as a swap file.
+    Busy,
+    /// Process was killed while it waited.
+    Interrupted,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Pipe,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata {
+    pub inode: InodeNumber,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Permission bits, for example `0o755`.
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub links: u16,
+    /// Modification time in seconds.
+    pub modified: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: FileName,
+    pub inode: InodeNumber,
+    pub file_type: FileType,
+}
+
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+bitflags! {
+    pub struct OpenFlags: u32 {
+        const READ = 1;
+        const WRITE = 1 << 1;
+        const CREATE = 1 << 2;
+        const TRUNCATE = 1 << 3;
+        const APPEND = 1 << 4;
+        const DIRECTORY = 1 << 5;
+    }
+}
+
+/// Interface which concrete filesystems implement.
+///
+/// Inode numbers are filesystem specific. Only `root`, `lookup`,
+/// `metadata`, `read` and `read_dir` are required, the default
+/// implementations of the other methods describe a read-only filesystem
+/// which doesn't track open files.
+pub trait FileSystem {
+    /// Filesystem type name, for example "ramfs".
+    fn name(&self) -> &'static str;
+    fn root(&self) -> InodeNumber;
+    fn lookup(&mut self, directory: InodeNumber, name: &str) -> Result<InodeNumber, FsError>;
+    fn metadata(&mut self, inode: InodeNumber) -> Result<Metadata, FsError>;
+    fn read(&mut self, inode: InodeNumber, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+    /// Returns directory entry number `index`, or `None` if there are no more entries.
+    fn read_dir(&mut self, directory: InodeNumber, index: usize) -> Result<Option<DirEntry>, FsError>;
+
+    fn write(&mut self, _inode: InodeNumber, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_link(&mut self, _inode: InodeNumber, _target: &mut PathBuf) -> Result<(), FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn create(&mut self, _directory: InodeNumber, _name: &str, _file_type: FileType, _mode: u16) -> Result<InodeNumber, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&mut self, _directory: InodeNumber, _name: &str, _target: &str) -> Result<InodeNumber, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&mut self, _inode: InodeNumber, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Remove a file, a symlink or an empty directory.
+    fn remove(&mut self, _directory: InodeNumber, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// Oldest offset which can still be read from the inode. Reads from
+    /// older offsets start here instead, so streams which drop old data,
+    /// like the kernel log, move their readers past the dropped data.
+    fn first_offset(&mut self, _inode: InodeNumber) -> u64 {
+        0
+    }
+
+    /// Called when a file descriptor to the inode is opened or duplicated.
+    fn open(&mut self, _inode: InodeNumber, _flags: OpenFlags) {}
+
+    /// Called when a file descriptor to the inode is closed.
+    fn close(&mut self, _inode: InodeNumber, _flags: OpenFlags) {}
+
+    /// True if the filesystem locks its own data, so the VFS doesn't
+    /// serialise calls to it. Filesystems which block, for example until
+    /// a pipe has data, must do this, because other threads have to call
+    /// them while they wait.
+    fn locks_internally(&self) -> bool {
+        false
+    }
+}
+
+/// Filesystem type which can be mounted with the `mount` shell command.
+pub struct FileSystemType {
+    pub name: &'static str,
+    /// Create a new filesystem instance. Argument `source` is for
+    /// example a device path and it might be empty.
+    pub mount: fn(source: &str) -> Result<&'static mut dyn FileSystem, FsError>,
+}
+
+/// Inode from some mounted filesystem.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VNode {
+    pub mount: usize,
+    pub inode: InodeNumber,
+}
+
+/// Path component which path resolution has visited.
+#[derive(Clone)]
+pub struct Dentry {
+    pub name: FileName,
+    pub vnode: VNode,
+}
+
+type DentryStack = ArrayVec<[Dentry; MAX_PATH_DEPTH]>;
+
+#[derive(Clone)]
+pub struct MountInfo {
+    /// Internal filesystems which aren't in the directory tree have an
+    /// empty path.
+    pub path: PathBuf,
+    pub source: FileName,
+    file_system_type: &'static str,
+}
+
+impl MountInfo {
+    pub fn file_system_type(&self) -> &'static str {
+        self.file_system_type
+    }
+}
+
+struct Mount {
+    info: MountInfo,
+    /// Inode which this mount hides. Root filesystem doesn't have one.
+    covered: Option<VNode>,
+    root: InodeNumber,
+    fs: &'static mut dyn FileSystem,
+    /// Serialises calls to `fs`. `None` if the filesystem locks its own
+    /// data.
+    lock: Option<Mutex<()>>,
+}
+
+// Filesystems are called only through `Vfs::with_fs`, which holds the
+// lock of the mount or relies on the filesystem's own locking.
+unsafe impl Send for Mount {}
+
+#[derive(Debug, Copy, Clone)]
+pub struct OpenFile {
+    pub vnode: VNode,
+    pub offset: u64,
+    pub flags: OpenFlags,
+}
+
+pub struct FileDescriptorTable {
+    files: [Option<OpenFile>; FILE_DESCRIPTOR_TABLE_SIZE],
+}
+
+impl FileDescriptorTable {
+    pub fn new() -> Self {
+        Self {
+            files: [None; FILE_DESCRIPTOR_TABLE_SIZE],
+        }
+    }
+
+    fn insert(&mut self, file: OpenFile) -> Result<FileDescriptor, FsError> {
+        for (fd, slot) in self.files.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(file);
+                return Ok(fd);
+            }
+        }
+
+        Err(FsError::TooManyOpenFiles)
+    }
+
+    pub fn get(&self, fd: FileDescriptor) -> Result<&OpenFile, FsError> {
+        self.files.get(fd).and_then(|f| f.as_ref()).ok_or(FsError::BadFileDescriptor)
+    }
+
+    pub fn get_mut(&mut self, fd: FileDescriptor) -> Result<&mut OpenFile, FsError> {
+        self.files.get_mut(fd).and_then(|f| f.as_mut()).ok_or(FsError::BadFileDescriptor)
+    }
+
+    fn remove(&mut self, fd: FileDescriptor) -> Result<OpenFile, FsError> {
+        self.files.get_mut(fd).and_then(|f| f.take()).ok_or(FsError::BadFileDescriptor)
+    }
+}
+
+/// Working directory and open files of a shell session or a task.
+///
+/// Contexts are copied with `Vfs::clone_context` and their files are
+/// closed with `Vfs::close_all`, so that filesystems see every open and
+/// close.
+pub struct Context {
+    current_directory: PathBuf,
+    pub files: FileDescriptorTable,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        let mut current_directory = PathBuf::new();
+        current_directory.push('/');
+
+        Self {
+            current_directory,
+            files: FileDescriptorTable::new(),
+        }
+    }
+
+    /// New context with the same working directory and no open files.
+    pub fn without_files(&self) -> Self {
+        Self {
+            current_directory: self.current_directory,
+            files: FileDescriptorTable::new(),
+        }
+    }
+
+    pub fn current_directory(&self) -> &str {
+        &self.current_directory
+    }
+
+    /// Absolute path of `path`, which can be relative to the working
+    /// directory. Components are not resolved.
+    pub fn absolute_path(&self, path: &str) -> Result<PathBuf, FsError> {
+        let mut absolute = PathBuf::new();
+        if !path.starts_with('/') {
+            absolute.push_str(&self.current_directory);
+            if !absolute.ends_with('/') {
+                absolute.try_push('/').map_err(|_| FsError::NameTooLong)?;
+            }
+        }
+
+        absolute.try_push_str(path).map_err(|_| FsError::NameTooLong)?;
+        Ok(absolute)
+    }
+
+    /// Change the working directory to the working directory of `other`.
+    pub fn copy_current_directory(&mut self, other: &Context) {
+        self.current_directory = other.current_directory;
+    }
+}
+
+/// Mounted filesystems and registered filesystem types.
+///
+/// The mount table is behind a spinlock which is held only to look up
+/// mounts, never while a filesystem is called. Calls to a filesystem are
+/// serialised with a mutex of its mount, so a thread which waits for a
+/// disk doesn't stop other threads from using other filesystems. Mounts
+/// are never removed.
+pub struct Vfs {
+    mounts: IrqSpinlock<ArrayVec<[Mount; MOUNT_TABLE_SIZE]>>,
+    file_system_types: IrqSpinlock<ArrayVec<[FileSystemType; FILE_SYSTEM_TYPE_COUNT]>>,
+    /// Inodes which are used without an open file, with reference
+    /// counts. These can't be removed or truncated.
+    retained: IrqSpinlock<ArrayVec<[(VNode, usize); RETAINED_VNODE_COUNT]>>,
+}
+
+/// Set once by `init` before other threads start.
+static mut VFS: Option<Vfs> = None;
+
+/// Create the VFS and mount `root` at `/`.
+pub fn init(root: &'static mut dyn FileSystem) {
+    let vfs = Vfs {
+        mounts: IrqSpinlock::new("mount table", ArrayVec::new()),
+        file_system_types: IrqSpinlock::new("filesystem types", ArrayVec::new()),
+        retained: IrqSpinlock::new("retained vnodes", ArrayVec::new()),
+    };
+
+    let mut path = PathBuf::new();
+    path.push('/');
+    vfs.add_mount(path, FileName::from("none").unwrap(), None, root)
+        .expect("Root filesystem mounting failed");
+
+    unsafe {
+        VFS = Some(vfs);
+    }
+}
+
+pub fn vfs() -> &'static Vfs {
+    unsafe {
+        VFS.as_ref().expect("VFS is not initialized")
+    }
+}
+
+impl Vfs {
+    pub fn register_file_system_type(&self, fs_type: FileSystemType) {
+        if self.file_system_types.lock().try_push(fs_type).is_err() {
+            panic!("too many filesystem types");
+        }
+    }
+
+    pub fn file_system_types(&self) -> ArrayVec<[&'static str; FILE_SYSTEM_TYPE_COUNT]> {
+        self.file_system_types.lock().iter().map(|t| t.name).collect()
+    }
+
+    pub fn mounts(&self) -> ArrayVec<[MountInfo; MOUNT_TABLE_SIZE]> {
+        self.mounts.lock().iter()
+            .filter(|mount| !mount.info.path.is_empty())
+            .map(|mount| mount.info.clone())
+            .collect()
+    }
+
+    /// Add a filesystem which isn't attached to the directory tree and
+    /// return its mount index. Its files can be opened only with
+    /// `open_vnode`.
+    pub fn mount_internal(&self, fs: &'static mut dyn FileSystem) -> Result<usize, FsError> {
+        self.add_mount(PathBuf::new(), FileName::from("none").unwrap(), None, fs)
+    }
+
+    fn add_mount(&self, path: PathBuf, source: FileName, covered: Option<VNode>, fs: &'static mut dyn FileSystem) -> Result<usize, FsError> {
+        let lock = if fs.locks_internally() {
+            None
+        } else {
+            Some(Mutex::new(fs.name(), ()))
+        };
+
+        let mut mounts = self.mounts.lock();
+        mounts.try_push(Mount {
+            info: MountInfo {
+                path,
+                source,
+                file_system_type: fs.name(),
+            },
+            covered,
+            root: fs.root(),
+            fs,
+            lock,
+        }).map_err(|_| FsError::MountTableFull)?;
+
+        Ok(mounts.len() - 1)
+    }
+
+    /// Call `f` with the filesystem of mount `mount`.
+    fn with_fs<T>(&self, mount: usize, f: impl FnOnce(&mut dyn FileSystem) -> T) -> T {
+        let (fs, lock) = {
+            let mut mounts = self.mounts.lock();
+            let mount = &mut mounts[mount];
+            (&mut *mount.fs as *mut dyn FileSystem, mount.lock.as_ref().map(|lock| lock as *const Mutex<()>))
+        };
+
+        // Mounts are never removed, so the filesystem and its lock stay
+        // valid after the mount table is unlocked.
+        let _guard = lock.map(|lock| unsafe { &*lock }.lock());
+        f(unsafe { &mut *fs })
+    }
+
+    fn metadata(&self, vnode: VNode) -> Result<Metadata, FsError> {
+        self.with_fs(vnode.mount, |fs| fs.metadata(vnode.inode))
+    }
+
+    fn root_dentry(&self) -> Dentry {
+        let mut name = FileName::new();
+        name.push('/');
+        Dentry {
+            name,
+            vnode: VNode { mount: 0, inode: self.mounts.lock()[0].root },
+        }
+    }
+
+    /// If there is a filesystem mounted on `vnode`, return root of that filesystem.
+    fn cross_mount_points(&self, mut vnode: VNode) -> VNode {
+        let mounts = self.mounts.lock();
+        while let Some(i) = mounts.iter().position(|m| m.covered == Some(vnode)) {
+            vnode = VNode { mount: i, inode: mounts[i].root };
+        }
+        vnode
+    }
+
+    /// Walk `path` starting from the directory at the top of `stack`.
+    fn walk(&self, stack: &mut DentryStack, path: &str, follow_last: bool, symlink_depth: usize) -> Result<(), FsError> {
+        if path.len() > PATH_MAX_LENGTH {
+            return Err(FsError::PathTooLong);
+        }
+
+        if path.starts_with('/') {
+            stack.clear();
+            stack.push(self.root_dentry());
+        }
+
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+
+        while let Some(component) = components.next() {
+            let is_last = components.peek().is_none();
+
+            match component {
+                "." => continue,
+                ".." => {
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            if component.len() > FILE_NAME_MAX_LENGTH {
+                return Err(FsError::NameTooLong);
+            }
+
+            let directory = stack.last().ok_or(FsError::InvalidPath)?.vnode;
+            if self.metadata(directory)?.file_type != FileType::Directory {
+                return Err(FsError::NotDirectory);
+            }
+
+            let inode = self.with_fs(directory.mount, |fs| fs.lookup(directory.inode, component))?;
+            let vnode = self.cross_mount_points(VNode { mount: directory.mount, inode });
+
+            if self.metadata(vnode)?.file_type == FileType::Symlink && (!is_last || follow_last) {
+                if symlink_depth >= MAX_SYMLINK_DEPTH {
+                    return Err(FsError::TooManySymlinks);
+                }
+
+                let mut target = PathBuf::new();
+                self.with_fs(vnode.mount, |fs| fs.read_link(vnode.inode, &mut target))?;
+                self.walk(stack, &target, true, symlink_depth + 1)?;
+                continue;
+            }
+
+            let dentry = Dentry {
+                name: FileName::from(component).map_err(|_| FsError::NameTooLong)?,
+                vnode,
+            };
+            stack.try_push(dentry).map_err(|_| FsError::PathTooLong)?;
+        }
+
+        Ok(())
+    }
+
+    fn resolve_stack(&self, ctx: &Context, path: &str, follow_last: bool) -> Result<DentryStack, FsError> {
+        if path.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+
+        let mut stack = DentryStack::new();
+        self.walk(&mut stack, &ctx.current_directory, true, 0)?;
+        self.walk(&mut stack, path, follow_last, 0)?;
+        Ok(stack)
+    }
+
+    fn resolve(&self, ctx: &Context, path: &str, follow_last: bool) -> Result<VNode, FsError> {
+        let stack = self.resolve_stack(ctx, path, follow_last)?;
+        stack.last().map(|d| d.vnode).ok_or(FsError::InvalidPath)
+    }
+
+    /// Resolve directory part of the path and return it with the last path component.
+    fn resolve_parent<'a>(&self, ctx: &Context, path: &'a str) -> Result<(VNode, &'a str), FsError> {
+        let path = path.trim_end_matches('/');
+        let (directory, name) = match path.rfind('/') {
+            Some(0) => ("/", &path[1..]),
+            Some(i) => (&path[..i], &path[i+1..]),
+            None => (".", path),
+        };
+
+        match name {
+            "" | "." | ".." => return Err(FsError::InvalidPath),
+            _ if name.len() > FILE_NAME_MAX_LENGTH => return Err(FsError::NameTooLong),
+            _ => (),
+        }
+
+        let directory = self.resolve(ctx, directory, true)?;
+        if self.metadata(directory)?.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+
+        Ok((directory, name))
+    }
+
+    pub fn mount(&self, ctx: &Context, fs_type: &str, path: &str, source: &str) -> Result<(), FsError> {
+        if self.mounts.lock().is_full() {
+            return Err(FsError::MountTableFull);
+        }
+
+        let stack = self.resolve_stack(ctx, path, true)?;
+        let covered = stack.last().ok_or(FsError::InvalidPath)?.vnode;
+        if self.metadata(covered)?.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+
+        let source = FileName::from(source).map_err(|_| FsError::NameTooLong)?;
+        let mount = self.file_system_types.lock().iter()
+            .find(|t| t.name == fs_type)
+            .ok_or(FsError::UnknownFileSystemType)?
+            .mount;
+        let path = stack_to_path(&stack)?;
+        let fs = mount(&source)?;
+
+        self.add_mount(path, source, Some(covered), fs).map(|_| ())
+    }
+
+    pub fn change_directory(&self, ctx: &mut Context, path: &str) -> Result<(), FsError> {
+        let stack = self.resolve_stack(ctx, path, true)?;
+        let vnode = stack.last().ok_or(FsError::InvalidPath)?.vnode;
+        if self.metadata(vnode)?.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+
+        ctx.current_directory = stack_to_path(&stack)?;
+        Ok(())
+    }
+
+    pub fn stat(&self, ctx: &Context, path: &str) -> Result<Metadata, FsError> {
+        let vnode = self.resolve(ctx, path, true)?;
+        self.metadata(vnode)
+    }
+
+    /// Like `stat` but doesn't follow a symlink at the end of the path.
+    pub fn lstat(&self, ctx: &Context, path: &str) -> Result<Metadata, FsError> {
+        let vnode = self.resolve(ctx, path, false)?;
+        self.metadata(vnode)
+    }
+
+    pub fn fstat(&self, ctx: &Context, fd: FileDescriptor) -> Result<Metadata, FsError> {
+        let vnode = ctx.files.get(fd)?.vnode;
+        self.metadata(vnode)
+    }
+
+    pub fn read_link(&self, ctx: &Context, path: &str, target: &mut PathBuf) -> Result<(), FsError> {
+        let vnode = self.resolve(ctx, path, false)?;
+        self.with_fs(vnode.mount, |fs| fs.read_link(vnode.inode, target))
+    }
+
+    pub fn open(&self, ctx: &mut Context, path: &str, flags: OpenFlags) -> Result<FileDescriptor, FsError> {
+        let vnode = match self.resolve(ctx, path, true) {
+            Ok(vnode) => vnode,
+            Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                let (directory, name) = self.resolve_parent(ctx, path)?;
+                let inode = self.with_fs(directory.mount, |fs| fs.create(directory.inode, name, FileType::Regular, 0o644))?;
+                VNode { mount: directory.mount, inode }
+            }
+            Err(e) => return Err(e),
+        };
+
+        let metadata = self.metadata(vnode)?;
+
+        if metadata.file_type == FileType::Directory {
+            if flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE | OpenFlags::APPEND) {
+                return Err(FsError::IsDirectory);
+            }
+        } else if flags.contains(OpenFlags::DIRECTORY) {
+            return Err(FsError::NotDirectory);
+        }
+
+        if flags.contains(OpenFlags::TRUNCATE) && metadata.file_type == FileType::Regular {
+            if self.is_retained(vnode) {
+                return Err(FsError::Busy);
+            }
+            self.with_fs(vnode.mount, |fs| fs.truncate(vnode.inode, 0))?;
+        }
+
+        self.open_vnode(ctx, vnode, flags)
+    }
+
+    /// Open an inode without resolving a path. Pipes use this.
+    pub fn open_vnode(&self, ctx: &mut Context, vnode: VNode, flags: OpenFlags) -> Result<FileDescriptor, FsError> {
+        let fd = ctx.files.insert(OpenFile {
+            vnode,
+            offset: 0,
+            flags,
+        })?;
+
+        self.with_fs(vnode.mount, |fs| fs.open(vnode.inode, flags));
+        Ok(fd)
+    }
+
+    pub fn close(&self, ctx: &mut Context, fd: FileDescriptor) -> Result<(), FsError> {
+        let file = ctx.files.remove(fd)?;
+        self.with_fs(file.vnode.mount, |fs| fs.close(file.vnode.inode, file.flags));
+        Ok(())
+    }
+
+    /// Close all files of the context.
+    pub fn close_all(&self, ctx: &mut Context) {
+        for fd in 0..FILE_DESCRIPTOR_TABLE_SIZE {
+            let _ = self.close(ctx, fd);
+        }
+    }
+
+    /// Make `new_fd` refer to the same file as `fd`. If `new_fd` is
+    /// open, it is closed first. File offset is copied, not shared.
+    pub fn duplicate(&self, ctx: &mut Context, fd: FileDescriptor, new_fd: FileDescriptor) -> Result<(), FsError> {
+        let file = *ctx.files.get(fd)?;
+        if fd != new_fd {
+            self.install(ctx, new_fd, file)?;
+        }
+        Ok(())
+    }
+
+    /// Like `duplicate`, but `new_fd` is in another context.
+    pub fn duplicate_into(&self, source: &Context, fd: FileDescriptor, target: &mut Context, new_fd: FileDescriptor) -> Result<(), FsError> {
+        let file = *source.files.get(fd)?;
+        self.install(target, new_fd, file)
+    }
+
+    fn install(&self, ctx: &mut Context, fd: FileDescriptor, file: OpenFile) -> Result<(), FsError> {
+        if fd >= FILE_DESCRIPTOR_TABLE_SIZE {
+            return Err(FsError::BadFileDescriptor);
+        }
+
+        let _ = self.close(ctx, fd);
+        ctx.files.files[fd] = Some(file);
+        self.with_fs(file.vnode.mount, |fs| fs.open(file.vnode.inode, file.flags));
+        Ok(())
+    }
+
+    /// Copy working directory and open files. Forked processes and
+    /// programs which the shell starts get their files with this.
+    pub fn clone_context(&self, ctx: &Context) -> Context {
+        let mut clone = ctx.without_files();
+        clone.files.files = ctx.files.files;
+
+        for file in clone.files.files.iter().filter_map(|file| file.as_ref()) {
+            self.with_fs(file.vnode.mount, |fs| fs.open(file.vnode.inode, file.flags));
+        }
+
+        clone
+    }
+
+    pub fn read(&self, ctx: &mut Context, fd: FileDescriptor, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let file = *ctx.files.get(fd)?;
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadFileDescriptor);
+        }
+
+        let (offset, count) = self.with_fs(file.vnode.mount, |fs| {
+            let offset = file.offset.max(fs.first_offset(file.vnode.inode));
+            fs.read(file.vnode.inode, offset, buffer).map(|count| (offset, count))
+        })?;
+        ctx.files.get_mut(fd)?.offset = offset + count as u64;
+        Ok(count)
+    }
+
+    /// Read from an inode without an open file. Memory mapped files and
+    /// swap files use this.
+    pub fn read_vnode(&self, vnode: VNode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        self.with_fs(vnode.mount, |fs| fs.read(vnode.inode, offset, buffer))
+    }
+
+    /// Write to an inode without an open file. Swap files use this.
+    pub fn write_vnode(&self, vnode: VNode, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.with_fs(vnode.mount, |fs| fs.write(vnode.inode, offset, data))
+    }
+
+    /// Keep `vnode` from being removed or truncated until
+    /// `release_vnode` is called. Users of `read_vnode` and
+    /// `write_vnode` take a reference while they use the inode.
+    pub fn retain_vnode(&self, vnode: VNode) -> Result<(), FsError> {
+        let mut retained = self.retained.lock();
+
+        if let Some((_, count)) = retained.iter_mut().find(|(retained_vnode, _)| *retained_vnode == vnode) {
+            *count += 1;
+            return Ok(());
+        }
+
+        retained.try_push((vnode, 1)).map_err(|_| FsError::NoSpace)
+    }
+
+    pub fn release_vnode(&self, vnode: VNode) {
+        let mut retained = self.retained.lock();
+
+        if let Some(i) = retained.iter().position(|(retained_vnode, _)| *retained_vnode == vnode) {
+            retained[i].1 -= 1;
+            if retained[i].1 == 0 {
+                retained.swap_remove(i);
+            }
+        }
+    }
+
+    fn is_retained(&self, vnode: VNode) -> bool {
+        self.retained.lock().iter().any(|(retained_vnode, _)| *retained_vnode == vnode)
+    }
+
+    pub fn write(&self, ctx: &mut Context, fd: FileDescriptor, data: &[u8]) -> Result<usize, FsError> {
+        let file = *ctx.files.get(fd)?;
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadFileDescriptor);
+        }
+
+        let offset = if file.flags.contains(OpenFlags::APPEND) {
+            self.metadata(file.vnode)?.size
+        } else {
+            file.offset
+        };
+
+        let count = self.with_fs(file.vnode.mount, |fs| fs.write(file.vnode.inode, offset, data))?;
+        ctx.files.get_mut(fd)?.offset = offset + count as u64;
+        Ok(count)
+    }
+
+    pub fn seek(&self, ctx: &mut Context, fd: FileDescriptor, position: SeekFrom) -> Result<u64, FsError> {
+        let file = *ctx.files.get(fd)?;
+
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(delta) => (file.offset, delta),
+            SeekFrom::End(delta) => (self.metadata(file.vnode)?.size, delta),
+        };
+
+        let new_offset = (base as i64).checked_add(delta)
+            .filter(|&offset| offset >= 0)
+            .ok_or(FsError::InvalidArgument)?;
+
+        ctx.files.get_mut(fd)?.offset = new_offset as u64;
+        Ok(new_offset as u64)
+    }
+
+    /// Read next directory entry. File offset is used as the entry index.
+    pub fn read_dir(&self, ctx: &mut Context, fd: FileDescriptor) -> Result<Option<DirEntry>, FsError> {
+        let file = *ctx.files.get(fd)?;
+        let entry = self.with_fs(file.vnode.mount, |fs| fs.read_dir(file.vnode.inode, file.offset as usize))?;
+
+        if entry.is_some() {
+            ctx.files.get_mut(fd)?.offset += 1;
+        }
+
+        Ok(entry)
+    }
+
+    pub fn create_directory(&self, ctx: &Context, path: &str) -> Result<(), FsError> {
+        let (directory, name) = self.resolve_parent(ctx, path)?;
+        self.with_fs(directory.mount, |fs| fs.create(directory.inode, name, FileType::Directory, 0o755)).map(|_| ())
+    }
+
+    pub fn symlink(&self, ctx: &Context, target: &str, path: &str) -> Result<(), FsError> {
+        let (directory, name) = self.resolve_parent(ctx, path)?;
+        self.with_fs(directory.mount, |fs| fs.symlink(directory.inode, name, target)).map(|_| ())
+    }
+
+    pub fn remove(&self, ctx: &Context, path: &str) -> Result<(), FsError> {
+        let (directory, name) = self.resolve_parent(ctx, path)?;
+        let inode = self.with_fs(directory.mount, |fs| fs.lookup(directory.inode, name))?;
+        let vnode = VNode { mount: directory.mount, inode };
+
+        if self.mounts.lock().iter().any(|m| m.covered == Some(vnode)) {
+            return Err(FsError::PermissionDenied);
+        }
+
+        if self.is_retained(vnode) {
+            return Err(FsError::Busy);
+        }
+
+        self.with_fs(directory.mount, |fs| fs.remove(directory.inode, name))
+    }
+
+    pub fn sync(&self) -> Result<(), FsError> {
+        let count = self.mounts.lock().len();
+        for mount in 0..count {
+            self.with_fs(mount, |fs| fs.sync())?;
+        }
+        Ok(())
+    }
+}
+
+fn stack_to_path(stack: &DentryStack) -> Result<PathBuf, FsError> {
+    let mut path = PathBuf::new();
+
+    for dentry in stack.iter().skip(1) {
+        path.try_push('/').map_err(|_| FsError::PathTooLong)?;
+        path.try_push_str(&dentry.name).map_err(|_| FsError::PathTooLong)?;
+    }
+
+    if path.is_empty() {
+        path.push('/');
+    }
+
+    Ok(path)
+}
@@ -0,0 +1,535 @@
+//! Loadable VGA text mode fonts.
+//!
+//! Fonts are PC Screen Font (PSF) version 1 or 2 files with glyphs which
+//! are at most 8 pixels wide, for example from a boot module. Glyphs are
+//! written to VGA memory plane 2, where the text mode reads them. The row
+//! count follows the glyph height: 16 pixel high glyphs give 80x25 and 8
+//! pixel high glyphs 80x50.
+//!
+//! Fonts with more than 256 glyphs use 512 glyph mode. Bit 3 of the
+//! attribute selects glyphs 256-511 instead of a bright foreground, so
+//! only 8 foreground colours are available. A Unicode table in the font
+//! maps characters to glyphs. Fonts without a table are assumed to be in
+//! code page 437 order.
+//!
+//! The BIOS font is saved during boot, so it can be restored. An 8x8
+//! version of it, which is made by combining glyph rows, gives 80x50
+//! without a font file.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use arrayvec::ArrayVec;
+
+use crate::cp437;
+use crate::shell::Io;
+use crate::shell_command::{self, Builtin, CommandError, RegistryError};
+use crate::shell_completion::{ArgumentCompletion, Completions};
+use crate::shell_parser::Arguments;
+use crate::sync::{self, IrqSpinlock, Mutex};
+use crate::vfs::{self, Context, FsError, OpenFlags};
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02;
+const PSF1_MODE_HAS_SEQUENCES: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+pub const MAX_GLYPHS: usize = 512;
+const MIN_HEIGHT: usize = 8;
+const MAX_HEIGHT: usize = 16;
+/// Bytes of a glyph in plane 2. Rows below the glyph height are unused.
+const GLYPH_SLOT_SIZE: usize = 32;
+/// Plane 2 offset of the glyphs 256-511 in 512 glyph mode.
+const SECOND_MAP_OFFSET: usize = 0x4000;
+/// Scan lines of the 400 line text mode.
+const SCAN_LINES: usize = 400;
+pub const MAX_ROWS: usize = 50;
+const MAX_UNICODE_ENTRIES: usize = 2048;
+const MAX_FONT_FILE_SIZE: usize = 32 * 1024;
+
+const PLANE_2_ADDRESS: usize = 0xA0000;
+
+const SEQUENCER_INDEX: u16 = 0x3C4;
+const GRAPHICS_INDEX: u16 = 0x3CE;
+const CRTC_INDEX: u16 = 0x3D4;
+const ATTRIBUTE_INDEX: u16 = 0x3C0;
+/// Reading resets the attribute controller to expect an index.
+const INPUT_STATUS_1: u16 = 0x3DA;
+
+const SEQUENCER_MAP_MASK: u8 = 0x02;
+const SEQUENCER_CHARACTER_MAP_SELECT: u8 = 0x03;
+const SEQUENCER_MEMORY_MODE: u8 = 0x04;
+const GRAPHICS_READ_MAP_SELECT: u8 = 0x04;
+const GRAPHICS_MODE: u8 = 0x05;
+const GRAPHICS_MISCELLANEOUS: u8 = 0x06;
+const CRTC_MAXIMUM_SCAN_LINE: u8 = 0x09;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+/// Attribute controller index with the palette address source bit, which
+/// keeps the screen enabled.
+const ATTRIBUTE_COLOUR_PLANE_ENABLE: u8 = 0x12 | 0x20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FontError {
+    /// Not a PSF font.
+    UnknownFormat,
+    /// File ends before the glyphs or the Unicode table.
+    Truncated,
+    /// Glyphs are wider than 8 pixels or the height is not supported.
+    UnsupportedSize,
+    TooManyGlyphs,
+    FileTooLarge,
+    Fs(FsError),
+}
+
+impl From<FsError> for FontError {
+    fn from(error: FsError) -> Self {
+        FontError::Fs(error)
+    }
+}
+
+/// Unicode table of a font file.
+#[derive(Debug, Copy, Clone)]
+enum UnicodeTable<'a> {
+    Psf1(&'a [u8]),
+    Psf2(&'a [u8]),
+}
+
+/// Font which is parsed from a PSF file or made from the BIOS font.
+/// Glyphs have one byte per row.
+#[derive(Debug)]
+pub struct Font<'a> {
+    height: usize,
+    glyph_count: usize,
+    /// Bytes between glyphs in `glyphs`.
+    glyph_size: usize,
+    glyphs: &'a [u8],
+    unicode_table: Option<UnicodeTable<'a>>,
+}
+
+impl<'a> Font<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else {
+            Err(FontError::UnknownFormat)
+        }
+    }
+
+    fn parse_psf1(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.len() < PSF1_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+
+        let mode = data[2];
+        let height = usize::from(data[3]);
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let end = PSF1_HEADER_SIZE + glyph_count * height;
+        if data.len() < end {
+            return Err(FontError::Truncated);
+        }
+
+        let unicode_table = if mode & (PSF1_MODE_HAS_TABLE | PSF1_MODE_HAS_SEQUENCES) != 0 {
+            Some(UnicodeTable::Psf1(&data[end..]))
+        } else {
+            None
+        };
+
+        Self::new(height, glyph_count, height, &data[PSF1_HEADER_SIZE..end], unicode_table)
+    }
+
+    fn parse_psf2(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.len() < PSF2_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+
+        let field = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&data[i * 4..i * 4 + 4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+
+        let header_size = field(2);
+        let flags = field(3) as u32;
+        let glyph_count = field(4);
+        let glyph_size = field(5);
+        let height = field(6);
+        let width = field(7);
+
+        if width == 0 || width > 8 || glyph_size != height || height < MIN_HEIGHT || height > MAX_HEIGHT {
+            return Err(FontError::UnsupportedSize);
+        }
+
+        if glyph_count > MAX_GLYPHS {
+            return Err(FontError::TooManyGlyphs);
+        }
+
+        let end = glyph_count.checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(FontError::Truncated)?;
+        if header_size < PSF2_HEADER_SIZE || data.len() < end {
+            return Err(FontError::Truncated);
+        }
+
+        let unicode_table = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            Some(UnicodeTable::Psf2(&data[end..]))
+        } else {
+            None
+        };
+
+        Self::new(height, glyph_count, glyph_size, &data[header_size..end], unicode_table)
+    }
+
+    fn new(height: usize, glyph_count: usize, glyph_size: usize, glyphs: &'a [u8], unicode_table: Option<UnicodeTable<'a>>) -> Result<Self, FontError> {
+        if height < MIN_HEIGHT || height > MAX_HEIGHT {
+            return Err(FontError::UnsupportedSize);
+        }
+
+        Ok(Self {
+            height,
+            glyph_count,
+            glyph_size,
+            glyphs,
+            unicode_table,
+        })
+    }
+
+    /// Row `row` of glyph `glyph`, zero for glyphs which the font
+    /// doesn't have.
+    fn row(&self, glyph: usize, row: usize) -> u8 {
+        if glyph < self.glyph_count && row < self.height {
+            self.glyphs[glyph * self.glyph_size + row]
+        } else {
+            0
+        }
+    }
+
+    /// Characters of the Unicode table sorted by code point. Sequences
+    /// and characters outside the Basic Multilingual Plane are skipped.
+    fn unicode_map(&self) -> Result<Option<UnicodeMap>, FontError> {
+        let mut map = UnicodeMap::new();
+
+        match self.unicode_table {
+            Some(UnicodeTable::Psf1(table)) => {
+                let mut values = table.chunks_exact(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+                for glyph in 0..self.glyph_count {
+                    let mut sequence = false;
+                    loop {
+                        match values.next().ok_or(FontError::Truncated)? {
+                            PSF1_SEPARATOR => break,
+                            PSF1_START_SEQUENCE => sequence = true,
+                            code_point if !sequence => {
+                                let _ = map.try_push((code_point, glyph as u16));
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+            Some(UnicodeTable::Psf2(mut table)) => {
+                for glyph in 0..self.glyph_count {
+                    let end = table.iter().position(|&byte| byte == PSF2_SEPARATOR).ok_or(FontError::Truncated)?;
+                    let characters = &table[..end];
+                    let characters = match characters.iter().position(|&byte| byte == PSF2_START_SEQUENCE) {
+                        Some(sequences) => &characters[..sequences],
+                        None => characters,
+                    };
+
+                    for c in core::str::from_utf8(characters).map_err(|_| FontError::UnknownFormat)?.chars() {
+                        if (c as u32) <= u32::from(u16::max_value()) {
+                            let _ = map.try_push((c as u16, glyph as u16));
+                        }
+                    }
+
+                    table = &table[end + 1..];
+                }
+            }
+            None => return Ok(None),
+        }
+
+        map.sort_unstable_by_key(|&(code_point, _)| code_point);
+        Ok(Some(map))
+    }
+}
+
+/// Code points and glyphs of the loaded font.
+type UnicodeMap = ArrayVec<[(u16, u16); MAX_UNICODE_ENTRIES]>;
+
+/// `None` if the font is in code page 437 order.
+static UNICODE_MAP: IrqSpinlock<Option<UnicodeMap>> = IrqSpinlock::new("font unicode map", None);
+
+static HEIGHT: AtomicUsize = AtomicUsize::new(16);
+static ROWS: AtomicUsize = AtomicUsize::new(25);
+static GLYPHS_512: AtomicBool = AtomicBool::new(false);
+/// Incremented when a font is loaded.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// BIOS font which `init` saves.
+static mut BIOS_FONT: [u8; 256 * GLYPH_SLOT_SIZE] = [0; 256 * GLYPH_SLOT_SIZE];
+static BIOS_FONT_HEIGHT: AtomicUsize = AtomicUsize::new(16);
+
+static FONT_FILE: Mutex<[u8; MAX_FONT_FILE_SIZE]> = Mutex::new("font file", [0; MAX_FONT_FILE_SIZE]);
+
+unsafe fn read_register(index_port: u16, index: u8) -> u8 {
+    x86::io::outb(index_port, index);
+    x86::io::inb(index_port + 1)
+}
+
+unsafe fn write_register(index_port: u16, index: u8, value: u8) {
+    x86::io::outb(index_port, index);
+    x86::io::outb(index_port + 1, value);
+}
+
+/// Map plane 2, which contains the glyphs, to `PLANE_2_ADDRESS` while
+/// `function` runs. Interrupts are disabled, so nothing writes the text
+/// buffer meanwhile.
+fn with_plane_2<T>(function: impl FnOnce(*mut u8) -> T) -> T {
+    let interrupts = sync::disable_interrupts();
+
+    let result = unsafe {
+        let map_mask = read_register(SEQUENCER_INDEX, SEQUENCER_MAP_MASK);
+        let memory_mode = read_register(SEQUENCER_INDEX, SEQUENCER_MEMORY_MODE);
+        let read_map = read_register(GRAPHICS_INDEX, GRAPHICS_READ_MAP_SELECT);
+        let mode = read_register(GRAPHICS_INDEX, GRAPHICS_MODE);
+        let miscellaneous = read_register(GRAPHICS_INDEX, GRAPHICS_MISCELLANEOUS);
+
+        // Sequential addressing of plane 2 at 0xA0000.
+        write_register(SEQUENCER_INDEX, SEQUENCER_MAP_MASK, 0x04);
+        write_register(SEQUENCER_INDEX, SEQUENCER_MEMORY_MODE, 0x07);
+        write_register(GRAPHICS_INDEX, GRAPHICS_READ_MAP_SELECT, 0x02);
+        write_register(GRAPHICS_INDEX, GRAPHICS_MODE, 0x00);
+        write_register(GRAPHICS_INDEX, GRAPHICS_MISCELLANEOUS, 0x04);
+
+        let result = function(PLANE_2_ADDRESS as *mut u8);
+
+        write_register(SEQUENCER_INDEX, SEQUENCER_MAP_MASK, map_mask);
+        write_register(SEQUENCER_INDEX, SEQUENCER_MEMORY_MODE, memory_mode);
+        write_register(GRAPHICS_INDEX, GRAPHICS_READ_MAP_SELECT, read_map);
+        write_register(GRAPHICS_INDEX, GRAPHICS_MODE, mode);
+        write_register(GRAPHICS_INDEX, GRAPHICS_MISCELLANEOUS, miscellaneous);
+
+        result
+    };
+
+    sync::restore_interrupts(interrupts);
+    result
+}
+
+/// Plane 2 offset of glyph `glyph`.
+fn glyph_offset(glyph: usize) -> usize {
+    if glyph < 256 {
+        glyph * GLYPH_SLOT_SIZE
+    } else {
+        SECOND_MAP_OFFSET + (glyph - 256) * GLYPH_SLOT_SIZE
+    }
+}
+
+/// Save the BIOS font. Call before loading fonts.
+pub fn init() {
+    let height = unsafe { usize::from(read_register(CRTC_INDEX, CRTC_MAXIMUM_SCAN_LINE) & 0x1F) + 1 };
+
+    with_plane_2(|plane| {
+        for (i, byte) in unsafe { BIOS_FONT.iter_mut() }.enumerate() {
+            *byte = unsafe { core::ptr::read_volatile(plane.add(i)) };
+        }
+    });
+
+    BIOS_FONT_HEIGHT.store(height, Ordering::SeqCst);
+    HEIGHT.store(height, Ordering::SeqCst);
+    ROWS.store((SCAN_LINES / height).min(MAX_ROWS), Ordering::SeqCst);
+}
+
+/// Write the glyphs of `font` to VGA memory and change the glyph height,
+/// the row count and the cursor shape. Terminals notice the change from
+/// `generation`.
+pub fn load(font: &Font) -> Result<(), FontError> {
+    let unicode_map = font.unicode_map()?;
+    let glyphs_512 = font.glyph_count > 256;
+    let glyph_count = if glyphs_512 { 512 } else { 256 };
+    let height = font.height;
+
+    let interrupts = sync::disable_interrupts();
+
+    with_plane_2(|plane| {
+        for glyph in 0..glyph_count {
+            let offset = glyph_offset(glyph);
+            for row in 0..GLYPH_SLOT_SIZE {
+                unsafe { core::ptr::write_volatile(plane.add(offset + row), font.row(glyph, row)) };
+            }
+        }
+    });
+
+    unsafe {
+        let scan_line = read_register(CRTC_INDEX, CRTC_MAXIMUM_SCAN_LINE);
+        write_register(CRTC_INDEX, CRTC_MAXIMUM_SCAN_LINE, scan_line & 0xE0 | (height - 1) as u8);
+        let cursor_start = read_register(CRTC_INDEX, CRTC_CURSOR_START);
+        write_register(CRTC_INDEX, CRTC_CURSOR_START, cursor_start & 0xE0 | (height - 3) as u8);
+        let cursor_end = read_register(CRTC_INDEX, CRTC_CURSOR_END);
+        write_register(CRTC_INDEX, CRTC_CURSOR_END, cursor_end & 0xE0 | (height - 2) as u8);
+
+        // Map 0 for attribute bit 3 clear and map 1 for bit 3 set.
+        let map_select = if glyphs_512 { 0x04 } else { 0x00 };
+        write_register(SEQUENCER_INDEX, SEQUENCER_CHARACTER_MAP_SELECT, map_select);
+
+        // Attribute bit 3 doesn't select a bright foreground in 512
+        // glyph mode.
+        x86::io::inb(INPUT_STATUS_1);
+        x86::io::outb(ATTRIBUTE_INDEX, ATTRIBUTE_COLOUR_PLANE_ENABLE);
+        x86::io::outb(ATTRIBUTE_INDEX, if glyphs_512 { 0x07 } else { 0x0F });
+    }
+
+    *UNICODE_MAP.lock() = unicode_map;
+    HEIGHT.store(height, Ordering::SeqCst);
+    ROWS.store((SCAN_LINES / height).min(MAX_ROWS), Ordering::SeqCst);
+    GLYPHS_512.store(glyphs_512, Ordering::SeqCst);
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+
+    sync::restore_interrupts(interrupts);
+
+    Ok(())
+}
+
+/// Restore the BIOS font and 80x25.
+pub fn load_bios_font() -> Result<(), FontError> {
+    let glyphs = unsafe { &BIOS_FONT };
+    load(&Font::new(BIOS_FONT_HEIGHT.load(Ordering::SeqCst), 256, GLYPH_SLOT_SIZE, glyphs, None)?)
+}
+
+/// Load an 8x8 version of the BIOS font, which gives 80x50. Each row
+/// combines the rows of the BIOS font which it covers.
+pub fn load_bios_font_8x8() -> Result<(), FontError> {
+    const HEIGHT_8X8: usize = 8;
+
+    let bios_height = BIOS_FONT_HEIGHT.load(Ordering::SeqCst);
+    let bios_font = unsafe { &BIOS_FONT };
+    let mut glyphs = [0u8; 256 * HEIGHT_8X8];
+
+    for (glyph, rows) in glyphs.chunks_exact_mut(HEIGHT_8X8).enumerate() {
+        let bios_glyph = &bios_font[glyph * GLYPH_SLOT_SIZE..][..bios_height];
+        for (row, byte) in rows.iter_mut().enumerate() {
+            let start = row * bios_height / HEIGHT_8X8;
+            let end = ((row + 1) * bios_height / HEIGHT_8X8).max(start + 1);
+            *byte = bios_glyph[start..end].iter().fold(0, |byte, &bios_row| byte | bios_row);
+        }
+    }
+
+    load(&Font::new(HEIGHT_8X8, 256, HEIGHT_8X8, &glyphs, None)?)
+}
+
+/// Load a PSF font file.
+pub fn load_file(ctx: &mut Context, path: &str) -> Result<(), FontError> {
+    let mut buffer = FONT_FILE.lock();
+
+    let vfs = vfs::vfs();
+    let fd = vfs.open(ctx, path, OpenFlags::READ)?;
+    let mut size = 0;
+    let result = loop {
+        if size == buffer.len() {
+            let mut byte = [0];
+            break match vfs.read(ctx, fd, &mut byte) {
+                Ok(0) => Ok(()),
+                Ok(_) => Err(FontError::FileTooLarge),
+                Err(e) => Err(e.into()),
+            };
+        }
+
+        match vfs.read(ctx, fd, &mut buffer[size..]) {
+            Ok(0) => break Ok(()),
+            Ok(count) => size += count,
+            Err(e) => break Err(e.into()),
+        }
+    };
+    vfs.close(ctx, fd)?;
+    result?;
+
+    load(&Font::parse(&buffer[..size])?)
+}
+
+/// Glyph of `c` in the loaded font. Characters which the font doesn't
+/// have are shown as a similar code page 437 character or `?`.
+pub fn glyph(c: char) -> u16 {
+    let map = UNICODE_MAP.lock();
+    let map = match &*map {
+        Some(map) => map,
+        None => return u16::from(cp437::from_char(c)),
+    };
+
+    let find = |c: char| {
+        map.binary_search_by_key(&(c as u32), |&(code_point, _)| u32::from(code_point))
+            .ok()
+            .map(|i| map[i].1)
+    };
+
+    find(c)
+        .or_else(|| find(cp437::to_char(cp437::from_char(c))))
+        .or_else(|| find('?'))
+        .unwrap_or(0)
+}
+
+/// Glyph height in pixels.
+pub fn height() -> usize {
+    HEIGHT.load(Ordering::SeqCst)
+}
+
+/// Text rows of the current font.
+pub fn rows() -> usize {
+    ROWS.load(Ordering::SeqCst)
+}
+
+/// True if attribute bit 3 selects glyphs 256-511.
+pub fn has_512_glyphs() -> bool {
+    GLYPHS_512.load(Ordering::SeqCst)
+}
+
+/// Number which changes when a font is loaded.
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+static COMMANDS: [Builtin; 1] = [
+    Builtin {
+        name: "setfont",
+        usage: "[80x25 | 80x50 | <path>]",
+        help: "Load a PSF font or the BIOS font, or print the font size",
+        run: setfont_command,
+        complete: ArgumentCompletion::Function(complete_setfont),
+    },
+];
+
+pub fn register_commands() -> Result<(), RegistryError> {
+    shell_command::register_builtins(&COMMANDS)
+}
+
+fn setfont_command(mut args: Arguments, io: &mut Io) -> Result<(), CommandError> {
+    let argument = args.next();
+    args.end()?;
+
+    match argument {
+        None => {
+            let glyph_count = if has_512_glyphs() { 512 } else { 256 };
+            let _ = writeln!(io, "8x{} font, {} glyphs, 80x{}", height(), glyph_count, rows());
+        }
+        Some("80x25") => load_bios_font()?,
+        Some("80x50") => load_bios_font_8x8()?,
+        Some(path) => load_file(&mut io.ctx, path)?,
+    }
+
+    Ok(())
+}
+
+fn complete_setfont(_argument: usize, completions: &mut Completions) {
+    completions.add("80x25", false);
+    completions.add("80x50", false);
+    completions.add_paths();
+}
@@ -38,3 +38,28 @@ pub fn new_vga_text_mode() -> Option<VgaTextMode> {
         Some(text_mode)
     }
 }
+
+const VGA_TEXT_BUFFER_ADDRESS: usize = 0xB8000;
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+/// Write character and attribute `value` to cell `index` of the text
+/// buffer. `VgaTextMode` only handles 25 rows, and this works with the
+/// row counts of `vga_font`.
+pub fn write_cell(index: usize, value: u16) {
+    unsafe {
+        core::ptr::write_volatile((VGA_TEXT_BUFFER_ADDRESS as *mut u16).add(index), value);
+    }
+}
+
+/// Move the cursor to cell `index`.
+pub fn set_cursor_index(index: usize) {
+    unsafe {
+        x86::io::outb(CRTC_INDEX, CRTC_CURSOR_LOCATION_HIGH);
+        x86::io::outb(CRTC_DATA, (index >> 8) as u8);
+        x86::io::outb(CRTC_INDEX, CRTC_CURSOR_LOCATION_LOW);
+        x86::io::outb(CRTC_DATA, index as u8);
+    }
+}
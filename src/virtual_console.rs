@@ -0,0 +1,275 @@
+//! Virtual consoles.
+//!
+//! Consoles 1-5 have their own terminal, scrollback buffer, command line
+//! and shell session with a working directory. Console 6 shows the
+//! kernel log. Alt+F1..F6 switches the visible console, which is drawn
+//! again to the VGA text buffer.
+//!
+//! Command lines of a console run in its session thread, so a command
+//! which runs for a long time doesn't stop the other consoles. Threads
+//! inherit the console of the thread which starts them, so console
+//! output of commands, background jobs and programs goes to the console
+//! where they were started.
+
+use core::fmt::Write;
+
+use arrayvec::ArrayVec;
+
+use crate::console;
+use crate::idt;
+use crate::input::KeyPress;
+use crate::kmsg;
+use crate::scheduler::{self, Priority, ThreadError, ThreadId};
+use crate::shell;
+use crate::shell_history::HistoryLine;
+use crate::sync::{IrqSpinlock, WaitQueue};
+use crate::terminal::{CommandStore, Terminal};
+use crate::utf8::Utf8Decoder;
+use crate::vfs::Context;
+use crate::vga_font;
+use crate::vga_text;
+use crate::vma::VmaError;
+
+pub const CONSOLE_COUNT: usize = 6;
+/// Console which shows the kernel log.
+pub const LOG_CONSOLE: usize = CONSOLE_COUNT - 1;
+
+/// Shell session of a console.
+struct Session {
+    cmd_store: CommandStore,
+    /// Session thread, which is started for the first command line.
+    thread: Option<ThreadId>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            cmd_store: CommandStore::new(),
+            thread: None,
+        }
+    }
+
+    /// Give `line` to the session thread of console `console`.
+    fn run(&mut self, console: usize, line: HistoryLine) -> Result<(), ThreadError> {
+        if self.thread.is_none() {
+            self.thread = Some(scheduler::spawn("shell-session", Priority::Normal, session_thread, console)?);
+        }
+
+        with_session_lines(|lines| {
+            lines[console].line = Some(line);
+            lines[console].running = true;
+        });
+        COMMAND_LINE_READY.wake_all();
+        Ok(())
+    }
+}
+
+/// State of a console which its session thread shares with the main
+/// loop.
+struct SessionLine {
+    /// Command line which the session thread runs next.
+    line: Option<HistoryLine>,
+    /// Working directory of the session after its latest command line.
+    /// The main loop completes paths relative to it.
+    ctx: Context,
+    /// True until the session thread has run the command line.
+    running: bool,
+}
+
+type SessionLineTable = ArrayVec<[SessionLine; CONSOLE_COUNT]>;
+
+static SESSION_LINES: IrqSpinlock<Option<SessionLineTable>> = IrqSpinlock::new("session lines", None);
+
+/// Session threads which wait for a command line.
+static COMMAND_LINE_READY: WaitQueue = WaitQueue::new("command line ready");
+
+fn with_session_lines<T>(function: impl FnOnce(&mut SessionLineTable) -> T) -> T {
+    let mut lines = SESSION_LINES.lock();
+    function(lines.as_mut().expect("virtual consoles are not created"))
+}
+
+/// Thread which runs the command lines of console `console`. Its
+/// console output goes to the console.
+fn session_thread(console: usize) -> usize {
+    scheduler::set_console(console);
+    let mut ctx = with_session_lines(|lines| lines[console].ctx.without_files());
+
+    loop {
+        let mut line = HistoryLine::new();
+        COMMAND_LINE_READY.wait_until(|| with_session_lines(|lines| match lines[console].line.take() {
+            Some(next) => {
+                line = next;
+                true
+            }
+            None => false,
+        }));
+
+        let mut out = console::ConsoleWriter;
+        shell::run_command_line(&mut out, &mut ctx, &line);
+        let _ = writeln!(out);
+
+        with_session_lines(|lines| {
+            lines[console].ctx = ctx.without_files();
+            lines[console].running = false;
+        });
+        idt::request_wake_up();
+    }
+}
+
+struct VirtualConsole {
+    terminal: Terminal,
+    /// `None` for the log console.
+    session: Option<Session>,
+}
+
+pub struct VirtualConsoles {
+    consoles: ArrayVec<[VirtualConsole; CONSOLE_COUNT]>,
+    active: usize,
+    /// Kernel log stream position which the log console has reached.
+    log_position: u64,
+    log_decoder: Utf8Decoder,
+    /// `vga_font::generation` of the font which the terminals use.
+    font_generation: usize,
+}
+
+impl VirtualConsoles {
+    /// The boot terminal becomes the first console, which keeps the
+    /// working directory of `ctx`.
+    pub fn new(terminal: Terminal, ctx: Context) -> Result<Self, VmaError> {
+        let mut lines = ArrayVec::new();
+        lines.push(SessionLine {
+            line: None,
+            ctx,
+            running: false,
+        });
+
+        let mut consoles = ArrayVec::new();
+        consoles.push(VirtualConsole {
+            terminal,
+            session: Some(Session::new()),
+        });
+
+        for i in 1..CONSOLE_COUNT {
+            // Only the visible terminal writes to VGA memory, so the
+            // terminals can have handles to the same memory.
+            let text_mode = unsafe { vga_text::new_vga_text_mode_unsafe() };
+            let shell = i != LOG_CONSOLE;
+            let session = if shell {
+                Some(Session::new())
+            } else {
+                None
+            };
+
+            lines.push(SessionLine {
+                line: None,
+                ctx: Context::new(),
+                running: false,
+            });
+            consoles.push(VirtualConsole {
+                terminal: Terminal::new_hidden(text_mode, shell)?,
+                session,
+            });
+        }
+
+        *SESSION_LINES.lock() = Some(lines);
+
+        Ok(Self {
+            consoles,
+            active: 0,
+            log_position: 0,
+            log_decoder: Utf8Decoder::new(),
+            font_generation: vga_font::generation(),
+        })
+    }
+
+    pub fn active_terminal(&mut self) -> &mut Terminal {
+        &mut self.consoles[self.active].terminal
+    }
+
+    /// Show console `index` if it exists.
+    pub fn switch(&mut self, index: usize) {
+        if index >= self.consoles.len() || index == self.active {
+            return;
+        }
+
+        self.consoles[self.active].terminal.hide();
+        self.active = index;
+        self.consoles[index].terminal.show();
+    }
+
+    /// Handle a key press on the active console. Enter gives the command
+    /// line of a shell console to its session thread. Until the command
+    /// line has finished, keys only scroll the output.
+    pub fn handle_key(&mut self, key: KeyPress) {
+        if let KeyPress::AltFunction(number) = key {
+            self.switch(number - 1);
+            return;
+        }
+
+        let active = self.active;
+        let console = &mut self.consoles[active];
+
+        let ctx = with_session_lines(|lines| {
+            if lines[active].running {
+                None
+            } else {
+                Some(lines[active].ctx.without_files())
+            }
+        });
+
+        match (&mut console.session, &ctx) {
+            (Some(session), Some(ctx)) => {
+                if let Some(line) = console.terminal.update_command_line(key, &mut session.cmd_store, ctx) {
+                    match HistoryLine::from(line) {
+                        Ok(line) => {
+                            if let Err(e) = session.run(active, line) {
+                                let _ = writeln!(console.terminal, "shell: {:?}", e);
+                            }
+                        }
+                        Err(_) => {
+                            let _ = writeln!(console.terminal, "shell: command line is too long");
+                        }
+                    }
+                }
+            }
+            _ => {
+                console.terminal.scroll_output(&key);
+            }
+        }
+    }
+
+    /// Update the terminals if a font has been loaded, for example with
+    /// `setfont`.
+    fn update_font(&mut self) {
+        let generation = vga_font::generation();
+        if generation == self.font_generation {
+            return;
+        }
+
+        self.font_generation = generation;
+        for console in &mut self.consoles {
+            console.terminal.update_font();
+        }
+    }
+
+    /// Write buffered console output and new kernel log messages.
+    pub fn flush(&mut self) {
+        self.update_font();
+        for (i, console) in self.consoles.iter_mut().enumerate() {
+            console::flush(i, &mut console.terminal);
+        }
+
+        let log = &mut self.consoles[LOG_CONSOLE].terminal;
+        let mut buffer = [0u8; 256];
+        loop {
+            let count = kmsg::read(&mut self.log_position, &mut buffer);
+            if count == 0 {
+                break;
+            }
+
+            self.log_decoder.push_bytes(&buffer[..count], |c| {
+                let _ = log.write_char(c);
+            });
+        }
+    }
+}
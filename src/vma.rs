@@ -0,0 +1,696 @@
+//! Virtual memory areas of user address spaces.
+//!
+//! Every `AddressSpace` has a `MemoryMap` which lists page aligned areas
+//! of user space and their protection. Pages of an area are mapped when
+//! the page fault handler notices the first access to the page, so large
+//! areas don't use memory before they are used. Anonymous areas are
+//! zero filled and file backed areas are read from a file. Writes to
+//! file backed areas are not written back to the file. Memory maps hold
+//! a VFS reference to every mapped file, so the file can't be removed
+//! while it is mapped.
+//!
+//! Heap of a process is an anonymous area which `brk` grows and shrinks.
+//!
+//! Swap might evict pages of any process, so page tables of processes
+//! are modified with interrupts disabled.
+//!
+//! `KernelBuffer` is a large kernel buffer whose pages are mapped on
+//! first access in the same way.
+
+use arrayvec::ArrayVec;
+use bitflags::bitflags;
+
+use crate::frame_allocator::{self, Frame};
+use crate::page_table::{self, AddressSpace, L1Flags, MapError, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START};
+use crate::page_table::{KERNEL_BUFFER_END, KERNEL_BUFFER_START};
+use crate::swap;
+use crate::sync::{self, IrqSpinlock};
+use crate::vfs::{self, FsError, VNode};
+
+pub const MAX_AREAS: usize = 32;
+
+/// Areas without a fixed address are placed after this address.
+pub const MMAP_BASE: usize = 0x8000_0000;
+
+type Areas = ArrayVec<[MemoryArea; MAX_AREAS]>;
+
+bitflags! {
+    /// Values are the same as Linux `PROT_*` values.
+    pub struct Protection: u32 {
+        const READ = 1;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backing {
+    /// Zero filled memory.
+    Anonymous,
+    /// Private copy of a file. Area starts from `offset` of the file.
+    File { vnode: VNode, offset: u64 },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryArea {
+    pub start: usize,
+    pub end: usize,
+    pub protection: Protection,
+    pub backing: Backing,
+}
+
+impl MemoryArea {
+    /// Part `start..end` of this area.
+    fn slice(&self, start: usize, end: usize) -> Self {
+        let backing = match self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { vnode, offset } => Backing::File {
+                vnode,
+                offset: offset + (start - self.start) as u64,
+            },
+        };
+
+        Self {
+            start,
+            end,
+            protection: self.protection,
+            backing,
+        }
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Check if `next` continues this area.
+    fn can_merge(&self, next: &Self) -> bool {
+        if self.end != next.start || self.protection != next.protection {
+            return false;
+        }
+
+        match (self.backing, next.backing) {
+            (Backing::Anonymous, Backing::Anonymous) => true,
+            (Backing::File { vnode, offset }, Backing::File { vnode: next_vnode, offset: next_offset }) => {
+                vnode == next_vnode && offset + (self.end - self.start) as u64 == next_offset
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    /// Address is not page aligned, length is zero or the range is not
+    /// in user space.
+    InvalidRange,
+    /// Part of the range doesn't belong to any area.
+    NotMapped,
+    /// No free range is large enough.
+    NoFreeRange,
+    TooManyAreas,
+    OutOfMemory,
+    Map(MapError),
+    Fs(FsError),
+}
+
+impl From<MapError> for VmaError {
+    fn from(error: MapError) -> Self {
+        VmaError::Map(error)
+    }
+}
+
+impl From<FsError> for VmaError {
+    fn from(error: FsError) -> Self {
+        VmaError::Fs(error)
+    }
+}
+
+/// Memory areas of one address space.
+pub struct MemoryMap {
+    /// Areas sorted by start address. Areas don't overlap.
+    areas: Areas,
+    heap_start: usize,
+    /// Current program break.
+    heap_end: usize,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self {
+            areas: ArrayVec::new(),
+            heap_start: 0,
+            heap_end: 0,
+        }
+    }
+
+    pub fn areas(&self) -> &[MemoryArea] {
+        &self.areas
+    }
+
+    /// Area containing `address`.
+    pub fn find(&self, address: usize) -> Option<&MemoryArea> {
+        self.areas.iter().find(|area| area.start <= address && address < area.end)
+    }
+
+    /// Set start of the heap. The heap is empty until `brk` grows it.
+    pub fn set_heap_start(&mut self, address: usize) {
+        let start = page_align_up(address).unwrap_or(USER_SPACE_END);
+        self.heap_start = start;
+        self.heap_end = start;
+    }
+
+    /// Add an area for pages which the caller maps. Areas in the range
+    /// are replaced, but their pages are not unmapped.
+    pub fn add(&mut self, start: usize, end: usize, protection: Protection, backing: Backing) -> Result<(), VmaError> {
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 || start >= end || start < USER_SPACE_START || end > USER_SPACE_END {
+            return Err(VmaError::InvalidRange);
+        }
+
+        self.remove(start, end)?;
+        self.insert(MemoryArea {
+            start,
+            end,
+            protection,
+            backing,
+        })
+    }
+
+    fn is_free(&self, start: usize, end: usize) -> bool {
+        !self.areas.iter().any(|area| area.overlaps(start, end))
+    }
+
+    /// Check that areas cover the whole range.
+    fn is_mapped(&self, start: usize, end: usize) -> bool {
+        let mut address = start;
+
+        for area in self.areas.iter().filter(|area| area.overlaps(start, end)) {
+            if area.start > address {
+                return false;
+            }
+            address = area.end;
+        }
+
+        address >= end
+    }
+
+    /// First free range of `length` bytes in `base..limit`.
+    fn find_free(&self, base: usize, limit: usize, length: usize) -> Option<usize> {
+        let mut start = base;
+
+        for area in self.areas.iter().filter(|area| area.end > base) {
+            if area.start.saturating_sub(start) >= length {
+                return Some(start);
+            }
+            start = core::cmp::max(start, area.end);
+        }
+
+        if limit.saturating_sub(start) >= length {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Replace the areas. References to the files of `areas` are taken
+    /// before the references of the old areas are released, so files
+    /// which stay mapped keep their references.
+    fn set_areas(&mut self, areas: Areas) -> Result<(), VmaError> {
+        for (i, vnode) in file_vnodes(&areas).enumerate() {
+            if let Err(e) = vfs::vfs().retain_vnode(vnode) {
+                file_vnodes(&areas).take(i).for_each(|vnode| vfs::vfs().release_vnode(vnode));
+                return Err(e.into());
+            }
+        }
+
+        file_vnodes(&self.areas).for_each(|vnode| vfs::vfs().release_vnode(vnode));
+        self.areas = areas;
+        Ok(())
+    }
+
+    /// Insert an area to a free range.
+    fn insert(&mut self, area: MemoryArea) -> Result<(), VmaError> {
+        let mut areas = Areas::new();
+        let mut inserted = false;
+
+        for &existing in &self.areas {
+            if !inserted && existing.start > area.start {
+                push_area(&mut areas, area)?;
+                inserted = true;
+            }
+            push_area(&mut areas, existing)?;
+        }
+
+        if !inserted {
+            push_area(&mut areas, area)?;
+        }
+
+        self.set_areas(areas)
+    }
+
+    /// Remove range `start..end` from the areas. Areas which are partly
+    /// in the range are split.
+    fn remove(&mut self, start: usize, end: usize) -> Result<(), VmaError> {
+        let mut areas = Areas::new();
+
+        for area in &self.areas {
+            if !area.overlaps(start, end) {
+                push_area(&mut areas, *area)?;
+                continue;
+            }
+
+            if area.start < start {
+                push_area(&mut areas, area.slice(area.start, start))?;
+            }
+            if end < area.end {
+                push_area(&mut areas, area.slice(end, area.end))?;
+            }
+        }
+
+        self.set_areas(areas)
+    }
+
+    /// Change protection of range `start..end`. Areas must cover the
+    /// whole range.
+    fn protect(&mut self, start: usize, end: usize, protection: Protection) -> Result<(), VmaError> {
+        if !self.is_mapped(start, end) {
+            return Err(VmaError::NotMapped);
+        }
+
+        let mut areas = Areas::new();
+
+        for area in &self.areas {
+            if !area.overlaps(start, end) {
+                push_area(&mut areas, *area)?;
+                continue;
+            }
+
+            let middle_start = core::cmp::max(area.start, start);
+            let middle_end = core::cmp::min(area.end, end);
+
+            if area.start < middle_start {
+                push_area(&mut areas, area.slice(area.start, middle_start))?;
+            }
+
+            let mut middle = area.slice(middle_start, middle_end);
+            middle.protection = protection;
+            push_area(&mut areas, middle)?;
+
+            if middle_end < area.end {
+                push_area(&mut areas, area.slice(middle_end, area.end))?;
+            }
+        }
+
+        self.set_areas(areas)
+    }
+}
+
+impl Clone for MemoryMap {
+    fn clone(&self) -> Self {
+        // Files are already referenced by `self`, so taking another
+        // reference doesn't need a new slot and can't fail.
+        for vnode in file_vnodes(&self.areas) {
+            let _ = vfs::vfs().retain_vnode(vnode);
+        }
+
+        Self {
+            areas: self.areas.clone(),
+            heap_start: self.heap_start,
+            heap_end: self.heap_end,
+        }
+    }
+}
+
+impl Drop for MemoryMap {
+    fn drop(&mut self) {
+        file_vnodes(&self.areas).for_each(|vnode| vfs::vfs().release_vnode(vnode));
+    }
+}
+
+/// Files of file backed areas.
+fn file_vnodes(areas: &[MemoryArea]) -> impl Iterator<Item=VNode> + '_ {
+    areas.iter().filter_map(|area| match area.backing {
+        Backing::File { vnode, .. } => Some(vnode),
+        Backing::Anonymous => None,
+    })
+}
+
+/// Add an area to the end of a sorted area list. The area is merged to
+/// the last area if possible.
+fn push_area(areas: &mut Areas, area: MemoryArea) -> Result<(), VmaError> {
+    if let Some(last) = areas.last_mut() {
+        if last.can_merge(&area) {
+            last.end = area.end;
+            return Ok(());
+        }
+    }
+
+    areas.try_push(area).map_err(|_| VmaError::TooManyAreas)
+}
+
+fn without_interrupts<T>(function: impl FnOnce() -> T) -> T {
+    let interrupts = sync::disable_interrupts();
+    let result = function();
+    sync::restore_interrupts(interrupts);
+    result
+}
+
+fn page_align_up(value: usize) -> Option<usize> {
+    value.checked_add(PAGE_SIZE - 1).map(|value| value & !(PAGE_SIZE - 1))
+}
+
+/// Check that the range is a page aligned user space range. Returns the
+/// end of the range.
+fn check_range(address: usize, length: usize) -> Result<usize, VmaError> {
+    if address % PAGE_SIZE != 0 || length == 0 {
+        return Err(VmaError::InvalidRange);
+    }
+
+    let end = page_align_up(length)
+        .and_then(|length| address.checked_add(length))
+        .ok_or(VmaError::InvalidRange)?;
+
+    if address < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(VmaError::InvalidRange);
+    }
+
+    Ok(end)
+}
+
+/// Page table flags for pages of an area. Pages of areas without any
+/// protection flags are not accessible from user mode.
+fn page_flags(protection: Protection) -> L1Flags {
+    let mut flags = L1Flags::PRESENT;
+    if !protection.is_empty() {
+        flags |= L1Flags::USER_SUPERVISOR;
+    }
+    if protection.contains(Protection::WRITE) {
+        flags |= L1Flags::READ_WRITE;
+    }
+    if !protection.contains(Protection::EXECUTE) {
+        flags |= L1Flags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Map an area of `length` bytes. If `address` is `None`, a free range
+/// is selected. Otherwise existing mappings in the range are replaced.
+/// Pages are mapped when they are accessed for the first time. Returns
+/// the start address of the area.
+pub fn map(address_space: &mut AddressSpace, address: Option<usize>, length: usize, protection: Protection, backing: Backing) -> Result<usize, VmaError> {
+    without_interrupts(|| map_area(address_space, address, length, protection, backing))
+}
+
+fn map_area(address_space: &mut AddressSpace, address: Option<usize>, length: usize, protection: Protection, backing: Backing) -> Result<usize, VmaError> {
+    if let Backing::File { offset, .. } = backing {
+        if offset % PAGE_SIZE as u64 != 0 {
+            return Err(VmaError::InvalidRange);
+        }
+    }
+
+    let length = page_align_up(length)
+        .filter(|&length| length != 0)
+        .ok_or(VmaError::InvalidRange)?;
+
+    let start = match address {
+        Some(address) => {
+            let end = check_range(address, length)?;
+            unmap_range(address_space, address, end)?;
+            address
+        }
+        None => address_space.memory_map.find_free(MMAP_BASE, USER_SPACE_END, length).ok_or(VmaError::NoFreeRange)?,
+    };
+
+    address_space.memory_map.insert(MemoryArea {
+        start,
+        end: start + length,
+        protection,
+        backing,
+    })?;
+
+    Ok(start)
+}
+
+/// Remove areas and mapped pages from the range.
+pub fn unmap(address_space: &mut AddressSpace, address: usize, length: usize) -> Result<(), VmaError> {
+    let end = check_range(address, length)?;
+    without_interrupts(|| unmap_range(address_space, address, end))
+}
+
+fn unmap_range(address_space: &mut AddressSpace, start: usize, end: usize) -> Result<(), VmaError> {
+    address_space.memory_map.remove(start, end)?;
+
+    for page in (start..end).step_by(PAGE_SIZE) {
+        if let Some(frame) = address_space.unmap_user_page(page) {
+            frame_allocator::free_frame(frame);
+        }
+    }
+
+    Ok(())
+}
+
+/// Change protection of the range and its mapped pages.
+pub fn protect(address_space: &mut AddressSpace, address: usize, length: usize, protection: Protection) -> Result<(), VmaError> {
+    let end = check_range(address, length)?;
+    without_interrupts(|| protect_range(address_space, address, end, protection))
+}
+
+fn protect_range(address_space: &mut AddressSpace, address: usize, end: usize, protection: Protection) -> Result<(), VmaError> {
+    address_space.memory_map.protect(address, end, protection)?;
+
+    for page in (address..end).step_by(PAGE_SIZE) {
+        if let Some(entry) = address_space.user_page_mut(page) {
+            let frame = Frame::containing_address(entry.address() as usize);
+            let mut flags = page_flags(protection);
+
+            // Shared frames must be copied before writing.
+            let shared = entry.flags().contains(L1Flags::COPY_ON_WRITE) || frame_allocator::reference_count(frame) > 1;
+            if flags.contains(L1Flags::READ_WRITE) && shared {
+                flags.remove(L1Flags::READ_WRITE);
+                flags.insert(L1Flags::COPY_ON_WRITE);
+            }
+
+            entry.flags_mut(flags);
+            address_space.flush_user_page(page);
+        }
+    }
+
+    Ok(())
+}
+
+/// Set the program break to `address`. Returns the new program break
+/// or the current program break if the heap can't be changed.
+pub fn brk(address_space: &mut AddressSpace, address: usize) -> usize {
+    without_interrupts(|| set_program_break(address_space, address))
+}
+
+fn set_program_break(address_space: &mut AddressSpace, address: usize) -> usize {
+    let memory_map = &mut address_space.memory_map;
+    let (heap_start, old_break) = (memory_map.heap_start, memory_map.heap_end);
+
+    if heap_start == 0 || address < heap_start {
+        return old_break;
+    }
+
+    let old_end = page_align_up(old_break).unwrap_or(USER_SPACE_END);
+    let new_end = match page_align_up(address) {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return old_break,
+    };
+
+    if new_end > old_end {
+        if !memory_map.is_free(old_end, new_end) {
+            return old_break;
+        }
+
+        let area = MemoryArea {
+            start: old_end,
+            end: new_end,
+            protection: Protection::READ | Protection::WRITE,
+            backing: Backing::Anonymous,
+        };
+
+        if memory_map.insert(area).is_err() {
+            return old_break;
+        }
+    } else if new_end < old_end && unmap_range(address_space, new_end, old_end).is_err() {
+        return old_break;
+    }
+
+    address_space.memory_map.heap_end = address;
+    address
+}
+
+/// Handle a page fault at `address`. Pages of areas are mapped when they
+/// are accessed for the first time, swapped out pages are read back and
+/// copy-on-write pages are copied when they are written. Returns false
+/// if the access is not allowed.
+///
+/// Interrupts are not disabled while files and swap are read. Swap
+/// changes page tables of other processes only with interrupts disabled,
+/// and it checks them again after its own I/O.
+pub fn handle_page_fault(address_space: &mut AddressSpace, address: usize, write: bool) -> bool {
+    let page = address & !(PAGE_SIZE - 1);
+
+    if address_space.user_page_mut(page).is_some() {
+        return write && address_space.handle_copy_on_write(page).unwrap_or(false);
+    }
+
+    let area = match address_space.memory_map.find(page) {
+        Some(area) => *area,
+        None => return false,
+    };
+
+    if area.protection.is_empty() || (write && !area.protection.contains(Protection::WRITE)) {
+        return false;
+    }
+
+    let swapped = address_space.user_entry_mut(page)
+        .map(|entry| swap::swapped_slot(entry).is_some())
+        .unwrap_or(false);
+
+    if swapped {
+        swap::swap_in(address_space, page, page_flags(area.protection))
+    } else {
+        populate(address_space, &area, page).is_ok()
+    }
+}
+
+/// Map a frame to `page` and fill it with the contents of the area.
+fn populate(address_space: &mut AddressSpace, area: &MemoryArea, page: usize) -> Result<(), VmaError> {
+    let frame = swap::allocate_zeroed_frame().ok_or(VmaError::OutOfMemory)?;
+
+    let result = match area.backing {
+        Backing::Anonymous => Ok(()),
+        Backing::File { vnode, offset } => read_file_page(frame, vnode, offset + (page - area.start) as u64),
+    }.and_then(|_| {
+        without_interrupts(|| address_space.map_user_page(page, frame, page_flags(area.protection)))
+            .map_err(VmaError::from)
+    });
+
+    if result.is_err() {
+        frame_allocator::free_frame(frame);
+    }
+
+    result
+}
+
+/// Read one page from a file. Part of the page after the end of the
+/// file stays zero.
+fn read_file_page(frame: Frame, vnode: VNode, offset: u64) -> Result<(), VmaError> {
+    let data = unsafe { frame.data_mut() };
+    let mut count = 0;
+
+    while count < data.len() {
+        match vfs::vfs().read_vnode(vnode, offset + count as u64, &mut data[count..])? {
+            0 => break,
+            read_count => count += read_count,
+        }
+    }
+
+    Ok(())
+}
+
+/// Areas of kernel buffers. These use the page tables of the kernel, which
+/// every address space shares.
+static KERNEL_MAP: IrqSpinlock<Option<MemoryMap>> = IrqSpinlock::new("kernel memory map", None);
+
+/// Zero filled kernel memory which is reserved from the kernel buffer
+/// range of the page tables. Frames are allocated when pages are
+/// accessed for the first time and freed when the buffer is dropped.
+/// Pages of kernel buffers are not swapped out.
+pub struct KernelBuffer {
+    start: usize,
+    length: usize,
+}
+
+impl KernelBuffer {
+    /// Reserve `length` bytes. Memory is allocated as it is used.
+    pub fn new(length: usize) -> Result<Self, VmaError> {
+        let length = page_align_up(length)
+            .filter(|&length| length != 0)
+            .ok_or(VmaError::InvalidRange)?;
+
+        let mut map = KERNEL_MAP.lock();
+        let map = map.get_or_insert_with(MemoryMap::new);
+        let start = map.find_free(KERNEL_BUFFER_START, KERNEL_BUFFER_END, length).ok_or(VmaError::NoFreeRange)?;
+
+        map.insert(MemoryArea {
+            start,
+            end: start + length,
+            protection: Protection::READ | Protection::WRITE,
+            backing: Backing::Anonymous,
+        })?;
+
+        Ok(Self {
+            start,
+            length,
+        })
+    }
+
+    /// Start address of the buffer, which is page aligned.
+    pub fn address(&self) -> usize {
+        self.start
+    }
+
+    /// Length of the buffer, rounded up to whole pages.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.start as *const u8, self.length) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.start as *mut u8, self.length) }
+    }
+}
+
+impl Drop for KernelBuffer {
+    fn drop(&mut self) {
+        let mut map = KERNEL_MAP.lock();
+
+        if let Some(map) = map.as_mut() {
+            let _ = map.remove(self.start, self.start + self.length);
+        }
+
+        for page in (self.start..self.start + self.length).step_by(PAGE_SIZE) {
+            if let Some(frame) = page_table::unmap_kernel_buffer_page(page) {
+                frame_allocator::free_frame(frame);
+            }
+        }
+    }
+}
+
+/// True if `address` is in the kernel buffer range of the page tables.
+pub fn is_kernel_buffer_address(address: usize) -> bool {
+    address >= KERNEL_BUFFER_START && address < KERNEL_BUFFER_END
+}
+
+/// Map a zeroed frame to the page of a `KernelBuffer` which contains
+/// `address`. Returns false if the address doesn't belong to a buffer or
+/// memory is low.
+pub fn handle_kernel_page_fault(address: usize) -> bool {
+    let page = address & !(PAGE_SIZE - 1);
+    let map = KERNEL_MAP.lock();
+
+    if map.as_ref().and_then(|map| map.find(page)).is_none() {
+        return false;
+    }
+
+    let frame = match frame_allocator::allocate_zeroed_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    if page_table::map_kernel_buffer_page(page, frame).is_err() {
+        frame_allocator::free_frame(frame);
+        return false;
+    }
+
+    true
+}
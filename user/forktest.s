@@ -0,0 +1,85 @@
+# Process test program. Forks a child which runs /bin/hello with
+# execve, waits for the child and exits with the exit status of the
+# child.
+
+.code32
+
+.set SYS_EXIT, 0
+.set SYS_WRITE, 2
+.set SYS_FORK, 7
+.set SYS_EXECVE, 8
+.set SYS_WAITPID, 9
+.set STDOUT, 1
+
+.text
+.global _start
+
+_start:
+    mov $SYS_FORK, %eax
+    int $0x80
+
+    test %eax, %eax
+    js fork_failed
+    jz child
+
+    # Parent waits for any child.
+    mov $SYS_WAITPID, %eax
+    mov $-1, %ebx
+    mov $status, %ecx
+    int $0x80
+
+    mov $SYS_WRITE, %eax
+    mov $STDOUT, %ebx
+    mov $parent_message, %ecx
+    mov $parent_message_length, %edx
+    int $0x80
+
+    # Exit status is in bits 8-15.
+    mov status, %ebx
+    shr $8, %ebx
+    and $0xFF, %ebx
+    mov $SYS_EXIT, %eax
+    int $0x80
+
+child:
+    mov $SYS_EXECVE, %eax
+    mov $path, %ebx
+    mov $path_length, %ecx
+    mov $arguments, %edx
+    xor %esi, %esi
+    int $0x80
+
+    # Only reached if execve failed.
+    mov $SYS_EXIT, %eax
+    mov $127, %ebx
+    int $0x80
+
+fork_failed:
+    mov $SYS_EXIT, %eax
+    mov $1, %ebx
+    int $0x80
+
+.section .rodata
+
+path:
+    .ascii "/bin/hello"
+.set path_length, . - path
+
+argument_0:
+    .asciz "hello"
+argument_1:
+    .asciz "from"
+argument_2:
+    .asciz "child"
+
+arguments:
+    .long argument_0, argument_1, argument_2, 0
+
+parent_message:
+    .ascii "Child process exited\n"
+.set parent_message_length, . - parent_message
+
+.data
+
+status:
+    .long 0
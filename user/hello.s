@@ -0,0 +1,66 @@
+# User mode test program. Prints a message and the command line
+# arguments, one per line.
+
+.code32
+
+.set SYS_EXIT, 0
+.set SYS_WRITE, 2
+.set STDOUT, 1
+
+.text
+.global _start
+
+_start:
+    mov $SYS_WRITE, %eax
+    mov $STDOUT, %ebx
+    mov $message, %ecx
+    mov $message_length, %edx
+    int $0x80
+
+    # argc
+    mov (%esp), %esi
+    # argv
+    lea 4(%esp), %edi
+
+print_argument:
+    test %esi, %esi
+    jz exit
+
+    # Find string length.
+    mov (%edi), %ecx
+    mov %ecx, %edx
+find_end:
+    cmpb $0, (%edx)
+    je write_argument
+    inc %edx
+    jmp find_end
+
+write_argument:
+    sub %ecx, %edx
+    mov $SYS_WRITE, %eax
+    mov $STDOUT, %ebx
+    int $0x80
+
+    mov $SYS_WRITE, %eax
+    mov $STDOUT, %ebx
+    mov $newline, %ecx
+    mov $1, %edx
+    int $0x80
+
+    add $4, %edi
+    dec %esi
+    jmp print_argument
+
+exit:
+    mov $SYS_EXIT, %eax
+    xor %ebx, %ebx
+    int $0x80
+
+.section .rodata
+
+message:
+    .ascii "Hello from ELF executable\n"
+.set message_length, . - message
+
+newline:
+    .ascii "\n"
@@ -0,0 +1,84 @@
+# Memory mapping test program. Maps a large anonymous area, writes to
+# its first and last page, unmaps it and grows the heap with brk.
+
+.code32
+
+.set SYS_EXIT, 0
+.set SYS_WRITE, 2
+.set SYS_MMAP, 14
+.set SYS_MUNMAP, 15
+.set SYS_BRK, 17
+.set STDOUT, 1
+
+.set PROT_READ, 1
+.set PROT_WRITE, 2
+.set MAP_PRIVATE, 0x02
+.set MAP_ANONYMOUS, 0x20
+
+.set AREA_SIZE, 4 * 1024 * 1024
+.set HEAP_INCREMENT, 8192
+
+.text
+.global _start
+
+_start:
+    mov $SYS_MMAP, %eax
+    xor %ebx, %ebx
+    mov $AREA_SIZE, %ecx
+    mov $(PROT_READ | PROT_WRITE), %edx
+    mov $(MAP_PRIVATE | MAP_ANONYMOUS), %esi
+    mov $-1, %edi
+    xor %ebp, %ebp
+    int $0x80
+
+    # Return values from -4095 to -1 are errors.
+    cmp $-4095, %eax
+    jae failed
+
+    # Pages are mapped when they are written.
+    movl $1, (%eax)
+    movl $2, AREA_SIZE - 4(%eax)
+
+    mov %eax, %ebx
+    mov $SYS_MUNMAP, %eax
+    mov $AREA_SIZE, %ecx
+    int $0x80
+
+    test %eax, %eax
+    jnz failed
+
+    mov $SYS_BRK, %eax
+    xor %ebx, %ebx
+    int $0x80
+    mov %eax, %edi
+
+    lea HEAP_INCREMENT(%edi), %ebx
+    mov $SYS_BRK, %eax
+    int $0x80
+
+    cmp %ebx, %eax
+    jne failed
+
+    movl $3, (%edi)
+    movl $4, HEAP_INCREMENT - 4(%edi)
+
+    mov $SYS_WRITE, %eax
+    mov $STDOUT, %ebx
+    mov $message, %ecx
+    mov $message_length, %edx
+    int $0x80
+
+    mov $SYS_EXIT, %eax
+    xor %ebx, %ebx
+    int $0x80
+
+failed:
+    mov $SYS_EXIT, %eax
+    mov $1, %ebx
+    int $0x80
+
+.section .rodata
+
+message:
+    .ascii "Memory mapping works\n"
+.set message_length, . - message
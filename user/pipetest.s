@@ -0,0 +1,145 @@
+# Pipe test program. Forks a child which runs /bin/hello with its
+# standard output redirected to a pipe. Parent reads the pipe until end
+# of file, writes what it read to standard output, waits for the child
+# and exits with the exit status of the child.
+
+.code32
+
+.set SYS_EXIT, 0
+.set SYS_READ, 1
+.set SYS_WRITE, 2
+.set SYS_CLOSE, 4
+.set SYS_FORK, 7
+.set SYS_EXECVE, 8
+.set SYS_WAITPID, 9
+.set SYS_PIPE, 18
+.set SYS_DUP2, 19
+.set STDOUT, 1
+.set BUFFER_SIZE, 256
+
+.text
+.global _start
+
+_start:
+    mov $SYS_PIPE, %eax
+    mov $pipe_fds, %ebx
+    int $0x80
+
+    test %eax, %eax
+    js failed
+
+    mov $SYS_FORK, %eax
+    int $0x80
+
+    test %eax, %eax
+    js failed
+    jz child
+
+    # Parent doesn't write to the pipe, so it gets end of file when
+    # the child exits.
+    mov $SYS_CLOSE, %eax
+    mov write_fd, %ebx
+    int $0x80
+
+    mov $SYS_WRITE, %eax
+    mov $STDOUT, %ebx
+    mov $parent_message, %ecx
+    mov $parent_message_length, %edx
+    int $0x80
+
+read_loop:
+    mov $SYS_READ, %eax
+    mov read_fd, %ebx
+    mov $buffer, %ecx
+    mov $BUFFER_SIZE, %edx
+    int $0x80
+
+    test %eax, %eax
+    jle read_done
+
+    mov %eax, %edx
+    mov $SYS_WRITE, %eax
+    mov $STDOUT, %ebx
+    mov $buffer, %ecx
+    int $0x80
+    jmp read_loop
+
+read_done:
+    mov $SYS_WAITPID, %eax
+    mov $-1, %ebx
+    mov $status, %ecx
+    int $0x80
+
+    # Exit status is in bits 8-15.
+    mov status, %ebx
+    shr $8, %ebx
+    and $0xFF, %ebx
+    mov $SYS_EXIT, %eax
+    int $0x80
+
+child:
+    mov $SYS_DUP2, %eax
+    mov write_fd, %ebx
+    mov $STDOUT, %ecx
+    int $0x80
+
+    mov $SYS_CLOSE, %eax
+    mov read_fd, %ebx
+    int $0x80
+
+    mov $SYS_CLOSE, %eax
+    mov write_fd, %ebx
+    int $0x80
+
+    mov $SYS_EXECVE, %eax
+    mov $path, %ebx
+    mov $path_length, %ecx
+    mov $arguments, %edx
+    xor %esi, %esi
+    int $0x80
+
+    # Only reached if execve failed.
+    mov $SYS_EXIT, %eax
+    mov $127, %ebx
+    int $0x80
+
+failed:
+    mov $SYS_EXIT, %eax
+    mov $1, %ebx
+    int $0x80
+
+.section .rodata
+
+path:
+    .ascii "/bin/hello"
+.set path_length, . - path
+
+argument_0:
+    .asciz "hello"
+argument_1:
+    .asciz "through"
+argument_2:
+    .asciz "pipe"
+
+arguments:
+    .long argument_0, argument_1, argument_2, 0
+
+parent_message:
+    .ascii "Parent read from pipe:\n"
+.set parent_message_length, . - parent_message
+
+.data
+
+pipe_fds:
+read_fd:
+    .long 0
+write_fd:
+    .long 0
+
+status:
+    .long 0
+
+.bss
+
+buffer:
+    .skip BUFFER_SIZE